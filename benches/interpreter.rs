@@ -0,0 +1,34 @@
+use chip8_wasm::cpu::CPU;
+use criterion::{criterion_group, criterion_main, Criterion};
+
+const INSTRUCTIONS_PER_FRAME: u32 = 1000;
+
+// ADD V0, 1; ADD V1, V0; XOR V2, V1; SE V0, 0; JP 0x200; ADD V3, 1; JP 0x200
+const ARITHMETIC: [u8; 14] = [0x70, 0x01, 0x81, 0x04, 0x82, 0x13, 0x30, 0x00, 0x12, 0x00, 0x73, 0x01, 0x12, 0x00];
+
+// Draws sprites at random positions and counts in BCD, roughly what games spend their time on
+// LD I, 0x300; RND V0, 0x3F; RND V1, 0x1F; DRW V0, V1, 5; LD B, V2; LD V2, [I]; ADD V2, 1; JP 0x200
+const SPRITES: [u8; 16] = [
+    0xA3, 0x00, 0xC0, 0x3F, 0xC1, 0x1F, 0xD0, 0x15, 0xF2, 0x33, 0xF2, 0x65, 0x72, 0x01, 0x12, 0x00,
+];
+
+// Compares decoding every instruction in tick() with running from the block cache
+fn bench_rom(c: &mut Criterion, name: &str, rom: &[u8]) {
+    let mut group = c.benchmark_group(name);
+    for &(label, cached) in &[("tick", false), ("block_cache", true)] {
+        let mut cpu = CPU::new();
+        cpu.load_rom(rom).unwrap();
+        cpu.seed_rng(0);
+        cpu.set_block_cache(cached);
+        group.bench_function(label, |b| b.iter(|| cpu.run_frame(INSTRUCTIONS_PER_FRAME).unwrap()));
+    }
+    group.finish();
+}
+
+fn interpreter(c: &mut Criterion) {
+    bench_rom(c, "arithmetic", &ARITHMETIC);
+    bench_rom(c, "sprites", &SPRITES);
+}
+
+criterion_group!(benches, interpreter);
+criterion_main!(benches);
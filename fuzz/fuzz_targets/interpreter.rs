@@ -0,0 +1,38 @@
+#![no_main]
+
+use chip8_wasm::cpu::{CpuError, CPU};
+use chip8_wasm::stack::StackConfig;
+use chip8_wasm::timing::Timing;
+use chip8_wasm::variant::Variant;
+use libfuzzer_sys::fuzz_target;
+
+const FRAMES: u32 = 100;
+const INSTRUCTIONS_PER_FRAME: u32 = 10;
+
+// The first byte picks the configuration: bits 0-1 the variant, bit 2 enables CDP1802 machine
+// code, bit 3 the VIP timing, bits 4-5 the stack preset and bit 6 keeps the stack in memory.
+// The next two bytes are the keypad state, the rest is an image of the address space.
+// Errors returned by the CPU are expected, only panics count as crashes. Machine code that runs
+// away ends the run, it takes a million steps each time.
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 3 {
+        return;
+    }
+
+    let config = data[0];
+    let mut cpu = CPU::from_memory(&data[3..]);
+    cpu.set_variant(Variant::all()[(config & 0b11) as usize]);
+    cpu.set_machine_code(config & 0b100 != 0);
+    if config & 0b1000 != 0 {
+        cpu.set_timing(Timing::CosmacVip);
+    }
+    let presets = StackConfig::presets();
+    let (_, stack) = presets[(config >> 4 & 0b11) as usize % presets.len()];
+    cpu.set_stack_config(stack.with_memory(config & 0b100_0000 != 0));
+    cpu.keypad_mut().set_state(u16::from_le_bytes([data[1], data[2]]));
+    for _ in 0..FRAMES {
+        if let Err(CpuError::MachineCode { .. }) = cpu.run_frame(INSTRUCTIONS_PER_FRAME) {
+            break;
+        }
+    }
+});
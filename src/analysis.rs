@@ -0,0 +1,436 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::memory::{PROGRAM_OFFSET, TOTAL_MEMORY};
+use crate::romdb::Platform;
+
+// Instructions beyond the original CHIP-8 set
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Extension {
+    // 0nnn, a call into CDP1802 machine code
+    MachineCode,
+    Schip,
+    XoChip,
+}
+
+// Instructions whose behaviour depends on a flag of Quirks
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Quirk {
+    VfReset,
+    ShiftUsesVy,
+    LoadStoreIncrementsI,
+    JumpUsesVx,
+    ClipSprites,
+}
+
+// A run of instructions that is only entered at the start and only left at the end
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Block {
+    pub start: u16,
+    // Address after the last instruction
+    pub end: u16,
+    pub successors: Vec<u16>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Analysis {
+    pub blocks: BTreeMap<u16, Block>,
+    // Start address and length of data drawn with DRW after a constant LD I, addr
+    pub sprites: BTreeMap<u16, u16>,
+    // Instructions that store into memory holding reachable code
+    pub self_modifying: BTreeSet<u16>,
+    // Bnnn, the target depends on a register and isn't followed
+    pub computed_jumps: BTreeSet<u16>,
+    pub invalid: BTreeSet<u16>,
+    pub extensions: BTreeMap<u16, Extension>,
+    pub quirk_sensitive: BTreeMap<u16, Quirk>,
+}
+
+impl Analysis {
+    pub fn is_code(&self, addr: u16) -> bool {
+        self.blocks
+            .range(..=addr)
+            .next_back()
+            .is_some_and(|(_, block)| addr < block.end)
+    }
+
+    pub fn extensions_used(&self) -> BTreeSet<Extension> {
+        self.extensions.values().copied().collect()
+    }
+
+    pub fn quirks_used(&self) -> BTreeSet<Quirk> {
+        self.quirk_sensitive.values().copied().collect()
+    }
+
+    // The oldest platform that runs every instruction found
+    pub fn platform(&self) -> Platform {
+        let extensions = self.extensions_used();
+        if extensions.contains(&Extension::XoChip) {
+            Platform::XoChip
+        } else if extensions.contains(&Extension::Schip) {
+            Platform::Schip
+        } else {
+            Platform::Chip8
+        }
+    }
+}
+
+// Where execution can continue after an instruction
+enum Flow {
+    Next,
+    Jump(u16),
+    Call(u16),
+    Skip,
+    // RET, EXIT, computed jumps and invalid instructions
+    Stop,
+}
+
+struct Decoded {
+    instr: u16,
+    // XO-CHIP's F000 nnnn is four bytes long
+    len: u16,
+    flow: Flow,
+}
+
+// Follows all paths from the program start of a ROM
+pub fn analyze_rom(rom: &[u8]) -> Analysis {
+    let mut memory = vec![0; TOTAL_MEMORY];
+    let len = rom.len().min(TOTAL_MEMORY - PROGRAM_OFFSET);
+    memory[PROGRAM_OFFSET..PROGRAM_OFFSET + len].copy_from_slice(&rom[..len]);
+    analyze(&memory, PROGRAM_OFFSET as u16)
+}
+
+pub fn analyze(memory: &[u8], entry: u16) -> Analysis {
+    analyze_entries(memory, &[entry])
+}
+
+// Same as analyze, for code that is also entered elsewhere, e.g. through jump tables
+pub fn analyze_entries(memory: &[u8], entries: &[u16]) -> Analysis {
+    let mut analysis = Analysis::default();
+
+    // Find every reachable instruction and where it continues
+    let mut decoded: BTreeMap<u16, Decoded> = BTreeMap::new();
+    let mut successors: BTreeMap<u16, Vec<u16>> = BTreeMap::new();
+    let mut todo = entries.to_vec();
+    while let Some(addr) = todo.pop() {
+        if decoded.contains_key(&addr) {
+            continue;
+        }
+
+        let instruction = match decode(memory, addr) {
+            Some(instruction) => instruction,
+            None => Decoded { instr: 0, len: 2, flow: Flow::Stop },
+        };
+        classify(&mut analysis, memory, addr, &instruction);
+
+        let next = addr.wrapping_add(instruction.len);
+        let targets = match instruction.flow {
+            Flow::Next => vec![next],
+            Flow::Jump(target) => vec![target],
+            Flow::Call(target) => vec![target, next],
+            Flow::Skip => {
+                let skipped = decode(memory, next).map_or(2, |skipped| skipped.len);
+                vec![next, next.wrapping_add(skipped)]
+            }
+            Flow::Stop => vec![],
+        };
+
+        todo.extend(targets.iter().copied());
+        successors.insert(addr, targets);
+        decoded.insert(addr, instruction);
+    }
+
+    // Blocks start at the entry, at every branch target and where paths join
+    let mut leaders: BTreeSet<u16> = BTreeSet::new();
+    let mut predecessors: BTreeMap<u16, usize> = BTreeMap::new();
+    leaders.extend(entries.iter().copied());
+    for (&addr, targets) in &successors {
+        let next = addr.wrapping_add(decoded[&addr].len);
+        if targets.as_slice() != [next] {
+            leaders.extend(targets.iter().copied());
+        }
+        for &target in targets {
+            *predecessors.entry(target).or_insert(0) += 1;
+        }
+    }
+    leaders.extend(predecessors.iter().filter(|&(_, &count)| count > 1).map(|(&addr, _)| addr));
+
+    for &start in &leaders {
+        let mut addr = start;
+        loop {
+            let next = addr.wrapping_add(decoded[&addr].len);
+            let targets = &successors[&addr];
+            if targets.as_slice() == [next] && !leaders.contains(&next) {
+                addr = next;
+                continue;
+            }
+
+            analysis.blocks.insert(
+                start,
+                Block {
+                    start,
+                    end: next,
+                    successors: targets.clone(),
+                },
+            );
+            break;
+        }
+    }
+
+    track_i(&mut analysis, &decoded);
+    analysis
+}
+
+fn fetch(memory: &[u8], addr: u16) -> Option<u16> {
+    let high = *memory.get(addr as usize)?;
+    let low = *memory.get(addr as usize + 1)?;
+    Some((high as u16) << 8 | low as u16)
+}
+
+fn decode(memory: &[u8], addr: u16) -> Option<Decoded> {
+    let instr = fetch(memory, addr)?;
+    let (len, flow) = match instr >> 12 {
+        0x0 if instr == 0x00EE || instr == 0x00FD => (2, Flow::Stop),
+        0x1 => (2, Flow::Jump(instr & 0x0FFF)),
+        0x2 => (2, Flow::Call(instr & 0x0FFF)),
+        0x3 | 0x4 => (2, Flow::Skip),
+        0x5 | 0x9 if instr & 0x000F == 0 => (2, Flow::Skip),
+        0xB => (2, Flow::Stop),
+        0xE if instr & 0x00FF == 0x9E || instr & 0x00FF == 0xA1 => (2, Flow::Skip),
+        0xF if instr == 0xF000 => {
+            fetch(memory, addr.wrapping_add(2))?;
+            (4, Flow::Next)
+        }
+        _ if is_valid(instr) => (2, Flow::Next),
+        _ => (2, Flow::Stop),
+    };
+    Some(Decoded { instr, len, flow })
+}
+
+fn is_valid(instr: u16) -> bool {
+    extension(instr).is_some()
+        || match instr >> 12 {
+            0x0 => instr == 0x00E0 || instr == 0x00EE,
+            0x5 | 0x9 => instr & 0x000F == 0,
+            0x8 => matches!(instr & 0x000F, 0x0..=0x7 | 0xE),
+            0xE => matches!(instr & 0x00FF, 0x9E | 0xA1),
+            0xF => matches!(instr & 0x00FF, 0x07 | 0x0A | 0x15 | 0x18 | 0x1E | 0x29 | 0x33 | 0x55 | 0x65),
+            _ => true,
+        }
+}
+
+fn extension(instr: u16) -> Option<Extension> {
+    match instr >> 12 {
+        0x0 => match instr {
+            0x00E0 | 0x00EE => None,
+            0x00FB..=0x00FF => Some(Extension::Schip),
+            _ if instr & 0xFFF0 == 0x00C0 => Some(Extension::Schip),
+            _ if instr & 0xFFF0 == 0x00D0 => Some(Extension::XoChip),
+            _ => Some(Extension::MachineCode),
+        },
+        0x5 if matches!(instr & 0x000F, 0x2 | 0x3) => Some(Extension::XoChip),
+        0xD if instr & 0x000F == 0 => Some(Extension::Schip),
+        0xF if instr == 0xF000 || instr == 0xF002 => Some(Extension::XoChip),
+        0xF => match instr & 0x00FF {
+            0x30 | 0x75 | 0x85 => Some(Extension::Schip),
+            0x01 | 0x3A => Some(Extension::XoChip),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn quirk(instr: u16) -> Option<Quirk> {
+    match instr >> 12 {
+        0x8 => match instr & 0x000F {
+            0x1..=0x3 => Some(Quirk::VfReset),
+            0x6 | 0xE => Some(Quirk::ShiftUsesVy),
+            _ => None,
+        },
+        0xB => Some(Quirk::JumpUsesVx),
+        0xD => Some(Quirk::ClipSprites),
+        0xF if matches!(instr & 0x00FF, 0x55 | 0x65) => Some(Quirk::LoadStoreIncrementsI),
+        _ => None,
+    }
+}
+
+fn classify(analysis: &mut Analysis, memory: &[u8], addr: u16, instruction: &Decoded) {
+    let instr = instruction.instr;
+    if fetch(memory, addr).is_none() || !is_valid(instr) {
+        analysis.invalid.insert(addr);
+        return;
+    }
+
+    if instr >> 12 == 0xB {
+        analysis.computed_jumps.insert(addr);
+    }
+    if let Some(extension) = extension(instr) {
+        analysis.extensions.insert(addr, extension);
+    }
+    if let Some(quirk) = quirk(instr) {
+        analysis.quirk_sensitive.insert(addr, quirk);
+    }
+}
+
+// Follows constant values of I through each block to find sprites and stores into code. I is
+// unknown at the start of a block and after anything that computes it.
+fn track_i(analysis: &mut Analysis, decoded: &BTreeMap<u16, Decoded>) {
+    let mut sprites = Vec::new();
+    let mut stores = Vec::new();
+    for block in analysis.blocks.values() {
+        let mut i: Option<u16> = None;
+        let mut addr = block.start;
+        while addr != block.end {
+            let instruction = &decoded[&addr];
+            let instr = instruction.instr;
+            match instr >> 12 {
+                0xA => i = Some(instr & 0x0FFF),
+                0xD => {
+                    if let Some(i) = i {
+                        let len = match instr & 0x000F {
+                            0 => 32,
+                            n => n,
+                        };
+                        sprites.push((i, len));
+                    }
+                }
+                // F000 nnnn points past the 4K this analysis covers
+                0xF if instr == 0xF000 => i = None,
+                0xF => match instr & 0x00FF {
+                    0x33 => stores.push((addr, i, 3)),
+                    0x55 => {
+                        stores.push((addr, i, ((instr >> 8) & 0x0F) + 1));
+                        i = None;
+                    }
+                    0x1E | 0x29 | 0x30 | 0x65 => i = None,
+                    _ => {}
+                },
+                _ => {}
+            }
+            addr = addr.wrapping_add(instruction.len);
+        }
+    }
+
+    for (start, len) in sprites {
+        let entry = analysis.sprites.entry(start).or_insert(0);
+        *entry = (*entry).max(len);
+    }
+    for (addr, i, len) in stores {
+        if let Some(i) = i {
+            if (i..i.saturating_add(len)).any(|target| analysis.is_code(target)) {
+                analysis.self_modifying.insert(addr);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rom(instrs: &[u16]) -> Vec<u8> {
+        instrs.iter().flat_map(|instr| instr.to_be_bytes()).collect()
+    }
+
+    fn block(start: u16, end: u16, successors: &[u16]) -> Block {
+        Block {
+            start,
+            end,
+            successors: successors.to_vec(),
+        }
+    }
+
+    #[test]
+    fn control_flow() {
+        let analysis = analyze_rom(&rom(&[
+            0x6000, // 200: LD V0, 0
+            0x220C, // 202: CALL 20C
+            0x3001, // 204: SE V0, 1
+            0x1204, // 206: JP 204
+            0x120A, // 208: JP 20A
+            0x00FF, // 20A: HIGH
+            0x7001, // 20C: ADD V0, 1
+            0x00EE, // 20E: RET
+        ]));
+
+        assert_eq!(
+            vec![
+                block(0x200, 0x204, &[0x20C, 0x204]),
+                block(0x204, 0x206, &[0x206, 0x208]),
+                block(0x206, 0x208, &[0x204]),
+                // A jump to the next instruction doesn't end a block
+                block(0x208, 0x20C, &[0x20C]),
+                block(0x20C, 0x210, &[]),
+            ],
+            analysis.blocks.values().cloned().collect::<Vec<_>>()
+        );
+        assert!(analysis.is_code(0x20E));
+        assert!(!analysis.is_code(0x210));
+        assert!(analysis.invalid.is_empty());
+
+        // 00FF is reached through the skip and jump, so it is SCHIP code
+        assert_eq!(Platform::Schip, analysis.platform());
+    }
+
+    #[test]
+    fn computed_jumps_and_invalid() {
+        let analysis = analyze_rom(&rom(&[
+            0x3000, // 200: SE V0, 0
+            0xB300, // 202: JP V0, 300
+            0x5121, // 204: invalid
+        ]));
+
+        assert_eq!(vec![0x202], analysis.computed_jumps.iter().copied().collect::<Vec<_>>());
+        assert_eq!(vec![0x204], analysis.invalid.iter().copied().collect::<Vec<_>>());
+        assert_eq!(Some(&Quirk::JumpUsesVx), analysis.quirk_sensitive.get(&0x202));
+        assert_eq!(Platform::Chip8, analysis.platform());
+    }
+
+    #[test]
+    fn sprites_and_self_modification() {
+        let analysis = analyze_rom(&rom(&[
+            0xA20E, // 200: LD I, 20E
+            0xD015, // 202: DRW V0, V1, 5
+            0xD013, // 204: DRW V0, V1, 3
+            0xA202, // 206: LD I, 202
+            0xF155, // 208: LD [I], V1
+            0xF065, // 20A: LD V0, [I]
+            0x120C, // 20C: JP 20C
+            0xF090, // 20E: sprite
+        ]));
+
+        assert_eq!(vec![(0x20E, 5)], analysis.sprites.iter().map(|(&a, &l)| (a, l)).collect::<Vec<_>>());
+        assert_eq!(vec![0x208], analysis.self_modifying.iter().copied().collect::<Vec<_>>());
+        assert_eq!(
+            vec![Quirk::LoadStoreIncrementsI, Quirk::ClipSprites],
+            analysis.quirks_used().into_iter().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn extensions() {
+        let analysis = analyze_rom(&rom(&[
+            0x00E0, // 200: CLS
+            0x0300, // 202: SYS 300
+            0x00FE, // 204: LOW
+            0x3000, // 206: SE V0, 0
+            0xF000, // 208: LD I, long 0x0220
+            0x0220, // 20A
+            0xD010, // 20C: DRW V0, V1, 0
+            0x00FD, // 20E: EXIT
+        ]));
+
+        assert_eq!(
+            vec![
+                (0x202, Extension::MachineCode),
+                (0x204, Extension::Schip),
+                (0x208, Extension::XoChip),
+                (0x20C, Extension::Schip),
+                (0x20E, Extension::Schip),
+            ],
+            analysis.extensions.into_iter().collect::<Vec<_>>()
+        );
+
+        // The skip jumps over all four bytes of F000 nnnn
+        assert_eq!(Some(&block(0x200, 0x208, &[0x208, 0x20C])), analysis.blocks.get(&0x200));
+    }
+}
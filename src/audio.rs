@@ -0,0 +1,261 @@
+use crate::cpu::CPU;
+
+pub const DEFAULT_FREQUENCY: f32 = 440.0;
+pub const DEFAULT_VOLUME: f32 = 0.25;
+pub const DEFAULT_SAMPLE_RATE: u32 = 44100;
+
+pub const FRAMES_PER_SECOND: u64 = 60;
+
+// Implemented by native frontends to receive the generated PCM samples.
+pub trait AudioSink {
+    fn sample_rate(&self) -> u32;
+
+    // Samples are mono, in the range -1.0..=1.0
+    fn queue_samples(&mut self, samples: &[f32]);
+}
+
+// Band-limited square wave generator for the CHIP-8 beeper. Aliasing at the edges is
+// suppressed using PolyBLEP, see: https://www.martin-finke.de/articles/audio-plugins-018-polyblep-oscillator/
+#[derive(Debug)]
+pub struct SquareWave {
+    frequency: f32,
+    volume: f32,
+    sample_rate: u32,
+
+    // Position within the current period, 0.0..1.0
+    phase: f32,
+}
+
+impl SquareWave {
+    pub fn new(sample_rate: u32) -> Self {
+        SquareWave {
+            frequency: DEFAULT_FREQUENCY,
+            volume: DEFAULT_VOLUME,
+            sample_rate,
+            phase: 0.0,
+        }
+    }
+
+    pub fn frequency(&self) -> f32 {
+        self.frequency
+    }
+
+    pub fn set_frequency(&mut self, frequency: f32) {
+        // Stay below nyquist, otherwise the wave folds back into audible garbage
+        let nyquist = self.sample_rate as f32 / 2.0;
+        self.frequency = frequency.clamp(0.0, nyquist);
+    }
+
+    pub fn volume(&self) -> f32 {
+        self.volume
+    }
+
+    pub fn set_volume(&mut self, volume: f32) {
+        self.volume = volume.clamp(0.0, 1.0);
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+        self.set_frequency(self.frequency);
+    }
+
+    // Fills the whole buffer with either the tone or silence. The phase restarts on silence so
+    // every beep starts the same way.
+    pub fn fill(&mut self, buffer: &mut [f32], active: bool) {
+        if !active || self.sample_rate == 0 {
+            self.phase = 0.0;
+            for sample in buffer.iter_mut() {
+                *sample = 0.0;
+            }
+            return;
+        }
+
+        let dt = self.frequency / self.sample_rate as f32;
+        for sample in buffer.iter_mut() {
+            let mut value = if self.phase < 0.5 { 1.0 } else { -1.0 };
+            value += poly_blep(self.phase, dt);
+            value -= poly_blep((self.phase + 0.5) % 1.0, dt);
+            *sample = value * self.volume;
+
+            self.phase += dt;
+            if self.phase >= 1.0 {
+                self.phase -= 1.0;
+            }
+        }
+    }
+}
+
+// Plays the samples MEGA-CHIP programs start, 8-bit unsigned PCM in the machine's memory. It
+// adds to whatever is in the buffer already, so it goes on top of the beeper.
+#[derive(Debug)]
+pub struct SamplePlayer {
+    volume: f32,
+    sample_rate: u32,
+
+    // Id of the sample being played and the position in it, in its own samples
+    playing: Option<u32>,
+    position: f64,
+}
+
+impl SamplePlayer {
+    pub fn new(sample_rate: u32) -> Self {
+        SamplePlayer {
+            volume: DEFAULT_VOLUME,
+            sample_rate,
+            playing: None,
+            position: 0.0,
+        }
+    }
+
+    pub fn volume(&self) -> f32 {
+        self.volume
+    }
+
+    pub fn set_volume(&mut self, volume: f32) {
+        self.volume = volume.clamp(0.0, 1.0);
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+    }
+
+    // Continues the CPU's current sample, or starts it over if the program started it again.
+    // Samples that don't loop stay silent once they're done.
+    pub fn mix(&mut self, cpu: &CPU, buffer: &mut [f32]) {
+        let sample = match cpu.sample() {
+            Some(sample) if sample.len > 0 && self.sample_rate > 0 => sample,
+            _ => {
+                self.playing = None;
+                return;
+            }
+        };
+        if self.playing != Some(sample.id) {
+            self.playing = Some(sample.id);
+            self.position = 0.0;
+        }
+
+        let memory = cpu.memory();
+        let step = sample.rate as f64 / self.sample_rate as f64;
+        for out in buffer.iter_mut() {
+            if self.position >= sample.len as f64 {
+                if !sample.looping {
+                    break;
+                }
+                self.position %= sample.len as f64;
+            }
+            let byte = memory[(sample.addr as usize + self.position as usize) % memory.len()];
+            *out += (byte as f32 - 128.0) / 128.0 * self.volume;
+            self.position += step;
+        }
+    }
+}
+
+// Number of samples that belong to the given frame. Rounding is spread over the frames so that
+// the running total always matches the sample rate.
+pub(crate) fn samples_in_frame(frame: u64, sample_rate: u32) -> usize {
+    let sample_rate = sample_rate as u64;
+    let start = frame * sample_rate / FRAMES_PER_SECOND;
+    let end = (frame + 1) * sample_rate / FRAMES_PER_SECOND;
+    (end - start) as usize
+}
+
+// Polynomial correction around a discontinuity at t = 0
+fn poly_blep(t: f32, dt: f32) -> f32 {
+    if t < dt {
+        let t = t / dt;
+        t + t - t * t - 1.0
+    } else if t > 1.0 - dt {
+        let t = (t - 1.0) / dt;
+        t * t + t + t + 1.0
+    } else {
+        0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn silence() {
+        let mut wave = SquareWave::new(DEFAULT_SAMPLE_RATE);
+        let mut buffer = [1.0; 64];
+        wave.fill(&mut buffer, false);
+        assert!(buffer.iter().all(|&s| s == 0.0));
+    }
+
+    #[test]
+    fn tone() {
+        let mut wave = SquareWave::new(8000);
+        wave.set_frequency(100.0);
+        wave.set_volume(0.5);
+
+        // One full period is 80 samples
+        let mut buffer = [0.0; 80];
+        wave.fill(&mut buffer, true);
+
+        assert!(buffer.iter().all(|&s| s.abs() <= 0.5));
+        assert_eq!(0.5, buffer[20]);
+        assert_eq!(-0.5, buffer[60]);
+
+        // Edges are smoothed instead of jumping straight between the extremes
+        assert!(buffer[40].abs() < 0.5);
+        assert!(buffer[0].abs() < 0.5);
+    }
+
+    #[test]
+    fn samples() {
+        // Header: 1000Hz and 4 samples, then a square wave
+        let rom = [
+            0x01, 0x00, 0x02, 0x08, // LDHI I, 0x000208
+            0x06, 0x00, // DIGISND 0
+            0x12, 0x06, // JP 0x206
+            0x03, 0xE8, 0x00, 0x00, 0x04, 0x00,
+            0xFF, 0xFF, 0x01, 0x01,
+        ];
+        let mut cpu = CPU::new();
+        cpu.set_variant(crate::variant::Variant::MegaChip);
+        cpu.load_rom(&rom).unwrap();
+        let mut player = SamplePlayer::new(2000);
+        player.set_volume(1.0);
+
+        let mut buffer = [0.0; 4];
+        player.mix(&cpu, &mut buffer);
+        assert_eq!([0.0; 4], buffer);
+
+        cpu.run_instructions(2).unwrap();
+        let mut buffer = [0.5; 10];
+        player.mix(&cpu, &mut buffer);
+        let expected = 0.5 + 127.0 / 128.0;
+        assert_eq!(expected, buffer[0]);
+        assert_eq!(expected, buffer[3]);
+        assert_eq!(0.5 - 127.0 / 128.0, buffer[4]);
+        // Looped back to the start
+        assert_eq!(expected, buffer[8]);
+
+        // Starting it again restarts it
+        player.mix(&cpu, &mut [0.0; 3]);
+        cpu.set_ip(0x204);
+        cpu.run_instructions(1).unwrap();
+        let mut buffer = [0.0; 1];
+        player.mix(&cpu, &mut buffer);
+        assert_eq!(127.0 / 128.0, buffer[0]);
+    }
+
+    #[test]
+    fn clamp_settings() {
+        let mut wave = SquareWave::new(8000);
+        wave.set_frequency(10000.0);
+        assert_eq!(4000.0, wave.frequency());
+
+        wave.set_volume(2.0);
+        assert_eq!(1.0, wave.volume());
+
+        wave.set_volume(-1.0);
+        assert_eq!(0.0, wave.volume());
+    }
+}
@@ -0,0 +1,228 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+use crate::cpu::{CpuError, LoadError, CPU, DEFAULT_INSTRUCTIONS_PER_FRAME};
+use crate::font::Font;
+use crate::headless::{Framebuffer, Headless, InputScript};
+use crate::quirks::Quirks;
+use crate::stack::StackConfig;
+use crate::timing::Timing;
+use crate::variant::Variant;
+
+// One ROM run of a batch. The seed is fixed so every run of the same job ends the same way.
+#[derive(Debug, Clone)]
+pub struct Job {
+    rom: Vec<u8>,
+    frames: u32,
+    seed: u64,
+    quirks: Quirks,
+    variant: Variant,
+    stack_config: StackConfig,
+    timing: Timing,
+    font: Font,
+    machine_code: bool,
+    instructions_per_frame: u32,
+    input: InputScript,
+}
+
+impl Job {
+    pub fn new(rom: &[u8], frames: u32) -> Self {
+        Job {
+            rom: rom.to_vec(),
+            frames,
+            seed: 0,
+            quirks: Quirks::default(),
+            variant: Variant::default(),
+            stack_config: StackConfig::default(),
+            timing: Timing::default(),
+            font: Font::default(),
+            machine_code: false,
+            instructions_per_frame: DEFAULT_INSTRUCTIONS_PER_FRAME,
+            input: InputScript::new(),
+        }
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    pub fn with_quirks(mut self, quirks: Quirks) -> Self {
+        self.quirks = quirks;
+        self
+    }
+
+    pub fn with_variant(mut self, variant: Variant) -> Self {
+        self.variant = variant;
+        self
+    }
+
+    pub fn with_stack_config(mut self, stack_config: StackConfig) -> Self {
+        self.stack_config = stack_config;
+        self
+    }
+
+    // With Timing::CosmacVip the instructions per frame are ignored
+    pub fn with_timing(mut self, timing: Timing) -> Self {
+        self.timing = timing;
+        self
+    }
+
+    pub fn with_font(mut self, font: Font) -> Self {
+        self.font = font;
+        self
+    }
+
+    // Runs 0nnn as CDP1802 machine code, see CPU::set_machine_code
+    pub fn with_machine_code(mut self, enabled: bool) -> Self {
+        self.machine_code = enabled;
+        self
+    }
+
+    pub fn with_instructions_per_frame(mut self, instructions_per_frame: u32) -> Self {
+        self.instructions_per_frame = instructions_per_frame;
+        self
+    }
+
+    pub fn with_input(mut self, input: InputScript) -> Self {
+        self.input = input;
+        self
+    }
+
+    fn run(&self) -> Result<Outcome, LoadError> {
+        // The variant goes first, it decides where the program is loaded
+        let mut cpu = CPU::new();
+        cpu.set_variant(self.variant);
+        cpu.set_quirks(self.quirks);
+        cpu.set_stack_config(self.stack_config);
+        cpu.set_timing(self.timing);
+        cpu.set_font(self.font);
+        cpu.set_machine_code(self.machine_code);
+        cpu.seed_rng(self.seed);
+        cpu.load_rom(&self.rom)?;
+
+        let mut headless = Headless::new(cpu)
+            .with_instructions_per_frame(self.instructions_per_frame)
+            .with_input(self.input.clone());
+        let error = headless.run_frames(self.frames).err();
+        Ok(Outcome {
+            frames: headless.frame(),
+            error,
+            state_hash: headless.cpu().state_hash(),
+            screen: Framebuffer::from_screen(headless.cpu().screen()),
+        })
+    }
+}
+
+// How a job ended. A CPU error stops the job early, frames tells how many completed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Outcome {
+    pub frames: u32,
+    pub error: Option<CpuError>,
+    pub state_hash: u64,
+    pub screen: Framebuffer,
+}
+
+// Runs the jobs on up to the given number of threads, the results are in the order of the jobs.
+// Threads pick up the next job as soon as they are done, so long and short runs mix well.
+pub fn run_batch(jobs: &[Job], threads: usize) -> Vec<Result<Outcome, LoadError>> {
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new(Vec::with_capacity(jobs.len()));
+
+    thread::scope(|scope| {
+        for _ in 0..threads.clamp(1, jobs.len().max(1)) {
+            scope.spawn(|| loop {
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                let job = match jobs.get(index) {
+                    Some(job) => job,
+                    None => break,
+                };
+                let result = job.run();
+                results.lock().unwrap().push((index, result));
+            });
+        }
+    });
+
+    let mut results = results.into_inner().unwrap();
+    results.sort_by_key(|&(index, _)| index);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+// One thread per core, or a single one if that can't be determined
+pub fn default_threads() -> usize {
+    thread::available_parallelism().map_or(1, |threads| threads.get())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::font::FontSet;
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn cpu_is_send() {
+        assert_send_sync::<CPU>();
+        assert_send_sync::<Job>();
+    }
+
+    #[test]
+    fn matches_sequential_runs() {
+        // Draws random bytes as sprites, so every seed ends with a different screen
+        let rom = [0xC0, 0xFF, 0xA3, 0x00, 0xF0, 0x55, 0xD1, 0x21, 0x71, 0x01, 0x12, 0x00];
+        let mut jobs: Vec<Job> = (0..24)
+            .map(|seed| Job::new(&rom, 30 + seed as u32).with_seed(seed))
+            .collect();
+        jobs.push(Job::new(&[], 10));
+        // Runs into an invalid instruction
+        jobs.push(Job::new(&[0x00, 0xE0, 0xFF, 0xFF], 10).with_quirks(Quirks::cosmac_vip()));
+
+        let results = run_batch(&jobs, 4);
+        assert_eq!(jobs.len(), results.len());
+        for (job, result) in jobs.iter().zip(&results).take(24) {
+            assert_eq!(&job.run().unwrap(), result.as_ref().unwrap());
+            assert_eq!(job.frames, result.as_ref().unwrap().frames);
+        }
+        assert_ne!(results[0].as_ref().unwrap().screen, results[1].as_ref().unwrap().screen);
+
+        assert!(matches!(results[24], Err(LoadError::Empty)));
+        let failed = results[25].as_ref().unwrap();
+        assert_eq!(0, failed.frames);
+        assert_eq!(Some(CpuError::InvalidInstruction { instr: 0xFFFF, addr: 0x202 }), failed.error);
+
+        assert!(run_batch(&[], 4).is_empty());
+        let single: Vec<Option<Outcome>> = run_batch(&jobs, 1).into_iter().map(Result::ok).collect();
+        assert_eq!(results.into_iter().map(Result::ok).collect::<Vec<_>>(), single);
+    }
+
+    #[test]
+    fn configured() {
+        // CALL 0x306; JP 0x302; LD I, digit 0; RET, on CHIP-8X it's loaded at 0x300
+        let rom = [0x23, 0x06, 0x13, 0x02, 0xF0, 0x29, 0x00, 0xEE];
+        let font = Font::new(FontSet::Dream6800).with_address(0x20).unwrap();
+        let job = Job::new(&rom, 5)
+            .with_variant(Variant::Chip8X)
+            .with_stack_config(StackConfig::cosmac_vip().with_memory(true))
+            .with_timing(Timing::CosmacVip)
+            .with_font(font)
+            .with_machine_code(true);
+        let outcome = &run_batch(&[job], 2)[0];
+
+        let mut cpu = CPU::new();
+        cpu.set_variant(Variant::Chip8X);
+        cpu.set_stack_config(StackConfig::cosmac_vip().with_memory(true));
+        cpu.set_timing(Timing::CosmacVip);
+        cpu.set_font(font);
+        cpu.set_machine_code(true);
+        cpu.seed_rng(0);
+        cpu.load_rom(&rom).unwrap();
+        let mut headless = Headless::new(cpu);
+        headless.run_frames(5).unwrap();
+        assert_eq!(headless.cpu().state_hash(), outcome.as_ref().unwrap().state_hash);
+        assert_eq!(None, outcome.as_ref().unwrap().error);
+
+        // Without the configuration the same program ends up somewhere else
+        assert_ne!(Job::new(&rom, 5).run().unwrap().state_hash, outcome.as_ref().unwrap().state_hash);
+    }
+}
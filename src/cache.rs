@@ -0,0 +1,175 @@
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::instr::{self, Instr};
+use crate::memory::TOTAL_MEMORY;
+use crate::variant::Variant;
+
+// Blocks end after this many instructions even without a branch. This bounds how far back a
+// write has to look for blocks that contain it.
+const MAX_BLOCK_LEN: usize = 64;
+
+// Predecoded straight-line runs of instructions, keyed by the address of the first one. Only the
+// last instruction of a block may branch, so a block runs front to back without checking IP.
+pub struct BlockCache {
+    blocks: Vec<Option<Box<[Instr]>>>,
+    // How many blocks contain each byte, so writes to plain data are cheap to dismiss
+    coverage: Vec<u16>,
+    // Blocks are decoded for this variant
+    variant: Variant,
+}
+
+impl BlockCache {
+    pub fn new() -> Self {
+        BlockCache::with_size(TOTAL_MEMORY)
+    }
+
+    fn with_size(size: usize) -> Self {
+        BlockCache {
+            blocks: vec![None; size],
+            coverage: vec![0; size],
+            variant: Variant::default(),
+        }
+    }
+
+    // Drops every block if the variant changes, they would decode differently. Covers as much
+    // of the variant's memory as IP can reach.
+    pub fn set_variant(&mut self, variant: Variant) {
+        if variant != self.variant {
+            *self = BlockCache::with_size(variant.memory_size().min(1 << 16));
+            self.variant = variant;
+        }
+    }
+
+    pub fn contains(&self, addr: usize) -> bool {
+        self.blocks.get(addr).is_some_and(|block| block.is_some())
+    }
+
+    // Length of the block at addr, decoding it first if needed. 0 if nothing can be cached
+    // there: the address is at the end of memory or holds an invalid instruction.
+    pub fn lookup(&mut self, memory: &[u8], addr: usize) -> usize {
+        let size = self.blocks.len();
+        if addr + 1 >= size {
+            return 0;
+        }
+        if let Some(block) = &self.blocks[addr] {
+            return block.len();
+        }
+
+        let mut instrs = Vec::new();
+        let mut pc = addr;
+        while pc + 1 < size && instrs.len() < MAX_BLOCK_LEN {
+            let decoded = match instr::decode(u16::from_be_bytes([memory[pc], memory[pc + 1]]), self.variant) {
+                Some(decoded) => decoded,
+                // Left to the interpreter, which reports the error
+                None => break,
+            };
+            instrs.push(decoded);
+            pc += 2;
+            if decoded.is_branch() {
+                break;
+            }
+        }
+
+        let len = instrs.len();
+        if len > 0 {
+            for count in &mut self.coverage[addr..pc] {
+                *count += 1;
+            }
+            self.blocks[addr] = Some(instrs.into_boxed_slice());
+        }
+        len
+    }
+
+    // The block at addr has to be cached
+    pub fn instr(&self, addr: usize, index: usize) -> Instr {
+        self.blocks[addr].as_ref().unwrap()[index]
+    }
+
+    // Drops all blocks that contain any byte from start to end, inclusive
+    pub fn invalidate(&mut self, start: usize, end: usize) {
+        if start >= self.blocks.len() {
+            return;
+        }
+        let end = end.min(self.blocks.len() - 1);
+        if self.coverage[start..=end].iter().all(|&count| count == 0) {
+            return;
+        }
+
+        for addr in start.saturating_sub(MAX_BLOCK_LEN * 2 - 1)..=end {
+            let len = match &self.blocks[addr] {
+                Some(block) => block.len() * 2,
+                None => continue,
+            };
+            if addr + len > start {
+                self.blocks[addr] = None;
+                for count in &mut self.coverage[addr..addr + len] {
+                    *count -= 1;
+                }
+            }
+        }
+    }
+}
+
+impl Default for BlockCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocks() {
+        let mut memory = [0; TOTAL_MEMORY];
+        // LD V0, 1; ADD V0, 2; SE V0, 3; JP 0x200; LD V1, 4; invalid
+        memory[0x200..0x20C].copy_from_slice(&[0x60, 0x01, 0x70, 0x02, 0x30, 0x03, 0x12, 0x00, 0x61, 0x04, 0xFF, 0xFF]);
+
+        let mut cache = BlockCache::new();
+        assert_eq!(3, cache.lookup(&memory, 0x200));
+        assert_eq!(Instr::SeImm(0, 3), cache.instr(0x200, 2));
+        // Blocks may overlap
+        assert_eq!(2, cache.lookup(&memory, 0x202));
+        assert_eq!(1, cache.lookup(&memory, 0x206));
+        assert_eq!(1, cache.lookup(&memory, 0x208));
+        assert_eq!(0, cache.lookup(&memory, 0x20A));
+        assert!(!cache.contains(0x20A));
+
+        // Data next to code doesn't affect it
+        cache.invalidate(0x20A, 0x20B);
+        assert!(cache.contains(0x208));
+
+        cache.invalidate(0x203, 0x203);
+        assert!(!cache.contains(0x200));
+        assert!(!cache.contains(0x202));
+        assert!(cache.contains(0x206));
+        assert_eq!(0, cache.coverage[0x200]);
+        assert_eq!(1, cache.coverage[0x206]);
+
+        // Straight-line code gets split up, the end of memory stops a block
+        let memory = [0x60; TOTAL_MEMORY];
+        assert_eq!(MAX_BLOCK_LEN, cache.lookup(&memory, 0x300));
+        assert_eq!(3, cache.lookup(&memory, 0xFFA));
+        assert_eq!(0, cache.lookup(&memory, 0xFFF));
+        assert_eq!(0, cache.lookup(&memory, 0xFFFF));
+    }
+
+    #[test]
+    fn variant() {
+        let mut memory = [0; TOTAL_MEMORY];
+        // JP V0, 0x312 on CHIP-8, colours a row on CHIP-8X; LD V1, 4; invalid
+        memory[0x200..0x206].copy_from_slice(&[0xB3, 0x12, 0x61, 0x04, 0xFF, 0xFF]);
+
+        let mut cache = BlockCache::new();
+        assert_eq!(1, cache.lookup(&memory, 0x200));
+        cache.set_variant(Variant::Chip8X);
+        assert!(!cache.contains(0x200));
+        assert_eq!(2, cache.lookup(&memory, 0x200));
+        assert_eq!(Instr::ColourRows(3, 1, 2), cache.instr(0x200, 0));
+        cache.set_variant(Variant::Chip8X);
+        assert!(cache.contains(0x200));
+    }
+}
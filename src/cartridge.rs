@@ -0,0 +1,379 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use serde::Deserialize;
+
+use crate::cpu::{LoadError, CPU, DEFAULT_INSTRUCTIONS_PER_FRAME};
+use crate::font::{Font, FontSet};
+use crate::octo::{self, AssembleError};
+use crate::quirks::Quirks;
+use crate::romdb::{self, Colors};
+use crate::stack::StackConfig;
+use crate::variant::Variant;
+
+// Octo's keyboard layout, the left four columns of a QWERTY keyboard
+const OCTO_KEYMAP: [(&str, u8); 16] = [
+    ("1", 0x1),
+    ("2", 0x2),
+    ("3", 0x3),
+    ("4", 0xC),
+    ("q", 0x4),
+    ("w", 0x5),
+    ("e", 0x6),
+    ("r", 0xD),
+    ("a", 0x7),
+    ("s", 0x8),
+    ("d", 0x9),
+    ("f", 0xE),
+    ("z", 0xA),
+    ("x", 0x0),
+    ("c", 0xB),
+    ("v", 0xF),
+];
+
+#[derive(Debug)]
+pub enum CartridgeError {
+    Gif(gif::DecodingError),
+    // The length prefix asks for more bytes than the image holds
+    Truncated { expected: usize, actual: usize },
+    Json(serde_json::Error),
+    // The Octo source the cartridge holds doesn't assemble
+    Assemble(AssembleError),
+}
+
+impl fmt::Display for CartridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CartridgeError::Gif(err) => write!(f, "Invalid cartridge image: {}", err),
+            CartridgeError::Truncated { expected, actual } => write!(
+                f,
+                "Cartridge payload is truncated, expected {} bytes but only {} are present",
+                expected, actual
+            ),
+            CartridgeError::Json(err) => write!(f, "Invalid cartridge payload: {}", err),
+            CartridgeError::Assemble(err) => write!(f, "Invalid cartridge program: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for CartridgeError {}
+
+impl From<gif::DecodingError> for CartridgeError {
+    fn from(err: gif::DecodingError) -> Self {
+        CartridgeError::Gif(err)
+    }
+}
+
+impl From<serde_json::Error> for CartridgeError {
+    fn from(err: serde_json::Error) -> Self {
+        CartridgeError::Json(err)
+    }
+}
+
+impl From<AssembleError> for CartridgeError {
+    fn from(err: AssembleError) -> Self {
+        CartridgeError::Assemble(err)
+    }
+}
+
+// Settings stored by Octo next to the program. A quirk flag set to true selects the SCHIP
+// behaviour, missing flags keep Octo's defaults.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct OctoOptions {
+    // Instructions per frame
+    pub tickrate: Option<u32>,
+    pub fill_color: Option<String>,
+    pub background_color: Option<String>,
+    pub shift_quirks: Option<bool>,
+    pub load_store_quirks: Option<bool>,
+    pub clip_quirks: Option<bool>,
+    pub jump_quirks: Option<bool>,
+    pub logic_quirks: Option<bool>,
+    pub font_style: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Program {
+    Bytes(Vec<u8>),
+    Source(String),
+}
+
+#[derive(Deserialize)]
+struct Payload {
+    program: Program,
+    #[serde(default)]
+    options: OctoOptions,
+}
+
+// An Octo cartridge: a GIF whose pixels carry the program and its options. The low two bits of
+// every pixel's palette index, over all frames and most significant pair first, form a 32 bit
+// big endian length followed by that many bytes of UTF-8 JSON:
+//
+// { "program": ": main\n  clear ...", "options": { "tickrate": 20, "shiftQuirks": false, ... } }
+//
+// Octo saves the source text as the program, which is assembled on loading. The assembled bytes
+// are accepted as well, as an array of numbers.
+pub struct Cartridge {
+    pub program: Vec<u8>,
+    pub options: OctoOptions,
+}
+
+impl Cartridge {
+    pub fn from_gif(data: &[u8]) -> Result<Self, CartridgeError> {
+        let payload = extract_payload(data)?;
+        let payload: Payload = serde_json::from_slice(&payload)?;
+
+        let program = match payload.program {
+            Program::Bytes(program) => program,
+            Program::Source(source) => octo::assemble(&source)?,
+        };
+        Ok(Cartridge {
+            program,
+            options: payload.options,
+        })
+    }
+
+    pub fn is_gif(data: &[u8]) -> bool {
+        data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a")
+    }
+
+    pub fn quirks(&self) -> Quirks {
+        // Octo's defaults match XO-CHIP
+        let options = &self.options;
+        let quirks = Quirks::xo_chip();
+        Quirks {
+            vf_reset: options.logic_quirks.unwrap_or(quirks.vf_reset),
+            shift_uses_vy: options.shift_quirks.map_or(quirks.shift_uses_vy, |quirk| !quirk),
+            load_store_increments_i: options
+                .load_store_quirks
+                .map_or(quirks.load_store_increments_i, |quirk| !quirk),
+            jump_uses_vx: options.jump_quirks.unwrap_or(quirks.jump_uses_vx),
+            clip_sprites: options.clip_quirks.unwrap_or(quirks.clip_sprites),
+        }
+    }
+
+    pub fn instructions_per_frame(&self) -> u32 {
+        self.options.tickrate.unwrap_or(DEFAULT_INSTRUCTIONS_PER_FRAME)
+    }
+
+    // Octo's own glyphs unless another known style is selected
+    pub fn font(&self) -> Font {
+        let set = self.options.font_style.as_deref().and_then(FontSet::from_name);
+        Font::new(set.unwrap_or(FontSet::Octo))
+    }
+
+    // Only present if both colours are given and valid
+    pub fn colors(&self) -> Option<Colors> {
+        let foreground = romdb::parse_rgb(self.options.fill_color.as_ref()?)?;
+        let background = romdb::parse_rgb(self.options.background_color.as_ref()?)?;
+        Some(Colors { foreground, background })
+    }
+
+    pub fn keymap(&self) -> BTreeMap<String, u8> {
+        OCTO_KEYMAP.iter().map(|&(name, key)| (name.to_string(), key)).collect()
+    }
+
+    // Loads the program and switches to the quirks and font it was written for. Whatever ran
+    // before may have left another variant or stack behind, Octo programs expect its own.
+    pub fn load(&self, cpu: &mut CPU) -> Result<(), LoadError> {
+        cpu.set_variant(Variant::Chip8);
+        cpu.set_stack_config(StackConfig::octo());
+        cpu.load_rom(&self.program)?;
+        cpu.set_quirks(self.quirks());
+        cpu.set_font(self.font());
+        Ok(())
+    }
+}
+
+fn extract_payload(data: &[u8]) -> Result<Vec<u8>, CartridgeError> {
+    let mut options = gif::DecodeOptions::new();
+    options.set_color_output(gif::ColorOutput::Indexed);
+    let mut decoder = options.read_info(data)?;
+
+    let mut bytes = Vec::new();
+    let mut byte = 0u8;
+    let mut pairs = 0;
+    while let Some(frame) = decoder.read_next_frame()? {
+        for &index in frame.buffer.iter() {
+            byte = (byte << 2) | (index & 0b11);
+            pairs += 1;
+            if pairs == 4 {
+                bytes.push(byte);
+                byte = 0;
+                pairs = 0;
+            }
+        }
+    }
+
+    if bytes.len() < 4 {
+        return Err(CartridgeError::Truncated { expected: 4, actual: bytes.len() });
+    }
+    let actual = bytes.len() - 4;
+    let expected = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize;
+    if expected > actual {
+        return Err(CartridgeError::Truncated { expected, actual });
+    }
+
+    Ok(bytes[4..4 + expected].to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WIDTH: u16 = 32;
+    const HEIGHT: u16 = 16;
+
+    // Builds a cartridge the way Octo does, spreading the payload over as many frames as needed
+    fn cartridge(json: &str) -> Vec<u8> {
+        let mut data = (json.len() as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(json.as_bytes());
+
+        let mut indices: Vec<u8> = data
+            .iter()
+            .flat_map(|&byte| (0..4).rev().map(move |pair| (byte >> (pair * 2)) & 0b11))
+            // The upper bits are the picture, they must not matter
+            .enumerate()
+            .map(|(i, pair)| ((i % 4) as u8) << 2 | pair)
+            .collect();
+        let frame_size = WIDTH as usize * HEIGHT as usize;
+        let frames = indices.len().div_ceil(frame_size);
+        indices.resize(frames * frame_size, 0);
+
+        let palette: Vec<u8> = (0..16).flat_map(|i| [i * 16, i * 16, i * 16]).collect();
+        let mut gif = Vec::new();
+        {
+            let mut encoder = gif::Encoder::new(&mut gif, WIDTH, HEIGHT, &palette).unwrap();
+            for chunk in indices.chunks(frame_size) {
+                let frame = gif::Frame::from_indexed_pixels(WIDTH, HEIGHT, chunk.to_vec(), None);
+                encoder.write_frame(&frame).unwrap();
+            }
+        }
+        gif
+    }
+
+    #[test]
+    fn load() {
+        let json = r##"{
+            "program": [0, 224, 18, 0],
+            "options": {
+                "tickrate": 500,
+                "fillColor": "#FFCC00",
+                "fillColor2": "#FF6600",
+                "backgroundColor": "#996600",
+                "shiftQuirks": true,
+                "loadStoreQuirks": true,
+                "clipQuirks": true,
+                "jumpQuirks": false,
+                "logicQuirks": false,
+                "vBlankQuirks": false,
+                "fontStyle": "vip"
+            }
+        }"##;
+        let gif = cartridge(json);
+        assert!(Cartridge::is_gif(&gif));
+
+        let cartridge = Cartridge::from_gif(&gif).unwrap();
+        assert_eq!(vec![0x00, 0xE0, 0x12, 0x00], cartridge.program);
+        assert_eq!(500, cartridge.instructions_per_frame());
+        assert_eq!(
+            Some(Colors {
+                foreground: [0xFF, 0xCC, 0x00],
+                background: [0x99, 0x66, 0x00],
+            }),
+            cartridge.colors()
+        );
+        assert_eq!(Some(&0x5), cartridge.keymap().get("w"));
+
+        let mut cpu = CPU::new();
+        cpu.set_variant(Variant::Chip8X);
+        cpu.set_stack_config(StackConfig::cosmac_vip());
+        cartridge.load(&mut cpu).unwrap();
+        assert_eq!(Variant::Chip8, cpu.variant());
+        assert_eq!(StackConfig::octo(), cpu.stack_config());
+        assert_eq!(&[0x00, 0xE0, 0x12, 0x00], &cpu.memory()[0x200..0x204]);
+        assert_eq!(
+            Quirks {
+                vf_reset: false,
+                shift_uses_vy: false,
+                load_store_increments_i: false,
+                jump_uses_vx: false,
+                clip_sprites: true,
+            },
+            cpu.quirks()
+        );
+        assert_eq!(&Font::new(FontSet::CosmacVip), cpu.font());
+    }
+
+    #[test]
+    fn defaults() {
+        // Long enough to need several frames
+        let program: Vec<String> = (0..300).map(|i| (i % 256).to_string()).collect();
+        let gif = cartridge(&format!(r#"{{ "program": [{}] }}"#, program.join(",")));
+
+        let cartridge = Cartridge::from_gif(&gif).unwrap();
+        assert_eq!(300, cartridge.program.len());
+        assert_eq!(Quirks::xo_chip(), cartridge.quirks());
+        assert_eq!(DEFAULT_INSTRUCTIONS_PER_FRAME, cartridge.instructions_per_frame());
+        assert_eq!(None, cartridge.colors());
+        assert_eq!(Font::default(), cartridge.font());
+    }
+
+    #[test]
+    fn source() {
+        // The payload as Octo writes it, the source with every option of the editor
+        let json = r##"{
+            "program": "# Chip8 is a virtual machine designed in 1977 for programming video games.\n: main\n\tclear\n\tv0 := 0\n\tloop\n\t\tv0 += 1\n\tagain\n",
+            "options": {
+                "tickrate": 20,
+                "fillColor": "#FFCC00",
+                "fillColor2": "#FF6600",
+                "blendColor": "#662200",
+                "backgroundColor": "#996600",
+                "buzzColor": "#FFAA00",
+                "quietColor": "#000000",
+                "shiftQuirks": false,
+                "loadStoreQuirks": false,
+                "vfOrderQuirks": false,
+                "clipQuirks": false,
+                "vBlankQuirks": false,
+                "jumpQuirks": false,
+                "screenRotation": 0,
+                "maxSize": 3584,
+                "touchInputMode": "none",
+                "logicQuirks": false,
+                "fontStyle": "octo",
+                "displayScale": 4
+            }
+        }"##;
+        let cartridge = Cartridge::from_gif(&cartridge(json)).unwrap();
+        assert_eq!(vec![0x00, 0xE0, 0x60, 0x00, 0x70, 0x01, 0x12, 0x04], cartridge.program);
+        assert_eq!(20, cartridge.instructions_per_frame());
+    }
+
+    #[test]
+    fn invalid() {
+        let source = cartridge(r#"{ "program": ": main\n  loop" }"#);
+        assert!(matches!(Cartridge::from_gif(&source), Err(CartridgeError::Assemble(_))));
+
+        let json = cartridge(r#"{ "options": {} }"#);
+        assert!(matches!(Cartridge::from_gif(&json), Err(CartridgeError::Json(_))));
+
+        assert!(matches!(Cartridge::from_gif(b"GIF89a"), Err(CartridgeError::Gif(_))));
+
+        // Cut off payload: the length claims more bytes than the image carries
+        let mut gif = Vec::new();
+        {
+            let palette = [0; 12];
+            let mut encoder = gif::Encoder::new(&mut gif, 16, 1, &palette).unwrap();
+            let frame = gif::Frame::from_indexed_pixels(16, 1, vec![0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 3, 3], None);
+            encoder.write_frame(&frame).unwrap();
+        }
+        assert!(matches!(
+            Cartridge::from_gif(&gif),
+            Err(CartridgeError::Truncated { expected: 15, actual: 0 })
+        ));
+    }
+}
@@ -0,0 +1,321 @@
+// RCA CDP1802, the processor of the COSMAC VIP. CHIP-8 programs for the VIP call into machine
+// code with 0nnn, which runs until the routine returns to the interpreter with SEP R4.
+// Instruction set from the RCA CDP1802 user manual: http://www.cosmacelf.com/publications/data-sheets/cdp1802.pdf
+//
+// The I/O lines aren't connected: OUT discards its byte, INP reads 0 and the EF flags are clear.
+
+// The interpreter's register conventions on a 4K VIP
+pub const VARIABLES: u16 = 0xEF0;
+pub const STACK_TOP: u16 = 0xECF;
+pub const DISPLAY: u16 = 0xF00;
+pub const REG_PC: usize = 3;
+pub const REG_INTERPRETER: usize = 4;
+pub const REG_CHIP8_PC: usize = 5;
+pub const REG_VX: usize = 6;
+pub const REG_VY: usize = 7;
+pub const REG_TIMERS: usize = 8;
+pub const REG_I: usize = 0xA;
+pub const REG_DISPLAY_PAGE: usize = 0xB;
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Cdp1802 {
+    pub r: [u16; 16],
+    pub d: u8,
+    pub df: bool,
+    // Indices of the program counter and the data pointer among r
+    pub p: u8,
+    pub x: u8,
+    pub t: u8,
+    pub ie: bool,
+    pub q: bool,
+}
+
+impl Cdp1802 {
+    pub fn new() -> Self {
+        Cdp1802::default()
+    }
+
+    fn read(memory: &[u8], addr: u16) -> u8 {
+        memory[addr as usize % memory.len()]
+    }
+
+    fn write(memory: &mut [u8], addr: u16, value: u8) {
+        let len = memory.len();
+        memory[addr as usize % len] = value;
+    }
+
+    // Reads the byte at the program counter and advances it
+    fn immediate(&mut self, memory: &[u8]) -> u8 {
+        let p = self.p as usize;
+        let value = Cdp1802::read(memory, self.r[p]);
+        self.r[p] = self.r[p].wrapping_add(1);
+        value
+    }
+
+    fn rx(&self, memory: &[u8]) -> u8 {
+        Cdp1802::read(memory, self.r[self.x as usize])
+    }
+
+    // D - operand, DF is set if there was no borrow
+    fn subtract(&mut self, minuend: u8, subtrahend: u8, borrow: bool) {
+        let result = minuend as i16 - subtrahend as i16 - borrow as i16;
+        self.d = result as u8;
+        self.df = result >= 0;
+    }
+
+    fn add(&mut self, a: u8, b: u8, carry: bool) {
+        let result = a as u16 + b as u16 + carry as u16;
+        self.d = result as u8;
+        self.df = result > 0xFF;
+    }
+
+    // Runs one instruction and returns the machine cycles it took
+    pub fn step(&mut self, memory: &mut [u8]) -> u32 {
+        let opcode = self.immediate(memory);
+        let n = (opcode & 0x0F) as usize;
+        let p = self.p as usize;
+        let x = self.x as usize;
+
+        match opcode >> 4 {
+            0x0 if n == 0 => {} // IDL, DMA and interrupts are handled by the host
+            0x0 => self.d = Cdp1802::read(memory, self.r[n]), // LDN
+            0x1 => self.r[n] = self.r[n].wrapping_add(1), // INC
+            0x2 => self.r[n] = self.r[n].wrapping_sub(1), // DEC
+            0x3 => {
+                // Short branches replace the low byte of the program counter
+                let target = self.immediate(memory);
+                if self.short_condition(n) {
+                    self.r[p] = (self.r[p] & 0xFF00) | target as u16;
+                }
+            }
+            0x4 => {
+                // LDA
+                self.d = Cdp1802::read(memory, self.r[n]);
+                self.r[n] = self.r[n].wrapping_add(1);
+            }
+            0x5 => Cdp1802::write(memory, self.r[n], self.d), // STR
+            0x6 => match n {
+                0x0..=0x7 => self.r[x] = self.r[x].wrapping_add(1), // IRX, OUT
+                0x8 => {}
+                _ => {
+                    // INP
+                    self.d = 0;
+                    Cdp1802::write(memory, self.r[x], 0);
+                }
+            },
+            0x7 => match n {
+                0x0 | 0x1 => {
+                    // RET, DIS
+                    let value = self.rx(memory);
+                    self.r[x] = self.r[x].wrapping_add(1);
+                    self.x = value >> 4;
+                    self.p = value & 0x0F;
+                    self.ie = n == 0x0;
+                }
+                0x2 => {
+                    // LDXA
+                    self.d = self.rx(memory);
+                    self.r[x] = self.r[x].wrapping_add(1);
+                }
+                0x3 => {
+                    // STXD
+                    Cdp1802::write(memory, self.r[x], self.d);
+                    self.r[x] = self.r[x].wrapping_sub(1);
+                }
+                0x4 => self.add(self.rx(memory), self.d, self.df), // ADC
+                0x5 => self.subtract(self.rx(memory), self.d, !self.df), // SDB
+                0x6 => {
+                    // SHRC
+                    let carry = self.d & 1 != 0;
+                    self.d = (self.d >> 1) | (self.df as u8) << 7;
+                    self.df = carry;
+                }
+                0x7 => self.subtract(self.d, self.rx(memory), !self.df), // SMB
+                0x8 => Cdp1802::write(memory, self.r[x], self.t), // SAV
+                0x9 => {
+                    // MARK
+                    self.t = self.x << 4 | self.p;
+                    Cdp1802::write(memory, self.r[2], self.t);
+                    self.x = self.p;
+                    self.r[2] = self.r[2].wrapping_sub(1);
+                }
+                0xA => self.q = false, // REQ
+                0xB => self.q = true,  // SEQ
+                0xC => {
+                    // ADCI
+                    let value = self.immediate(memory);
+                    self.add(value, self.d, self.df);
+                }
+                0xD => {
+                    // SDBI
+                    let value = self.immediate(memory);
+                    self.subtract(value, self.d, !self.df);
+                }
+                0xE => {
+                    // SHLC
+                    let carry = self.d & 0x80 != 0;
+                    self.d = (self.d << 1) | self.df as u8;
+                    self.df = carry;
+                }
+                _ => {
+                    // SMBI
+                    let value = self.immediate(memory);
+                    self.subtract(self.d, value, !self.df);
+                }
+            },
+            0x8 => self.d = self.r[n] as u8,        // GLO
+            0x9 => self.d = (self.r[n] >> 8) as u8, // GHI
+            0xA => self.r[n] = (self.r[n] & 0xFF00) | self.d as u16, // PLO
+            0xB => self.r[n] = (self.r[n] & 0x00FF) | (self.d as u16) << 8, // PHI
+            0xC => {
+                // Long branches and skips, all of them take an extra cycle
+                let taken = self.long_condition(n);
+                if matches!(n, 0x0..=0x3 | 0x9..=0xB) {
+                    let high = Cdp1802::read(memory, self.r[p]);
+                    let low = Cdp1802::read(memory, self.r[p].wrapping_add(1));
+                    self.r[p] = if taken { u16::from_be_bytes([high, low]) } else { self.r[p].wrapping_add(2) };
+                } else if taken {
+                    self.r[p] = self.r[p].wrapping_add(2);
+                }
+                return 3;
+            }
+            0xD => self.p = n as u8, // SEP
+            0xE => self.x = n as u8, // SEX
+            _ => match n {
+                0x0 => self.d = self.rx(memory), // LDX
+                0x1 => self.d |= self.rx(memory), // OR
+                0x2 => self.d &= self.rx(memory), // AND
+                0x3 => self.d ^= self.rx(memory), // XOR
+                0x4 => self.add(self.rx(memory), self.d, false), // ADD
+                0x5 => self.subtract(self.rx(memory), self.d, false), // SD
+                0x6 => {
+                    // SHR
+                    self.df = self.d & 1 != 0;
+                    self.d >>= 1;
+                }
+                0x7 => self.subtract(self.d, self.rx(memory), false), // SM
+                0xE => {
+                    // SHL
+                    self.df = self.d & 0x80 != 0;
+                    self.d <<= 1;
+                }
+                _ => {
+                    let value = self.immediate(memory);
+                    match n {
+                        0x8 => self.d = value, // LDI
+                        0x9 => self.d |= value, // ORI
+                        0xA => self.d &= value, // ANI
+                        0xB => self.d ^= value, // XRI
+                        0xC => self.add(value, self.d, false), // ADI
+                        0xD => self.subtract(value, self.d, false), // SDI
+                        _ => self.subtract(self.d, value, false), // SMI
+                    }
+                }
+            },
+        }
+        2
+    }
+
+    // 0x30 - 0x3F: BR, BQ, BZ, BDF, B1-B4, then the same negated with NBR first
+    fn short_condition(&self, n: usize) -> bool {
+        let condition = match n & 0x7 {
+            0x0 => true,
+            0x1 => self.q,
+            0x2 => self.d == 0,
+            0x3 => self.df,
+            _ => false,
+        };
+        condition != (n & 0x8 != 0)
+    }
+
+    // 0xC0 - 0xCF: LBR, LBQ, LBZ, LBDF, NOP, LSNQ, LSNZ, LSNF, LSKP, LBNQ, LBNZ, LBNF, LSIE, LSQ,
+    // LSZ, LSDF
+    fn long_condition(&self, n: usize) -> bool {
+        match n {
+            0x0 => true,
+            0x1 => self.q,
+            0x2 => self.d == 0,
+            0x3 => self.df,
+            0x4 => false,
+            0x5 => !self.q,
+            0x6 => self.d != 0,
+            0x7 => !self.df,
+            0x8 => true,
+            0x9 => !self.q,
+            0xA => self.d != 0,
+            0xB => !self.df,
+            0xC => self.ie,
+            0xD => self.q,
+            0xE => self.d == 0,
+            _ => self.df,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(program: &[u8], steps: usize) -> (Cdp1802, Vec<u8>) {
+        let mut memory = vec![0; 0x1000];
+        memory[..program.len()].copy_from_slice(program);
+        let mut cpu = Cdp1802::new();
+        for _ in 0..steps {
+            cpu.step(&mut memory);
+        }
+        (cpu, memory)
+    }
+
+    #[test]
+    fn arithmetic() {
+        // LDI 0xF0; ADI 0x20; PHI R1; SMI 0x20; PLO R1
+        let (cpu, _) = run(&[0xF8, 0xF0, 0xFC, 0x20, 0xB1, 0xFF, 0x20, 0xA1], 5);
+        assert_eq!(0x10F0, cpu.r[1]);
+        // 0x10 - 0x20 borrows
+        assert_eq!(0xF0, cpu.d);
+        assert!(!cpu.df);
+
+        // LDI 0x81; SHL; SHLC; SHRC
+        let (cpu, _) = run(&[0xF8, 0x81, 0xFE, 0x7E, 0x76], 3);
+        assert_eq!(0x05, cpu.d);
+        assert!(!cpu.df);
+        let (cpu, _) = run(&[0xF8, 0x81, 0xFE, 0x7E, 0x76], 4);
+        assert_eq!(0x02, cpu.d);
+        assert!(cpu.df);
+    }
+
+    #[test]
+    fn memory() {
+        // LDI 0x01; PHI R2; LDI 0x10; PLO R2; SEX R2; LDI 0xAB; STXD; LDI 0xCD; STXD; IRX; LDXA; LDN R2
+        let program = [0xF8, 0x01, 0xB2, 0xF8, 0x10, 0xA2, 0xE2, 0xF8, 0xAB, 0x73, 0xF8, 0xCD, 0x73, 0x60, 0x72, 0x02];
+        let (cpu, memory) = run(&program, 12);
+        assert_eq!(&[0xCD, 0xAB], &memory[0x10F..0x111]);
+        assert_eq!(0x0110, cpu.r[2]);
+        assert_eq!(0xAB, cpu.d);
+    }
+
+    #[test]
+    fn branches() {
+        // LDI 0; BZ 0x06; LDI 1; LBNZ 0x0100; SEQ; LSQ; IDL; IDL; SEP R4
+        let program = [0xF8, 0x00, 0x32, 0x06, 0xF8, 0x01, 0xCA, 0x01, 0x00, 0x7B, 0xCD, 0x00, 0x00, 0xD4];
+        let (cpu, _) = run(&program, 6);
+        assert_eq!(0, cpu.d);
+        assert!(cpu.q);
+        assert_eq!(4, cpu.p);
+
+        // MARK saves X and P, RET restores them
+        let mut memory = vec![0; 0x1000];
+        memory[..2].copy_from_slice(&[0x79, 0x70]);
+        let mut cpu = Cdp1802::new();
+        cpu.r[2] = 0x80;
+        cpu.x = 5;
+        assert_eq!(2, cpu.step(&mut memory));
+        assert_eq!(0x50, memory[0x80]);
+        assert_eq!((0, 0), (cpu.x, cpu.p));
+        cpu.r[1] = 0x80;
+        cpu.x = 1;
+        cpu.step(&mut memory);
+        assert_eq!((5, 0), (cpu.x, cpu.p));
+        assert!(cpu.ie);
+    }
+}
@@ -0,0 +1,587 @@
+// Self-checking conformance ROMs, assembled in place so every expectation can follow the quirks
+// under test. Each ROM runs a series of numbered checks and finishes by drawing an 'A'. A failing
+// check clears the screen and draws 'F' followed by its number in hex instead, which shows up in
+// the screen diff of the headless runner.
+
+use crate::cpu::CPU;
+use crate::headless::{Framebuffer, Headless, InputScript};
+use crate::quirks::Quirks;
+
+const FAIL: u16 = 0x202;
+const SCRATCH: u16 = 0x800;
+
+// Enough to finish every check within the first frame, so timers only move when a ROM waits
+pub const INSTRUCTIONS_PER_FRAME: u32 = 1000;
+pub const FRAMES: u32 = 10;
+
+// Held for the whole run, used by the keypad checks
+pub const HELD_KEY: u8 = 0x5;
+
+const PASS: &str = "
+    ####
+    #..#
+    ####
+    #..#
+    #..#
+";
+
+// Independent copy of the glyphs, to catch changes in font.rs
+const GLYPHS: [[u8; 5]; 16] = [
+    [0xF0, 0x90, 0x90, 0x90, 0xF0],
+    [0x20, 0x60, 0x20, 0x20, 0x70],
+    [0xF0, 0x10, 0xF0, 0x80, 0xF0],
+    [0xF0, 0x10, 0xF0, 0x10, 0xF0],
+    [0x90, 0x90, 0xF0, 0x10, 0x10],
+    [0xF0, 0x80, 0xF0, 0x10, 0xF0],
+    [0xF0, 0x80, 0xF0, 0x90, 0xF0],
+    [0xF0, 0x10, 0x20, 0x40, 0x40],
+    [0xF0, 0x90, 0xF0, 0x90, 0xF0],
+    [0xF0, 0x90, 0xF0, 0x10, 0xF0],
+    [0xF0, 0x90, 0xF0, 0x90, 0x90],
+    [0xE0, 0x90, 0xE0, 0x90, 0xE0],
+    [0xF0, 0x80, 0x80, 0x80, 0xF0],
+    [0xE0, 0x90, 0x90, 0x90, 0xE0],
+    [0xF0, 0x80, 0xF0, 0x80, 0xF0],
+    [0xF0, 0x80, 0xF0, 0x80, 0x80],
+];
+
+struct Rom {
+    bytes: Vec<u8>,
+    checks: u8,
+}
+
+impl Rom {
+    fn new() -> Self {
+        let mut rom = Rom {
+            bytes: Vec::new(),
+            checks: 0,
+        };
+        let start = rom.forward_jump();
+        assert_eq!(FAIL, rom.addr());
+
+        rom.op(0x00E0); // CLS
+        rom.op(0x600F); // LD V0, 0xF
+        rom.op(0xF029); // LD F, V0
+        rom.op(0x6100); // LD V1, 0
+        rom.op(0xD115); // DRW V1, V1, 5
+
+        // High nibble of the check number. Shifting V0 onto itself works with either shift quirk.
+        rom.op(0x80E0); // LD V0, VE
+        for _ in 0..4 {
+            rom.op(0x8006); // SHR V0, V0
+        }
+        rom.op(0xF029); // LD F, V0
+        rom.op(0x6105); // LD V1, 5
+        rom.op(0x6200); // LD V2, 0
+        rom.op(0xD125); // DRW V1, V2, 5
+
+        // Low nibble
+        rom.op(0x80E0); // LD V0, VE
+        rom.op(0x630F); // LD V3, 0xF
+        rom.op(0x8032); // AND V0, V3
+        rom.op(0xF029); // LD F, V0
+        rom.op(0x610A); // LD V1, 10
+        rom.op(0xD125); // DRW V1, V2, 5
+        rom.op(0x1000 | rom.addr()); // JP self
+
+        rom.land(start);
+        rom
+    }
+
+    fn addr(&self) -> u16 {
+        0x200 + self.bytes.len() as u16
+    }
+
+    fn op(&mut self, op: u16) {
+        self.bytes.extend_from_slice(&op.to_be_bytes());
+    }
+
+    // Emits a JP that gets its target once land() is called
+    fn forward_jump(&mut self) -> u16 {
+        let at = self.addr();
+        self.op(0x1000);
+        at
+    }
+
+    fn land(&mut self, at: u16) {
+        let target = 0x1000 | self.addr();
+        let index = (at - 0x200) as usize;
+        self.bytes[index..index + 2].copy_from_slice(&target.to_be_bytes());
+    }
+
+    // Numbers the following expectations in VE
+    fn check(&mut self) {
+        self.checks += 1;
+        self.op(0x6E00 | self.checks as u16); // LD VE, n
+    }
+
+    fn expect(&mut self, register: u8, value: u8) {
+        self.op(0x3000 | (register as u16) << 8 | value as u16); // SE Vx, byte
+        self.op(0x1000 | FAIL);
+    }
+
+    fn expect_skip(&mut self, op: u16) {
+        self.op(op);
+        self.op(0x1000 | FAIL);
+    }
+
+    fn expect_no_skip(&mut self, op: u16) {
+        self.op(op);
+        self.op(0x1000 | (self.addr() + 4));
+        self.op(0x1000 | FAIL);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        self.op(0x00E0); // CLS
+        self.op(0x600A); // LD V0, 0xA
+        self.op(0xF029); // LD F, V0
+        self.op(0x6100); // LD V1, 0
+        self.op(0xD115); // DRW V1, V1, 5
+        self.op(0x1000 | self.addr()); // JP self
+        self.bytes
+    }
+}
+
+fn opcodes() -> Vec<u8> {
+    let mut rom = Rom::new();
+
+    rom.check(); // 6xkk
+    rom.op(0x6142);
+    rom.expect(1, 0x42);
+
+    rom.check(); // 7xkk wraps and leaves VF alone
+    rom.op(0x6F07);
+    rom.op(0x61FF);
+    rom.op(0x7102);
+    rom.expect(1, 0x01);
+    rom.expect(0xF, 0x07);
+
+    rom.check(); // 3xkk, 4xkk
+    rom.op(0x6105);
+    rom.expect_skip(0x3105);
+    rom.expect_no_skip(0x3106);
+    rom.expect_skip(0x4106);
+    rom.expect_no_skip(0x4105);
+
+    rom.check(); // 5xy0, 9xy0
+    rom.op(0x6205);
+    rom.expect_skip(0x5120);
+    rom.expect_no_skip(0x9120);
+    rom.op(0x6206);
+    rom.expect_no_skip(0x5120);
+    rom.expect_skip(0x9120);
+
+    rom.check(); // 8xy0 - 8xy3
+    rom.op(0x6233);
+    rom.op(0x8120);
+    rom.expect(1, 0x33);
+    rom.op(0x620F);
+    rom.op(0x8121);
+    rom.expect(1, 0x3F);
+    rom.op(0x6133);
+    rom.op(0x8122);
+    rom.expect(1, 0x03);
+    rom.op(0x6133);
+    rom.op(0x8123);
+    rom.expect(1, 0x3C);
+
+    rom.check(); // Annn, Fx55, Fx65
+    rom.op(0x6012);
+    rom.op(0x6134);
+    rom.op(0xA000 | SCRATCH);
+    rom.op(0xF155);
+    rom.op(0x6000);
+    rom.op(0x6100);
+    rom.op(0xA000 | SCRATCH);
+    rom.op(0xF165);
+    rom.expect(0, 0x12);
+    rom.expect(1, 0x34);
+
+    rom.check(); // Fx1E
+    rom.op(0xA000 | SCRATCH);
+    rom.op(0x6201);
+    rom.op(0xF21E);
+    rom.op(0xF065);
+    rom.expect(0, 0x34);
+
+    rom.check(); // 1nnn
+    let skip = rom.forward_jump();
+    rom.op(0x1000 | FAIL);
+    rom.land(skip);
+
+    rom.check(); // 2nnn, 00EE
+    let skip = rom.forward_jump();
+    let sub = rom.addr();
+    rom.op(0x6155);
+    rom.op(0x00EE);
+    rom.land(skip);
+    rom.op(0x6100);
+    rom.op(0x2000 | sub);
+    rom.expect(1, 0x55);
+
+    rom.check(); // Cxkk
+    rom.op(0x61FF);
+    rom.op(0xC100);
+    rom.expect(1, 0x00);
+
+    rom.check(); // 00E0
+    rom.op(0x6000);
+    rom.op(0xF029);
+    rom.op(0xD005);
+    rom.op(0x00E0);
+    rom.op(0xD005);
+    rom.expect(0xF, 0);
+    rom.op(0x00E0);
+
+    rom.check(); // Ex9E, ExA1
+    rom.op(0x6100 | HELD_KEY as u16);
+    rom.expect_skip(0xE19E);
+    rom.expect_no_skip(0xE1A1);
+    rom.op(0x6100 | (HELD_KEY as u16 + 1));
+    rom.expect_no_skip(0xE19E);
+    rom.expect_skip(0xE1A1);
+
+    rom.check(); // Fx0A
+    rom.op(0x6100);
+    rom.op(0xF10A);
+    rom.expect(1, HELD_KEY);
+
+    rom.check(); // Fx15, Fx07, counting down to zero
+    rom.op(0x6103);
+    rom.op(0xF115);
+    rom.op(0xF207);
+    rom.expect(2, 3);
+    let wait = rom.addr();
+    rom.op(0xF207);
+    rom.op(0x3200);
+    rom.op(0x1000 | wait);
+
+    rom.check(); // Fx18
+    rom.op(0x6101);
+    rom.op(0xF118);
+
+    rom.finish()
+}
+
+fn flags() -> Vec<u8> {
+    let mut rom = Rom::new();
+
+    rom.check(); // 8xy4
+    rom.op(0x61F0);
+    rom.op(0x6220);
+    rom.op(0x8124);
+    rom.expect(1, 0x10);
+    rom.expect(0xF, 1);
+    rom.op(0x6101);
+    rom.op(0x8124);
+    rom.expect(1, 0x21);
+    rom.expect(0xF, 0);
+
+    rom.check(); // 8xy5, no borrow on equal values
+    rom.op(0x6130);
+    rom.op(0x8125);
+    rom.expect(1, 0x10);
+    rom.expect(0xF, 1);
+    rom.op(0x8125);
+    rom.expect(1, 0xF0);
+    rom.expect(0xF, 0);
+    rom.op(0x6120);
+    rom.op(0x8125);
+    rom.expect(1, 0x00);
+    rom.expect(0xF, 1);
+
+    rom.check(); // 8xy7
+    rom.op(0x6110);
+    rom.op(0x8127);
+    rom.expect(1, 0x10);
+    rom.expect(0xF, 1);
+    rom.op(0x6140);
+    rom.op(0x8127);
+    rom.expect(1, 0xE0);
+    rom.expect(0xF, 0);
+
+    rom.check(); // 8xy6, 8xyE on a single register
+    rom.op(0x6105);
+    rom.op(0x8116);
+    rom.expect(1, 0x02);
+    rom.expect(0xF, 1);
+    rom.op(0x8116);
+    rom.expect(1, 0x01);
+    rom.expect(0xF, 0);
+    rom.op(0x6181);
+    rom.op(0x811E);
+    rom.expect(1, 0x02);
+    rom.expect(0xF, 1);
+    rom.op(0x811E);
+    rom.expect(1, 0x04);
+    rom.expect(0xF, 0);
+
+    rom.check(); // VF as operand, the flag wins over the result
+    rom.op(0x6FF0);
+    rom.op(0x6120);
+    rom.op(0x8F14);
+    rom.expect(0xF, 1);
+    rom.op(0x6F10);
+    rom.op(0x8F15);
+    rom.expect(0xF, 0);
+    rom.op(0x6F10);
+    rom.op(0x8F17);
+    rom.expect(0xF, 1);
+    rom.op(0x6F02);
+    rom.op(0x8FF6);
+    rom.expect(0xF, 0);
+    rom.op(0x6F81);
+    rom.op(0x8FFE);
+    rom.expect(0xF, 1);
+
+    rom.finish()
+}
+
+fn bcd_font() -> Vec<u8> {
+    let mut rom = Rom::new();
+
+    for &value in &[234_u8, 7, 90] {
+        rom.check();
+        rom.op(0x6100 | value as u16);
+        rom.op(0xA000 | SCRATCH);
+        rom.op(0xF133);
+        rom.op(0xA000 | SCRATCH);
+        rom.op(0xF265);
+        rom.expect(0, value / 100);
+        rom.expect(1, value / 10 % 10);
+        rom.expect(2, value % 10);
+    }
+
+    for (digit, glyph) in GLYPHS.iter().enumerate() {
+        rom.check();
+        rom.op(0x6A00 | digit as u16);
+        rom.op(0xFA29);
+        rom.op(0xF465);
+        for (row, &line) in glyph.iter().enumerate() {
+            rom.expect(row as u8, line);
+        }
+    }
+
+    rom.finish()
+}
+
+fn stack() -> Vec<u8> {
+    let mut rom = Rom::new();
+
+    // Recurses until V0 reaches the depth of the original VIP stack
+    rom.check();
+    let skip = rom.forward_jump();
+    let sub = rom.addr();
+    rom.op(0x7001); // ADD V0, 1
+    rom.op(0x300C); // SE V0, 12
+    rom.op(0x2000 | sub);
+    rom.op(0x00EE);
+    rom.land(skip);
+    rom.op(0x6000);
+    rom.op(0x2000 | sub);
+    rom.expect(0, 12);
+
+    // The stack is usable again after unwinding
+    rom.check();
+    rom.op(0x6000);
+    rom.op(0x2000 | sub);
+    rom.expect(0, 12);
+
+    rom.finish()
+}
+
+fn sprites() -> Vec<u8> {
+    let mut rom = Rom::new();
+
+    rom.check(); // Drawing twice erases and collides
+    rom.op(0x6000);
+    rom.op(0xF029);
+    rom.op(0x610A);
+    rom.op(0xD115);
+    rom.expect(0xF, 0);
+    rom.op(0xD115);
+    rom.expect(0xF, 1);
+
+    rom.check(); // Overlapping different sprites
+    rom.op(0xD115);
+    rom.op(0x6001);
+    rom.op(0xF029);
+    rom.op(0xD115);
+    rom.expect(0xF, 1);
+
+    rom.check(); // VF is cleared again when nothing collides
+    rom.op(0x6214);
+    rom.op(0xD215);
+    rom.expect(0xF, 0);
+    rom.op(0x00E0);
+
+    rom.finish()
+}
+
+fn quirks(quirks: Quirks) -> Vec<u8> {
+    let mut rom = Rom::new();
+
+    // Bnnn has to come first, its target has to be within 0x2nn to double as Bxnn
+    rom.check();
+    rom.op(0x6000);
+    rom.op(0x6204);
+    rom.op(0x6300);
+    let target = rom.addr() + 2;
+    assert_eq!(0x200, target & 0xF00);
+    rom.op(0xB000 | target);
+    rom.op(0x6301);
+    let done = rom.forward_jump();
+    rom.op(0x6302);
+    rom.land(done);
+    rom.expect(3, if quirks.jump_uses_vx { 2 } else { 1 });
+
+    rom.check(); // VF reset
+    for &op in &[0x8121, 0x8122, 0x8123] {
+        rom.op(0x6F05);
+        rom.op(op);
+        rom.expect(0xF, if quirks.vf_reset { 0 } else { 5 });
+    }
+
+    rom.check(); // Shifting
+    rom.op(0x6102);
+    rom.op(0x6281);
+    rom.op(0x8126);
+    rom.expect(1, if quirks.shift_uses_vy { 0x40 } else { 0x01 });
+    rom.expect(0xF, if quirks.shift_uses_vy { 1 } else { 0 });
+    rom.op(0x6102);
+    rom.op(0x812E);
+    rom.expect(1, if quirks.shift_uses_vy { 0x02 } else { 0x04 });
+    rom.expect(0xF, if quirks.shift_uses_vy { 1 } else { 0 });
+
+    rom.check(); // Load and store increment I
+    rom.op(0xA000 | SCRATCH);
+    rom.op(0x6001);
+    rom.op(0x6102);
+    rom.op(0x6203);
+    rom.op(0xF255);
+    rom.op(0xF065);
+    rom.expect(0, if quirks.load_store_increments_i { 0 } else { 1 });
+
+    rom.check(); // Clipping at the right edge
+    rom.op(0x6000);
+    rom.op(0xF029);
+    rom.op(0x613E);
+    rom.op(0x6200);
+    rom.op(0xD121);
+    rom.op(0x6301);
+    rom.op(0xF31E);
+    rom.op(0x6100);
+    rom.op(0xD121);
+    rom.expect(0xF, if quirks.clip_sprites { 0 } else { 1 });
+    rom.op(0x00E0);
+
+    rom.check(); // Clipping at the bottom edge
+    rom.op(0x6008);
+    rom.op(0xF029);
+    rom.op(0x6100);
+    rom.op(0x621E);
+    rom.op(0xD125);
+    rom.op(0x6000);
+    rom.op(0xF029);
+    rom.op(0x6200);
+    rom.op(0xD121);
+    rom.expect(0xF, if quirks.clip_sprites { 0 } else { 1 });
+    rom.op(0x00E0);
+
+    rom.finish()
+}
+
+// Every ROM with expectations for the given quirks
+pub fn roms(quirks: Quirks) -> Vec<(&'static str, Vec<u8>)> {
+    vec![
+        ("opcodes", opcodes()),
+        ("flags", flags()),
+        ("bcd_font", bcd_font()),
+        ("stack", stack()),
+        ("sprites", sprites()),
+        ("quirks", self::quirks(quirks)),
+    ]
+}
+
+fn run(name: &str, rom: &[u8], quirks: (&str, Quirks)) {
+    let mut cpu = CPU::new();
+    cpu.load_rom(rom).unwrap();
+    cpu.set_quirks(quirks.1);
+
+    let mut headless = Headless::new(cpu)
+        .with_instructions_per_frame(INSTRUCTIONS_PER_FRAME)
+        .with_input(InputScript::new().hold(0, &[HELD_KEY]));
+    headless.run_frames(FRAMES).unwrap();
+
+    let pass = Framebuffer::from_ascii(PASS).unwrap();
+    if let Err(mismatch) = headless.check_screen(&pass) {
+        panic!("{} failed with {} quirks\n{}", name, quirks.0, mismatch);
+    }
+}
+
+#[test]
+fn conformance_opcodes() {
+    for &preset in Quirks::presets().iter() {
+        run("opcodes", &opcodes(), preset);
+    }
+}
+
+#[test]
+fn conformance_flags() {
+    for &preset in Quirks::presets().iter() {
+        run("flags", &flags(), preset);
+    }
+}
+
+#[test]
+fn conformance_bcd_font() {
+    for &preset in Quirks::presets().iter() {
+        run("bcd_font", &bcd_font(), preset);
+    }
+}
+
+#[test]
+fn conformance_stack() {
+    for &preset in Quirks::presets().iter() {
+        run("stack", &stack(), preset);
+    }
+}
+
+#[test]
+fn conformance_sprites() {
+    for &preset in Quirks::presets().iter() {
+        run("sprites", &sprites(), preset);
+    }
+}
+
+#[test]
+fn conformance_quirks() {
+    for &preset in Quirks::presets().iter() {
+        run("quirks", &quirks(preset.1), preset);
+    }
+}
+
+// Makes sure a failing check is actually visible
+#[test]
+fn conformance_failure() {
+    let mut rom = Rom::new();
+    rom.check();
+    rom.check();
+    rom.op(0x6101);
+    rom.expect(1, 2);
+    let rom = rom.finish();
+
+    let mut headless = Headless::from_rom(&rom).unwrap();
+    headless.run_frames(FRAMES).unwrap();
+
+    let expected = Framebuffer::from_ascii("
+        ####.####.####
+        #....#..#....#
+        ####.#..#.####
+        #....#..#.#...
+        #....####.####
+    ").unwrap();
+    if let Err(mismatch) = headless.check_screen(&expected) {
+        panic!("{}", mismatch);
+    }
+}
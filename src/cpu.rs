@@ -1,268 +1,1431 @@
-use std::fs::File;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::fmt;
+#[cfg(feature = "std")]
+use std::fs;
+#[cfg(feature = "std")]
 use std::io;
-use std::io::prelude::*;
+#[cfg(feature = "std")]
+use std::path::Path;
 
 use rand::prelude::*;
-
-use crate::font;
-use crate::screen::Screen;
+use rand_chacha::ChaCha20Rng;
+
+use crate::cache::BlockCache;
+use crate::cdp1802::{self, Cdp1802};
+use crate::fnv::Fnv1a;
+use crate::font::{self, Font};
+use crate::instr::{self, Instr};
+use crate::keypad::Keypad;
+use crate::megachip::{self, Canvas, MegaChip, Sample, MEGA_HEIGHT, MEGA_WIDTH, SCHIP_HEIGHT, SCHIP_WIDTH};
+use crate::memory::{BoundsPolicy, Memory, MemoryError, PROGRAM_OFFSET, TOTAL_MEMORY};
+use crate::quirks::Quirks;
+use crate::screen::{Colours, Screen, SCREEN_HEIGHT, SCREEN_WIDTH, ZONE_WIDTH};
+use crate::stack::StackConfig;
 use crate::timer::Timer;
+use crate::timing::{self, Timing};
+use crate::variant::{self, Variant};
+
+pub(crate) const REGISTER_COUNT: usize = 16;
+
+pub const DEFAULT_INSTRUCTIONS_PER_FRAME: u32 = 10;
+
+// Programs for the ETI 660 start further up in memory
+pub const ETI_660_PROGRAM_OFFSET: u16 = 0x600;
+
+// 1802 instructions a machine code routine may run before it counts as hung
+const MACHINE_CODE_LIMIT: u32 = 1 << 20;
+// The VIP interpreter's work area: stack, variables and display buffer
+const VIP_WORK_AREA: usize = 0xEA0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpuError {
+    InvalidInstruction { instr: u16, addr: u16 },
+    StackOverflow { addr: u16 },
+    StackUnderflow { addr: u16 },
+    Memory { error: MemoryError, addr: u16 },
+    // A 0nnn routine didn't return to the interpreter
+    MachineCode { addr: u16 },
+}
+
+impl fmt::Display for CpuError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CpuError::InvalidInstruction { instr, addr } => {
+                write!(f, "Invalid instruction {:#06x} @ {:#06x}", instr, addr)
+            }
+            CpuError::StackOverflow { addr } => write!(f, "Stack overflow @ {:#06x}", addr),
+            CpuError::StackUnderflow { addr } => write!(f, "Stack underflow @ {:#06x}", addr),
+            CpuError::Memory { error, addr } => write!(f, "{} @ {:#06x}", error, addr),
+            CpuError::MachineCode { addr } => write!(f, "Machine code routine called @ {:#06x} doesn't return", addr),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for CpuError {}
+
+#[derive(Debug)]
+pub enum LoadError {
+    Empty,
+    TooLarge { size: usize, max: usize },
+    InvalidAddress { addr: u16 },
+    #[cfg(feature = "std")]
+    Io(io::Error),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::Empty => write!(f, "ROM is empty"),
+            LoadError::TooLarge { size, max } => {
+                write!(f, "ROM is too large: {} bytes, but only {} fit", size, max)
+            }
+            LoadError::InvalidAddress { addr } => write!(f, "Cannot load ROM at {:#06x}", addr),
+            #[cfg(feature = "std")]
+            LoadError::Io(err) => write!(f, "Cannot read ROM: {}", err),
+        }
+    }
+}
 
-const TOTAL_MEMORY: usize = 4096;
-const PROGRAM_OFFSET: usize = 0x200;
-const REGISTER_COUNT: usize = 16;
-const STACK_SIZE: usize = 16;
+#[cfg(feature = "std")]
+impl std::error::Error for LoadError {}
 
+#[cfg(feature = "std")]
+impl From<io::Error> for LoadError {
+    fn from(err: io::Error) -> Self {
+        LoadError::Io(err)
+    }
+}
+
+// A snapshot of the machine for rewinding, e.g. by rollback netplay. Configuration like the
+// quirks is not part of it.
+#[derive(Clone)]
+pub struct State {
+    ip: u16,
+    sp: usize,
+    stack: Vec<u16>,
+    registers: [u8; REGISTER_COUNT],
+    addr_reg: u32,
+    memory: Box<[u8]>,
+    screen: Box<[bool]>,
+    keys: u16,
+    delay_timer: u8,
+    sound_timer: u8,
+    rng: ChaCha20Rng,
+    cycles: i32,
+    display_wait: bool,
+    colours: Option<Colours>,
+    second_keys: u16,
+    output_port: u8,
+    input_port: Option<u8>,
+    mega: Option<MegaChip>,
+    canvas: Option<Canvas>,
+}
+
+impl State {
+    // Same as CPU::state_hash() of the machine it was saved from
+    pub fn hash(&self) -> u64 {
+        let mut hasher = Fnv1a::new();
+        hasher.write(&self.ip.to_le_bytes());
+        hasher.write(&(self.sp as u32).to_le_bytes());
+        for addr in self.stack.iter() {
+            hasher.write(&addr.to_le_bytes());
+        }
+        hasher.write(&self.registers);
+        // Only MEGA-CHIP has more than 16 bits
+        hasher.write(&(self.addr_reg as u16).to_le_bytes());
+        if self.addr_reg > 0xFFFF {
+            hasher.write(&[(self.addr_reg >> 16) as u8]);
+        }
+        hasher.write(&self.memory);
+        for &pixel in self.screen.iter() {
+            hasher.write(&[pixel as u8]);
+        }
+        hasher.write(&self.keys.to_le_bytes());
+        hasher.write(&[self.delay_timer, self.sound_timer]);
+        // Always zero without VIP timing, left out then so older hashes stay valid
+        if self.cycles != 0 || self.display_wait {
+            hasher.write(&self.cycles.to_le_bytes());
+            hasher.write(&[self.display_wait as u8]);
+        }
+        // Only CHIP-8X has a colour layer, and only it can see the rest
+        if let Some(colours) = &self.colours {
+            hasher.write(colours.zones());
+            hasher.write(&[colours.background()]);
+            hasher.write(&self.second_keys.to_le_bytes());
+            hasher.write(&[self.output_port]);
+            match self.input_port {
+                Some(value) => hasher.write(&[1, value]),
+                None => hasher.write(&[0]),
+            }
+        }
+        if let Some(mega) = &self.mega {
+            mega.hash(&mut hasher);
+        }
+        if let Some(canvas) = &self.canvas {
+            canvas.hash(&mut hasher);
+        }
+        hasher.finish()
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)]
 pub struct CPU {
     // Instruction pointer
     ip: u16,
 
-    // Stack pointer
-    sp: u8,
-    stack: [u16; STACK_SIZE],
+    // Number of return addresses, which are kept in stack unless the stack is in memory
+    sp: usize,
+    stack: Vec<u16>,
+    stack_config: StackConfig,
 
-    memory: [u8; TOTAL_MEMORY],
+    memory: Memory,
     registers: [u8; REGISTER_COUNT],
-    addr_reg: u16,
+    // 16 bits wide, 24 on MEGA-CHIP
+    addr_reg: u32,
 
-    rng: Box<dyn RngCore>,
+    // A concrete generator rather than a trait object, so save states can include it
+    rng: ChaCha20Rng,
+    quirks: Quirks,
+    font: Font,
 
     screen: Screen,
+    keypad: Keypad,
 
     delay_timer: Timer,
     sound_timer: Timer,
+
+    cache: BlockCache,
+    cache_enabled: bool,
+
+    // Runs 0nnn as 1802 machine code instead of rejecting it
+    machine_code: bool,
+
+    timing: Timing,
+    // Machine cycles left in the frame with VIP timing, negative if the last instruction ran over
+    cycles: i32,
+    // A DRW is waiting for the display interrupt
+    display_wait: bool,
+
+    variant: Variant,
+    // CHIP-8X: the VP-580 keypad, and the last byte written to and the next one read from the
+    // I/O port
+    second_keypad: Keypad,
+    output_port: u8,
+    input_port: Option<u8>,
+    // Only on MEGA-CHIP
+    mega: Option<MegaChip>,
+}
+
+#[cfg(feature = "std")]
+fn initial_rng() -> ChaCha20Rng {
+    ChaCha20Rng::from_entropy()
+}
+
+// There is no entropy source without an OS, boards have to call seed_rng with their own
+#[cfg(not(feature = "std"))]
+fn initial_rng() -> ChaCha20Rng {
+    ChaCha20Rng::seed_from_u64(0)
+}
+
+impl Default for CPU {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl CPU {
     pub fn new() -> Self {
         let mut cpu = CPU {
             ip: PROGRAM_OFFSET as u16,
-            sp: 0x00,
-            stack: [0; STACK_SIZE],
-            memory: [0; TOTAL_MEMORY],
+            sp: 0,
+            stack: Vec::new(),
+            stack_config: StackConfig::default(),
+            memory: Memory::new(),
             registers: [0; REGISTER_COUNT],
             addr_reg: 0x0000,
-            rng: Box::new(rand::thread_rng()),
+            rng: initial_rng(),
+            quirks: Quirks::default(),
+            font: Font::default(),
             screen: Screen::new(),
+            keypad: Keypad::new(),
             delay_timer: Timer::new(),
             sound_timer: Timer::new(),
+            cache: BlockCache::new(),
+            cache_enabled: true,
+            machine_code: false,
+            timing: Timing::default(),
+            cycles: 0,
+            display_wait: false,
+            variant: Variant::default(),
+            second_keypad: Keypad::new(),
+            output_port: 0,
+            input_port: None,
+            mega: None,
         };
 
-        font::load_fonts(&mut cpu.memory);
+        cpu.font.load(cpu.memory.as_mut_slice());
+        cpu
+    }
+
+    // Starts executing at PROGRAM_OFFSET within an image of the whole address space, including
+    // the area usually reserved for the interpreter. Excess bytes are ignored.
+    pub fn from_memory(image: &[u8]) -> Self {
+        let mut cpu = CPU::new();
+        let len = image.len().min(cpu.memory.len());
+        cpu.memory.as_mut_slice()[..len].copy_from_slice(&image[..len]);
         cpu
     }
 
-    pub fn load_from_file(&mut self, name: String) -> Result<(), io::Error> {
-        const BUFFER_SIZE: usize = TOTAL_MEMORY - PROGRAM_OFFSET;
-        let mut buffer = [0; BUFFER_SIZE];
-        let mut file = File::open(name)?;
-        file.read(&mut buffer)?;
+    // Puts the machine back into its power-on state. Configuration like quirks and the memory
+    // policy is kept.
+    pub fn reset(&mut self) {
+        self.ip = self.variant.program_start();
+        self.sp = 0;
+        self.stack.clear();
+        self.registers = [0; REGISTER_COUNT];
+        self.addr_reg = 0;
+        self.memory.clear();
+        self.font.load(self.memory.as_mut_slice());
+        self.load_big_font();
+        self.screen.clear();
+        self.keypad.set_state(0);
+        self.delay_timer.set_timeout(0);
+        self.sound_timer.set_timeout(0);
+        self.cycles = 0;
+        self.display_wait = false;
+        if self.screen.colours().is_some() {
+            self.screen.set_colours(Some(Colours::new()));
+        }
+        self.second_keypad.set_state(0);
+        self.output_port = 0;
+        self.input_port = None;
+        if self.mega.is_some() {
+            self.mega = Some(MegaChip::new());
+            self.set_mega_mode(false);
+        }
+    }
+
+    // At the variant's program start, 0x300 on CHIP-8X and 0x200 everywhere else
+    pub fn load_rom(&mut self, rom: &[u8]) -> Result<(), LoadError> {
+        self.load_rom_at(self.variant.program_start(), rom)
+    }
 
-        for i in 0..BUFFER_SIZE {
-            self.memory[i + PROGRAM_OFFSET] = buffer[i];
+    // Resets the machine, then loads the ROM and starts executing at the given address
+    pub fn load_rom_at(&mut self, addr: u16, rom: &[u8]) -> Result<(), LoadError> {
+        let start = addr as usize;
+        let end = self.memory.len();
+        if !(PROGRAM_OFFSET..end).contains(&start) {
+            return Err(LoadError::InvalidAddress { addr });
         }
+        if rom.is_empty() {
+            return Err(LoadError::Empty);
+        }
+        if rom.len() > end - start {
+            return Err(LoadError::TooLarge { size: rom.len(), max: end - start });
+        }
+
+        self.reset();
+        self.memory.as_mut_slice()[start..start + rom.len()].copy_from_slice(rom);
+        self.ip = addr;
+        Ok(())
+    }
+
+    #[cfg(feature = "std")]
+    pub fn load_from_file<P: AsRef<Path>>(&mut self, path: P) -> Result<(), LoadError> {
+        let rom = fs::read(path)?;
+        self.load_rom(&rom)
+    }
+
+    pub fn tick(&mut self) -> Result<(), CpuError> {
+        let addr = self.ip;
+        let fault = |error| CpuError::Memory { error, addr };
+        let high = self.memory.read(addr as u32).map_err(fault)?;
+        let low = self.memory.read(addr.wrapping_add(1) as u32).map_err(fault)?;
+        self.run_instr(u16::from_be_bytes([high, low]))
+    }
+
+    // Runs one 60Hz frame: a batch of instructions followed by a timer decrement
+    pub fn run_frame(&mut self, instructions: u32) -> Result<(), CpuError> {
+        self.run_instructions(instructions)?;
+        self.tick_timers();
+        Ok(())
+    }
+
+    // The instruction half of a frame, for hosts that need to look at the CPU before the timers
+    // count down. With VIP timing the count is ignored.
+    pub fn run_instructions(&mut self, instructions: u32) -> Result<(), CpuError> {
+        if self.timing == Timing::CosmacVip {
+            self.run_cycles()
+        } else if self.cache_enabled {
+            self.run_cached(instructions)
+        } else {
+            for _ in 0..instructions {
+                self.tick()?;
+            }
+            Ok(())
+        }
+    }
+
+    // Runs instructions from predecoded blocks, with tick() for whatever can't be cached
+    fn run_cached(&mut self, mut instructions: u32) -> Result<(), CpuError> {
+        while instructions > 0 {
+            self.invalidate_written();
+            let start = self.ip as usize;
+            let len = self.cache.lookup(self.memory.as_slice(), start);
+            if len == 0 {
+                self.tick()?;
+                instructions -= 1;
+                continue;
+            }
+
+            for i in 0..len.min(instructions as usize) {
+                self.execute(self.cache.instr(start, i))?;
+                instructions -= 1;
+                // The rest of the block is stale if a store overwrote it
+                if self.invalidate_written() && !self.cache.contains(start) {
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
 
+    // Runs for the machine cycles the VIP interpreter has in a frame. An instruction that runs
+    // over borrows from the next frame. DRW first idles until the display interrupt, which
+    // limits programs to one sprite per frame like on the VIP.
+    fn run_cycles(&mut self) -> Result<(), CpuError> {
+        self.cycles += timing::FRAME_BUDGET;
+        while self.cycles > 0 {
+            let addr = self.ip;
+            let fault = |error| CpuError::Memory { error, addr };
+            let high = self.memory.read(addr as u32).map_err(fault)?;
+            let low = self.memory.read(addr.wrapping_add(1) as u32).map_err(fault)?;
+            let opcode = u16::from_be_bytes([high, low]);
+            let decoded = match instr::decode(opcode, self.variant) {
+                Some(decoded) => decoded,
+                None => return self.run_instr(opcode),
+            };
+
+            if let Instr::Drw(..) = decoded {
+                self.display_wait = !self.display_wait;
+                if self.display_wait {
+                    // The rest of the frame is spent waiting
+                    self.cycles = 0;
+                    break;
+                }
+            }
+
+            self.cycles -= timing::cycles(decoded, &self.registers);
+            self.execute(decoded)?;
+            if decoded.is_skip() && self.ip == addr.wrapping_add(4) {
+                self.cycles -= timing::SKIP_CYCLES;
+            }
+        }
         Ok(())
     }
 
-    pub fn tick(&mut self) {
-        let instr = u16::from_be_bytes([self.memory[self.ip as usize], self.memory[(self.ip + 1) as usize]]);
-        self.run_instr(instr);
+    // Drops cached blocks that memory writes touched since the last call
+    fn invalidate_written(&mut self) -> bool {
+        match self.memory.take_written() {
+            Some((start, end)) => {
+                self.cache.invalidate(start, end);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn tick_timers(&mut self) {
+        self.delay_timer.tick();
+        self.sound_timer.tick();
+    }
+
+    // The generator is fixed rather than rand's StdRng, which may change between versions, so a
+    // seed gives the same numbers in every build
+    pub fn seed_rng(&mut self, seed: u64) {
+        self.rng = ChaCha20Rng::seed_from_u64(seed);
+    }
+
+    pub fn ip(&self) -> u16 {
+        self.ip
+    }
+
+    pub fn registers(&self) -> &[u8; REGISTER_COUNT] {
+        &self.registers
+    }
+
+    pub fn addr_reg(&self) -> u32 {
+        self.addr_reg
+    }
+
+    // I plus an offset, wrapping around at its width
+    fn addr_offset(&self, offset: u32) -> u32 {
+        let mask = if self.mega.is_some() { 0xFF_FFFF } else { 0xFFFF };
+        self.addr_reg.wrapping_add(offset) & mask
+    }
+
+    // Return addresses currently on the stack, oldest first
+    pub fn stack(&self) -> Vec<u16> {
+        if !self.stack_config.in_memory() {
+            return self.stack.clone();
+        }
+        (0..self.sp)
+            .map(|index| {
+                let slot = StackConfig::slot(index) as usize;
+                let byte = |addr: usize| self.memory[addr % self.memory.len()];
+                u16::from_be_bytes([byte(slot), byte(slot + 1)])
+            })
+            .collect()
+    }
+
+    pub fn memory(&self) -> &[u8] {
+        self.memory.as_slice()
+    }
+
+    // The rest of the state a recompiled module works on, for recompiler::read_state. Writes
+    // through these bypass the memory policy and the block cache.
+    #[cfg(feature = "std")]
+    pub(crate) fn memory_mut(&mut self) -> &mut [u8] {
+        self.memory.as_mut_slice()
+    }
+
+    #[cfg(feature = "std")]
+    pub(crate) fn registers_mut(&mut self) -> &mut [u8; REGISTER_COUNT] {
+        &mut self.registers
+    }
+
+    #[cfg(feature = "std")]
+    pub(crate) fn set_addr_reg(&mut self, addr: u32) {
+        self.addr_reg = addr;
+    }
+
+    #[cfg(feature = "std")]
+    pub(crate) fn set_ip(&mut self, addr: u16) {
+        self.ip = addr;
+    }
+
+    #[cfg(feature = "std")]
+    pub(crate) fn set_timers(&mut self, delay: u8, sound: u8) {
+        self.delay_timer.set_timeout(delay);
+        self.sound_timer.set_timeout(sound);
+    }
+
+    // Only for stacks outside memory
+    #[cfg(feature = "std")]
+    pub(crate) fn set_stack(&mut self, stack: Vec<u16>) {
+        self.sp = stack.len();
+        self.stack = stack;
+    }
+
+    #[cfg(feature = "std")]
+    pub(crate) fn screen_mut(&mut self) -> &mut Screen {
+        &mut self.screen
+    }
+
+    #[cfg(feature = "std")]
+    pub(crate) fn rng_mut(&mut self) -> &mut ChaCha20Rng {
+        &mut self.rng
     }
 
-    fn run_instr(&mut self, instr: u16) {
-        println!("Running instruction: {:#06x} @ IP: {:#06x}", instr, self.ip);
+    // With the block cache, run_frame() executes predecoded instructions instead of decoding
+    // every one. Both give the same results, the cache is just faster. Enabled by default.
+    pub fn set_block_cache(&mut self, enabled: bool) {
+        self.cache_enabled = enabled;
+    }
+
+    // Hash of everything a program can observe or change, apart from the random number
+    // generator. Two machines with the same hash are in the same state.
+    pub fn state_hash(&self) -> u64 {
+        self.save_state().hash()
+    }
+
+    pub fn save_state(&self) -> State {
+        State {
+            ip: self.ip,
+            sp: self.sp,
+            stack: self.stack.clone(),
+            registers: self.registers,
+            addr_reg: self.addr_reg,
+            memory: self.memory.as_slice().into(),
+            screen: self.screen.pixels().into(),
+            keys: self.keypad.state(),
+            delay_timer: self.delay_timer.get_timeout(),
+            sound_timer: self.sound_timer.get_timeout(),
+            rng: self.rng.clone(),
+            cycles: self.cycles,
+            display_wait: self.display_wait,
+            colours: self.screen.colours().cloned(),
+            second_keys: self.second_keypad.state(),
+            output_port: self.output_port,
+            input_port: self.input_port,
+            mega: self.mega.clone(),
+            canvas: self.screen.canvas().cloned(),
+        }
+    }
+
+    // Puts the machine back into a saved state. The configuration stays as it is.
+    pub fn load_state(&mut self, state: &State) {
+        self.ip = state.ip;
+        self.sp = state.sp;
+        self.stack.clone_from(&state.stack);
+        self.registers = state.registers;
+        self.addr_reg = state.addr_reg;
+        // As much as both have, in case it was saved with another variant
+        let len = state.memory.len().min(self.memory.len());
+        self.memory.as_mut_slice()[..len].copy_from_slice(&state.memory[..len]);
+        // MEGA-CHIP switches the screen size, the rest only if it was saved with the same size
+        if self.mega.is_some() && state.mega.is_some() {
+            self.mega = state.mega.clone();
+            let enabled = state.canvas.is_some();
+            self.set_mega_mode(enabled);
+            self.screen.set_canvas(state.canvas.clone());
+        }
+        if state.screen.len() == self.screen.pixels().len() {
+            self.screen.pixels_mut().copy_from_slice(&state.screen);
+        }
+        self.keypad.set_state(state.keys);
+        self.delay_timer.set_timeout(state.delay_timer);
+        self.sound_timer.set_timeout(state.sound_timer);
+        self.rng = state.rng.clone();
+        self.cycles = state.cycles;
+        self.display_wait = state.display_wait;
+        // A state saved with another variant leaves the colour layer as the current one has it
+        if let (Some(colours), Some(saved)) = (self.screen.colours_mut(), &state.colours) {
+            *colours = saved.clone();
+        }
+        self.second_keypad.set_state(state.second_keys);
+        self.output_port = state.output_port;
+        self.input_port = state.input_port;
+    }
+
+    pub fn memory_policy(&self) -> BoundsPolicy {
+        self.memory.policy()
+    }
+
+    pub fn set_memory_policy(&mut self, policy: BoundsPolicy) {
+        self.memory.set_policy(policy);
+    }
+
+    // Faults on writes below the variant's program start, where the interpreter and fonts live
+    pub fn set_interpreter_protected(&mut self, protect: bool) {
+        self.memory.set_interpreter_protected(protect);
+    }
+
+    pub fn is_interpreter_protected(&self) -> bool {
+        self.memory.is_interpreter_protected()
+    }
+
+    pub fn delay_timer(&self) -> u8 {
+        self.delay_timer.get_timeout()
+    }
+
+    pub fn sound_timer(&self) -> u8 {
+        self.sound_timer.get_timeout()
+    }
+
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
+    }
+
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
+    }
+
+    pub fn set_machine_code(&mut self, enabled: bool) {
+        self.machine_code = enabled;
+    }
+
+    pub fn is_machine_code_enabled(&self) -> bool {
+        self.machine_code
+    }
+
+    pub fn stack_config(&self) -> StackConfig {
+        self.stack_config
+    }
+
+    // Empties the stack, return addresses don't move between memory and the CPU
+    pub fn set_stack_config(&mut self, config: StackConfig) {
+        self.stack_config = config;
+        self.sp = 0;
+        self.stack.clear();
+    }
+
+    pub fn timing(&self) -> Timing {
+        self.timing
+    }
+
+    pub fn set_timing(&mut self, timing: Timing) {
+        self.timing = timing;
+        self.cycles = 0;
+        self.display_wait = false;
+    }
+
+    pub fn variant(&self) -> Variant {
+        self.variant
+    }
+
+    // Clears the screen if its size changes. CHIP-8X also gets a fresh colour layer and
+    // MEGA-CHIP its registers with mega mode off, the other variants have neither. Memory grows
+    // or shrinks to what the variant addresses.
+    pub fn set_variant(&mut self, variant: Variant) {
+        self.variant = variant;
+        self.cache.set_variant(variant);
+        self.memory.resize(variant.memory_size());
+        self.memory.set_interpreter_end(variant.program_start() as usize);
+        let (width, height) = variant.screen_size();
+        self.screen.resize(width, height);
+        self.screen.set_canvas(None);
+        self.screen.set_colours(match variant {
+            Variant::Chip8X => Some(Colours::new()),
+            Variant::Chip8 | Variant::HiRes | Variant::MegaChip => None,
+        });
+        self.mega = match variant {
+            Variant::MegaChip => Some(MegaChip::new()),
+            Variant::Chip8 | Variant::Chip8X | Variant::HiRes => None,
+        };
+    }
+
+    // Switches between the MEGA-CHIP display and the plain one, both start out blank
+    fn set_mega_mode(&mut self, enabled: bool) {
+        if let Some(mega) = &mut self.mega {
+            mega.enabled = enabled;
+            if enabled {
+                self.screen.resize(MEGA_WIDTH, MEGA_HEIGHT);
+                self.screen.set_canvas(Some(Canvas::new(MEGA_WIDTH * MEGA_HEIGHT)));
+            } else {
+                let (width, height) = if mega.hires { (SCHIP_WIDTH, SCHIP_HEIGHT) } else { self.variant.screen_size() };
+                self.screen.resize(width, height);
+                self.screen.set_canvas(None);
+            }
+        }
+    }
+
+    // The MEGA-CHIP registers, on that variant
+    pub fn mega(&self) -> Option<&MegaChip> {
+        self.mega.as_ref()
+    }
+
+    // The sample MEGA-CHIP is playing, its bytes are in memory()
+    pub fn sample(&self) -> Option<Sample> {
+        self.mega.as_ref().and_then(|mega| mega.sample)
+    }
+
+    pub fn font(&self) -> &Font {
+        &self.font
+    }
+
+    // Replaces the glyphs in memory right away, the old location is cleared. On MEGA-CHIP the
+    // big font moves along.
+    pub fn set_font(&mut self, font: Font) {
+        let old = self.font.address() as usize;
+        for byte in &mut self.memory.as_mut_slice()[old..old + font::FONT_SIZE] {
+            *byte = 0;
+        }
+        if let Some(old) = self.big_font_address() {
+            let old = old as usize;
+            for byte in &mut self.memory.as_mut_slice()[old..old + megachip::BIG_FONT.len()] {
+                *byte = 0;
+            }
+        }
+        self.font = font;
+        self.font.load(self.memory.as_mut_slice());
+        self.load_big_font();
+    }
+
+    // Where the 8x10 digits of MEGA-CHIP's Fx30 are, right after the font if there's room below
+    // the program, otherwise right before it
+    fn big_font_address(&self) -> Option<u16> {
+        self.mega.as_ref()?;
+        let size = megachip::BIG_FONT.len() as u16;
+        let after = self.font.address() + font::FONT_SIZE as u16;
+        if (after + size) as usize <= PROGRAM_OFFSET {
+            Some(after)
+        } else {
+            Some(self.font.address() - size)
+        }
+    }
+
+    fn load_big_font(&mut self) {
+        if let Some(addr) = self.big_font_address() {
+            let addr = addr as usize;
+            self.memory.as_mut_slice()[addr..addr + megachip::BIG_FONT.len()].copy_from_slice(&megachip::BIG_FONT);
+        }
+    }
+
+    pub fn screen(&self) -> &Screen {
+        &self.screen
+    }
+
+    pub fn keypad(&self) -> &Keypad {
+        &self.keypad
+    }
+
+    pub fn keypad_mut(&mut self) -> &mut Keypad {
+        &mut self.keypad
+    }
+
+    // Read by ExF2 and ExF5 on CHIP-8X
+    pub fn second_keypad(&self) -> &Keypad {
+        &self.second_keypad
+    }
+
+    pub fn second_keypad_mut(&mut self) -> &mut Keypad {
+        &mut self.second_keypad
+    }
+
+    // The last byte FxF8 wrote, on the VP-595 sound board it sets the pitch of the tone
+    pub fn output_port(&self) -> u8 {
+        self.output_port
+    }
+
+    // Makes a byte available to FxFB, which waits until there is one. Replaces a byte that hasn't
+    // been read yet.
+    pub fn set_input_port(&mut self, value: u8) {
+        self.input_port = Some(value);
+    }
+
+    // The byte FxFB will read, if there is one
+    pub fn input_port(&self) -> Option<u8> {
+        self.input_port
+    }
+
+    pub fn is_sound_playing(&self) -> bool {
+        self.sound_timer.get_timeout() > 0
+    }
+
+    // addr is the CALL, for errors
+    fn push_return(&mut self, ret: u16, addr: u16) -> Result<(), CpuError> {
+        if !self.stack_config.has_room(self.sp) {
+            return Err(CpuError::StackOverflow { addr });
+        }
+        if self.stack_config.in_memory() {
+            let fault = |error| CpuError::Memory { error, addr };
+            let slot = StackConfig::slot(self.sp);
+            let [high, low] = ret.to_be_bytes();
+            self.memory.write(slot as u32, high).map_err(fault)?;
+            self.memory.write(slot.wrapping_add(1) as u32, low).map_err(fault)?;
+        } else {
+            self.stack.push(ret);
+        }
+        self.sp += 1;
+        Ok(())
+    }
+
+    // addr is the RET, for errors
+    fn pop_return(&mut self, addr: u16) -> Result<u16, CpuError> {
+        if self.sp == 0 {
+            return Err(CpuError::StackUnderflow { addr });
+        }
+        let ret = if self.stack_config.in_memory() {
+            let fault = |error| CpuError::Memory { error, addr };
+            let slot = StackConfig::slot(self.sp - 1);
+            let high = self.memory.read(slot as u32).map_err(fault)?;
+            let low = self.memory.read(slot.wrapping_add(1) as u32).map_err(fault)?;
+            u16::from_be_bytes([high, low])
+        } else {
+            self.stack.pop().unwrap_or(0)
+        };
+        self.sp -= 1;
+        Ok(ret)
+    }
+
+    fn run_instr(&mut self, instr: u16) -> Result<(), CpuError> {
+        match instr::decode(instr, self.variant) {
+            Some(decoded) => self.execute(decoded),
+            None => {
+                let addr = self.ip;
+                self.ip = self.ip.wrapping_add(2);
+                Err(CpuError::InvalidInstruction { instr, addr })
+            }
+        }
+    }
+
+    // Runs an 1802 routine the way the VIP interpreter calls it. V0-VF and the display buffer are
+    // placed in the interpreter's work area at the top of memory for the routine to use, and read
+    // back when it returns. Whatever the program had there is restored afterwards. Returns the
+    // machine cycles the routine took.
+    fn call_machine_code(&mut self, target: u16, addr: u16) -> Result<u32, CpuError> {
+        let memory = self.memory.as_mut_slice();
+        let mut saved = [0; TOTAL_MEMORY - VIP_WORK_AREA];
+        saved.copy_from_slice(&memory[VIP_WORK_AREA..TOTAL_MEMORY]);
+
+        let variables = cdp1802::VARIABLES as usize;
+        let display = cdp1802::DISPLAY as usize;
+        memory[variables..variables + REGISTER_COUNT].copy_from_slice(&self.registers);
+        for (byte, pixels) in memory[display..].iter_mut().zip(self.screen.pixels().chunks(8)) {
+            *byte = pixels.iter().fold(0, |byte, &pixel| byte << 1 | pixel as u8);
+        }
+
+        let mut cdp = Cdp1802::new();
+        cdp.p = cdp1802::REG_PC as u8;
+        cdp.x = 2;
+        cdp.r[cdp1802::REG_PC] = target;
+        cdp.r[2] = cdp1802::STACK_TOP;
+        cdp.r[cdp1802::REG_CHIP8_PC] = self.ip;
+        cdp.r[cdp1802::REG_VX] = cdp1802::VARIABLES + (target >> 8 & 0x0F);
+        cdp.r[cdp1802::REG_VY] = cdp1802::VARIABLES + (target >> 4 & 0x0F);
+        cdp.r[cdp1802::REG_TIMERS] = u16::from_be_bytes([self.delay_timer.get_timeout(), self.sound_timer.get_timeout()]);
+        cdp.r[cdp1802::REG_I] = self.addr_reg as u16;
+        cdp.r[cdp1802::REG_DISPLAY_PAGE] = cdp1802::DISPLAY;
+
+        let mut cycles = 0;
+        let mut steps = 0;
+        while cdp.p as usize != cdp1802::REG_INTERPRETER {
+            if steps == MACHINE_CODE_LIMIT {
+                memory[VIP_WORK_AREA..TOTAL_MEMORY].copy_from_slice(&saved);
+                return Err(CpuError::MachineCode { addr });
+            }
+            cycles += cdp.step(memory);
+            steps += 1;
+        }
+
+        self.registers.copy_from_slice(&memory[variables..variables + REGISTER_COUNT]);
+        for (pixels, &byte) in self.screen.pixels_mut().chunks_mut(8).zip(&memory[display..]) {
+            for (bit, pixel) in pixels.iter_mut().enumerate() {
+                *pixel = byte & (0x80 >> bit) != 0;
+            }
+        }
+        memory[VIP_WORK_AREA..TOTAL_MEMORY].copy_from_slice(&saved);
+
+        let [delay, sound] = cdp.r[cdp1802::REG_TIMERS].to_be_bytes();
+        self.delay_timer.set_timeout(delay);
+        self.sound_timer.set_timeout(sound);
+        self.addr_reg = cdp.r[cdp1802::REG_I] as u32;
+        self.ip = cdp.r[cdp1802::REG_CHIP8_PC];
+        Ok(cycles)
+    }
+
+    // Runs the decoded instruction at IP
+    fn execute(&mut self, instr: Instr) -> Result<(), CpuError> {
+        let addr = self.ip;
+        let fault = |error| CpuError::Memory { error, addr };
 
         // Increment IP before jumps
-        self.ip += 2;
+        self.ip = self.ip.wrapping_add(2);
 
-        // The instruction's first nibble indicates the type of operation. Some have multiple
-        // different instructions. For those we'll switch later.
         // ASM-like notation and instructions taken from: http://devernay.free.fr/hacks/chip8/C8TECH10.HTM
-        let class: u8 = (instr >> 12) as u8;
-        match class {
-            0x0 => {
-                if instr == 0x00EE { // 0x00EE - RET
-                    self.ip = self.stack[self.sp as usize];
-                    self.sp -= 1;
-                } else if instr == 0x00E0 { // 0x00E0 - CLS
-                    self.screen.clear();
-                } else {
-                    panic!("Unimplemented call instruction")
+        match instr {
+            Instr::Sys(target) => { // 0x0nnn - SYS addr
+                // The routines see the display as the VIP's 256 byte page, which only holds 64x32.
+                // Larger screens, like the 64x64 one of hi-res, refuse them.
+                let fits = (self.screen.width(), self.screen.height()) == (SCREEN_WIDTH, SCREEN_HEIGHT);
+                if !self.machine_code || !fits {
+                    return Err(CpuError::InvalidInstruction { instr: target, addr });
+                }
+                let cycles = self.call_machine_code(target, addr)?;
+                if self.timing == Timing::CosmacVip {
+                    self.cycles -= cycles as i32;
                 }
             }
-            0x1 => { // 0x1nnn - JP addr
-                self.ip = instr & 0x0FFF;
+            Instr::Cls => { // 0x00E0 - CLS
+                // In mega mode it shows the canvas and starts drawing the next frame instead
+                if self.screen.canvas().is_some() {
+                    self.screen.present();
+                } else {
+                    self.screen.clear();
+                }
             }
-            0x2 => { // 0x2nnn - CALL addr
-                self.sp += 1;
-                self.stack[self.sp as usize] = self.ip;
-                self.ip = instr & 0x0FFF;
+            Instr::Ret => { // 0x00EE - RET
+                self.ip = self.pop_return(addr)?;
             }
-            0x3 => { // 0x3xkk - SE Vx, byte
-                let register = (instr >> 8) & 0x0F;
-                let value = instr as u8;
-                if self.registers[register as usize] == value {
-                    self.ip += 2;
+            Instr::Jp(target) => { // 0x1nnn - JP addr
+                // The hi-res setup code would only switch to the display we already have
+                let boot = variant::HIRES_BOOT & 0x0FFF;
+                if self.variant == Variant::HiRes && addr == PROGRAM_OFFSET as u16 && target == boot {
+                    self.ip = variant::HIRES_START;
+                } else {
+                    self.ip = target;
                 }
             }
-            0x4 => { // 0x4xkk - SNE Vx, byte
-                let register = (instr >> 8) & 0x0F;
-                let value = instr as u8;
-                if self.registers[register as usize] != value {
-                    self.ip += 2;
+            Instr::Call(target) => { // 0x2nnn - CALL addr
+                self.push_return(self.ip, addr)?;
+                self.ip = target;
+            }
+            Instr::SeImm(x, value) => { // 0x3xkk - SE Vx, byte
+                if self.registers[x] == value {
+                    self.ip = self.ip.wrapping_add(2);
                 }
             }
-            0x5 => { // 0x5xy0 - SE Vx, Vy
-                if instr & 0x0F != 0 {
-                    panic!("Invalid skip operation")
+            Instr::SneImm(x, value) => { // 0x4xkk - SNE Vx, byte
+                if self.registers[x] != value {
+                    self.ip = self.ip.wrapping_add(2);
                 }
-
-                let register_x = (instr >> 8) & 0x0F;
-                let register_y = (instr >> 4) & 0x0F;
-                if self.registers[register_x as usize] == self.registers[register_y as usize] {
-                    self.ip += 2;
+            }
+            Instr::SeReg(x, y) => { // 0x5xy0 - SE Vx, Vy
+                if self.registers[x] == self.registers[y] {
+                    self.ip = self.ip.wrapping_add(2);
                 }
             }
-            0x6 => { // 0x6xkk - LE Vx, byte
-                let register = (instr >> 8) & 0x0F;
-                let value = instr as u8;
-                self.registers[register as usize] = value;
+            Instr::LdImm(x, value) => { // 0x6xkk - LE Vx, byte
+                self.registers[x] = value;
             }
-            0x7 => { // 0x7xkk - ADD Vx, byte
-                let register = ((instr >> 8) & 0x0F) as usize;
-                self.registers[register] = self.registers[register].wrapping_add(instr as u8);
+            Instr::AddImm(x, value) => { // 0x7xkk - ADD Vx, byte
+                self.registers[x] = self.registers[x].wrapping_add(value);
             }
-            0x8 => {
-                let register_x = (instr >> 8) & 0x0F;
-                let register_y = (instr >> 4) & 0x0F;
-                let vx = self.registers[register_x as usize];
-                let vy = self.registers[register_y as usize];
-                self.registers[register_x as usize] = match (instr & 0x0F) as u8 {
-                    0x0 => vy, // 0x8xy0 - LD Vx, Vy
-                    0x1 => vx | vy, // 0x8xy1 - OR Vx, Vy
-                    0x2 => vx & vy, // 0x8xy2 - AND Vx, Vy
-                    0x3 => vx ^ vy, // 0x8xy3 - XOR Vx, Vy
-                    0x4 => { // 0x8xy4 - ADD Vx, Vy
+            Instr::LdReg(x, y)
+            | Instr::Or(x, y)
+            | Instr::And(x, y)
+            | Instr::Xor(x, y)
+            | Instr::Add(x, y)
+            | Instr::Sub(x, y)
+            | Instr::Shr(x, y)
+            | Instr::Subn(x, y)
+            | Instr::Shl(x, y) => {
+                let vx = self.registers[x];
+                let vy = self.registers[y];
+                let shift_source = if self.quirks.shift_uses_vy { vy } else { vx };
+                let vf_reset = if self.quirks.vf_reset { Some(0) } else { None };
+
+                // The flag is written after the result, so it wins if Vx is VF
+                let (result, flag) = match instr {
+                    Instr::LdReg(..) => (vy, None), // 0x8xy0 - LD Vx, Vy
+                    Instr::Or(..) => (vx | vy, vf_reset), // 0x8xy1 - OR Vx, Vy
+                    Instr::And(..) => (vx & vy, vf_reset), // 0x8xy2 - AND Vx, Vy
+                    Instr::Xor(..) => (vx ^ vy, vf_reset), // 0x8xy3 - XOR Vx, Vy
+                    Instr::Add(..) => { // 0x8xy4 - ADD Vx, Vy
                         let (res, ovl) = vx.overflowing_add(vy);
-                        self.registers[0xF as usize] = ovl as u8;
-                        res
+                        (res, Some(ovl as u8))
                     }
-                    0x5 => { // 0x8xy5 - SUB Vx, Vy
+                    Instr::Sub(..) => { // 0x8xy5 - SUB Vx, Vy
                         let (res, ovl) = vx.overflowing_sub(vy);
-                        self.registers[0xF as usize] = !ovl as u8;
-                        res
+                        (res, Some(!ovl as u8))
                     }
-                    0x6 => { // 0x8xy6 - SHR Vx {, Vy}
-                        self.registers[0xF as usize] = vx & 1;
-                        vx >> 1
+                    Instr::Shr(..) => { // 0x8xy6 - SHR Vx {, Vy}
+                        (shift_source >> 1, Some(shift_source & 1))
                     }
-                    0x7 => { // 0x8xy7 - SUBN Vx, Vy
+                    Instr::Subn(..) => { // 0x8xy7 - SUBN Vx, Vy
                         let (res, ovl) = vy.overflowing_sub(vx);
-                        self.registers[0xF as usize] = !ovl as u8;
-                        res
+                        (res, Some(!ovl as u8))
                     }
-                    0xE => { // 0x8xyE - SHL Vx {, Vy}
-                        self.registers[0xF as usize] = vx >> 7;
-                        vx << 1
+                    _ => { // 0x8xyE - SHL Vx {, Vy}
+                        (shift_source << 1, Some(shift_source >> 7))
                     }
-                    _ => panic!("Invalid bit operation")
                 };
+
+                self.registers[x] = result;
+                if let Some(flag) = flag {
+                    self.registers[0xF] = flag;
+                }
+            }
+            Instr::SneReg(x, y) => { // 0x9xy0 - SNE Vx, Vy
+                if self.registers[x] != self.registers[y] {
+                    self.ip = self.ip.wrapping_add(2);
+                }
+            }
+            Instr::LdAddr(value) => { // 0xAnnn - LD I, addr
+                self.addr_reg = value as u32;
+            }
+            Instr::JpReg(x, target) => { // 0xBnnn - JP V0, addr
+                let register = if self.quirks.jump_uses_vx { x } else { 0x0 };
+                self.ip = target + (self.registers[register] as u16);
+            }
+            Instr::Rnd(x, mask) => { // 0xCxkk - RND Vx, byte
+                let rnd = self.rng.next_u32() as u8;
+                self.registers[x] = rnd & mask;
+            }
+            Instr::Drw(x, y, _) if self.screen.canvas().is_some() => { // 0xDxyn - MEGA-CHIP DRW Vx, Vy
+                // Sprites are sprite_width by sprite_height palette indices at I, 0 is
+                // transparent. They're clipped at the edges, the position doesn't wrap.
+                let (left, top) = (self.registers[x] as usize, self.registers[y] as usize);
+                let (width, height) = self.mega.as_ref().map_or((0, 0), |mega| (mega.sprite_width, mega.sprite_height));
+                let mut collision = false;
+                for row in 0..height {
+                    for column in 0..width {
+                        let (x, y) = (left + column, top + row);
+                        if x >= MEGA_WIDTH || y >= MEGA_HEIGHT {
+                            continue;
+                        }
+                        let index = self.memory.read(self.addr_offset((row * width + column) as u32)).map_err(fault)?;
+                        if index == 0 {
+                            continue;
+                        }
+                        if let (Some(mega), Some(canvas)) = (&self.mega, self.screen.canvas_mut()) {
+                            let colour = mega.palette[index as usize];
+                            collision |= canvas.plot(y * MEGA_WIDTH + x, index, colour, mega.blend, mega.collision_colour);
+                        }
+                    }
+                }
+                self.registers[0xF] = collision as u8;
+            }
+            Instr::Drw(x, y, 0) if self.mega.is_some() => { // 0xDxy0 - MEGA-CHIP DRW Vx, Vy, 0
+                // Like SCHIP a 16x16 sprite of two bytes per line, drawn as two 8 pixel halves
+                let x = self.registers[x] as usize % self.screen.width();
+                let y_start = self.registers[y] as usize % self.screen.height();
+
+                let mut collision = false;
+                for i in 0..16 {
+                    let y = y_start + i as usize;
+                    for half in 0..2 {
+                        let line = self.memory.read(self.addr_offset(i * 2 + half)).map_err(fault)?;
+                        let x = x + half as usize * 8;
+                        if !self.quirks.clip_sprites {
+                            let (x, y) = (x % self.screen.width(), y % self.screen.height());
+                            collision |= self.screen.draw_sprite_line_wrapped(x, y, line);
+                        } else if x < self.screen.width() && y < self.screen.height() {
+                            collision |= self.screen.draw_sprite_line(x, y, line);
+                        }
+                    }
+                }
+                self.registers[0xF] = collision as u8;
+            }
+            Instr::Drw(x, y, line_count) => { // 0xDxyn - DRW Vx, Vy, nibble
+                // The start position wraps around, the sprite itself gets clipped at the edges
+                let x = self.registers[x] as usize % self.screen.width();
+                let y_start = self.registers[y] as usize % self.screen.height();
+
+                let mut collision = false;
+                for i in 0..line_count as u32 {
+                    let line = self.memory.read(self.addr_offset(i)).map_err(fault)?;
+                    let y = y_start + i as usize;
+                    if !self.quirks.clip_sprites {
+                        let y = y % self.screen.height();
+                        collision |= self.screen.draw_sprite_line_wrapped(x, y, line);
+                    } else if y < self.screen.height() {
+                        collision |= self.screen.draw_sprite_line(x, y, line);
+                    }
+                }
+                self.registers[0xF] = collision as u8;
+            }
+            Instr::Skp(x) => { // 0xEx9E - SKP Vx
+                if self.keypad.is_pressed(self.registers[x]) {
+                    self.ip = self.ip.wrapping_add(2);
+                }
+            }
+            Instr::Sknp(x) => { // 0xExA1 - SKNP Vx
+                if !self.keypad.is_pressed(self.registers[x]) {
+                    self.ip = self.ip.wrapping_add(2);
+                }
+            }
+            Instr::LdVxDt(x) => { // 0xFx07 - LD Vx, DT
+                self.registers[x] = self.delay_timer.get_timeout();
+            }
+            Instr::LdVxKey(x) => { // 0xFx0A - LD Vx, K
+                // Keep executing this instruction until a key is pressed
+                match self.keypad.first_pressed() {
+                    Some(key) => self.registers[x] = key,
+                    None => self.ip = addr,
+                }
+            }
+            Instr::LdDtVx(x) => { // 0xFx15 - LD DT, Vx
+                self.delay_timer.set_timeout(self.registers[x]);
+            }
+            Instr::LdStVx(x) => { // 0xFx18 - LD ST, Vx
+                self.sound_timer.set_timeout(self.registers[x]);
+            }
+            Instr::AddAddr(x) => { // 0xFx1E - ADD I, Vx
+                self.addr_reg = self.addr_offset(self.registers[x] as u32);
+            }
+            Instr::LdFont(x) => { // 0xFx29 - LD F, Vx
+                self.addr_reg = self.font.sprite_address(self.registers[x]) as u32;
+            }
+            Instr::LdBcd(x) => { // 0xFx33 - LD B, Vx
+                let reg = self.registers[x];
+                self.memory.write(self.addr_reg, reg / 100).map_err(fault)?;
+                self.memory.write(self.addr_offset(1), (reg / 10) % 10).map_err(fault)?;
+                self.memory.write(self.addr_offset(2), reg % 10).map_err(fault)?;
+            }
+            Instr::LdMemVx(x) => { // 0xFx55 - LD [I], Vx
+                for i in 0..=x {
+                    self.memory.write(self.addr_offset(i as u32), self.registers[i]).map_err(fault)?;
+                }
+                if self.quirks.load_store_increments_i {
+                    self.addr_reg = self.addr_offset(x as u32 + 1);
+                }
+            }
+            Instr::LdVxMem(x) => { // 0xFx65 - LD Vx, [I]
+                for i in 0..=x {
+                    self.registers[i] = self.memory.read(self.addr_offset(i as u32)).map_err(fault)?;
+                }
+                if self.quirks.load_store_increments_i {
+                    self.addr_reg = self.addr_offset(x as u32 + 1);
+                }
+            }
+            Instr::CycleBackground => { // 0x02A0 - CHIP-8X background colour
+                if let Some(colours) = self.screen.colours_mut() {
+                    colours.cycle_background();
+                }
+            }
+            Instr::ColourZones(x, y) => { // 0xBxy0 - CHIP-8X colour 8x4 zones
+                // The low nibbles of Vx and Vx+1 select the first zone, the high nibbles how many
+                // more follow to the right and down
+                let columns = self.registers[x];
+                let rows = self.registers[(x + 1) & 0x0F];
+                let colour = self.registers[y];
+                if let Some(colours) = self.screen.colours_mut() {
+                    for row in (rows & 0x0F)..=(rows & 0x0F) + (rows >> 4) {
+                        for column in (columns & 0x0F)..=(columns & 0x0F) + (columns >> 4) {
+                            for line in 0..4 {
+                                colours.set_zone(column as usize, row as usize * 4 + line, colour);
+                            }
+                        }
+                    }
+                }
+            }
+            Instr::ColourRows(x, y, rows) => { // 0xBxyn - CHIP-8X colour 8x1 zones
+                // Vx and Vx+1 are a position like for DRW, the zones below it are coloured
+                let column = self.registers[x] as usize % self.screen.width() / ZONE_WIDTH;
+                let top = self.registers[(x + 1) & 0x0F] as usize % self.screen.height();
+                let colour = self.registers[y];
+                if let Some(colours) = self.screen.colours_mut() {
+                    for line in top..top + rows as usize {
+                        colours.set_zone(column, line, colour);
+                    }
+                }
+            }
+            Instr::SkpSecond(x) => { // 0xExF2 - CHIP-8X SKP Vx on the second keypad
+                if self.second_keypad.is_pressed(self.registers[x]) {
+                    self.ip = self.ip.wrapping_add(2);
+                }
+            }
+            Instr::SknpSecond(x) => { // 0xExF5 - CHIP-8X SKNP Vx on the second keypad
+                if !self.second_keypad.is_pressed(self.registers[x]) {
+                    self.ip = self.ip.wrapping_add(2);
+                }
+            }
+            Instr::Out(x) => { // 0xFxF8 - CHIP-8X OUT Vx
+                self.output_port = self.registers[x];
+            }
+            Instr::In(x) => { // 0xFxFB - CHIP-8X IN Vx
+                // Keep executing this instruction until the port has a byte
+                match self.input_port.take() {
+                    Some(value) => self.registers[x] = value,
+                    None => self.ip = addr,
+                }
+            }
+            Instr::MegaOff => { // 0x0010 - MEGA-CHIP MEGAOFF
+                self.set_mega_mode(false);
+            }
+            Instr::MegaOn => { // 0x0011 - MEGA-CHIP MEGAON
+                self.set_mega_mode(true);
+            }
+            Instr::LdAddrLong(high) => { // 0x01nn nnnn - MEGA-CHIP LDHI I, addr
+                // The low 16 bits are the next opcode
+                let next = self.ip;
+                let middle = self.memory.read(next as u32).map_err(fault)?;
+                let low = self.memory.read(next.wrapping_add(1) as u32).map_err(fault)?;
+                self.addr_reg = u32::from_be_bytes([0, high, middle, low]);
+                self.ip = self.ip.wrapping_add(2);
+            }
+            Instr::LdPalette(count) => { // 0x02nn - MEGA-CHIP LDPAL nn
+                // ARGB colours at I for indices 1 to nn
+                let mut colours = [0; 256];
+                for (i, colour) in colours.iter_mut().enumerate().skip(1).take(count as usize) {
+                    let mut argb = [0; 4];
+                    for (j, byte) in argb.iter_mut().enumerate() {
+                        *byte = self.memory.read(self.addr_offset(((i - 1) * 4 + j) as u32)).map_err(fault)?;
+                    }
+                    *colour = u32::from_be_bytes(argb);
+                }
+                if let Some(mega) = &mut self.mega {
+                    mega.palette[1..=count as usize].copy_from_slice(&colours[1..=count as usize]);
+                }
+            }
+            Instr::SpriteWidth(width) => { // 0x03nn - MEGA-CHIP SPRW nn
+                if let Some(mega) = &mut self.mega {
+                    mega.sprite_width = if width == 0 { 256 } else { width as usize };
+                }
+            }
+            Instr::SpriteHeight(height) => { // 0x04nn - MEGA-CHIP SPRH nn
+                if let Some(mega) = &mut self.mega {
+                    mega.sprite_height = if height == 0 { 256 } else { height as usize };
+                }
+            }
+            Instr::ScreenAlpha(alpha) => { // 0x05nn - MEGA-CHIP ALPHA nn
+                if let Some(canvas) = self.screen.canvas_mut() {
+                    canvas.set_alpha(alpha);
+                }
+            }
+            Instr::PlaySample(looping) => { // 0x060n - MEGA-CHIP DIGISND n
+                // A header at I: the rate as 16 bits and the length as 24 bits, then a byte of
+                // padding before the samples
+                let mut header = [0; 5];
+                for (i, byte) in header.iter_mut().enumerate() {
+                    *byte = self.memory.read(self.addr_offset(i as u32)).map_err(fault)?;
+                }
+                let start = self.addr_offset(6);
+                if let Some(mega) = &mut self.mega {
+                    mega.samples_started = mega.samples_started.wrapping_add(1);
+                    mega.sample = Some(Sample {
+                        addr: start,
+                        len: u32::from_be_bytes([0, header[2], header[3], header[4]]),
+                        rate: u16::from_be_bytes([header[0], header[1]]),
+                        looping,
+                        id: mega.samples_started,
+                    });
+                }
+            }
+            Instr::StopSample => { // 0x0700 - MEGA-CHIP STOPSND
+                if let Some(mega) = &mut self.mega {
+                    mega.sample = None;
+                }
+            }
+            Instr::Blend(mode) => { // 0x080n - MEGA-CHIP BMODE n
+                if let Some(mega) = &mut self.mega {
+                    mega.blend = mode;
+                }
+            }
+            Instr::CollisionColour(colour) => { // 0x09nn - MEGA-CHIP CCOL nn
+                if let Some(mega) = &mut self.mega {
+                    mega.collision_colour = colour;
+                }
+            }
+            Instr::ScrollUp(lines) => { // 0x00Bn - MEGA-CHIP SCU n
+                self.screen.scroll(0, -(lines as isize));
+            }
+            Instr::ScrollDown(lines) => { // 0x00Cn - MEGA-CHIP SCD n
+                self.screen.scroll(0, lines as isize);
+            }
+            Instr::ScrollRight => { // 0x00FB - MEGA-CHIP SCR
+                self.screen.scroll(4, 0);
             }
-            0x9 => { // 0x9xy0 - SNE Vx, Vy
-                if instr & 0x0F != 0 {
-                    panic!("Invalid skip operation")
-                }
-
-                let register_x = (instr >> 8) & 0x0F;
-                let register_y = (instr >> 4) & 0x0F;
-                if self.registers[register_x as usize] != self.registers[register_y as usize] {
-                    self.ip += 2;
+            Instr::ScrollLeft => { // 0x00FC - MEGA-CHIP SCL
+                self.screen.scroll(-4, 0);
+            }
+            Instr::Exit => { // 0x00FD - MEGA-CHIP EXIT
+                // There's no interpreter to return to, so the program stays here
+                self.ip = addr;
+            }
+            Instr::LowRes | Instr::HighRes => { // 0x00FE - MEGA-CHIP LOW, 0x00FF - HIGH
+                // Takes effect right away outside of mega mode, otherwise once 0010 turns it off
+                let hires = instr == Instr::HighRes;
+                if let Some(mega) = &mut self.mega {
+                    mega.hires = hires;
+                    if !mega.enabled {
+                        self.set_mega_mode(false);
+                    }
                 }
             }
-            0xA => { // 0xAnnn - LD I, addr
-                self.addr_reg = instr & 0x0FFF;
+            Instr::LdBigFont(x) => { // 0xFx30 - MEGA-CHIP LD HF, Vx
+                // Only the digits 0-9 have big glyphs
+                if let Some(font) = self.big_font_address() {
+                    let digit = (self.registers[x] & 0x0F) % 10;
+                    self.addr_reg = (font + digit as u16 * megachip::BIG_LINES_PER_SPRITE as u16) as u32;
+                }
             }
-            0xB => { // 0xBnnn - JP V0, addr
-                self.ip = (instr & 0x0FFF) + (self.registers[0x0] as u16);
+            Instr::SaveFlags(x) => { // 0xFx75 - MEGA-CHIP LD R, Vx
+                let count = (x + 1).min(megachip::FLAG_COUNT);
+                if let Some(mega) = &mut self.mega {
+                    mega.flags[..count].copy_from_slice(&self.registers[..count]);
+                }
             }
-            0xC => { // 0xCxkk - RND Vx, byte
-                let register = (instr >> 8) & 0x0F;
-                let mask = instr as u8;
-                let rnd = self.rng.next_u32() as u8;
-                self.registers[register as usize] = rnd & mask;
-            }
-            0xD => { // 0xDxyn - DRW Vx, Vy, nibble
-                let mut any_changes = false;
-                let x = ((instr >> 8) & 0x0F) as u8;
-                let y_start = ((instr >> 12) & 0x0F) as u8;
-                let line_count = (instr & 0x0F) as u8;
-                for i in 0..=line_count {
-                    let line = self.memory[(self.addr_reg + i as u16) as usize];
-                    any_changes |= self.screen.draw_sprite_line(x, y_start + i, line);
-                }
-                self.registers[0xF] = any_changes as u8;
-            }
-            0xF => {
-                let value = ((instr >> 8) & 0x0F) as u8;
-                match instr as u8 {
-                    0x07 => { // 0xFx07 - LD Vx, DT
-                        self.registers[value as usize] = self.delay_timer.get_timeout();
-                    }
-                    0x15 => { // 0xFx15 - LD DT, Vx
-                        self.delay_timer.set_timeout(self.registers[value as usize]);
-                    }
-                    0x18 => { // 0xFx18 - LD ST, Vx
-                        self.sound_timer.set_timeout(self.registers[value as usize]);
-                    }
-                    0x1E => { // 0xFx1E - ADD I, Vx
-                        self.addr_reg += self.registers[value as usize] as u16;
-                    }
-                    0x29 => { // 0xFx29 - LD F, Vx
-                        let letter = self.registers[value as usize] as u8;
-                        self.addr_reg = font::find_font_sprite(letter) as u16;
-                    }
-                    0x33 => { // 0xFx33 - LD B, Vx
-                        let reg = self.registers[value as usize];
-                        self.memory[self.addr_reg as usize] = reg / 100;
-                        self.memory[(self.addr_reg + 1) as usize] = (reg / 10) % 10;
-                        self.memory[(self.addr_reg + 2) as usize] = reg % 10;
-                    }
-                    0x55 => { // 0xFx55 - LD [I], Vx
-                        for i in 0..=value {
-                            self.memory[(self.addr_reg + i as u16) as usize] = self.registers[i as usize];
-                        }
-                    }
-                    0x65 => { // 0xFx65 - LD Vx, [I]
-                        for i in 0..=value {
-                            self.registers[i as usize] = self.memory[(self.addr_reg + i as u16) as usize];
-                        }
-                    }
-                    _ => panic!("Invalid instruction F")
+            Instr::LoadFlags(x) => { // 0xFx85 - MEGA-CHIP LD Vx, R
+                let count = (x + 1).min(megachip::FLAG_COUNT);
+                if let Some(mega) = &self.mega {
+                    self.registers[..count].copy_from_slice(&mega.flags[..count]);
                 }
             }
-            _ => panic!("Invalid instruction")
         }
+
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::font::FontSet;
+    use crate::megachip;
+    use crate::reference;
+    use rand::rngs::StdRng;
+
+    #[test]
+    fn load_rom() {
+        let mut cpu = CPU::new();
+        cpu.load_rom(&[0x12, 0x34, 0x56]).unwrap();
+        assert_eq!(0x0200, cpu.ip);
+        assert_eq!([0x12, 0x34, 0x56, 0x00], cpu.memory()[0x200..0x204]);
+        assert_eq!(0xF0, cpu.memory[0x000]);
+
+        // Leftovers of a previous ROM are gone
+        cpu.load_rom(&[0x78]).unwrap();
+        assert_eq!([0x78, 0x00], cpu.memory()[0x200..0x202]);
+    }
+
+    #[test]
+    fn load_rom_validation() {
+        let mut cpu = CPU::new();
+        let max = TOTAL_MEMORY - PROGRAM_OFFSET;
+        cpu.load_rom(&vec![0xAA; max]).unwrap();
+        assert_eq!(0xAA, cpu.memory[0xFFF]);
+
+        match cpu.load_rom(&vec![0xAA; max + 1]) {
+            Err(LoadError::TooLarge { size, max: m }) => {
+                assert_eq!(max + 1, size);
+                assert_eq!(max, m);
+            }
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        assert!(matches!(cpu.load_rom(&[]), Err(LoadError::Empty)));
+        assert!(matches!(cpu.load_rom_at(0x0100, &[0x00]), Err(LoadError::InvalidAddress { addr: 0x0100 })));
+        assert!(matches!(cpu.load_rom_at(0x1000, &[0x00]), Err(LoadError::InvalidAddress { addr: 0x1000 })));
+
+        // A failed load leaves the previous program in place
+        assert_eq!(0xAA, cpu.memory[0xFFF]);
+    }
+
+    #[test]
+    fn load_rom_eti_660() {
+        let mut cpu = CPU::new();
+        cpu.load_rom_at(ETI_660_PROGRAM_OFFSET, &[0x61, 0x23]).unwrap();
+        assert_eq!(0x0600, cpu.ip);
+        assert_eq!(0x00, cpu.memory[0x200]);
+
+        cpu.tick().unwrap();
+        assert_eq!(0x23, cpu.registers[1]);
+        assert!(matches!(
+            cpu.load_rom_at(ETI_660_PROGRAM_OFFSET, &[0; 0xA01]),
+            Err(LoadError::TooLarge { size: 0xA01, max: 0xA00 })
+        ));
+    }
+
+    #[test]
+    fn load_from_file() {
+        let path = std::env::temp_dir().join("chip8_load_from_file.ch8");
+        fs::write(&path, [0x12, 0x34]).unwrap();
+        let mut cpu = CPU::new();
+        let result = cpu.load_from_file(&path);
+        fs::remove_file(&path).unwrap();
+
+        result.unwrap();
+        assert_eq!([0x12, 0x34], cpu.memory()[0x200..0x202]);
+        assert!(matches!(cpu.load_from_file("does/not/exist.ch8"), Err(LoadError::Io(_))));
+    }
+
+    #[test]
+    fn reset() {
+        let mut cpu = CPU::new();
+        cpu.set_quirks(Quirks::cosmac_vip());
+        cpu.load_rom(&[0x61, 0x23, 0x22, 0x00]).unwrap();
+        cpu.run_frame(2).unwrap();
+        cpu.registers[5] = 100;
+        cpu.run_instr(0xF518).unwrap();
+        cpu.keypad.press(0x1);
+
+        cpu.reset();
+        assert_eq!(0x0200, cpu.ip);
+        assert_eq!(0, cpu.registers[1]);
+        assert!(cpu.stack().is_empty());
+        assert_eq!(0, cpu.sound_timer());
+        assert_eq!(0, cpu.keypad.state());
+        assert_eq!(0, cpu.memory[0x200]);
+        assert_eq!(0xF0, cpu.memory[0x000]);
+        assert_eq!(Quirks::cosmac_vip(), cpu.quirks());
+    }
 
     #[test]
     fn instr_ret() {
         let mut cpu = CPU::new();
         cpu.ip = 0x1234;
-        cpu.stack[0] = 0x1111;
-        cpu.stack[1] = 0x2222;
-        cpu.stack[2] = 0x3333;
-        cpu.sp = 1;
+        cpu.stack = vec![0x1111, 0x2222];
+        cpu.sp = 2;
 
-        cpu.run_instr(0x00EE);
+        cpu.run_instr(0x00EE).unwrap();
 
-        assert_eq!(0, cpu.sp);
+        assert_eq!(1, cpu.sp);
         assert_eq!(0x2222, cpu.ip);
+        assert_eq!(vec![0x1111], cpu.stack());
     }
 
     #[test]
@@ -271,7 +1434,7 @@ mod tests {
         cpu.ip = 0x1234;
         cpu.sp = 1;
 
-        cpu.run_instr(0x1456);
+        cpu.run_instr(0x1456).unwrap();
 
         assert_eq!(1, cpu.sp);
         assert_eq!(0x0456, cpu.ip);
@@ -281,18 +1444,14 @@ mod tests {
     fn instr_call() {
         let mut cpu = CPU::new();
         cpu.ip = 0x1234;
-        cpu.stack[0] = 0x1111;
-        cpu.stack[1] = 0x2222;
-        cpu.stack[2] = 0x3333;
-        cpu.sp = 0;
+        cpu.stack = vec![0x1111];
+        cpu.sp = 1;
 
-        cpu.run_instr(0x2456);
+        cpu.run_instr(0x2456).unwrap();
 
-        assert_eq!(1, cpu.sp);
+        assert_eq!(2, cpu.sp);
         assert_eq!(0x0456, cpu.ip);
-        assert_eq!(0x1111, cpu.stack[0]);
-        assert_eq!(0x1234 + 2, cpu.stack[1]);
-        assert_eq!(0x3333, cpu.stack[2]);
+        assert_eq!(vec![0x1111, 0x1234 + 2], cpu.stack());
     }
 
     #[test]
@@ -301,10 +1460,10 @@ mod tests {
         cpu.ip = 0x0100;
         cpu.registers[1] = 0x12;
 
-        cpu.run_instr(0x3113);
+        cpu.run_instr(0x3113).unwrap();
         assert_eq!(0x0102, cpu.ip);
 
-        cpu.run_instr(0x3112);
+        cpu.run_instr(0x3112).unwrap();
         assert_eq!(0x0106, cpu.ip);
     }
 
@@ -314,10 +1473,10 @@ mod tests {
         cpu.ip = 0x0100;
         cpu.registers[1] = 0x12;
 
-        cpu.run_instr(0x4113);
+        cpu.run_instr(0x4113).unwrap();
         assert_eq!(0x0104, cpu.ip);
 
-        cpu.run_instr(0x4112);
+        cpu.run_instr(0x4112).unwrap();
         assert_eq!(0x0106, cpu.ip);
     }
 
@@ -329,17 +1488,17 @@ mod tests {
         cpu.registers[2] = 0x12;
         cpu.registers[3] = 0x13;
 
-        cpu.run_instr(0x5130);
+        cpu.run_instr(0x5130).unwrap();
         assert_eq!(0x0102, cpu.ip);
 
-        cpu.run_instr(0x5120);
+        cpu.run_instr(0x5120).unwrap();
         assert_eq!(0x0106, cpu.ip);
     }
 
     #[test]
     fn instr_ld_imm() {
         let mut cpu = CPU::new();
-        cpu.run_instr(0x6123);
+        cpu.run_instr(0x6123).unwrap();
         assert_eq!(0x23, cpu.registers[1]);
     }
 
@@ -347,7 +1506,7 @@ mod tests {
     fn instr_add_imm() {
         let mut cpu = CPU::new();
         cpu.registers[1] = 0x11;
-        cpu.run_instr(0x7122);
+        cpu.run_instr(0x7122).unwrap();
         assert_eq!(0x33, cpu.registers[1]);
     }
 
@@ -356,7 +1515,7 @@ mod tests {
         let mut cpu = CPU::new();
         cpu.registers[1] = 0x11;
         cpu.registers[2] = 0x22;
-        cpu.run_instr(0x8120);
+        cpu.run_instr(0x8120).unwrap();
         assert_eq!(0x22, cpu.registers[1]);
     }
 
@@ -365,7 +1524,7 @@ mod tests {
         let mut cpu = CPU::new();
         cpu.registers[1] = 0b00110011;
         cpu.registers[2] = 0b00001111;
-        cpu.run_instr(0x8121);
+        cpu.run_instr(0x8121).unwrap();
         assert_eq!(0b00111111, cpu.registers[1]);
     }
 
@@ -374,7 +1533,7 @@ mod tests {
         let mut cpu = CPU::new();
         cpu.registers[1] = 0b00110011;
         cpu.registers[2] = 0b00001111;
-        cpu.run_instr(0x8122);
+        cpu.run_instr(0x8122).unwrap();
         assert_eq!(0b00000011, cpu.registers[1]);
     }
 
@@ -383,7 +1542,7 @@ mod tests {
         let mut cpu = CPU::new();
         cpu.registers[1] = 0b00110011;
         cpu.registers[2] = 0b00001111;
-        cpu.run_instr(0x8123);
+        cpu.run_instr(0x8123).unwrap();
         assert_eq!(0b00111100, cpu.registers[1]);
     }
 
@@ -393,11 +1552,11 @@ mod tests {
         cpu.registers[1] = 250;
         cpu.registers[2] = 5;
 
-        cpu.run_instr(0x8124);
+        cpu.run_instr(0x8124).unwrap();
         assert_eq!(255, cpu.registers[1]);
         assert_eq!(0, cpu.registers[0xF]);
 
-        cpu.run_instr(0x8124);
+        cpu.run_instr(0x8124).unwrap();
         assert_eq!(4, cpu.registers[1]);
         assert_eq!(1, cpu.registers[0xF]);
     }
@@ -408,11 +1567,11 @@ mod tests {
         cpu.registers[1] = 7;
         cpu.registers[2] = 5;
 
-        cpu.run_instr(0x8125);
+        cpu.run_instr(0x8125).unwrap();
         assert_eq!(2, cpu.registers[1]);
         assert_eq!(1, cpu.registers[0xF]);
 
-        cpu.run_instr(0x8125);
+        cpu.run_instr(0x8125).unwrap();
         assert_eq!(253, cpu.registers[1]);
         assert_eq!(0, cpu.registers[0xF]);
     }
@@ -422,11 +1581,11 @@ mod tests {
         let mut cpu = CPU::new();
         cpu.registers[1] = 0b01011010;
 
-        cpu.run_instr(0x8106);
+        cpu.run_instr(0x8106).unwrap();
         assert_eq!(0b00101101, cpu.registers[1]);
         assert_eq!(0, cpu.registers[0xF]);
 
-        cpu.run_instr(0x8106);
+        cpu.run_instr(0x8106).unwrap();
         assert_eq!(0b00010110, cpu.registers[1]);
         assert_eq!(1, cpu.registers[0xF]);
     }
@@ -437,12 +1596,12 @@ mod tests {
         cpu.registers[1] = 5;
         cpu.registers[2] = 7;
 
-        cpu.run_instr(0x8127);
+        cpu.run_instr(0x8127).unwrap();
         assert_eq!(2, cpu.registers[1]);
         assert_eq!(1, cpu.registers[0xF]);
 
         cpu.registers[2] = 1;
-        cpu.run_instr(0x8127);
+        cpu.run_instr(0x8127).unwrap();
         assert_eq!(255, cpu.registers[1]);
         assert_eq!(0, cpu.registers[0xF]);
     }
@@ -452,15 +1611,49 @@ mod tests {
         let mut cpu = CPU::new();
         cpu.registers[1] = 0b01011010;
 
-        cpu.run_instr(0x810E);
+        cpu.run_instr(0x810E).unwrap();
         assert_eq!(0b10110100, cpu.registers[1]);
         assert_eq!(0, cpu.registers[0xF]);
 
-        cpu.run_instr(0x810E);
+        cpu.run_instr(0x810E).unwrap();
         assert_eq!(0b01101000, cpu.registers[1]);
         assert_eq!(1, cpu.registers[0xF]);
     }
 
+    #[test]
+    fn instr_add_vf_operand() {
+        let mut cpu = CPU::new();
+        cpu.registers[0xF] = 200;
+        cpu.registers[1] = 100;
+        cpu.run_instr(0x8F14).unwrap();
+        assert_eq!(1, cpu.registers[0xF]);
+    }
+
+    #[test]
+    fn instr_or_vf_reset() {
+        let mut cpu = CPU::new();
+        cpu.registers[0xF] = 5;
+        cpu.run_instr(0x8121).unwrap();
+        assert_eq!(5, cpu.registers[0xF]);
+
+        cpu.set_quirks(Quirks::cosmac_vip());
+        cpu.run_instr(0x8121).unwrap();
+        assert_eq!(0, cpu.registers[0xF]);
+    }
+
+    #[test]
+    fn instr_shr_vy() {
+        let mut cpu = CPU::new();
+        cpu.set_quirks(Quirks::cosmac_vip());
+        cpu.registers[1] = 0x02;
+        cpu.registers[2] = 0x81;
+
+        cpu.run_instr(0x8126).unwrap();
+        assert_eq!(0x40, cpu.registers[1]);
+        assert_eq!(0x81, cpu.registers[2]);
+        assert_eq!(1, cpu.registers[0xF]);
+    }
+
     #[test]
     fn instr_sne_reg() {
         let mut cpu = CPU::new();
@@ -469,17 +1662,17 @@ mod tests {
         cpu.registers[2] = 0x12;
         cpu.registers[3] = 0x13;
 
-        cpu.run_instr(0x9130);
+        cpu.run_instr(0x9130).unwrap();
         assert_eq!(0x0104, cpu.ip);
 
-        cpu.run_instr(0x9120);
+        cpu.run_instr(0x9120).unwrap();
         assert_eq!(0x0106, cpu.ip);
     }
 
     #[test]
     fn instr_ld_addr() {
         let mut cpu = CPU::new();
-        cpu.run_instr(0xA123);
+        cpu.run_instr(0xA123).unwrap();
         assert_eq!(0x0123, cpu.addr_reg);
     }
 
@@ -490,25 +1683,116 @@ mod tests {
         cpu.sp = 1;
         cpu.registers[0] = 0x22;
 
-        cpu.run_instr(0xB111);
+        cpu.run_instr(0xB111).unwrap();
 
         assert_eq!(1, cpu.sp);
         assert_eq!(0x0133, cpu.ip);
     }
 
+    #[test]
+    fn instr_jmp_vx() {
+        let mut cpu = CPU::new();
+        cpu.set_quirks(Quirks::schip());
+        cpu.registers[0] = 0x22;
+        cpu.registers[1] = 0x33;
+
+        cpu.run_instr(0xB111).unwrap();
+        assert_eq!(0x0144, cpu.ip);
+    }
+
     #[test]
     fn instr_rnd() {
         let mut cpu = CPU::new();
-        cpu.rng = Box::new(rand::rngs::mock::StepRng::new(0b00111100, 0));
-        cpu.run_instr(0xC1F0);
-        assert_eq!(0b00110000, cpu.registers[1]);
+        cpu.seed_rng(3);
+        // The first two bytes from seed 3 are 0xFF and 0xDA
+        cpu.run_instr(0xC1F0).unwrap();
+        assert_eq!(0xF0, cpu.registers[1]);
+        cpu.run_instr(0xC23C).unwrap();
+        assert_eq!(0x18, cpu.registers[2]);
+    }
+
+    #[test]
+    fn instr_drw() {
+        let mut cpu = CPU::new();
+        cpu.registers[1] = 10;
+        cpu.registers[2] = 5;
+        cpu.addr_reg = cpu.font.sprite_address(0x1) as u32;
+
+        cpu.run_instr(0xD125).unwrap();
+        assert_eq!(0, cpu.registers[0xF]);
+        assert!(cpu.screen.get_pixel(12, 5));
+        assert!(cpu.screen.get_pixel(11, 6));
+        assert!(cpu.screen.get_pixel(13, 9));
+        assert!(!cpu.screen.get_pixel(11, 5));
+        assert!(!cpu.screen.get_pixel(12, 10));
+
+        // Drawing it again erases it and reports a collision
+        cpu.run_instr(0xD125).unwrap();
+        assert_eq!(1, cpu.registers[0xF]);
+        assert!(cpu.screen.pixels().iter().all(|&p| !p));
+    }
+
+    #[test]
+    fn instr_drw_clipped() {
+        let mut cpu = CPU::new();
+        cpu.registers[1] = 64 + 62;
+        cpu.registers[2] = 30;
+        cpu.addr_reg = cpu.font.sprite_address(0x8) as u32;
+
+        cpu.run_instr(0xD125).unwrap();
+        assert!(cpu.screen.get_pixel(62, 30));
+        assert!(cpu.screen.get_pixel(63, 30));
+        assert!(cpu.screen.get_pixel(62, 31));
+        assert_eq!(3, cpu.screen.pixels().iter().filter(|&&p| p).count());
+    }
+
+    #[test]
+    fn instr_skp() {
+        let mut cpu = CPU::new();
+        cpu.ip = 0x0100;
+        cpu.registers[1] = 0xA;
+
+        cpu.run_instr(0xE19E).unwrap();
+        assert_eq!(0x0102, cpu.ip);
+
+        cpu.keypad.press(0xA);
+        cpu.run_instr(0xE19E).unwrap();
+        assert_eq!(0x0106, cpu.ip);
+    }
+
+    #[test]
+    fn instr_sknp() {
+        let mut cpu = CPU::new();
+        cpu.ip = 0x0100;
+        cpu.registers[1] = 0xA;
+
+        cpu.run_instr(0xE1A1).unwrap();
+        assert_eq!(0x0104, cpu.ip);
+
+        cpu.keypad.press(0xA);
+        cpu.run_instr(0xE1A1).unwrap();
+        assert_eq!(0x0106, cpu.ip);
+    }
+
+    #[test]
+    fn instr_ld_vx_key() {
+        let mut cpu = CPU::new();
+        cpu.ip = 0x0100;
+
+        cpu.run_instr(0xF50A).unwrap();
+        assert_eq!(0x0100, cpu.ip);
+
+        cpu.keypad.press(0x7);
+        cpu.run_instr(0xF50A).unwrap();
+        assert_eq!(0x0102, cpu.ip);
+        assert_eq!(0x7, cpu.registers[5]);
     }
 
     #[test]
     fn instr_ld_vx_dt() {
         let mut cpu = CPU::new();
         cpu.delay_timer.set_timeout(100);
-        cpu.run_instr(0xF507);
+        cpu.run_instr(0xF507).unwrap();
         assert!(cpu.delay_timer.get_timeout() > 98);
         assert!(cpu.delay_timer.get_timeout() <= 100);
         assert!(cpu.registers[5] > 98);
@@ -519,7 +1803,7 @@ mod tests {
     fn instr_ld_dt_vx() {
         let mut cpu = CPU::new();
         cpu.registers[5] = 100;
-        cpu.run_instr(0xF515);
+        cpu.run_instr(0xF515).unwrap();
         assert!(cpu.delay_timer.get_timeout() > 98);
         assert!(cpu.delay_timer.get_timeout() <= 100);
     }
@@ -528,17 +1812,42 @@ mod tests {
     fn instr_ld_st_vx() {
         let mut cpu = CPU::new();
         cpu.registers[5] = 100;
-        cpu.run_instr(0xF518);
+        cpu.run_instr(0xF518).unwrap();
         assert!(cpu.sound_timer.get_timeout() > 98);
         assert!(cpu.sound_timer.get_timeout() <= 100);
     }
 
+    #[test]
+    fn sound_playing() {
+        let mut cpu = CPU::new();
+        assert!(!cpu.is_sound_playing());
+
+        cpu.registers[5] = 100;
+        cpu.run_instr(0xF518).unwrap();
+        assert!(cpu.is_sound_playing());
+    }
+
+    #[test]
+    fn run_frame() {
+        let mut cpu = CPU::new();
+        // Infinite loop: JP 0x200
+        cpu.memory[PROGRAM_OFFSET] = 0x12;
+        cpu.memory[PROGRAM_OFFSET + 1] = 0x00;
+        cpu.delay_timer.set_timeout(2);
+        cpu.sound_timer.set_timeout(1);
+
+        cpu.run_frame(DEFAULT_INSTRUCTIONS_PER_FRAME).unwrap();
+        assert_eq!(1, cpu.delay_timer.get_timeout());
+        assert!(!cpu.is_sound_playing());
+        assert_eq!(PROGRAM_OFFSET as u16, cpu.ip);
+    }
+
     #[test]
     fn instr_add_addr() {
         let mut cpu = CPU::new();
         cpu.addr_reg = 0x9821;
         cpu.registers[5] = 0x56;
-        cpu.run_instr(0xF51E);
+        cpu.run_instr(0xF51E).unwrap();
         assert_eq!(0x9877, cpu.addr_reg);
     }
 
@@ -546,9 +1855,29 @@ mod tests {
     fn instr_ld_font() {
         let mut cpu = CPU::new();
         cpu.registers[5] = 0x04;
-        cpu.run_instr(0xF529);
+        cpu.run_instr(0xF529).unwrap();
         assert_eq!(20, cpu.addr_reg);
         assert_eq!(0b10010000, cpu.memory[cpu.addr_reg as usize]);
+
+        // Only the low nibble counts
+        cpu.registers[5] = 0x14;
+        cpu.run_instr(0xF529).unwrap();
+        assert_eq!(20, cpu.addr_reg);
+    }
+
+    #[test]
+    fn set_font() {
+        let mut cpu = CPU::new();
+        cpu.set_font(Font::new(FontSet::CosmacVip).with_address(0x50).unwrap());
+        assert!(cpu.memory()[..0x50].iter().all(|&byte| byte == 0));
+        assert_eq!(FontSet::CosmacVip.glyphs()[..], cpu.memory()[0x50..0xA0]);
+
+        // Kept across loads
+        cpu.load_rom(&[0xF5, 0x29]).unwrap();
+        cpu.registers[5] = 0x1;
+        cpu.tick().unwrap();
+        assert_eq!(0x55, cpu.addr_reg);
+        assert_eq!(0x60, cpu.memory[0x55]);
     }
 
     #[test]
@@ -556,7 +1885,7 @@ mod tests {
         let mut cpu = CPU::new();
         cpu.addr_reg = 0x0345;
         cpu.registers[5] = 123;
-        cpu.run_instr(0xF533);
+        cpu.run_instr(0xF533).unwrap();
         assert_eq!(1, cpu.memory[0x0345]);
         assert_eq!(2, cpu.memory[0x0346]);
         assert_eq!(3, cpu.memory[0x0347]);
@@ -569,13 +1898,25 @@ mod tests {
         cpu.registers[0] = 12;
         cpu.registers[1] = 34;
         cpu.registers[2] = 56;
-        cpu.run_instr(0xF255);
+        cpu.run_instr(0xF255).unwrap();
         assert_eq!(12, cpu.memory[0x0345]);
         assert_eq!(34, cpu.memory[0x0346]);
         assert_eq!(56, cpu.memory[0x0347]);
         assert_eq!(0, cpu.memory[0x0348]);
     }
 
+    #[test]
+    fn instr_ld_mem_vx_increment() {
+        let mut cpu = CPU::new();
+        cpu.set_quirks(Quirks::cosmac_vip());
+        cpu.addr_reg = 0x0345;
+        cpu.run_instr(0xF255).unwrap();
+        assert_eq!(0x0348, cpu.addr_reg);
+
+        cpu.run_instr(0xF165).unwrap();
+        assert_eq!(0x034A, cpu.addr_reg);
+    }
+
     #[test]
     fn instr_ld_vx_mem() {
         let mut cpu = CPU::new();
@@ -583,10 +1924,592 @@ mod tests {
         cpu.memory[0x0345] = 12;
         cpu.memory[0x0346] = 34;
         cpu.memory[0x0347] = 56;
-        cpu.run_instr(0xF265);
+        cpu.run_instr(0xF265).unwrap();
         assert_eq!(12, cpu.registers[0]);
         assert_eq!(34, cpu.registers[1]);
         assert_eq!(56, cpu.registers[2]);
         assert_eq!(0, cpu.registers[3]);
     }
+
+    #[test]
+    fn invalid_instruction() {
+        let mut cpu = CPU::new();
+        cpu.ip = 0x0300;
+        assert_eq!(Err(CpuError::InvalidInstruction { instr: 0x5121, addr: 0x0300 }), cpu.run_instr(0x5121));
+        assert_eq!(Err(CpuError::InvalidInstruction { instr: 0xE1FF, addr: 0x0302 }), cpu.run_instr(0xE1FF));
+        assert_eq!(Err(CpuError::InvalidInstruction { instr: 0xF1FF, addr: 0x0304 }), cpu.run_instr(0xF1FF));
+    }
+
+    #[test]
+    fn stack_overflow() {
+        let mut cpu = CPU::new();
+        for _ in 0..16 {
+            cpu.run_instr(0x2200).unwrap();
+        }
+        assert_eq!(Err(CpuError::StackOverflow { addr: 0x0200 }), cpu.run_instr(0x2200));
+
+        cpu.set_stack_config(StackConfig::cosmac_vip());
+        for _ in 0..12 {
+            cpu.run_instr(0x2200).unwrap();
+        }
+        assert_eq!(Err(CpuError::StackOverflow { addr: 0x0200 }), cpu.run_instr(0x2200));
+
+        cpu.set_stack_config(StackConfig::octo());
+        for _ in 0..1000 {
+            cpu.run_instr(0x2200).unwrap();
+        }
+        assert_eq!(1000, cpu.stack().len());
+    }
+
+    #[test]
+    fn stack_in_memory() {
+        let mut cpu = CPU::new();
+        cpu.set_stack_config(StackConfig::cosmac_vip().with_memory(true));
+        cpu.ip = 0x0300;
+        cpu.run_instr(0x2400).unwrap();
+        cpu.run_instr(0x2500).unwrap();
+        assert_eq!(0x03, cpu.memory[0xECE]);
+        assert_eq!(0x02, cpu.memory[0xECF]);
+        assert_eq!(0x04, cpu.memory[0xECC]);
+        assert_eq!(0x02, cpu.memory[0xECD]);
+        assert_eq!(vec![0x0302, 0x0402], cpu.stack());
+
+        // Programs can rewrite where a subroutine returns to
+        cpu.memory.write(0xECC, 0x06).unwrap();
+        cpu.run_instr(0x00EE).unwrap();
+        assert_eq!(0x0602, cpu.ip);
+        cpu.run_instr(0x00EE).unwrap();
+        assert_eq!(0x0302, cpu.ip);
+        assert_eq!(Err(CpuError::StackUnderflow { addr: 0x0302 }), cpu.run_instr(0x00EE));
+    }
+
+    #[test]
+    fn stack_underflow() {
+        let mut cpu = CPU::new();
+        assert_eq!(Err(CpuError::StackUnderflow { addr: 0x0200 }), cpu.run_instr(0x00EE));
+    }
+
+    #[test]
+    fn regression_fetch_past_memory() {
+        let mut cpu = CPU::new();
+        cpu.memory[0xFFF] = 0x60;
+        cpu.memory[0x000] = 0x12;
+        cpu.ip = 0x0FFF;
+        cpu.tick().unwrap();
+        assert_eq!(0x12, cpu.registers[0]);
+    }
+
+    #[test]
+    fn regression_fetch_ip_overflow() {
+        let mut cpu = CPU::new();
+        cpu.ip = 0xFFFF;
+        cpu.tick().unwrap_err();
+        assert_eq!(0x0001, cpu.ip);
+    }
+
+    #[test]
+    fn regression_load_store_past_memory() {
+        let mut cpu = CPU::new();
+        cpu.addr_reg = 0x0FFE;
+        cpu.registers[0] = 1;
+        cpu.registers[1] = 2;
+        cpu.registers[2] = 3;
+        cpu.run_instr(0xF255).unwrap();
+        assert_eq!(3, cpu.memory[0x000]);
+
+        cpu.run_instr(0xF265).unwrap();
+        assert_eq!(3, cpu.registers[2]);
+
+        cpu.run_instr(0xF233).unwrap();
+    }
+
+    #[test]
+    fn regression_draw_past_memory() {
+        let mut cpu = CPU::new();
+        cpu.addr_reg = 0xFFFF;
+        cpu.run_instr(0xD00F).unwrap();
+    }
+
+    #[test]
+    fn regression_add_addr_overflow() {
+        let mut cpu = CPU::new();
+        cpu.addr_reg = 0xFFFF;
+        cpu.registers[0] = 2;
+        cpu.run_instr(0xF01E).unwrap();
+        assert_eq!(0x0001, cpu.addr_reg);
+    }
+
+    #[test]
+    fn memory_fault_policy() {
+        let mut cpu = CPU::new();
+        cpu.set_memory_policy(BoundsPolicy::Fault);
+        cpu.ip = 0x0300;
+        cpu.addr_reg = 0x0FFE;
+
+        let error = MemoryError::OutOfBounds { addr: 0x1000 };
+        assert_eq!(Err(CpuError::Memory { error, addr: 0x0300 }), cpu.run_instr(0xF255));
+        assert_eq!(Err(CpuError::Memory { error, addr: 0x0302 }), cpu.run_instr(0xF233));
+        assert_eq!(Err(CpuError::Memory { error, addr: 0x0304 }), cpu.run_instr(0xD003));
+
+        cpu.ip = 0x0FFE;
+        cpu.memory[0xFFE] = 0x60;
+        cpu.memory[0xFFF] = 0x12;
+        cpu.tick().unwrap();
+        assert_eq!(Err(CpuError::Memory { error, addr: 0x1000 }), cpu.tick());
+    }
+
+    #[test]
+    fn memory_clamp_policy() {
+        let mut cpu = CPU::new();
+        cpu.set_memory_policy(BoundsPolicy::Clamp);
+        cpu.addr_reg = 0x0FFF;
+        cpu.registers[0] = 1;
+        cpu.registers[1] = 2;
+        cpu.run_instr(0xF155).unwrap();
+        assert_eq!(2, cpu.memory[0xFFF]);
+        assert_eq!(0xF0, cpu.memory[0x000]);
+    }
+
+    #[test]
+    fn interpreter_protected() {
+        let mut cpu = CPU::new();
+        cpu.set_interpreter_protected(true);
+        cpu.ip = 0x0300;
+        cpu.addr_reg = 0x0000;
+
+        let error = MemoryError::ReadOnly { addr: 0x0000 };
+        assert_eq!(Err(CpuError::Memory { error, addr: 0x0300 }), cpu.run_instr(0xF055));
+        assert_eq!(0xF0, cpu.memory[0x000]);
+
+        // Reading fonts is still fine
+        cpu.run_instr(0xF065).unwrap();
+        assert_eq!(0xF0, cpu.registers[0]);
+
+        // The CHIP-8X interpreter reaches up to its programs at 0x300
+        cpu.set_variant(Variant::Chip8X);
+        cpu.ip = 0x0300;
+        cpu.addr_reg = 0x02FF;
+        let error = MemoryError::ReadOnly { addr: 0x02FF };
+        assert_eq!(Err(CpuError::Memory { error, addr: 0x0300 }), cpu.run_instr(0xF055));
+        cpu.addr_reg = 0x0300;
+        cpu.run_instr(0xF055).unwrap();
+    }
+
+    #[test]
+    fn machine_code() {
+        let mut rom = vec![0; 0x110];
+        // SYS 0x300; JP 0x202
+        rom[..4].copy_from_slice(&[0x03, 0x00, 0x12, 0x02]);
+        // SEX R6; LDI 1; ADD; STR R6 adds 1 to V3, which R6 points at for 0x3nn
+        // LDI 0x12; PLO RA sets the low byte of I
+        // LDI 0x80; STR RB sets the top left pixel
+        // LDI 0x33; PHI R8 sets the delay timer
+        // SEP R4 returns
+        rom[0x100..0x110].copy_from_slice(&[
+            0xE6, 0xF8, 0x01, 0xF4, 0x56, 0xF8, 0x12, 0xAA, 0xF8, 0x80, 0x5B, 0xF8, 0x33, 0xB8, 0xD4, 0x00,
+        ]);
+
+        let mut cpu = CPU::new();
+        cpu.load_rom(&rom).unwrap();
+        assert_eq!(Err(CpuError::InvalidInstruction { instr: 0x0300, addr: 0x200 }), cpu.tick());
+
+        cpu.set_machine_code(true);
+        cpu.load_rom(&rom).unwrap();
+        cpu.memory[0xEF3] = 0xAA;
+        cpu.registers[3] = 5;
+        cpu.tick().unwrap();
+        assert_eq!(0x202, cpu.ip);
+        assert_eq!(6, cpu.registers[3]);
+        assert_eq!(0x0012, cpu.addr_reg);
+        assert_eq!(0x33, cpu.delay_timer());
+        assert!(cpu.screen.get_pixel(0, 0));
+        assert_eq!(1, cpu.screen.pixels().iter().filter(|&&p| p).count());
+        // The work area belongs to the program again
+        assert_eq!(0xAA, cpu.memory[0xEF3]);
+
+        // BR to itself
+        rom[0x100..0x102].copy_from_slice(&[0x30, 0x00]);
+        cpu.load_rom(&rom).unwrap();
+        cpu.memory[0xEF3] = 0xAA;
+        assert_eq!(Err(CpuError::MachineCode { addr: 0x200 }), cpu.tick());
+        assert_eq!(0xAA, cpu.memory[0xEF3]);
+
+        // The display page can't hold the 64x64 screen
+        rom[0x100..0x102].copy_from_slice(&[0xE6, 0xF8]);
+        cpu.set_variant(Variant::HiRes);
+        cpu.load_rom(&rom).unwrap();
+        assert_eq!(Err(CpuError::InvalidInstruction { instr: 0x0300, addr: 0x200 }), cpu.tick());
+        assert_eq!(0, cpu.registers[3]);
+    }
+
+    #[test]
+    fn vip_timing() {
+        // LD V1, 1; loop: ADD I, V1; JP loop
+        let mut cpu = CPU::new();
+        cpu.set_timing(Timing::CosmacVip);
+        cpu.load_rom(&[0x61, 0x01, 0xF1, 0x1E, 0x12, 0x02]).unwrap();
+        cpu.run_frame(1).unwrap();
+        cpu.run_frame(1000).unwrap();
+        let first = cpu.addr_reg;
+        for _ in 2..60 {
+            cpu.run_frame(DEFAULT_INSTRUCTIONS_PER_FRAME).unwrap();
+        }
+
+        // A 68 cycle loop, the count passed to run_frame doesn't matter
+        let loops = |frames| (frames * timing::FRAME_BUDGET - 26) / 68;
+        assert!((loops(2)..=loops(2) + 1).contains(&(first as i32)), "{}", first);
+        assert!((loops(60)..=loops(60) + 1).contains(&(cpu.addr_reg as i32)), "{}", cpu.addr_reg);
+    }
+
+    #[test]
+    fn vip_display_wait() {
+        // loop: DRW V0, V0, 1; ADD V2, 1; JP loop
+        let mut cpu = CPU::new();
+        cpu.set_timing(Timing::CosmacVip);
+        cpu.load_rom(&[0xD0, 0x01, 0x72, 0x01, 0x12, 0x00]).unwrap();
+        for _ in 0..5 {
+            cpu.run_frame(0).unwrap();
+        }
+
+        // The first frame is spent waiting, after that one sprite is drawn per frame
+        let mut resumed = CPU::new();
+        resumed.set_timing(Timing::CosmacVip);
+        resumed.load_state(&cpu.save_state());
+        for _ in 0..5 {
+            cpu.run_frame(0).unwrap();
+            resumed.run_frame(0).unwrap();
+        }
+        assert_eq!(9, cpu.registers[2]);
+        assert!(cpu.screen.get_pixel(0, 0));
+        assert_eq!(cpu.state_hash(), resumed.state_hash());
+    }
+
+    #[test]
+    fn block_cache_self_modifying() {
+        // LD V1, 5; LD V0, 0x71; LD I, 0x208; LD [I], V0; LD V1, 1; JP 0x20A
+        // The store turns LD V1, 1 into ADD V1, 1 after its block has been decoded
+        let rom = [0x61, 0x05, 0x60, 0x71, 0xA2, 0x08, 0xF0, 0x55, 0x61, 0x01, 0x12, 0x0A];
+        let mut cpu = CPU::new();
+        cpu.load_rom(&rom).unwrap();
+        cpu.run_frame(6).unwrap();
+        assert_eq!(6, cpu.registers[1]);
+
+        // Writes by the host count as well
+        cpu.memory[0x208] = 0x61;
+        cpu.ip = 0x208;
+        cpu.run_frame(1).unwrap();
+        assert_eq!(1, cpu.registers[1]);
+    }
+
+    // Running from the block cache has to be indistinguishable from decoding every instruction,
+    // errors included. Execution goes on after them to get further into the programs.
+    #[test]
+    fn block_cache_random_programs() {
+        let mut rng = StdRng::seed_from_u64(0xB10C);
+        let presets = [Quirks::default(), Quirks::cosmac_vip(), Quirks::schip(), Quirks::xo_chip()];
+        for i in 0..100 {
+            let mut image = reference::random_image(&mut rng);
+            // Point half of the LD I instructions into the program so stores overwrite code
+            for pc in (0x200..0x400).step_by(2) {
+                if image[pc] >> 4 == 0xA && rng.gen() {
+                    let target: u16 = rng.gen_range(0x200, 0x400);
+                    image[pc..pc + 2].copy_from_slice(&(0xA000 | target).to_be_bytes());
+                }
+            }
+            let seed = rng.next_u64();
+            let keys = rng.next_u32() as u16;
+            let mut cpus: Vec<CPU> = [false, true]
+                .iter()
+                .map(|&enabled| {
+                    let mut cpu = CPU::from_memory(&image);
+                    cpu.set_block_cache(enabled);
+                    cpu.set_quirks(presets[i % presets.len()]);
+                    cpu.seed_rng(seed);
+                    cpu.keypad.set_state(keys);
+                    cpu
+                })
+                .collect();
+
+            for frame in 0..50 {
+                let expected = cpus[0].run_frame(7);
+                let actual = cpus[1].run_frame(7);
+                let context = format!("program {} frame {}", i, frame);
+                assert_eq!(expected, actual, "{}", context);
+                assert_eq!(cpus[0].ip, cpus[1].ip, "{}", context);
+                assert_eq!(cpus[0].registers, cpus[1].registers, "{}", context);
+                assert_eq!(cpus[0].addr_reg, cpus[1].addr_reg, "{}", context);
+                assert_eq!(cpus[0].stack(), cpus[1].stack(), "{}", context);
+                assert_eq!(cpus[0].delay_timer(), cpus[1].delay_timer(), "{}", context);
+                assert!(cpus[0].memory() == cpus[1].memory(), "memory {}", context);
+                assert!(cpus[0].screen.pixels() == cpus[1].screen.pixels(), "screen {}", context);
+            }
+        }
+    }
+
+    // Deterministic counterpart of the cargo-fuzz target in fuzz/
+    #[test]
+    fn random_memory_images() {
+        let mut rng = StdRng::seed_from_u64(0x0C8);
+        let mut image = [0; TOTAL_MEMORY];
+        let policies = [BoundsPolicy::Wrap, BoundsPolicy::Fault, BoundsPolicy::Clamp];
+        let presets = StackConfig::presets();
+        for i in 0..500 {
+            rng.fill_bytes(&mut image);
+            let mut cpu = CPU::from_memory(&image);
+            cpu.set_memory_policy(policies[i % policies.len()]);
+            cpu.set_interpreter_protected(i % 2 == 0);
+            // Picked the way the fuzz target does it
+            let config = rng.next_u32();
+            cpu.set_variant(Variant::all()[(config & 0b11) as usize]);
+            cpu.set_machine_code(config & 0b100 != 0);
+            if config & 0b1000 != 0 {
+                cpu.set_timing(Timing::CosmacVip);
+            }
+            let (_, stack) = presets[(config >> 4 & 0b11) as usize % presets.len()];
+            cpu.set_stack_config(stack.with_memory(config & 0b100_0000 != 0));
+            cpu.keypad.set_state(rng.next_u32() as u16);
+            // Errors are fine, panics are not. Keep going to get further into the image, unless
+            // machine code ran away, random images would keep doing that for a million steps each.
+            for _ in 0..100 {
+                if let Err(CpuError::MachineCode { .. }) = cpu.run_frame(DEFAULT_INSTRUCTIONS_PER_FRAME) {
+                    break;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn chip8x() {
+        let rom = [
+            0x02, 0xA0, // Cycle the background
+            0xB1, 0x30, // Colour zones V1, V1+1 with V3
+            0xB4, 0x33, // Colour three lines at V4, V5 with V3
+            0xE6, 0xF2, // SKP V6 on the second keypad
+            0xF3, 0xF8, // OUT V3
+            0xF7, 0xFB, // IN V7
+        ];
+        let mut cpu = CPU::new();
+        cpu.load_rom(&rom).unwrap();
+        cpu.registers[1] = 0x12;
+        assert_eq!(Err(CpuError::InvalidInstruction { instr: 0x02A0, addr: 0x200 }), cpu.tick());
+        cpu.tick().unwrap();
+        assert_eq!(0x130, cpu.ip);
+        assert!(cpu.screen.colours().is_none());
+
+        cpu.set_variant(Variant::Chip8X);
+        cpu.load_rom(&rom).unwrap();
+        // After the interpreter's larger work area
+        assert_eq!(0x300, cpu.ip);
+        assert_eq!(0x02, cpu.memory[0x300]);
+        // Zones 2 and 3 of the second and third row of 8x4 zones
+        cpu.registers[1] = 0x12;
+        cpu.registers[2] = 0x11;
+        cpu.registers[3] = 0x0C;
+        cpu.registers[4] = 60;
+        cpu.registers[5] = 30;
+        cpu.run_frame(3).unwrap();
+        let colours = cpu.screen.colours().unwrap();
+        assert_eq!(0, colours.background());
+        assert_eq!(4, colours.foreground(16, 4));
+        assert_eq!(4, colours.foreground(31, 11));
+        assert_eq!(1, colours.foreground(32, 4));
+        assert_eq!(1, colours.foreground(16, 3));
+        assert_eq!(1, colours.foreground(16, 12));
+        // Clipped at the bottom
+        assert_eq!(4, colours.foreground(56, 30));
+        assert_eq!(4, colours.foreground(63, 31));
+        assert_eq!(1, colours.foreground(56, 29));
+
+        cpu.registers[6] = 0x0B;
+        cpu.keypad.press(0xB);
+        cpu.tick().unwrap();
+        assert_eq!(0x308, cpu.ip);
+        cpu.ip = 0x306;
+        cpu.second_keypad_mut().press(0xB);
+        cpu.tick().unwrap();
+        assert_eq!(0x30A, cpu.ip);
+
+        cpu.ip = 0x308;
+        cpu.run_frame(10).unwrap();
+        assert_eq!(0x0C, cpu.output_port());
+        assert_eq!(0x30A, cpu.ip);
+        cpu.set_input_port(0x42);
+        cpu.tick().unwrap();
+        assert_eq!(0x42, cpu.registers[7]);
+        assert_eq!(0x30C, cpu.ip);
+
+        let state = cpu.save_state();
+        let hash = cpu.state_hash();
+        cpu.reset();
+        assert_eq!(1, cpu.screen.colours().unwrap().foreground(16, 4));
+        assert_eq!(0, cpu.second_keypad().state());
+        cpu.load_state(&state);
+        assert_eq!(hash, cpu.state_hash());
+        assert_eq!(4, cpu.screen.colours().unwrap().foreground(16, 4));
+    }
+
+    #[test]
+    fn hires() {
+        let mut rom = vec![0; 0xD0];
+        // JP 0x260, the setup code is never run
+        rom[..2].copy_from_slice(&[0x12, 0x60]);
+        rom[0x60..0x62].copy_from_slice(&[0xFF, 0xFF]);
+        // LD V1, 60; LD F, V0; DRW V0, V1, 5; CLS; JP 0x200
+        rom[0xC0..0xCA].copy_from_slice(&[0x61, 0x3C, 0xF0, 0x29, 0xD0, 0x15, 0x02, 0x30, 0x12, 0x00]);
+
+        let mut cpu = CPU::new();
+        cpu.set_variant(Variant::HiRes);
+        cpu.load_rom(&rom).unwrap();
+        assert_eq!(64, cpu.screen.height());
+        cpu.run_frame(4).unwrap();
+        assert_eq!(0x2C6, cpu.ip);
+        // Drawn at the bottom of the second page instead of wrapping to the top
+        assert!(cpu.screen.get_pixel(0, 60));
+        assert!(!cpu.screen.get_pixel(0, 0));
+        assert_eq!(0, cpu.registers[0xF]);
+
+        let hash = cpu.state_hash();
+        cpu.tick().unwrap();
+        assert!(cpu.screen.pixels().iter().all(|&p| !p));
+        // Jumps elsewhere to the boot address are left alone
+        cpu.tick().unwrap();
+        cpu.tick().unwrap();
+        assert_eq!(0x2C0, cpu.ip);
+        cpu.ip = 0x202;
+        cpu.memory.as_mut_slice()[0x202..0x204].copy_from_slice(&[0x12, 0x60]);
+        cpu.tick().unwrap();
+        assert_eq!(0x260, cpu.ip);
+        assert_ne!(hash, cpu.state_hash());
+
+        // Back to the usual screen, which doesn't know 0230
+        cpu.set_variant(Variant::Chip8);
+        assert_eq!(32, cpu.screen.height());
+        cpu.load_rom(&rom).unwrap();
+        cpu.tick().unwrap();
+        assert_eq!(0x260, cpu.ip);
+        cpu.ip = 0x2C6;
+        assert_eq!(Err(CpuError::InvalidInstruction { instr: 0x0230, addr: 0x2C6 }), cpu.tick());
+    }
+
+    #[test]
+    fn megachip() {
+        let mut rom = vec![0; 0x3A];
+        // MEGAON; LDHI I, 0x230; LDPAL 2; SPRW 2; SPRH 1; CCOL 2; LD V0, 16; LD V1, 5;
+        // LDHI I, 0x238; DRW V0, V1, 0; DRW V0, V1, 0; CLS; MEGAOFF
+        rom[..0x1E].copy_from_slice(&[
+            0x00, 0x11, 0x01, 0x00, 0x02, 0x30, 0x02, 0x02, 0x03, 0x02, 0x04, 0x01, 0x09, 0x02, 0x60, 0x10,
+            0x61, 0x05, 0x01, 0x00, 0x02, 0x38, 0xD0, 0x10, 0xD0, 0x10, 0x00, 0xE0, 0x00, 0x10,
+        ]);
+        // Two ARGB colours and a sprite using both
+        rom[0x30..0x3A].copy_from_slice(&[0xFF, 0x11, 0x22, 0x33, 0x80, 0x44, 0x55, 0x66, 0x01, 0x02]);
+
+        let mut cpu = CPU::new();
+        cpu.set_variant(Variant::MegaChip);
+        assert_eq!(megachip::MEGA_MEMORY, cpu.memory().len());
+        cpu.load_rom(&rom).unwrap();
+        assert_eq!(64, cpu.screen.width());
+        cpu.tick().unwrap();
+        assert_eq!((256, 192), (cpu.screen.width(), cpu.screen.height()));
+        for _ in 0..8 {
+            cpu.tick().unwrap();
+        }
+        assert_eq!(0x238, cpu.addr_reg);
+        let mega = cpu.mega().unwrap();
+        assert_eq!([0, 0xFF112233, 0x80445566, 0], mega.palette[..4]);
+        assert_eq!((2, 1, 2), (mega.sprite_width, mega.sprite_height, mega.collision_colour));
+
+        // Only covering colour 2 collides
+        let at = |x: usize, y: usize| y * megachip::MEGA_WIDTH + x;
+        cpu.tick().unwrap();
+        assert_eq!(0, cpu.registers[0xF]);
+        let hash = cpu.state_hash();
+        cpu.tick().unwrap();
+        assert_eq!(1, cpu.registers[0xF]);
+        assert_eq!(0, cpu.screen.canvas().unwrap().shown()[at(16, 5)]);
+        assert!(!cpu.screen.get_pixel(16, 5));
+        cpu.tick().unwrap();
+        assert_eq!(0xFF112233, cpu.screen.canvas().unwrap().shown()[at(16, 5)]);
+        assert!(cpu.screen.get_pixel(16, 5));
+        assert!(cpu.screen.get_pixel(17, 5));
+        assert!(!cpu.screen.get_pixel(18, 5));
+
+        let state = cpu.save_state();
+        cpu.tick().unwrap();
+        assert_eq!((64, 32), (cpu.screen.width(), cpu.screen.height()));
+        assert!(cpu.screen.canvas().is_none());
+        cpu.load_state(&state);
+        assert_eq!(256, cpu.screen.width());
+        assert_eq!(state.hash(), cpu.state_hash());
+        assert_ne!(hash, cpu.state_hash());
+
+        // I wraps around at 24 bits
+        cpu.addr_reg = 0xFF_FFFF;
+        cpu.registers[0] = 2;
+        cpu.execute(Instr::AddAddr(0)).unwrap();
+        assert_eq!(1, cpu.addr_reg);
+
+        // Blend modes beyond multiply don't exist
+        cpu.ip = 0x200;
+        cpu.memory.as_mut_slice()[0x200..0x202].copy_from_slice(&[0x08, 0x06]);
+        assert_eq!(Err(CpuError::InvalidInstruction { instr: 0x0806, addr: 0x200 }), cpu.tick());
+
+        cpu.set_variant(Variant::Chip8);
+        assert_eq!(TOTAL_MEMORY, cpu.memory().len());
+        assert!(cpu.mega().is_none());
+    }
+
+    #[test]
+    fn schip_on_megachip() {
+        // HIGH; LD I, 0x220; LD V0, 8; DRW V0, V0, 0; SCR; SCD 2; LD R, V1; LD V0, 0; LD V1, 0;
+        // LD V1, R; LD V0, 7; LD HF, V0; LOW; EXIT
+        let mut rom = vec![0; 0x40];
+        rom[..0x1C].copy_from_slice(&[
+            0x00, 0xFF, 0xA2, 0x20, 0x60, 0x08, 0xD0, 0x00, 0x00, 0xFB, 0x00, 0xC2, 0xF1, 0x75, 0x60, 0x00,
+            0x61, 0x00, 0xF1, 0x85, 0x60, 0x07, 0xF0, 0x30, 0x00, 0xFE, 0x00, 0xFD,
+        ]);
+        // A 16x16 sprite with only its corners set
+        rom[0x20] = 0x80;
+        rom[0x3F] = 0x01;
+
+        let mut cpu = CPU::new();
+        cpu.set_variant(Variant::MegaChip);
+        cpu.load_rom(&rom).unwrap();
+        cpu.tick().unwrap();
+        assert_eq!((128, 64), (cpu.screen.width(), cpu.screen.height()));
+        for _ in 0..3 {
+            cpu.tick().unwrap();
+        }
+        assert!(cpu.screen.get_pixel(8, 8));
+        assert!(cpu.screen.get_pixel(23, 23));
+        assert_eq!(0, cpu.registers[0xF]);
+
+        cpu.tick().unwrap();
+        cpu.tick().unwrap();
+        assert!(!cpu.screen.get_pixel(8, 8));
+        assert!(cpu.screen.get_pixel(12, 10));
+        assert!(cpu.screen.get_pixel(27, 25));
+
+        // The flags survive the registers being overwritten, and are part of the state
+        let hash = cpu.state_hash();
+        cpu.tick().unwrap();
+        assert_eq!([8, 0], cpu.mega().unwrap().flags[..2]);
+        assert_ne!(hash, cpu.state_hash());
+        for _ in 0..3 {
+            cpu.tick().unwrap();
+        }
+        assert_eq!((8, 0), (cpu.registers[0], cpu.registers[1]));
+
+        // The big font follows the small one
+        cpu.tick().unwrap();
+        cpu.tick().unwrap();
+        let seven = cpu.font().address() as u32 + font::FONT_SIZE as u32 + 7 * 10;
+        assert_eq!(seven, cpu.addr_reg);
+        assert_eq!(megachip::BIG_FONT[70..80], cpu.memory()[seven as usize..seven as usize + 10]);
+
+        cpu.tick().unwrap();
+        assert_eq!((64, 32), (cpu.screen.width(), cpu.screen.height()));
+        cpu.tick().unwrap();
+        cpu.tick().unwrap();
+        assert_eq!(0x21A, cpu.ip);
+    }
 }
@@ -0,0 +1,39 @@
+// 64 bit FNV-1a. Unlike the hashers in std its output is fixed, so hashes can be stored in files
+// and compared between builds.
+pub struct Fnv1a(u64);
+
+impl Fnv1a {
+    pub fn new() -> Self {
+        Fnv1a(0xCBF2_9CE4_8422_2325)
+    }
+
+    pub fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0 ^ byte as u64).wrapping_mul(0x0000_0100_0000_01B3);
+        }
+    }
+
+    pub fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+// Only used by the std frontends
+#[cfg(feature = "std")]
+pub fn hash(bytes: &[u8]) -> u64 {
+    let mut hasher = Fnv1a::new();
+    hasher.write(bytes);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_values() {
+        assert_eq!(0xCBF2_9CE4_8422_2325, hash(b""));
+        assert_eq!(0xAF63_DC4C_8601_EC8C, hash(b"a"));
+        assert_eq!(0x8594_4171_F739_67E8, hash(b"foobar"));
+    }
+}
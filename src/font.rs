@@ -1,119 +1,261 @@
-const MEMORY_OFFSET: usize = 0;
-const LINES_PER_SPRITE: usize = 5;
-
-const FONT_MAP: [u8; LINES_PER_SPRITE * 0x10] = [
-    0b11110000, // 0
-    0b10010000,
-    0b10010000,
-    0b10010000,
-    0b11110000,
-
-    0b00100000,  // 1
-    0b01100000,
-    0b00100000,
-    0b00100000,
-    0b01110000,
-
-    0b11110000, // 2
-    0b00010000,
-    0b11110000,
-    0b10000000,
-    0b11110000,
-
-    0b11110000, // 3
-    0b00010000,
-    0b11110000,
-    0b00010000,
-    0b11110000,
-
-    0b10010000, // 4
-    0b10010000,
-    0b11110000,
-    0b00010000,
-    0b00010000,
-
-    0b11110000, // 5
-    0b10000000,
-    0b11110000,
-    0b00010000,
-    0b11110000,
-
-    0b11110000, // 6
-    0b10000000,
-    0b11110000,
-    0b10010000,
-    0b11110000,
-
-    0b11110000, // 7
-    0b00010000,
-    0b00100000,
-    0b01000000,
-    0b01000000,
-
-    0b11110000, // 8
-    0b10010000,
-    0b11110000,
-    0b10010000,
-    0b11110000,
-
-    0b11110000, // 9
-    0b10010000,
-    0b11110000,
-    0b00010000,
-    0b11110000,
-
-    0b11110000, // A
-    0b10010000,
-    0b11110000,
-    0b10010000,
-    0b10010000,
-
-    0b11100000, // B
-    0b10010000,
-    0b11100000,
-    0b10010000,
-    0b11100000,
-
-    0b11110000, // C
-    0b10000000,
-    0b10000000,
-    0b10000000,
-    0b11110000,
-
-    0b11100000, // D
-    0b10010000,
-    0b10010000,
-    0b10010000,
-    0b11100000,
-
-    0b11110000, // E
-    0b10000000,
-    0b11110000,
-    0b10000000,
-    0b11110000,
-
-    0b11110000, // F
-    0b10000000,
-    0b11110000,
-    0b10000000,
-    0b10000000,
-];
+use core::fmt;
+
+use crate::memory::PROGRAM_OFFSET;
+
+pub const LINES_PER_SPRITE: usize = 5;
+pub const FONT_SIZE: usize = LINES_PER_SPRITE * 0x10;
+
+// Glyph shapes of the machines programs were written for, one 4x5 sprite per hex digit. Taken
+// from Octo, which collected them from the original interpreters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FontSet {
+    CosmacVip,
+    Dream6800,
+    Eti660,
+    FishNChips,
+    Octo,
+}
+
+impl FontSet {
+    pub fn all() -> [FontSet; 5] {
+        [FontSet::CosmacVip, FontSet::Dream6800, FontSet::Eti660, FontSet::FishNChips, FontSet::Octo]
+    }
+
+    // Same names as Octo's fontStyle option
+    pub fn name(self) -> &'static str {
+        match self {
+            FontSet::CosmacVip => "vip",
+            FontSet::Dream6800 => "dream6800",
+            FontSet::Eti660 => "eti660",
+            FontSet::FishNChips => "fish",
+            FontSet::Octo => "octo",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<FontSet> {
+        FontSet::all().iter().copied().find(|set| set.name() == name)
+    }
+
+    pub fn glyphs(self) -> &'static [u8; FONT_SIZE] {
+        match self {
+            FontSet::CosmacVip => &VIP,
+            FontSet::Dream6800 => &DREAM_6800,
+            FontSet::Eti660 => &ETI_660,
+            FontSet::FishNChips => &FISH_N_CHIPS,
+            FontSet::Octo => &OCTO,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FontError {
+    // Fonts are exactly 16 sprites of 5 lines
+    Size(usize),
+    // The glyphs have to fit below PROGRAM_OFFSET
+    Address(u16),
+}
+
+impl fmt::Display for FontError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FontError::Size(size) => write!(f, "Font has {} bytes instead of {}", size, FONT_SIZE),
+            FontError::Address(addr) => write!(f, "Font doesn't fit at {:#06x}", addr),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for FontError {}
+
+// The glyphs Fx29 points at and where they are stored in the interpreter area
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Font {
+    glyphs: [u8; FONT_SIZE],
+    address: u16,
+}
+
+impl Font {
+    pub fn new(set: FontSet) -> Self {
+        Font {
+            glyphs: *set.glyphs(),
+            address: 0,
+        }
+    }
+
+    pub fn custom(glyphs: &[u8]) -> Result<Self, FontError> {
+        if glyphs.len() != FONT_SIZE {
+            return Err(FontError::Size(glyphs.len()));
+        }
+
+        let mut font = Font::new(FontSet::Octo);
+        font.glyphs.copy_from_slice(glyphs);
+        Ok(font)
+    }
+
+    // Most interpreters since CHIP-48 put the font at 0x50
+    pub fn with_address(mut self, address: u16) -> Result<Self, FontError> {
+        if address as usize + FONT_SIZE > PROGRAM_OFFSET {
+            return Err(FontError::Address(address));
+        }
+        self.address = address;
+        Ok(self)
+    }
+
+    pub fn glyphs(&self) -> &[u8; FONT_SIZE] {
+        &self.glyphs
+    }
+
+    pub fn address(&self) -> u16 {
+        self.address
+    }
 
-pub fn load_fonts(memory: &mut [u8; 4096]) {
-    if MEMORY_OFFSET + FONT_MAP.len() > memory.len() {
-        panic!("Not enough memory to load fonts")
+    // Only the low nibble selects the digit, like on the VIP
+    pub fn sprite_address(&self, digit: u8) -> u16 {
+        self.address + (digit & 0x0F) as u16 * LINES_PER_SPRITE as u16
     }
 
-    for i in 0..FONT_MAP.len() {
-        memory[MEMORY_OFFSET + i] = FONT_MAP[i];
+    pub fn load(&self, memory: &mut [u8]) {
+        let start = self.address as usize;
+        memory[start..start + FONT_SIZE].copy_from_slice(&self.glyphs);
     }
 }
 
-pub fn find_font_sprite(letter: u8) -> usize {
-    if letter > 0xF {
-        eprintln!("Cannot find sprite for letter");
-        return MEMORY_OFFSET;
+impl Default for Font {
+    fn default() -> Self {
+        Font::new(FontSet::Octo)
+    }
+}
+
+const VIP: [u8; FONT_SIZE] = [
+    0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
+    0x60, 0x20, 0x20, 0x20, 0x70, // 1
+    0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
+    0xF0, 0x10, 0xF0, 0x10, 0xF0, // 3
+    0xA0, 0xA0, 0xF0, 0x20, 0x20, // 4
+    0xF0, 0x80, 0xF0, 0x10, 0xF0, // 5
+    0xF0, 0x80, 0xF0, 0x90, 0xF0, // 6
+    0xF0, 0x10, 0x10, 0x10, 0x10, // 7
+    0xF0, 0x90, 0xF0, 0x90, 0xF0, // 8
+    0xF0, 0x90, 0xF0, 0x10, 0xF0, // 9
+    0xF0, 0x90, 0xF0, 0x90, 0x90, // A
+    0xF0, 0x50, 0x70, 0x50, 0xF0, // B
+    0xF0, 0x80, 0x80, 0x80, 0xF0, // C
+    0xF0, 0x50, 0x50, 0x50, 0xF0, // D
+    0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
+    0xF0, 0x80, 0xF0, 0x80, 0x80, // F
+];
+
+const DREAM_6800: [u8; FONT_SIZE] = [
+    0xE0, 0xA0, 0xA0, 0xA0, 0xE0, // 0
+    0x40, 0x40, 0x40, 0x40, 0x40, // 1
+    0xE0, 0x20, 0xE0, 0x80, 0xE0, // 2
+    0xE0, 0x20, 0xE0, 0x20, 0xE0, // 3
+    0x80, 0xA0, 0xA0, 0xE0, 0x20, // 4
+    0xE0, 0x80, 0xE0, 0x20, 0xE0, // 5
+    0xE0, 0x80, 0xE0, 0xA0, 0xE0, // 6
+    0xE0, 0x20, 0x20, 0x20, 0x20, // 7
+    0xE0, 0xA0, 0xE0, 0xA0, 0xE0, // 8
+    0xE0, 0xA0, 0xE0, 0x20, 0xE0, // 9
+    0xE0, 0xA0, 0xE0, 0xA0, 0xA0, // A
+    0xC0, 0xA0, 0xE0, 0xA0, 0xC0, // B
+    0xE0, 0x80, 0x80, 0x80, 0xE0, // C
+    0xC0, 0xA0, 0xA0, 0xA0, 0xC0, // D
+    0xE0, 0x80, 0xE0, 0x80, 0xE0, // E
+    0xE0, 0x80, 0xC0, 0x80, 0x80, // F
+];
+
+const ETI_660: [u8; FONT_SIZE] = [
+    0xE0, 0xA0, 0xA0, 0xA0, 0xE0, // 0
+    0x20, 0x20, 0x20, 0x20, 0x20, // 1
+    0xE0, 0x20, 0xE0, 0x80, 0xE0, // 2
+    0xE0, 0x20, 0xE0, 0x20, 0xE0, // 3
+    0xA0, 0xA0, 0xE0, 0x20, 0x20, // 4
+    0xE0, 0x80, 0xE0, 0x20, 0xE0, // 5
+    0xE0, 0x80, 0xE0, 0xA0, 0xE0, // 6
+    0xE0, 0x20, 0x20, 0x20, 0x20, // 7
+    0xE0, 0xA0, 0xE0, 0xA0, 0xE0, // 8
+    0xE0, 0xA0, 0xE0, 0x20, 0xE0, // 9
+    0xE0, 0xA0, 0xE0, 0xA0, 0xA0, // A
+    0x80, 0x80, 0xE0, 0xA0, 0xE0, // B
+    0xE0, 0x80, 0x80, 0x80, 0xE0, // C
+    0x20, 0x20, 0xE0, 0xA0, 0xE0, // D
+    0xE0, 0x80, 0xE0, 0x80, 0xE0, // E
+    0xE0, 0x80, 0xC0, 0x80, 0x80, // F
+];
+
+const FISH_N_CHIPS: [u8; FONT_SIZE] = [
+    0x60, 0xA0, 0xA0, 0xA0, 0xC0, // 0
+    0x40, 0xC0, 0x40, 0x40, 0xE0, // 1
+    0xC0, 0x20, 0x40, 0x80, 0xE0, // 2
+    0xC0, 0x20, 0x40, 0x20, 0xC0, // 3
+    0x20, 0xA0, 0xE0, 0x20, 0x20, // 4
+    0xE0, 0x80, 0xC0, 0x20, 0xC0, // 5
+    0x40, 0x80, 0xC0, 0xA0, 0x40, // 6
+    0xE0, 0x20, 0x60, 0x40, 0x40, // 7
+    0x40, 0xA0, 0x40, 0xA0, 0x40, // 8
+    0x40, 0xA0, 0x60, 0x20, 0x40, // 9
+    0x40, 0xA0, 0xE0, 0xA0, 0xA0, // A
+    0xC0, 0xA0, 0xC0, 0xA0, 0xC0, // B
+    0x60, 0x80, 0x80, 0x80, 0x60, // C
+    0xC0, 0xA0, 0xA0, 0xA0, 0xC0, // D
+    0xE0, 0x80, 0xC0, 0x80, 0xE0, // E
+    0xE0, 0x80, 0xC0, 0x80, 0x80, // F
+];
+
+const OCTO: [u8; FONT_SIZE] = [
+    0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
+    0x20, 0x60, 0x20, 0x20, 0x70, // 1
+    0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
+    0xF0, 0x10, 0xF0, 0x10, 0xF0, // 3
+    0x90, 0x90, 0xF0, 0x10, 0x10, // 4
+    0xF0, 0x80, 0xF0, 0x10, 0xF0, // 5
+    0xF0, 0x80, 0xF0, 0x90, 0xF0, // 6
+    0xF0, 0x10, 0x20, 0x40, 0x40, // 7
+    0xF0, 0x90, 0xF0, 0x90, 0xF0, // 8
+    0xF0, 0x90, 0xF0, 0x10, 0xF0, // 9
+    0xF0, 0x90, 0xF0, 0x90, 0x90, // A
+    0xE0, 0x90, 0xE0, 0x90, 0xE0, // B
+    0xF0, 0x80, 0x80, 0x80, 0xF0, // C
+    0xE0, 0x90, 0x90, 0x90, 0xE0, // D
+    0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
+    0xF0, 0x80, 0xF0, 0x80, 0x80, // F
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn font_sets() {
+        for set in FontSet::all().iter() {
+            assert_eq!(Some(*set), FontSet::from_name(set.name()));
+            // Every glyph is 4 pixels wide
+            assert!(set.glyphs().iter().all(|line| line & 0x0F == 0), "{}", set.name());
+        }
+        assert_eq!(None, FontSet::from_name("schip"));
+    }
+
+    #[test]
+    fn sprite_address() {
+        let font = Font::new(FontSet::CosmacVip).with_address(0x50).unwrap();
+        assert_eq!(0x50, font.sprite_address(0));
+        assert_eq!(0x50 + 0xA * 5, font.sprite_address(0xA));
+        assert_eq!(font.sprite_address(0x3), font.sprite_address(0xF3));
+
+        let mut memory = [0; PROGRAM_OFFSET];
+        font.load(&mut memory);
+        assert_eq!(&memory[0x50..0xA0], &VIP[..]);
+        assert_eq!(0, memory[0x4F]);
+        assert_eq!(0, memory[0xA0]);
     }
 
-    MEMORY_OFFSET + (letter as usize * LINES_PER_SPRITE)
+    #[test]
+    fn invalid_fonts() {
+        assert_eq!(Err(FontError::Size(79)), Font::custom(&[0; 79]));
+        assert_eq!(Ok(0x1B0), Font::default().with_address(0x1B0).map(|font| font.address()));
+        assert_eq!(Err(FontError::Address(0x1B1)), Font::default().with_address(0x1B1));
+
+        let glyphs: Vec<u8> = (0..FONT_SIZE as u8).collect();
+        assert_eq!(&glyphs[..], &Font::custom(&glyphs).unwrap().glyphs()[..]);
+    }
 }
@@ -0,0 +1,497 @@
+use std::fmt;
+use std::path::Path;
+
+use crate::cpu::{CpuError, LoadError, CPU};
+use crate::host::Machine;
+use crate::megachip::{MEGA_HEIGHT, MEGA_WIDTH};
+use crate::screen::Screen;
+
+// Runs a ROM without any frontend, feeding it scripted input and comparing the screen against
+// expected fixtures. Used to test whole programs rather than single instructions.
+pub struct Headless {
+    machine: Machine<(), (), InputScript, ()>,
+}
+
+impl Headless {
+    pub fn new(cpu: CPU) -> Self {
+        Headless {
+            machine: Machine::new(cpu).with_input(InputScript::new()),
+        }
+    }
+
+    pub fn from_rom(rom: &[u8]) -> Result<Self, LoadError> {
+        let mut cpu = CPU::new();
+        cpu.load_rom(rom)?;
+        Ok(Headless::new(cpu))
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, LoadError> {
+        let mut cpu = CPU::new();
+        cpu.load_from_file(path)?;
+        Ok(Headless::new(cpu))
+    }
+
+    pub fn with_instructions_per_frame(mut self, instructions_per_frame: u32) -> Self {
+        self.machine = self.machine.with_instructions_per_frame(instructions_per_frame);
+        self
+    }
+
+    pub fn with_input(mut self, input: InputScript) -> Self {
+        self.machine = self.machine.with_input(input);
+        self
+    }
+
+    pub fn cpu(&self) -> &CPU {
+        self.machine.cpu()
+    }
+
+    // Number of frames run so far
+    pub fn frame(&self) -> u32 {
+        self.machine.frame()
+    }
+
+    pub fn run_frames(&mut self, frames: u32) -> Result<(), CpuError> {
+        self.machine.run_frames(frames)
+    }
+
+    pub fn run_until(&mut self, frame: u32) -> Result<(), CpuError> {
+        if frame > self.frame() {
+            self.run_frames(frame - self.frame())?;
+        }
+        Ok(())
+    }
+
+    pub fn check_screen(&self, expected: &Framebuffer) -> Result<(), Mismatch> {
+        let actual = Framebuffer::from_screen(self.cpu().screen());
+        if actual.matches(expected) {
+            Ok(())
+        } else {
+            Err(Mismatch {
+                frame: self.frame(),
+                expected: expected.clone(),
+                actual,
+            })
+        }
+    }
+
+    // Runs up to every checkpoint in order and stops at the first screen that doesn't match
+    pub fn check_checkpoints(&mut self, checkpoints: &[(u32, Framebuffer)]) -> Result<(), HeadlessError> {
+        for (frame, expected) in checkpoints {
+            self.run_until(*frame)?;
+            self.check_screen(expected)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum HeadlessError {
+    Cpu(CpuError),
+    Mismatch(Mismatch),
+}
+
+impl fmt::Display for HeadlessError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HeadlessError::Cpu(err) => write!(f, "{}", err),
+            HeadlessError::Mismatch(mismatch) => write!(f, "{}", mismatch),
+        }
+    }
+}
+
+impl std::error::Error for HeadlessError {}
+
+impl From<CpuError> for HeadlessError {
+    fn from(err: CpuError) -> Self {
+        HeadlessError::Cpu(err)
+    }
+}
+
+impl From<Mismatch> for HeadlessError {
+    fn from(mismatch: Mismatch) -> Self {
+        HeadlessError::Mismatch(mismatch)
+    }
+}
+
+// Keypad states that start at a given frame and are held until the next entry
+#[derive(Debug, Default, Clone)]
+pub struct InputScript {
+    events: Vec<(u32, u16)>,
+}
+
+impl InputScript {
+    pub fn new() -> Self {
+        InputScript {
+            events: Vec::new(),
+        }
+    }
+
+    pub fn hold(self, frame: u32, keys: &[u8]) -> Self {
+        let state = keys.iter().fold(0, |state, &key| state | 1 << (key & 0x0F));
+        self.set(frame, state)
+    }
+
+    pub fn release_all(self, frame: u32) -> Self {
+        self.set(frame, 0)
+    }
+
+    fn set(mut self, frame: u32, state: u16) -> Self {
+        self.events.retain(|&(f, _)| f != frame);
+        self.events.push((frame, state));
+        self.events.sort_by_key(|&(f, _)| f);
+        self
+    }
+
+    pub fn keys_at(&self, frame: u32) -> u16 {
+        self.events
+            .iter()
+            .take_while(|&&(f, _)| f <= frame)
+            .last()
+            .map_or(0, |&(_, state)| state)
+    }
+}
+
+// No screen is larger than the MEGA-CHIP one, bigger fixtures are refused before allocating them
+const MAX_FIXTURE_PIXELS: usize = MEGA_WIDTH * MEGA_HEIGHT;
+
+#[derive(Debug)]
+pub struct FixtureError(String);
+
+impl fmt::Display for FixtureError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Invalid fixture: {}", self.0)
+    }
+}
+
+impl std::error::Error for FixtureError {}
+
+// Monochrome image used as expected screen content. Fixtures may be smaller than the screen,
+// anything outside of them is expected to be off.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Framebuffer {
+    width: usize,
+    height: usize,
+    pixels: Vec<bool>,
+}
+
+impl Framebuffer {
+    pub fn from_screen(screen: &Screen) -> Self {
+        Framebuffer {
+            width: screen.width(),
+            height: screen.height(),
+            pixels: screen.pixels().to_vec(),
+        }
+    }
+
+    // One row per line, '#' is on and '.' is off. Surrounding whitespace and blank lines are
+    // ignored so fixtures can be indented in the source.
+    pub fn from_ascii(art: &str) -> Result<Self, FixtureError> {
+        let rows: Vec<&str> = art
+            .lines()
+            .map(|line| line.trim())
+            .skip_while(|line| line.is_empty())
+            .collect();
+        let rows = &rows[..rows.iter().rposition(|line| !line.is_empty()).map_or(0, |i| i + 1)];
+
+        let width = rows.iter().map(|row| row.chars().count()).max().unwrap_or(0);
+        let mut pixels = vec![false; width * rows.len()];
+        for (y, row) in rows.iter().enumerate() {
+            for (x, c) in row.chars().enumerate() {
+                pixels[y * width + x] = match c {
+                    '#' => true,
+                    '.' => false,
+                    _ => return Err(FixtureError(format!("unexpected character '{}' in row {}", c, y))),
+                };
+            }
+        }
+
+        Ok(Framebuffer {
+            width,
+            height: rows.len(),
+            pixels,
+        })
+    }
+
+    // Accepts both plain (P1) and raw (P4) portable bitmaps, see: http://netpbm.sourceforge.net/doc/pbm.html
+    pub fn from_pbm(data: &[u8]) -> Result<Self, FixtureError> {
+        let mut pos = 0;
+        let magic = next_token(data, &mut pos)?;
+        let raw = match magic {
+            b"P1" => false,
+            b"P4" => true,
+            _ => return Err(FixtureError(String::from("not a PBM file"))),
+        };
+        let width = parse_number(next_token(data, &mut pos)?)?;
+        let height = parse_number(next_token(data, &mut pos)?)?;
+        let size = width
+            .checked_mul(height)
+            .filter(|&size| size <= MAX_FIXTURE_PIXELS)
+            .ok_or_else(|| FixtureError(format!("{}x{} is larger than any screen", width, height)))?;
+
+        let mut pixels = Vec::with_capacity(size);
+        if raw {
+            // Exactly one whitespace byte separates the header from the bitmap
+            let bitmap = data.get(pos + 1..).unwrap_or(&[]);
+            let row_bytes = width.div_ceil(8);
+            if bitmap.len() < row_bytes * height {
+                return Err(FixtureError(String::from("bitmap is truncated")));
+            }
+            for y in 0..height {
+                for x in 0..width {
+                    let byte = bitmap[y * row_bytes + x / 8];
+                    pixels.push((byte >> (7 - x % 8)) & 1 == 1);
+                }
+            }
+        } else {
+            while pixels.len() < size {
+                skip_whitespace(data, &mut pos);
+                match data.get(pos) {
+                    Some(b'1') => pixels.push(true),
+                    Some(b'0') => pixels.push(false),
+                    Some(_) => return Err(FixtureError(String::from("unexpected character in bitmap"))),
+                    None => return Err(FixtureError(String::from("bitmap is truncated"))),
+                }
+                pos += 1;
+            }
+        }
+
+        Ok(Framebuffer {
+            width,
+            height,
+            pixels,
+        })
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    // Pixels outside of the framebuffer are considered off
+    pub fn get_pixel(&self, x: usize, y: usize) -> bool {
+        x < self.width && y < self.height && self.pixels[y * self.width + x]
+    }
+
+    pub fn matches(&self, other: &Framebuffer) -> bool {
+        let width = self.width.max(other.width);
+        let height = self.height.max(other.height);
+        (0..height).all(|y| (0..width).all(|x| self.get_pixel(x, y) == other.get_pixel(x, y)))
+    }
+
+    pub fn to_ascii(&self) -> String {
+        let mut art = String::with_capacity((self.width + 1) * self.height);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                art.push(if self.get_pixel(x, y) { '#' } else { '.' });
+            }
+            art.push('\n');
+        }
+        art
+    }
+
+    pub fn to_pbm(&self) -> String {
+        let mut pbm = format!("P1\n{} {}\n", self.width, self.height);
+        for y in 0..self.height {
+            let row: Vec<&str> = (0..self.width)
+                .map(|x| if self.get_pixel(x, y) { "1" } else { "0" })
+                .collect();
+            pbm.push_str(&row.join(" "));
+            pbm.push('\n');
+        }
+        pbm
+    }
+}
+
+fn skip_whitespace(data: &[u8], pos: &mut usize) {
+    while let Some(&c) = data.get(*pos) {
+        if c == b'#' {
+            // Comments run until the end of the line
+            while data.get(*pos).is_some_and(|&c| c != b'\n') {
+                *pos += 1;
+            }
+        } else if c.is_ascii_whitespace() {
+            *pos += 1;
+        } else {
+            break;
+        }
+    }
+}
+
+fn next_token<'a>(data: &'a [u8], pos: &mut usize) -> Result<&'a [u8], FixtureError> {
+    skip_whitespace(data, pos);
+    let start = *pos;
+    while data.get(*pos).is_some_and(|c| !c.is_ascii_whitespace()) {
+        *pos += 1;
+    }
+
+    if start == *pos {
+        return Err(FixtureError(String::from("header is truncated")));
+    }
+    Ok(&data[start..*pos])
+}
+
+fn parse_number(token: &[u8]) -> Result<usize, FixtureError> {
+    std::str::from_utf8(token)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| FixtureError(String::from("invalid size in header")))
+}
+
+// Displays the expected and actual screens side by side together with a diff, where '+' marks
+// pixels that are unexpectedly on and '-' pixels that should be on but aren't.
+#[derive(Debug)]
+pub struct Mismatch {
+    pub frame: u32,
+    pub expected: Framebuffer,
+    pub actual: Framebuffer,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let width = self.expected.width.max(self.actual.width);
+        let height = self.expected.height.max(self.actual.height);
+        let differences = (0..height)
+            .flat_map(|y| (0..width).map(move |x| (x, y)))
+            .filter(|&(x, y)| self.expected.get_pixel(x, y) != self.actual.get_pixel(x, y))
+            .count();
+
+        writeln!(f, "Screen mismatch at frame {}: {} pixels differ", self.frame, differences)?;
+        // Narrow screens are padded to the headings
+        let expected_width = width.max("expected".len());
+        let actual_width = width.max("actual".len());
+        writeln!(f, "{:<ew$} {:<aw$} diff", "expected", "actual", ew = expected_width, aw = actual_width)?;
+        for y in 0..height {
+            let mut expected = String::with_capacity(width);
+            let mut actual = String::with_capacity(width);
+            let mut diff = String::with_capacity(width);
+            for x in 0..width {
+                let e = self.expected.get_pixel(x, y);
+                let a = self.actual.get_pixel(x, y);
+                expected.push(if e { '#' } else { '.' });
+                actual.push(if a { '#' } else { '.' });
+                diff.push(match (e, a) {
+                    (false, true) => '+',
+                    (true, false) => '-',
+                    _ => ' ',
+                });
+            }
+            let row = format!("{:<ew$} {:<aw$} {}", expected, actual, diff, ew = expected_width, aw = actual_width);
+            writeln!(f, "{}", row.trim_end())?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Mismatch {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GLYPH_A: &str = "
+        ####
+        #..#
+        ####
+        #..#
+        #..#
+    ";
+
+    #[test]
+    fn ascii_fixture() {
+        let fb = Framebuffer::from_ascii(GLYPH_A).unwrap();
+        assert_eq!(4, fb.width());
+        assert_eq!(5, fb.height());
+        assert!(fb.get_pixel(0, 0));
+        assert!(!fb.get_pixel(1, 1));
+        assert!(!fb.get_pixel(10, 10));
+        assert_eq!("####\n#..#\n####\n#..#\n#..#\n", fb.to_ascii());
+
+        assert!(Framebuffer::from_ascii("#x").is_err());
+    }
+
+    #[test]
+    fn pbm_fixture() {
+        let expected = Framebuffer::from_ascii(GLYPH_A).unwrap();
+
+        let plain = Framebuffer::from_pbm(expected.to_pbm().as_bytes()).unwrap();
+        assert_eq!(expected, plain);
+
+        let raw = Framebuffer::from_pbm(b"P4\n# glyph\n4 5\n\xF0\x90\xF0\x90\x90").unwrap();
+        assert_eq!(expected, raw);
+
+        assert!(Framebuffer::from_pbm(b"P4\n4 5\n\xF0").is_err());
+        assert!(Framebuffer::from_pbm(b"P2\n4 5\n").is_err());
+        // Sizes that overflow or would need gigabytes are refused up front
+        assert!(Framebuffer::from_pbm(b"P1\n18446744073709551615 2\n").is_err());
+        assert!(Framebuffer::from_pbm(b"P1\n100000 100000\n").is_err());
+    }
+
+    #[test]
+    fn input_script() {
+        let script = InputScript::new()
+            .hold(5, &[0x1, 0xA])
+            .release_all(8);
+
+        assert_eq!(0, script.keys_at(0));
+        assert_eq!(0b0000_0100_0000_0010, script.keys_at(5));
+        assert_eq!(0b0000_0100_0000_0010, script.keys_at(7));
+        assert_eq!(0, script.keys_at(8));
+    }
+
+    #[test]
+    fn draw_pressed_key() {
+        let rom = [
+            0xF0, 0x0A, // LD V0, K
+            0xF0, 0x29, // LD F, V0
+            0xD1, 0x15, // DRW V1, V1, 5
+            0x12, 0x06, // JP 0x206
+        ];
+        let mut headless = Headless::from_rom(&rom)
+            .unwrap()
+            .with_input(InputScript::new().hold(5, &[0xA]).release_all(6));
+
+        let checkpoints = [
+            (4, Framebuffer::from_ascii("").unwrap()),
+            (10, Framebuffer::from_ascii(GLYPH_A).unwrap()),
+        ];
+        if let Err(err) = headless.check_checkpoints(&checkpoints) {
+            panic!("{}", err);
+        }
+        assert_eq!(10, headless.frame());
+    }
+
+    #[test]
+    fn mismatch_diff() {
+        let expected = Framebuffer::from_ascii("##\n..").unwrap();
+        let actual = Framebuffer::from_ascii("#.\n.#").unwrap();
+        let mismatch = Mismatch {
+            frame: 3,
+            expected,
+            actual,
+        };
+
+        assert_eq!(
+            "Screen mismatch at frame 3: 2 pixels differ\n\
+             expected actual diff\n\
+             ##       #.      -\n\
+             ..       .#      +\n",
+            mismatch.to_string()
+        );
+
+        // Wide screens push the headings apart
+        let mismatch = Mismatch {
+            frame: 0,
+            expected: Framebuffer::from_ascii("##########").unwrap(),
+            actual: Framebuffer::from_ascii("#########.").unwrap(),
+        };
+        assert_eq!(
+            "Screen mismatch at frame 0: 1 pixels differ\n\
+             expected   actual     diff\n\
+             ########## #########.          -\n",
+            mismatch.to_string()
+        );
+    }
+}
@@ -0,0 +1,393 @@
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
+use std::thread;
+#[cfg(feature = "std")]
+use std::time::{Duration, Instant};
+
+use crate::audio::{self, SamplePlayer, SquareWave};
+#[cfg(feature = "std")]
+use crate::audio::FRAMES_PER_SECOND;
+use crate::cpu::{CpuError, CPU, DEFAULT_INSTRUCTIONS_PER_FRAME};
+#[cfg(feature = "std")]
+use crate::headless::InputScript;
+use crate::screen::Screen;
+
+pub use crate::audio::AudioSink;
+
+// Shows the screen once per frame
+pub trait Presenter {
+    fn present(&mut self, screen: &Screen);
+}
+
+// Keypad state to use for a frame, one bit per key
+pub trait KeypadSource {
+    fn poll(&mut self, frame: u32) -> u16;
+}
+
+// Paces the machine. Called after every frame and returns once the next one is due.
+pub trait TimeSource {
+    fn wait_frame(&mut self);
+}
+
+// () stands in for any part of the host that isn't there: nothing is shown, no key is pressed,
+// no audio is produced and frames run as fast as possible
+impl Presenter for () {
+    fn present(&mut self, _screen: &Screen) {}
+}
+
+impl KeypadSource for () {
+    fn poll(&mut self, _frame: u32) -> u16 {
+        0
+    }
+}
+
+impl AudioSink for () {
+    fn sample_rate(&self) -> u32 {
+        0
+    }
+
+    fn queue_samples(&mut self, _samples: &[f32]) {}
+}
+
+impl TimeSource for () {
+    fn wait_frame(&mut self) {}
+}
+
+// The keys the host last reported, for frontends that get key events instead of polling
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct HeldKeys(pub u16);
+
+impl KeypadSource for HeldKeys {
+    fn poll(&mut self, _frame: u32) -> u16 {
+        self.0
+    }
+}
+
+#[cfg(feature = "std")]
+impl KeypadSource for InputScript {
+    fn poll(&mut self, frame: u32) -> u16 {
+        self.keys_at(frame)
+    }
+}
+
+// Runs at 60 frames per second of wall-clock time. Deadlines are kept on a fixed grid so sleep
+// inaccuracies don't add up, a host that falls behind continues from now rather than rushing.
+#[cfg(feature = "std")]
+#[derive(Debug, Default)]
+pub struct RealTime {
+    next: Option<Instant>,
+}
+
+#[cfg(feature = "std")]
+impl RealTime {
+    pub fn new() -> Self {
+        RealTime {
+            next: None,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl TimeSource for RealTime {
+    fn wait_frame(&mut self) {
+        let now = Instant::now();
+        let next = self.next.unwrap_or(now) + Duration::from_nanos(1_000_000_000 / FRAMES_PER_SECOND);
+        if next > now {
+            thread::sleep(next - now);
+            self.next = Some(next);
+        } else {
+            self.next = Some(now);
+        }
+    }
+}
+
+// Drives a CPU against a host: reads the keypad, runs a frame worth of instructions, feeds the
+// beeper and MEGA-CHIP samples to the audio sink, presents the screen and waits for the next
+// frame. Frontends only implement the traits for whatever they talk to.
+pub struct Machine<P, A, K, T> {
+    cpu: CPU,
+    presenter: P,
+    audio: A,
+    input: K,
+    time: T,
+    beeper: SquareWave,
+    samples: SamplePlayer,
+    instructions_per_frame: u32,
+    frame: u32,
+    buffer: Vec<f32>,
+}
+
+impl Machine<(), (), (), ()> {
+    pub fn new(cpu: CPU) -> Self {
+        Machine {
+            cpu,
+            presenter: (),
+            audio: (),
+            input: (),
+            time: (),
+            beeper: SquareWave::new(0),
+            samples: SamplePlayer::new(0),
+            instructions_per_frame: DEFAULT_INSTRUCTIONS_PER_FRAME,
+            frame: 0,
+            buffer: Vec::new(),
+        }
+    }
+}
+
+impl<P: Presenter, A: AudioSink, K: KeypadSource, T: TimeSource> Machine<P, A, K, T> {
+    pub fn with_presenter<Q: Presenter>(self, presenter: Q) -> Machine<Q, A, K, T> {
+        Machine {
+            cpu: self.cpu,
+            presenter,
+            audio: self.audio,
+            input: self.input,
+            time: self.time,
+            beeper: self.beeper,
+            samples: self.samples,
+            instructions_per_frame: self.instructions_per_frame,
+            frame: self.frame,
+            buffer: self.buffer,
+        }
+    }
+
+    pub fn with_audio<B: AudioSink>(self, audio: B) -> Machine<P, B, K, T> {
+        let mut machine = Machine {
+            cpu: self.cpu,
+            presenter: self.presenter,
+            audio,
+            input: self.input,
+            time: self.time,
+            beeper: self.beeper,
+            samples: self.samples,
+            instructions_per_frame: self.instructions_per_frame,
+            frame: self.frame,
+            buffer: self.buffer,
+        };
+        machine.sync_sample_rate();
+        machine
+    }
+
+    pub fn with_input<L: KeypadSource>(self, input: L) -> Machine<P, A, L, T> {
+        Machine {
+            cpu: self.cpu,
+            presenter: self.presenter,
+            audio: self.audio,
+            input,
+            time: self.time,
+            beeper: self.beeper,
+            samples: self.samples,
+            instructions_per_frame: self.instructions_per_frame,
+            frame: self.frame,
+            buffer: self.buffer,
+        }
+    }
+
+    pub fn with_time<U: TimeSource>(self, time: U) -> Machine<P, A, K, U> {
+        Machine {
+            cpu: self.cpu,
+            presenter: self.presenter,
+            audio: self.audio,
+            input: self.input,
+            time,
+            beeper: self.beeper,
+            samples: self.samples,
+            instructions_per_frame: self.instructions_per_frame,
+            frame: self.frame,
+            buffer: self.buffer,
+        }
+    }
+
+    pub fn with_instructions_per_frame(mut self, instructions_per_frame: u32) -> Self {
+        self.instructions_per_frame = instructions_per_frame;
+        self
+    }
+
+    pub fn instructions_per_frame(&self) -> u32 {
+        self.instructions_per_frame
+    }
+
+    // For programs that need a different speed than the one the machine was built with
+    pub fn set_instructions_per_frame(&mut self, instructions_per_frame: u32) {
+        self.instructions_per_frame = instructions_per_frame;
+    }
+
+    pub fn cpu(&self) -> &CPU {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut CPU {
+        &mut self.cpu
+    }
+
+    pub fn presenter(&self) -> &P {
+        &self.presenter
+    }
+
+    pub fn audio(&self) -> &A {
+        &self.audio
+    }
+
+    // Call sync_sample_rate after changing the sink's rate
+    pub fn audio_mut(&mut self) -> &mut A {
+        &mut self.audio
+    }
+
+    pub fn input(&self) -> &K {
+        &self.input
+    }
+
+    pub fn input_mut(&mut self) -> &mut K {
+        &mut self.input
+    }
+
+    pub fn beeper_mut(&mut self) -> &mut SquareWave {
+        &mut self.beeper
+    }
+
+    pub fn samples_mut(&mut self) -> &mut SamplePlayer {
+        &mut self.samples
+    }
+
+    // Brings the beeper and the sample player in line with the audio sink
+    pub fn sync_sample_rate(&mut self) {
+        self.beeper.set_sample_rate(self.audio.sample_rate());
+        self.samples.set_sample_rate(self.audio.sample_rate());
+    }
+
+    // Number of frames run so far
+    pub fn frame(&self) -> u32 {
+        self.frame
+    }
+
+    pub fn run_frame(&mut self) -> Result<(), CpuError> {
+        let keys = self.input.poll(self.frame);
+        self.cpu.keypad_mut().set_state(keys);
+        self.cpu.run_instructions(self.instructions_per_frame)?;
+        self.end_frame();
+        Ok(())
+    }
+
+    // Everything in a frame after the instructions: timers, audio, presenting and waiting. For
+    // hosts that run the instructions some other way, like on a recompiled module.
+    pub fn end_frame(&mut self) {
+        // A sound timer of n keeps the beeper on for exactly n frames
+        let active = self.cpu.is_sound_playing();
+        self.cpu.tick_timers();
+        self.buffer.resize(audio::samples_in_frame(self.frame as u64, self.audio.sample_rate()), 0.0);
+        if !self.buffer.is_empty() {
+            self.beeper.fill(&mut self.buffer, active);
+            self.samples.mix(&self.cpu, &mut self.buffer);
+            self.audio.queue_samples(&self.buffer);
+        }
+
+        self.presenter.present(self.cpu.screen());
+        self.frame += 1;
+        self.time.wait_frame();
+    }
+
+    pub fn run_frames(&mut self, frames: u32) -> Result<(), CpuError> {
+        for _ in 0..frames {
+            self.run_frame()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wav::WavRecorder;
+
+    #[derive(Default)]
+    struct Frames {
+        presented: u32,
+        lit: usize,
+    }
+
+    impl Presenter for Frames {
+        fn present(&mut self, screen: &Screen) {
+            self.presented += 1;
+            self.lit = screen.pixels().iter().filter(|&&on| on).count();
+        }
+    }
+
+    struct Ticks(u32);
+
+    impl TimeSource for Ticks {
+        fn wait_frame(&mut self) {
+            self.0 += 1;
+        }
+    }
+
+    #[test]
+    fn run_frames() {
+        // Waits for key 5, beeps for 3 frames and draws the digit
+        let rom = [
+            0xF0, 0x0A, // LD V0, K
+            0x61, 0x03, // LD V1, 3
+            0xF1, 0x18, // LD ST, V1
+            0xF0, 0x29, // LD F, V0
+            0xD2, 0x25, // DRW V2, V2, 5
+            0x12, 0x0A, // JP 0x20A
+        ];
+        let mut cpu = CPU::new();
+        cpu.load_rom(&rom).unwrap();
+
+        let mut machine = Machine::new(cpu)
+            .with_presenter(Frames::default())
+            .with_audio(WavRecorder::new(1000))
+            .with_input(InputScript::new().hold(2, &[5]))
+            .with_time(Ticks(0));
+        machine.run_frames(6).unwrap();
+
+        assert_eq!(6, machine.frame());
+        assert_eq!(6, machine.presenter().presented);
+        assert_eq!(6, machine.time.0);
+        assert_eq!(5, machine.cpu().registers()[0]);
+        // The 5 sprite has 14 pixels set
+        assert_eq!(14, machine.presenter().lit);
+
+        // Frames of 16 and 17 samples add up to exactly 100 samples in 6 frames
+        let samples = machine.audio().samples();
+        assert_eq!(100, samples.len());
+        let beeping: Vec<bool> = (0..6u64)
+            .map(|frame| {
+                let start = (frame * 1000 / 60) as usize;
+                samples[start..start + 16].iter().any(|&s| s != 0.0)
+            })
+            .collect();
+        assert_eq!(vec![false, false, true, true, true, false], beeping);
+    }
+
+    #[test]
+    fn held_keys() {
+        // LD V1, 7; SKP V1; JP 0x202; LD V0, 1; JP 0x208
+        let rom = [0x61, 0x07, 0xE1, 0x9E, 0x12, 0x02, 0x60, 0x01, 0x12, 0x08];
+        let mut cpu = CPU::new();
+        cpu.load_rom(&rom).unwrap();
+
+        let mut machine = Machine::new(cpu).with_input(HeldKeys::default());
+        machine.run_frame().unwrap();
+        assert_eq!(0, machine.cpu().registers()[0]);
+        machine.input_mut().0 = 1 << 7;
+        machine.run_frame().unwrap();
+        assert_eq!(1, machine.cpu().registers()[0]);
+    }
+
+    #[test]
+    fn matches_run_frame() {
+        let rom = [0xC0, 0xFF, 0x70, 0x01, 0xF0, 0x15, 0x12, 0x00];
+        let mut cpu = CPU::new();
+        cpu.seed_rng(7);
+        cpu.load_rom(&rom).unwrap();
+        let mut expected = CPU::new();
+        expected.load_state(&cpu.save_state());
+
+        let mut machine = Machine::new(cpu).with_instructions_per_frame(13);
+        machine.run_frames(20).unwrap();
+        for _ in 0..20 {
+            expected.run_frame(13).unwrap();
+        }
+        assert_eq!(expected.state_hash(), machine.cpu().state_hash());
+    }
+}
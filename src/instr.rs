@@ -0,0 +1,250 @@
+use crate::megachip::BlendMode;
+use crate::variant::Variant;
+
+// A decoded instruction. Register operands are indices into V0..VF, decoding doesn't depend on
+// the quirks so decoded instructions stay valid when those change. It does depend on the variant.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instr {
+    Sys(u16),                       // 0nnn, 1802 machine code
+    Cls,                            // 00E0
+    Ret,                            // 00EE
+    Jp(u16),                        // 1nnn
+    Call(u16),                      // 2nnn
+    SeImm(usize, u8),               // 3xkk
+    SneImm(usize, u8),              // 4xkk
+    SeReg(usize, usize),            // 5xy0
+    LdImm(usize, u8),               // 6xkk
+    AddImm(usize, u8),              // 7xkk
+    LdReg(usize, usize),            // 8xy0
+    Or(usize, usize),               // 8xy1
+    And(usize, usize),              // 8xy2
+    Xor(usize, usize),              // 8xy3
+    Add(usize, usize),              // 8xy4
+    Sub(usize, usize),              // 8xy5
+    Shr(usize, usize),              // 8xy6
+    Subn(usize, usize),             // 8xy7
+    Shl(usize, usize),              // 8xyE
+    SneReg(usize, usize),           // 9xy0
+    LdAddr(u16),                    // Annn
+    JpReg(usize, u16),              // Bnnn, x is only used with the jump quirk
+    Rnd(usize, u8),                 // Cxkk
+    Drw(usize, usize, u8),          // Dxyn
+    Skp(usize),                     // Ex9E
+    Sknp(usize),                    // ExA1
+    LdVxDt(usize),                  // Fx07
+    LdVxKey(usize),                 // Fx0A
+    LdDtVx(usize),                  // Fx15
+    LdStVx(usize),                  // Fx18
+    AddAddr(usize),                 // Fx1E
+    LdFont(usize),                  // Fx29
+    LdBcd(usize),                   // Fx33
+    LdMemVx(usize),                 // Fx55
+    LdVxMem(usize),                 // Fx65
+    CycleBackground,                // 02A0, CHIP-8X
+    ColourZones(usize, usize),      // Bxy0, CHIP-8X
+    ColourRows(usize, usize, u8),   // Bxyn, CHIP-8X
+    SkpSecond(usize),               // ExF2, CHIP-8X
+    SknpSecond(usize),              // ExF5, CHIP-8X
+    Out(usize),                     // FxF8, CHIP-8X
+    In(usize),                      // FxFB, CHIP-8X
+    MegaOff,                        // 0010, MEGA-CHIP
+    MegaOn,                         // 0011, MEGA-CHIP
+    LdAddrLong(u8),                 // 01nn nnnn, MEGA-CHIP
+    LdPalette(u8),                  // 02nn, MEGA-CHIP
+    SpriteWidth(u8),                // 03nn, MEGA-CHIP
+    SpriteHeight(u8),               // 04nn, MEGA-CHIP
+    ScreenAlpha(u8),                // 05nn, MEGA-CHIP
+    PlaySample(bool),               // 060n, MEGA-CHIP, looping if n is 0
+    StopSample,                     // 0700, MEGA-CHIP
+    Blend(BlendMode),               // 080n, MEGA-CHIP
+    CollisionColour(u8),            // 09nn, MEGA-CHIP
+    ScrollUp(u8),                   // 00Bn, MEGA-CHIP
+    ScrollDown(u8),                 // 00Cn, MEGA-CHIP
+    ScrollRight,                    // 00FB, MEGA-CHIP
+    ScrollLeft,                     // 00FC, MEGA-CHIP
+    Exit,                           // 00FD, MEGA-CHIP
+    LowRes,                         // 00FE, MEGA-CHIP
+    HighRes,                        // 00FF, MEGA-CHIP
+    LdBigFont(usize),               // Fx30, MEGA-CHIP
+    SaveFlags(usize),               // Fx75, MEGA-CHIP
+    LoadFlags(usize),               // Fx85, MEGA-CHIP
+}
+
+impl Instr {
+    // Instructions after which execution doesn't simply continue with the next one. 01nn is
+    // twice as long as the others.
+    pub fn is_branch(self) -> bool {
+        matches!(
+            self,
+            Instr::Sys(_)
+                | Instr::Ret
+                | Instr::Jp(_)
+                | Instr::Call(_)
+                | Instr::SeImm(..)
+                | Instr::SneImm(..)
+                | Instr::SeReg(..)
+                | Instr::SneReg(..)
+                | Instr::JpReg(..)
+                | Instr::Skp(_)
+                | Instr::Sknp(_)
+                | Instr::LdVxKey(_)
+                | Instr::SkpSecond(_)
+                | Instr::SknpSecond(_)
+                | Instr::In(_)
+                | Instr::LdAddrLong(_)
+                | Instr::Exit
+        )
+    }
+}
+
+impl Instr {
+    pub fn is_skip(self) -> bool {
+        matches!(
+            self,
+            Instr::SeImm(..)
+                | Instr::SneImm(..)
+                | Instr::SeReg(..)
+                | Instr::SneReg(..)
+                | Instr::Skp(_)
+                | Instr::Sknp(_)
+                | Instr::SkpSecond(_)
+                | Instr::SknpSecond(_)
+        )
+    }
+}
+
+// None for opcodes that aren't instructions of the variant
+pub fn decode(instr: u16, variant: Variant) -> Option<Instr> {
+    if variant == Variant::Chip8X {
+        if let Some(decoded) = decode_chip8x(instr) {
+            return Some(decoded);
+        }
+    }
+    if variant == Variant::MegaChip {
+        if let Some(decoded) = decode_megachip(instr) {
+            return Some(decoded);
+        }
+    }
+    // The hi-res interpreter's routine for clearing both pages
+    if variant == Variant::HiRes && instr == 0x0230 {
+        return Some(Instr::Cls);
+    }
+
+    let x = ((instr >> 8) & 0x0F) as usize;
+    let y = ((instr >> 4) & 0x0F) as usize;
+    let n = (instr & 0x0F) as u8;
+    let kk = instr as u8;
+    let nnn = instr & 0x0FFF;
+
+    let decoded = match instr >> 12 {
+        0x0 => match instr {
+            0x00E0 => Instr::Cls,
+            0x00EE => Instr::Ret,
+            _ => Instr::Sys(nnn),
+        },
+        0x1 => Instr::Jp(nnn),
+        0x2 => Instr::Call(nnn),
+        0x3 => Instr::SeImm(x, kk),
+        0x4 => Instr::SneImm(x, kk),
+        0x5 if n == 0 => Instr::SeReg(x, y),
+        0x6 => Instr::LdImm(x, kk),
+        0x7 => Instr::AddImm(x, kk),
+        0x8 => match n {
+            0x0 => Instr::LdReg(x, y),
+            0x1 => Instr::Or(x, y),
+            0x2 => Instr::And(x, y),
+            0x3 => Instr::Xor(x, y),
+            0x4 => Instr::Add(x, y),
+            0x5 => Instr::Sub(x, y),
+            0x6 => Instr::Shr(x, y),
+            0x7 => Instr::Subn(x, y),
+            0xE => Instr::Shl(x, y),
+            _ => return None,
+        },
+        0x9 if n == 0 => Instr::SneReg(x, y),
+        0xA => Instr::LdAddr(nnn),
+        0xB => Instr::JpReg(x, nnn),
+        0xC => Instr::Rnd(x, kk),
+        0xD => Instr::Drw(x, y, n),
+        0xE => match kk {
+            0x9E => Instr::Skp(x),
+            0xA1 => Instr::Sknp(x),
+            _ => return None,
+        },
+        0xF => match kk {
+            0x07 => Instr::LdVxDt(x),
+            0x0A => Instr::LdVxKey(x),
+            0x15 => Instr::LdDtVx(x),
+            0x18 => Instr::LdStVx(x),
+            0x1E => Instr::AddAddr(x),
+            0x29 => Instr::LdFont(x),
+            0x33 => Instr::LdBcd(x),
+            0x55 => Instr::LdMemVx(x),
+            0x65 => Instr::LdVxMem(x),
+            _ => return None,
+        },
+        _ => return None,
+    };
+    Some(decoded)
+}
+
+// Opcodes CHIP-8X adds or gives a different meaning, the rest decode as usual
+fn decode_chip8x(instr: u16) -> Option<Instr> {
+    let x = ((instr >> 8) & 0x0F) as usize;
+    let y = ((instr >> 4) & 0x0F) as usize;
+    let n = (instr & 0x0F) as u8;
+    let kk = instr as u8;
+
+    let decoded = match instr >> 12 {
+        // A routine of the interpreter, called like any other machine code
+        0x0 if instr == 0x02A0 => Instr::CycleBackground,
+        0xB if n == 0 => Instr::ColourZones(x, y),
+        0xB => Instr::ColourRows(x, y, n),
+        0xE if kk == 0xF2 => Instr::SkpSecond(x),
+        0xE if kk == 0xF5 => Instr::SknpSecond(x),
+        0xF if kk == 0xF8 => Instr::Out(x),
+        0xF if kk == 0xFB => Instr::In(x),
+        _ => return None,
+    };
+    Some(decoded)
+}
+
+// MEGA-CHIP adds opcodes in the 0nnn range, and has those of SCHIP it builds on. Dxy0 is the
+// usual DRW, it draws 16x16 sprites outside of mega mode.
+fn decode_megachip(instr: u16) -> Option<Instr> {
+    let x = ((instr >> 8) & 0x0F) as usize;
+    let n = (instr & 0x0F) as u8;
+    let nn = instr as u8;
+
+    let decoded = match instr >> 8 {
+        0x00 => match nn {
+            0x10 => Instr::MegaOff,
+            0x11 => Instr::MegaOn,
+            0xB0..=0xBF => Instr::ScrollUp(n),
+            0xC0..=0xCF => Instr::ScrollDown(n),
+            0xFB => Instr::ScrollRight,
+            0xFC => Instr::ScrollLeft,
+            0xFD => Instr::Exit,
+            0xFE => Instr::LowRes,
+            0xFF => Instr::HighRes,
+            _ => return None,
+        },
+        0x01 => Instr::LdAddrLong(nn),
+        0x02 => Instr::LdPalette(nn),
+        0x03 => Instr::SpriteWidth(nn),
+        0x04 => Instr::SpriteHeight(nn),
+        0x05 => Instr::ScreenAlpha(nn),
+        0x06 if nn <= 1 => Instr::PlaySample(nn == 0),
+        0x07 if nn == 0 => Instr::StopSample,
+        0x08 => Instr::Blend(BlendMode::from_code(nn)?),
+        0x09 => Instr::CollisionColour(nn),
+        0xF0..=0xFF => match nn {
+            0x30 => Instr::LdBigFont(x),
+            0x75 => Instr::SaveFlags(x),
+            0x85 => Instr::LoadFlags(x),
+            _ => return None,
+        },
+        _ => return None,
+    };
+    Some(decoded)
+}
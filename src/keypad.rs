@@ -0,0 +1,63 @@
+pub const KEY_COUNT: u8 = 16;
+
+// State of the hexadecimal keypad, one bit per key
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Keypad {
+    state: u16,
+}
+
+impl Keypad {
+    pub fn new() -> Self {
+        Keypad {
+            state: 0,
+        }
+    }
+
+    pub fn state(&self) -> u16 {
+        self.state
+    }
+
+    pub fn set_state(&mut self, state: u16) {
+        self.state = state;
+    }
+
+    pub fn press(&mut self, key: u8) {
+        self.state |= 1 << (key & 0x0F);
+    }
+
+    pub fn release(&mut self, key: u8) {
+        self.state &= !(1 << (key & 0x0F));
+    }
+
+    // Only the low nibble is used, like the original interpreter does
+    pub fn is_pressed(&self, key: u8) -> bool {
+        self.state & (1 << (key & 0x0F)) != 0
+    }
+
+    pub fn first_pressed(&self) -> Option<u8> {
+        (0..KEY_COUNT).find(|&key| self.is_pressed(key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn press_release() {
+        let mut keypad = Keypad::new();
+        assert_eq!(None, keypad.first_pressed());
+
+        keypad.press(0xA);
+        keypad.press(0x3);
+        assert!(keypad.is_pressed(0xA));
+        assert!(keypad.is_pressed(0x3));
+        assert!(!keypad.is_pressed(0x4));
+        assert_eq!(Some(0x3), keypad.first_pressed());
+        assert_eq!(0b0000_0100_0000_1000, keypad.state());
+
+        keypad.release(0x3);
+        assert!(!keypad.is_pressed(0x3));
+        assert_eq!(Some(0xA), keypad.first_pressed());
+    }
+}
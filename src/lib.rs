@@ -1,4 +1,50 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+#[cfg(feature = "std")]
+pub mod analysis;
+pub mod audio;
+#[cfg(feature = "std")]
+pub mod batch;
+#[cfg(feature = "std")]
+pub mod cartridge;
+pub mod cdp1802;
 pub mod cpu;
-mod screen;
+pub mod font;
+#[cfg(feature = "std")]
+pub mod headless;
+pub mod host;
+pub mod keypad;
+pub mod megachip;
+pub mod memory;
+#[cfg(feature = "std")]
+pub mod movie;
+#[cfg(feature = "std")]
+pub mod netplay;
+#[cfg(feature = "std")]
+pub mod octo;
+pub mod quirks;
+#[cfg(feature = "std")]
+pub mod recompiler;
+#[cfg(feature = "std")]
+pub mod romdb;
+pub mod screen;
+pub mod stack;
+pub mod timing;
+pub mod variant;
+mod cache;
+mod fnv;
+mod instr;
 mod timer;
-mod font;
+#[cfg(test)]
+mod conformance;
+#[cfg(test)]
+mod reference;
+#[cfg(feature = "std")]
+mod wasm;
+#[cfg(feature = "std")]
+pub mod wav;
+
+#[cfg(feature = "std")]
+pub use wasm::Emulator;
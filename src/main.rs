@@ -1,23 +1,200 @@
-use std::{thread, time};
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::{env, process};
 
-use cpu::CPU;
+use chip8_wasm::audio::DEFAULT_SAMPLE_RATE;
+use chip8_wasm::cartridge::Cartridge;
+use chip8_wasm::cpu::{CPU, DEFAULT_INSTRUCTIONS_PER_FRAME};
+use chip8_wasm::host::{Machine, RealTime};
+use chip8_wasm::movie::Movie;
+use chip8_wasm::romdb::RomDatabase;
+use chip8_wasm::variant::Variant;
+use chip8_wasm::wav::WavRecorder;
 
-mod cpu;
-mod font;
-mod screen;
-mod timer;
+const USAGE: &str =
+    "Usage: chip8-wasm <rom> [--rom-db <file.json>] [--audio-out <file.wav> --frames <n>] [--replay <movie>]";
+
+struct Options {
+    rom: String,
+    rom_db: Option<String>,
+    // The WAV file and how many frames go into it, only given together
+    audio_out: Option<(String, u32)>,
+    replay: Option<String>,
+}
+
+fn parse_args() -> Result<Options, String> {
+    let mut rom = None;
+    let mut rom_db = None;
+    let mut audio_out = None;
+    let mut frames = None;
+    let mut replay = None;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--rom-db" => {
+                rom_db = Some(args.next().ok_or("Missing file name for --rom-db")?);
+            }
+            "--audio-out" => {
+                audio_out = Some(args.next().ok_or("Missing file name for --audio-out")?);
+            }
+            "--frames" => {
+                let value = args.next().ok_or("Missing count for --frames")?;
+                frames = Some(value.parse().map_err(|_| format!("Invalid frame count: {}", value))?);
+            }
+            "--replay" => {
+                replay = Some(args.next().ok_or("Missing file name for --replay")?);
+            }
+            _ if rom.is_none() => rom = Some(arg),
+            _ => return Err(format!("Unexpected argument: {}", arg)),
+        }
+    }
+
+    let audio_out = match (audio_out, frames) {
+        (Some(file_name), Some(frames)) => Some((file_name, frames)),
+        (Some(_), None) => return Err(String::from("--audio-out requires --frames")),
+        // Frames only bound the run when rendering audio
+        (None, Some(_)) => return Err(String::from("--frames requires --audio-out")),
+        (None, None) => None,
+    };
+
+    Ok(Options {
+        rom: rom.ok_or("Missing ROM file")?,
+        rom_db,
+        audio_out,
+        replay,
+    })
+}
+
+// Renders the beeper for a fixed number of frames without any timing or audio device
+fn export_audio(emu: CPU, file_name: &str, frames: u32, instructions_per_frame: u32) {
+    let mut machine = Machine::new(emu)
+        .with_instructions_per_frame(instructions_per_frame)
+        .with_audio(WavRecorder::new(DEFAULT_SAMPLE_RATE));
+    if let Err(err) = machine.run_frames(frames) {
+        eprintln!("{}", err);
+    }
+
+    let file = File::create(file_name).expect("Cannot create audio file");
+    machine
+        .audio()
+        .write_to(&mut BufWriter::new(file))
+        .expect("Cannot write audio file");
+}
+
+// Both return the number of instructions per frame the program needs
+fn load_rom(emu: &mut CPU, db: &RomDatabase, rom: &[u8]) -> u32 {
+    // Hi-res and MEGA-CHIP programs can be told apart by how they start. Before loading, since
+    // MEGA-CHIP ones may not fit otherwise.
+    if db.lookup(rom).is_none() {
+        emu.set_variant(Variant::detect(rom));
+    }
+
+    match db.load_rom(emu, rom) {
+        Ok(Some(info)) => {
+            println!("{}", info.title);
+            info.instructions_per_frame()
+        }
+        Ok(None) => DEFAULT_INSTRUCTIONS_PER_FRAME,
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+    }
+}
+
+fn load_cartridge(emu: &mut CPU, data: &[u8]) -> u32 {
+    let cartridge = Cartridge::from_gif(data).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(1);
+    });
+    if let Err(err) = cartridge.load(emu) {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+    cartridge.instructions_per_frame()
+}
+
+// Checks that a recorded session still plays out the same, for bug reports and regressions
+fn replay(emu: &mut CPU, file_name: &str, rom: &[u8]) {
+    let movie = fs::read(file_name)
+        .map_err(|err| err.to_string())
+        .and_then(|data| Movie::from_bytes(&data).map_err(|err| err.to_string()))
+        .unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            process::exit(1);
+        });
+
+    match movie.replay(emu, rom) {
+        Ok(error) => {
+            if let Some(error) = error {
+                println!("{}", error);
+            }
+            println!("Replayed {} frames, final state matches", movie.frames.len());
+        }
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+    }
+}
 
 fn main() {
-    // TODO get file name dynamically
-    let file_name = String::from("games/TETRIS");
+    let options = parse_args().unwrap_or_else(|err| {
+        eprintln!("{}\n{}", err, USAGE);
+        process::exit(1);
+    });
+
+    // Entries from the user's database take precedence over the builtin ones
+    let mut db = RomDatabase::builtin();
+    if let Some(rom_db) = &options.rom_db {
+        match RomDatabase::from_file(rom_db) {
+            Ok(user_db) => db.extend(user_db),
+            Err(err) => {
+                eprintln!("{}", err);
+                process::exit(1);
+            }
+        }
+    }
+
+    let rom = fs::read(&options.rom).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(1);
+    });
 
     let mut emu: CPU = CPU::new();
-    emu
-        .load_from_file(file_name)
-        .expect("Cannot load from file");
+    if let Some(movie) = options.replay {
+        // Movies of cartridges refer to the program inside
+        let program = if Cartridge::is_gif(&rom) {
+            Cartridge::from_gif(&rom).map(|cartridge| cartridge.program).unwrap_or_else(|err| {
+                eprintln!("{}", err);
+                process::exit(1);
+            })
+        } else {
+            rom
+        };
+        replay(&mut emu, &movie, &program);
+        return;
+    }
+
+    let instructions_per_frame = if Cartridge::is_gif(&rom) {
+        load_cartridge(&mut emu, &rom)
+    } else {
+        load_rom(&mut emu, &db, &rom)
+    };
+
+    if let Some((audio_out, frames)) = options.audio_out {
+        export_audio(emu, &audio_out, frames, instructions_per_frame);
+        return;
+    }
 
+    let mut machine = Machine::new(emu)
+        .with_instructions_per_frame(instructions_per_frame)
+        .with_time(RealTime::new());
     loop {
-        emu.tick();
-        thread::sleep(time::Duration::from_millis(100));
+        if let Err(err) = machine.run_frame() {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
     }
 }
@@ -0,0 +1,303 @@
+use alloc::boxed::Box;
+use alloc::vec;
+
+use crate::fnv::Fnv1a;
+
+// The display 0011 switches to
+pub const MEGA_WIDTH: usize = 256;
+pub const MEGA_HEIGHT: usize = 192;
+
+// The SCHIP display 00FF switches to outside of mega mode
+pub const SCHIP_WIDTH: usize = 128;
+pub const SCHIP_HEIGHT: usize = 64;
+
+// How many registers Fx75 and Fx85 save and restore, like the HP-48 RPL flags SCHIP used
+pub const FLAG_COUNT: usize = 8;
+
+// The 8x10 glyphs of the digits 0-9 that Fx30 points at, taken from SCHIP 1.1
+pub const BIG_FONT: [u8; 100] = [
+    0x3C, 0x7E, 0xE7, 0xC3, 0xC3, 0xC3, 0xC3, 0xE7, 0x7E, 0x3C, // 0
+    0x18, 0x38, 0x58, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x3C, // 1
+    0x3E, 0x7F, 0xC3, 0x06, 0x0C, 0x18, 0x30, 0x60, 0xFF, 0xFF, // 2
+    0x3C, 0x7E, 0xC3, 0x03, 0x0E, 0x0E, 0x03, 0xC3, 0x7E, 0x3C, // 3
+    0x06, 0x0E, 0x1E, 0x36, 0x66, 0xC6, 0xFF, 0xFF, 0x06, 0x06, // 4
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFC, 0xFE, 0x03, 0xC3, 0x7E, 0x3C, // 5
+    0x3E, 0x7C, 0xC0, 0xC0, 0xFC, 0xFE, 0xC3, 0xC3, 0x7E, 0x3C, // 6
+    0xFF, 0xFF, 0x03, 0x06, 0x0C, 0x18, 0x30, 0x60, 0x60, 0x60, // 7
+    0x3C, 0x7E, 0xC3, 0xC3, 0x7E, 0x7E, 0xC3, 0xC3, 0x7E, 0x3C, // 8
+    0x3C, 0x7E, 0xC3, 0xC3, 0x7F, 0x3F, 0x03, 0x03, 0x3E, 0x7C, // 9
+];
+pub const BIG_LINES_PER_SPRITE: usize = 10;
+
+// I is 24 bits wide, all of it addresses memory
+pub const MEGA_MEMORY: usize = 1 << 24;
+
+// How 080n combines sprite pixels with what is already drawn
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum BlendMode {
+    #[default]
+    Normal,
+    // The sprite at 25%, 50% and 75% opacity
+    Alpha25,
+    Alpha50,
+    Alpha75,
+    Add,
+    Multiply,
+}
+
+impl BlendMode {
+    pub fn from_code(code: u8) -> Option<BlendMode> {
+        match code {
+            0 => Some(BlendMode::Normal),
+            1 => Some(BlendMode::Alpha25),
+            2 => Some(BlendMode::Alpha50),
+            3 => Some(BlendMode::Alpha75),
+            4 => Some(BlendMode::Add),
+            5 => Some(BlendMode::Multiply),
+            _ => None,
+        }
+    }
+
+    pub fn code(self) -> u8 {
+        self as u8
+    }
+
+    // Both colours are ARGB, every channel is blended the same way
+    pub fn blend(self, source: u32, dest: u32) -> u32 {
+        let channels = |f: &dyn Fn(u32, u32) -> u32| {
+            (0..4).fold(0, |out, i| {
+                let shift = i * 8;
+                out | f(source >> shift & 0xFF, dest >> shift & 0xFF).min(0xFF) << shift
+            })
+        };
+        let mix = |opacity: u32| channels(&|s, d| (s * opacity + d * (4 - opacity)) / 4);
+        match self {
+            BlendMode::Normal => source,
+            BlendMode::Alpha25 => mix(1),
+            BlendMode::Alpha50 => mix(2),
+            BlendMode::Alpha75 => mix(3),
+            BlendMode::Add => channels(&|s, d| s + d),
+            BlendMode::Multiply => channels(&|s, d| s * d / 0xFF),
+        }
+    }
+}
+
+// A digitised sound 060n started, 8-bit unsigned PCM in memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sample {
+    pub addr: u32,
+    pub len: u32,
+    // Samples per second
+    pub rate: u16,
+    pub looping: bool,
+    // Counts up with every 060n, so players can tell a restart from the same sample going on
+    pub id: u32,
+}
+
+// What 00E0 shows and DXYN draws to while mega mode is on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Canvas {
+    // ARGB, as of the last 00E0
+    shown: Box<[u32]>,
+    // ARGB, drawn since then
+    drawing: Box<[u32]>,
+    // The palette index last drawn to each pixel, for collisions
+    indices: Box<[u8]>,
+    // Applies to the whole display, set by 05nn
+    alpha: u8,
+}
+
+impl Canvas {
+    pub fn new(len: usize) -> Self {
+        Canvas {
+            shown: vec![0; len].into_boxed_slice(),
+            drawing: vec![0; len].into_boxed_slice(),
+            indices: vec![0; len].into_boxed_slice(),
+            alpha: 0xFF,
+        }
+    }
+
+    pub fn shown(&self) -> &[u32] {
+        &self.shown
+    }
+
+    pub fn indices(&self) -> &[u8] {
+        &self.indices
+    }
+
+    pub fn alpha(&self) -> u8 {
+        self.alpha
+    }
+
+    pub(crate) fn set_alpha(&mut self, alpha: u8) {
+        self.alpha = alpha;
+    }
+
+    // Puts a sprite pixel of the given palette index and colour on the canvas. Returns true if
+    // it covers a pixel of the collision colour.
+    pub(crate) fn plot(&mut self, index: usize, colour_index: u8, colour: u32, blend: BlendMode, collision_colour: u8) -> bool {
+        let collision = self.indices[index] != 0 && self.indices[index] == collision_colour;
+        self.drawing[index] = blend.blend(colour, self.drawing[index]);
+        self.indices[index] = colour_index;
+        collision
+    }
+
+    // Moves what was drawn since the last 00E0 by the given number of pixels, uncovering
+    // transparent ones
+    pub(crate) fn scroll(&mut self, width: usize, dx: isize, dy: isize) {
+        scroll(&mut self.drawing, width, dx, dy, 0);
+        scroll(&mut self.indices, width, dx, dy, 0);
+    }
+
+    // Shows what was drawn and starts the next frame from transparent black
+    pub(crate) fn present(&mut self) {
+        self.shown.copy_from_slice(&self.drawing);
+        self.drawing.fill(0);
+        self.indices.fill(0);
+    }
+
+    pub(crate) fn hash(&self, hasher: &mut Fnv1a) {
+        for colour in self.shown.iter().chain(self.drawing.iter()) {
+            hasher.write(&colour.to_le_bytes());
+        }
+        hasher.write(&self.indices);
+        hasher.write(&[self.alpha]);
+    }
+}
+
+// Moves the pixels of a row-major buffer right by dx and down by dy, filling in the blank value
+pub(crate) fn scroll<T: Copy>(pixels: &mut [T], width: usize, dx: isize, dy: isize, blank: T) {
+    let height = pixels.len() / width;
+    let source = |x: usize, y: usize| {
+        let x = x as isize - dx;
+        let y = y as isize - dy;
+        if (0..width as isize).contains(&x) && (0..height as isize).contains(&y) {
+            Some(y as usize * width + x as usize)
+        } else {
+            None
+        }
+    };
+    // Copy in the order that doesn't overwrite pixels before they're moved
+    let forward = dy < 0 || (dy == 0 && dx < 0);
+    for i in 0..pixels.len() {
+        let i = if forward { i } else { pixels.len() - 1 - i };
+        pixels[i] = source(i % width, i / width).map_or(blank, |from| pixels[from]);
+    }
+}
+
+// The registers MEGA-CHIP adds to the interpreter
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MegaChip {
+    // Switched by 0010 and 0011, otherwise the machine behaves like plain CHIP-8
+    pub enabled: bool,
+    // ARGB, index 0 is always transparent
+    pub palette: [u32; 256],
+    // In pixels, 03nn and 04nn set them with 0 meaning 256
+    pub sprite_width: usize,
+    pub sprite_height: usize,
+    pub blend: BlendMode,
+    pub collision_colour: u8,
+    pub sample: Option<Sample>,
+    // How many samples have been started
+    pub samples_started: u32,
+    // The 128x64 display of 00FF, used while mega mode is off
+    pub hires: bool,
+    // Where Fx75 saves registers
+    pub flags: [u8; FLAG_COUNT],
+}
+
+impl MegaChip {
+    pub fn new() -> Self {
+        MegaChip {
+            enabled: false,
+            palette: [0; 256],
+            sprite_width: 8,
+            sprite_height: 8,
+            blend: BlendMode::default(),
+            collision_colour: 0,
+            sample: None,
+            samples_started: 0,
+            hires: false,
+            flags: [0; FLAG_COUNT],
+        }
+    }
+
+    pub(crate) fn hash(&self, hasher: &mut Fnv1a) {
+        hasher.write(&[self.enabled as u8]);
+        for colour in self.palette.iter() {
+            hasher.write(&colour.to_le_bytes());
+        }
+        hasher.write(&(self.sprite_width as u16).to_le_bytes());
+        hasher.write(&(self.sprite_height as u16).to_le_bytes());
+        hasher.write(&[self.blend.code(), self.collision_colour]);
+        match self.sample {
+            Some(sample) => {
+                hasher.write(&[1]);
+                hasher.write(&sample.addr.to_le_bytes());
+                hasher.write(&sample.len.to_le_bytes());
+                hasher.write(&sample.rate.to_le_bytes());
+                hasher.write(&[sample.looping as u8]);
+                hasher.write(&sample.id.to_le_bytes());
+            }
+            None => hasher.write(&[0]),
+        }
+        hasher.write(&self.samples_started.to_le_bytes());
+        hasher.write(&[self.hires as u8]);
+        hasher.write(&self.flags);
+    }
+}
+
+impl Default for MegaChip {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blend_modes() {
+        let source = 0xFF_80_40_00;
+        let dest = 0xFF_00_40_80;
+        assert_eq!(source, BlendMode::Normal.blend(source, dest));
+        assert_eq!(0xFF_40_40_40, BlendMode::Alpha50.blend(source, dest));
+        assert_eq!(0xFF_20_40_60, BlendMode::Alpha25.blend(source, dest));
+        assert_eq!(0xFF_80_80_80, BlendMode::Add.blend(source, dest));
+        assert_eq!(0xFF_00_10_00, BlendMode::Multiply.blend(source, dest));
+        assert_eq!(0xFF_FF_FF_FF, BlendMode::Add.blend(0xFF_FF_FF_FF, 0xFF_01_01_01));
+
+        for code in 0..6 {
+            assert_eq!(Some(code), BlendMode::from_code(code).map(BlendMode::code));
+        }
+        assert_eq!(None, BlendMode::from_code(6));
+    }
+
+    #[test]
+    fn canvas() {
+        let mut canvas = Canvas::new(4);
+        assert!(!canvas.plot(1, 3, 0xFF_11_22_33, BlendMode::Normal, 3));
+        assert!(canvas.plot(1, 5, 0xFF_44_55_66, BlendMode::Normal, 3));
+        assert!(!canvas.plot(1, 5, 0xFF_44_55_66, BlendMode::Normal, 3));
+        assert_eq!(5, canvas.indices()[1]);
+        assert_eq!(0, canvas.shown()[1]);
+
+        canvas.present();
+        assert_eq!(0xFF_44_55_66, canvas.shown()[1]);
+        assert!(canvas.indices().iter().all(|&index| index == 0));
+    }
+
+    #[test]
+    fn scrolling() {
+        let mut pixels = [1, 2, 3, 4, 5, 6];
+        scroll(&mut pixels, 3, 1, 0, 0);
+        assert_eq!([0, 1, 2, 0, 4, 5], pixels);
+        scroll(&mut pixels, 3, -2, 0, 0);
+        assert_eq!([2, 0, 0, 5, 0, 0], pixels);
+        scroll(&mut pixels, 3, 0, 1, 9);
+        assert_eq!([9, 9, 9, 2, 0, 0], pixels);
+        scroll(&mut pixels, 3, 0, -1, 9);
+        assert_eq!([2, 0, 0, 9, 9, 9], pixels);
+    }
+}
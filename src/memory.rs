@@ -0,0 +1,276 @@
+use alloc::boxed::Box;
+use alloc::vec;
+use core::fmt;
+use core::ops::{Index, IndexMut};
+
+pub const TOTAL_MEMORY: usize = 4096;
+pub const PROGRAM_OFFSET: usize = 0x200;
+
+// What happens when an address past the end of memory is accessed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BoundsPolicy {
+    // Only the lower address bits are used, like on the original hardware
+    Wrap,
+    // The access fails with MemoryError::OutOfBounds
+    Fault,
+    // The last byte of memory is used instead
+    Clamp,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryError {
+    OutOfBounds { addr: u32 },
+    ReadOnly { addr: u32 },
+}
+
+impl fmt::Display for MemoryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MemoryError::OutOfBounds { addr } => write!(f, "Address {:#06x} is out of bounds", addr),
+            MemoryError::ReadOnly { addr } => write!(f, "Address {:#06x} is read-only", addr),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for MemoryError {}
+
+// Address space of the machine. All accesses by running programs go through read() and write()
+// so the bounds policy and write protection apply. Indexing bypasses both and is meant for the
+// host, e.g. for loading programs. TOTAL_MEMORY bytes unless a variant needs more.
+pub struct Memory {
+    bytes: Box<[u8]>,
+    policy: BoundsPolicy,
+    protect_interpreter: bool,
+    // Where the interpreter's area ends, PROGRAM_OFFSET unless the variant's is larger
+    interpreter_end: usize,
+    // Lowest and highest index written since the last take_written()
+    written: Option<(usize, usize)>,
+}
+
+impl Memory {
+    pub fn new() -> Self {
+        Memory {
+            bytes: vec![0; TOTAL_MEMORY].into_boxed_slice(),
+            policy: BoundsPolicy::Wrap,
+            protect_interpreter: false,
+            interpreter_end: PROGRAM_OFFSET,
+            written: None,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    // Grows or shrinks to the given number of bytes, keeping what fits
+    pub fn resize(&mut self, len: usize) {
+        if len != self.bytes.len() {
+            let mut bytes = vec![0; len].into_boxed_slice();
+            let kept = len.min(self.bytes.len());
+            bytes[..kept].copy_from_slice(&self.bytes[..kept]);
+            self.bytes = bytes;
+            self.written = None;
+            self.mark_written(0, len - 1);
+        }
+    }
+
+    pub fn policy(&self) -> BoundsPolicy {
+        self.policy
+    }
+
+    pub fn set_policy(&mut self, policy: BoundsPolicy) {
+        self.policy = policy;
+    }
+
+    pub fn is_interpreter_protected(&self) -> bool {
+        self.protect_interpreter
+    }
+
+    // Makes everything below the interpreter end, where the interpreter and fonts live, read-only
+    pub fn set_interpreter_protected(&mut self, protect: bool) {
+        self.protect_interpreter = protect;
+    }
+
+    pub fn interpreter_end(&self) -> usize {
+        self.interpreter_end
+    }
+
+    // The CHIP-8X interpreter takes up memory up to 0x300, the others up to PROGRAM_OFFSET
+    pub fn set_interpreter_end(&mut self, end: usize) {
+        self.interpreter_end = end;
+    }
+
+    fn resolve(&self, addr: u32) -> Result<usize, MemoryError> {
+        let index = addr as usize;
+        let len = self.bytes.len();
+        if index < len {
+            return Ok(index);
+        }
+
+        match self.policy {
+            BoundsPolicy::Wrap => Ok(index % len),
+            BoundsPolicy::Fault => Err(MemoryError::OutOfBounds { addr }),
+            BoundsPolicy::Clamp => Ok(len - 1),
+        }
+    }
+
+    // Zeroes all bytes, the configuration stays
+    pub fn clear(&mut self) {
+        self.bytes.fill(0);
+        self.mark_written(0, self.bytes.len() - 1);
+    }
+
+    fn mark_written(&mut self, start: usize, end: usize) {
+        self.written = Some(match self.written {
+            Some((low, high)) => (low.min(start), high.max(end)),
+            None => (start, end),
+        });
+    }
+
+    // The range of indices changed since the last call, for caches of memory contents. Changes
+    // through as_mut_slice() or indexing count as writes to the whole slice or the index.
+    pub fn take_written(&mut self) -> Option<(usize, usize)> {
+        self.written.take()
+    }
+
+    pub fn read(&self, addr: u32) -> Result<u8, MemoryError> {
+        Ok(self.bytes[self.resolve(addr)?])
+    }
+
+    pub fn write(&mut self, addr: u32, value: u8) -> Result<(), MemoryError> {
+        let index = self.resolve(addr)?;
+        if self.protect_interpreter && index < self.interpreter_end {
+            return Err(MemoryError::ReadOnly { addr });
+        }
+
+        self.bytes[index] = value;
+        self.mark_written(index, index);
+        Ok(())
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        self.mark_written(0, self.bytes.len() - 1);
+        &mut self.bytes
+    }
+}
+
+impl Default for Memory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Index<usize> for Memory {
+    type Output = u8;
+
+    fn index(&self, index: usize) -> &u8 {
+        &self.bytes[index]
+    }
+}
+
+impl IndexMut<usize> for Memory {
+    fn index_mut(&mut self, index: usize) -> &mut u8 {
+        self.mark_written(index, index);
+        &mut self.bytes[index]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wrap() {
+        let mut memory = Memory::new();
+        memory.write(0x1005, 0x12).unwrap();
+        assert_eq!(0x12, memory[0x005]);
+        assert_eq!(Ok(0x12), memory.read(0xF005));
+    }
+
+    #[test]
+    fn fault() {
+        let mut memory = Memory::new();
+        memory.set_policy(BoundsPolicy::Fault);
+        assert_eq!(Ok(()), memory.write(0x0FFF, 0x12));
+        assert_eq!(Ok(0x12), memory.read(0x0FFF));
+        assert_eq!(Err(MemoryError::OutOfBounds { addr: 0x1000 }), memory.write(0x1000, 0x34));
+        assert_eq!(Err(MemoryError::OutOfBounds { addr: 0x1000 }), memory.read(0x1000));
+        assert_eq!(0, memory[0x000]);
+    }
+
+    #[test]
+    fn clamp() {
+        let mut memory = Memory::new();
+        memory.set_policy(BoundsPolicy::Clamp);
+        memory.write(0x1234, 0x12).unwrap();
+        assert_eq!(0x12, memory[0xFFF]);
+        assert_eq!(Ok(0x12), memory.read(0xFFFF));
+    }
+
+    #[test]
+    fn protect_interpreter() {
+        let mut memory = Memory::new();
+        memory.set_interpreter_protected(true);
+        assert_eq!(Err(MemoryError::ReadOnly { addr: 0x01FF }), memory.write(0x01FF, 0x12));
+        assert_eq!(Ok(()), memory.write(0x0200, 0x12));
+        assert_eq!(Ok(0), memory.read(0x01FF));
+
+        // Wrapping around doesn't get past the protection
+        assert_eq!(Err(MemoryError::ReadOnly { addr: 0x1000 }), memory.write(0x1000, 0x12));
+
+        // The host can still load fonts
+        memory[0x000] = 0xF0;
+        assert_eq!(Ok(0xF0), memory.read(0x0000));
+
+        memory.set_interpreter_end(0x300);
+        assert_eq!(Err(MemoryError::ReadOnly { addr: 0x02FF }), memory.write(0x02FF, 0x12));
+        assert_eq!(Ok(()), memory.write(0x0300, 0x12));
+    }
+
+    #[test]
+    fn written() {
+        let mut memory = Memory::new();
+        assert_eq!(None, memory.take_written());
+
+        memory.write(0x1300, 0x12).unwrap();
+        memory.write(0x0280, 0x12).unwrap();
+        memory.read(0x0400).unwrap();
+        assert_eq!(Some((0x280, 0x300)), memory.take_written());
+        assert_eq!(None, memory.take_written());
+
+        // Failed writes don't change anything
+        memory.set_interpreter_protected(true);
+        memory.write(0x0100, 0x12).unwrap_err();
+        assert_eq!(None, memory.take_written());
+
+        memory[0x123] = 0x45;
+        assert_eq!(Some((0x123, 0x123)), memory.take_written());
+        memory.as_mut_slice();
+        assert_eq!(Some((0, TOTAL_MEMORY - 1)), memory.take_written());
+    }
+
+    #[test]
+    fn resize() {
+        let mut memory = Memory::new();
+        memory[0xFFF] = 0x12;
+        memory.resize(0x10000);
+        assert_eq!(0x10000, memory.len());
+        assert_eq!(Ok(0x12), memory.read(0xFFF));
+        assert_eq!(Ok(0), memory.read(0x1FFF));
+        memory.write(0x1_0005, 0x34).unwrap();
+        assert_eq!(0x34, memory[0x0005]);
+
+        memory.resize(TOTAL_MEMORY);
+        assert_eq!(Ok(0x12), memory.read(0xFFF));
+        assert_eq!(Some((0, TOTAL_MEMORY - 1)), memory.take_written());
+    }
+}
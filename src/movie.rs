@@ -0,0 +1,691 @@
+use std::fmt;
+
+use crate::cpu::{CpuError, LoadError, CPU};
+use crate::fnv;
+use crate::font::{self, Font, FontSet};
+use crate::memory::BoundsPolicy;
+use crate::quirks::Quirks;
+use crate::stack::StackConfig;
+use crate::timing::Timing;
+use crate::variant::Variant;
+
+const MAGIC: &[u8; 4] = b"CH8M";
+// Older versions can't be replayed, the state hash they were checked against has changed since
+const VERSION: u8 = 5;
+
+// Font byte for glyphs that aren't one of the sets, they follow it
+const CUSTOM_FONT: u8 = 0xFF;
+
+// Bits of the options byte
+const MACHINE_CODE: u8 = 0x01;
+const STACK_IN_MEMORY: u8 = 0x02;
+const UNLIMITED_STACK: u8 = 0x04;
+
+// A day at 60Hz. Runs are expanded while reading, so this bounds the memory a file can ask for.
+const MAX_FRAMES: usize = 24 * 60 * 60 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MovieError {
+    InvalidMagic,
+    UnsupportedVersion(u8),
+    // The file ends before everything it announces
+    Truncated,
+    // A field holds a value no recorder writes
+    Invalid,
+    // More frames than MAX_FRAMES
+    TooLong(u32),
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MovieError::InvalidMagic => write!(f, "Not a movie file"),
+            MovieError::UnsupportedVersion(version) => write!(f, "Unsupported movie version {}", version),
+            MovieError::Truncated => write!(f, "Movie file is truncated"),
+            MovieError::Invalid => write!(f, "Movie file is corrupt"),
+            MovieError::TooLong(frames) => write!(f, "Movie has {} frames, at most {} are supported", frames, MAX_FRAMES),
+        }
+    }
+}
+
+impl std::error::Error for MovieError {}
+
+#[derive(Debug)]
+pub enum ReplayError {
+    // The movie was recorded with a different program
+    RomMismatch { expected: u64, actual: u64 },
+    Load(LoadError),
+    // The final state differs from the recorded one
+    Desync { expected: u64, actual: u64 },
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReplayError::RomMismatch { expected, actual } => write!(
+                f,
+                "Movie was recorded with ROM {:016x}, not {:016x}",
+                expected, actual
+            ),
+            ReplayError::Load(err) => write!(f, "{}", err),
+            ReplayError::Desync { expected, actual } => write!(
+                f,
+                "Replay desynced, final state is {:016x} instead of {:016x}",
+                actual, expected
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ReplayError {}
+
+impl From<LoadError> for ReplayError {
+    fn from(err: LoadError) -> Self {
+        ReplayError::Load(err)
+    }
+}
+
+// The input of one frame
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Frame {
+    pub keys: u16,
+    // Only CHIP-8X has these
+    pub second_keys: u16,
+    // The byte waiting for FxFB when the frame started
+    pub input_port: Option<u8>,
+}
+
+// A recorded session: the configuration it started with and the input of every frame. Since
+// execution is deterministic that's enough to reproduce it exactly, the hash of the final state
+// verifies that it was.
+//
+// The file format is little endian:
+//   "CH8M", version (u8), quirks (u16, one bit each in declaration order), memory policy (u8,
+//   wrap, fault or clamp), interpreter protected (u8), timing (u8, instructions per frame or
+//   VIP), options (u8, bit 0 runs 0nnn as machine code, bit 1 keeps the stack in memory and
+//   bit 2 doesn't limit its depth), variant (u8, CHIP-8, CHIP-8X, hi-res or MEGA-CHIP), stack
+//   depth (u16), font (u8, index into FontSet::all() or 0xFF followed by the 80 bytes of
+//   glyphs), font address (u16), seed (u64), instructions per frame (u32), ROM hash (u64), final state hash
+//   (u64), frame count (u32)
+// followed by the frames as runs of equal input: run length (unsigned LEB128), keypad state
+// (u16) and on CHIP-8X the second keypad's state (u16) and the input port (u8, 0 if no byte is
+// waiting, followed by the byte if 1).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Movie {
+    pub seed: u64,
+    pub quirks: Quirks,
+    pub memory_policy: BoundsPolicy,
+    pub interpreter_protected: bool,
+    pub timing: Timing,
+    pub machine_code: bool,
+    pub variant: Variant,
+    pub stack: StackConfig,
+    pub font: Font,
+    pub instructions_per_frame: u32,
+    // FNV-1a of the program
+    pub rom_hash: u64,
+    // CPU::state_hash() after the last frame
+    pub final_hash: u64,
+    pub frames: Vec<Frame>,
+}
+
+impl Movie {
+    // Puts the machine into the state the recording started from
+    pub fn start(&self, cpu: &mut CPU, rom: &[u8]) -> Result<(), ReplayError> {
+        let actual = fnv::hash(rom);
+        if actual != self.rom_hash {
+            return Err(ReplayError::RomMismatch { expected: self.rom_hash, actual });
+        }
+
+        cpu.set_quirks(self.quirks);
+        cpu.set_memory_policy(self.memory_policy);
+        cpu.set_interpreter_protected(self.interpreter_protected);
+        cpu.set_timing(self.timing);
+        cpu.set_machine_code(self.machine_code);
+        cpu.set_variant(self.variant);
+        cpu.set_stack_config(self.stack);
+        cpu.set_font(self.font);
+        cpu.load_rom(rom)?;
+        cpu.seed_rng(self.seed);
+        Ok(())
+    }
+
+    // Runs the whole movie and verifies the final state. Errors the program ran into are part of
+    // the recording and don't stop the replay, the first one is returned.
+    pub fn replay(&self, cpu: &mut CPU, rom: &[u8]) -> Result<Option<CpuError>, ReplayError> {
+        self.start(cpu, rom)?;
+
+        let mut first_error = None;
+        for frame in self.frames.iter() {
+            cpu.keypad_mut().set_state(frame.keys);
+            cpu.second_keypad_mut().set_state(frame.second_keys);
+            if let Some(value) = frame.input_port {
+                cpu.set_input_port(value);
+            }
+            if let Err(err) = cpu.run_frame(self.instructions_per_frame) {
+                first_error.get_or_insert(err);
+            }
+        }
+
+        let actual = cpu.state_hash();
+        if actual != self.final_hash {
+            return Err(ReplayError::Desync { expected: self.final_hash, actual });
+        }
+        Ok(first_error)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(VERSION);
+        bytes.extend_from_slice(&quirk_bits(self.quirks).to_le_bytes());
+        bytes.push(match self.memory_policy {
+            BoundsPolicy::Wrap => 0,
+            BoundsPolicy::Fault => 1,
+            BoundsPolicy::Clamp => 2,
+        });
+        bytes.push(self.interpreter_protected as u8);
+        bytes.push(match self.timing {
+            Timing::InstructionsPerFrame => 0,
+            Timing::CosmacVip => 1,
+        });
+        let mut options = 0;
+        if self.machine_code {
+            options |= MACHINE_CODE;
+        }
+        if self.stack.in_memory() {
+            options |= STACK_IN_MEMORY;
+        }
+        if self.stack.depth().is_none() {
+            options |= UNLIMITED_STACK;
+        }
+        bytes.push(options);
+        bytes.push(match self.variant {
+            Variant::Chip8 => 0,
+            Variant::Chip8X => 1,
+            Variant::HiRes => 2,
+            Variant::MegaChip => 3,
+        });
+        bytes.extend_from_slice(&self.stack.depth().unwrap_or(0).to_le_bytes());
+        match FontSet::all().iter().position(|set| set.glyphs() == self.font.glyphs()) {
+            Some(index) => bytes.push(index as u8),
+            None => {
+                bytes.push(CUSTOM_FONT);
+                bytes.extend_from_slice(self.font.glyphs());
+            }
+        }
+        bytes.extend_from_slice(&self.font.address().to_le_bytes());
+        bytes.extend_from_slice(&self.seed.to_le_bytes());
+        bytes.extend_from_slice(&self.instructions_per_frame.to_le_bytes());
+        bytes.extend_from_slice(&self.rom_hash.to_le_bytes());
+        bytes.extend_from_slice(&self.final_hash.to_le_bytes());
+        bytes.extend_from_slice(&(self.frames.len() as u32).to_le_bytes());
+
+        let mut frames = self.frames.iter().peekable();
+        while let Some(&frame) = frames.next() {
+            let mut run = 1u32;
+            while frames.next_if_eq(&&frame).is_some() {
+                run += 1;
+            }
+            write_uleb(&mut bytes, run);
+            bytes.extend_from_slice(&frame.keys.to_le_bytes());
+            if self.variant == Variant::Chip8X {
+                bytes.extend_from_slice(&frame.second_keys.to_le_bytes());
+                match frame.input_port {
+                    Some(value) => bytes.extend_from_slice(&[1, value]),
+                    None => bytes.push(0),
+                }
+            }
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Movie, MovieError> {
+        let mut reader = Reader { bytes };
+        if reader.take(4)? != MAGIC {
+            return Err(MovieError::InvalidMagic);
+        }
+        let version = reader.u8()?;
+        if version != VERSION {
+            return Err(MovieError::UnsupportedVersion(version));
+        }
+
+        let quirks = quirks_from_bits(reader.u16()?)?;
+        let memory_policy = match reader.u8()? {
+            0 => BoundsPolicy::Wrap,
+            1 => BoundsPolicy::Fault,
+            2 => BoundsPolicy::Clamp,
+            _ => return Err(MovieError::Invalid),
+        };
+        let interpreter_protected = match reader.u8()? {
+            0 => false,
+            1 => true,
+            _ => return Err(MovieError::Invalid),
+        };
+        let timing = match reader.u8()? {
+            0 => Timing::InstructionsPerFrame,
+            1 => Timing::CosmacVip,
+            _ => return Err(MovieError::Invalid),
+        };
+        let options = reader.u8()?;
+        if options & !(MACHINE_CODE | STACK_IN_MEMORY | UNLIMITED_STACK) != 0 {
+            return Err(MovieError::Invalid);
+        }
+        let machine_code = options & MACHINE_CODE != 0;
+        let variant = match reader.u8()? {
+            0 => Variant::Chip8,
+            1 => Variant::Chip8X,
+            2 => Variant::HiRes,
+            3 => Variant::MegaChip,
+            _ => return Err(MovieError::Invalid),
+        };
+        let depth = reader.u16()?;
+        let depth = if options & UNLIMITED_STACK != 0 { None } else { Some(depth) };
+        let stack = StackConfig::new(depth).with_memory(options & STACK_IN_MEMORY != 0);
+        let font = match reader.u8()? {
+            CUSTOM_FONT => Font::custom(reader.take(font::FONT_SIZE)?).map_err(|_| MovieError::Invalid)?,
+            index => Font::new(*FontSet::all().get(index as usize).ok_or(MovieError::Invalid)?),
+        };
+        let font = font.with_address(reader.u16()?).map_err(|_| MovieError::Invalid)?;
+        let seed = reader.u64()?;
+        let instructions_per_frame = reader.u32()?;
+        let rom_hash = reader.u64()?;
+        let final_hash = reader.u64()?;
+        let count = reader.u32()?;
+        if count as usize > MAX_FRAMES {
+            return Err(MovieError::TooLong(count));
+        }
+        let count = count as usize;
+
+        // Not reserving count up front, the runs have to actually be there
+        let mut frames = Vec::new();
+        while frames.len() < count {
+            let run = reader.uleb()? as usize;
+            let mut frame = Frame { keys: reader.u16()?, ..Frame::default() };
+            if variant == Variant::Chip8X {
+                frame.second_keys = reader.u16()?;
+                frame.input_port = match reader.u8()? {
+                    0 => None,
+                    1 => Some(reader.u8()?),
+                    _ => return Err(MovieError::Invalid),
+                };
+            }
+            if run == 0 || run > count - frames.len() {
+                return Err(MovieError::Invalid);
+            }
+            frames.resize(frames.len() + run, frame);
+        }
+        if !reader.bytes.is_empty() {
+            return Err(MovieError::Invalid);
+        }
+
+        Ok(Movie {
+            seed,
+            quirks,
+            memory_policy,
+            interpreter_protected,
+            timing,
+            machine_code,
+            variant,
+            stack,
+            font,
+            instructions_per_frame,
+            rom_hash,
+            final_hash,
+            frames,
+        })
+    }
+}
+
+// Records a session from power-on. Set the keypad as usual and run frames through the recorder
+// instead of the CPU.
+pub struct Recorder {
+    movie: Movie,
+}
+
+impl Recorder {
+    // Restarts the program with the given seed, the rest of the configuration is taken from the
+    // CPU
+    pub fn new(cpu: &mut CPU, rom: &[u8], seed: u64, instructions_per_frame: u32) -> Result<Self, LoadError> {
+        cpu.load_rom(rom)?;
+        cpu.seed_rng(seed);
+        Ok(Recorder {
+            movie: Movie {
+                seed,
+                quirks: cpu.quirks(),
+                memory_policy: cpu.memory_policy(),
+                interpreter_protected: cpu.is_interpreter_protected(),
+                timing: cpu.timing(),
+                machine_code: cpu.is_machine_code_enabled(),
+                variant: cpu.variant(),
+                stack: cpu.stack_config(),
+                font: *cpu.font(),
+                instructions_per_frame,
+                rom_hash: fnv::hash(rom),
+                final_hash: 0,
+                frames: Vec::new(),
+            },
+        })
+    }
+
+    pub fn run_frame(&mut self, cpu: &mut CPU) -> Result<(), CpuError> {
+        self.record_frame(cpu);
+        cpu.run_frame(self.movie.instructions_per_frame)
+    }
+
+    // Notes the input of the frame about to run, for hosts that run it themselves with the same
+    // number of instructions
+    pub fn record_frame(&mut self, cpu: &CPU) {
+        let mut frame = Frame { keys: cpu.keypad().state(), ..Frame::default() };
+        if self.movie.variant == Variant::Chip8X {
+            frame.second_keys = cpu.second_keypad().state();
+            frame.input_port = cpu.input_port();
+        }
+        self.movie.frames.push(frame);
+    }
+
+    pub fn frames(&self) -> usize {
+        self.movie.frames.len()
+    }
+
+    pub fn finish(mut self, cpu: &CPU) -> Movie {
+        self.movie.final_hash = cpu.state_hash();
+        self.movie
+    }
+}
+
+fn quirk_bits(quirks: Quirks) -> u16 {
+    [
+        quirks.vf_reset,
+        quirks.shift_uses_vy,
+        quirks.load_store_increments_i,
+        quirks.jump_uses_vx,
+        quirks.clip_sprites,
+    ]
+    .iter()
+    .enumerate()
+    .fold(0, |bits, (i, &quirk)| bits | (quirk as u16) << i)
+}
+
+fn quirks_from_bits(bits: u16) -> Result<Quirks, MovieError> {
+    if bits >> 5 != 0 {
+        return Err(MovieError::Invalid);
+    }
+    let quirk = |i: u16| bits & (1 << i) != 0;
+    Ok(Quirks {
+        vf_reset: quirk(0),
+        shift_uses_vy: quirk(1),
+        load_store_increments_i: quirk(2),
+        jump_uses_vx: quirk(3),
+        clip_sprites: quirk(4),
+    })
+}
+
+fn write_uleb(bytes: &mut Vec<u8>, mut value: u32) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            bytes.push(byte);
+            return;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], MovieError> {
+        if self.bytes.len() < len {
+            return Err(MovieError::Truncated);
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, MovieError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, MovieError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, MovieError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn u64(&mut self) -> Result<u64, MovieError> {
+        let mut value = [0; 8];
+        value.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(value))
+    }
+
+    fn uleb(&mut self) -> Result<u32, MovieError> {
+        let mut value = 0u32;
+        for shift in (0..35).step_by(7) {
+            let byte = self.u8()?;
+            value |= ((byte & 0x7F) as u32).checked_shl(shift).ok_or(MovieError::Invalid)?;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(MovieError::Invalid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conformance;
+
+    // Waits for a key, draws its digit, repeats. Uses RND so the seed matters.
+    const ROM: [u8; 10] = [0xF0, 0x0A, 0xF0, 0x29, 0xC1, 0x3F, 0xD1, 0x15, 0x12, 0x00];
+
+    fn record(rom: &[u8], seed: u64, frames: u32) -> Movie {
+        let mut cpu = CPU::new();
+        cpu.set_quirks(Quirks::schip());
+        cpu.set_memory_policy(BoundsPolicy::Clamp);
+        let mut recorder = Recorder::new(&mut cpu, rom, seed, 15).unwrap();
+        for frame in 0..frames {
+            // Hold every key for a few frames, with pauses in between
+            let keys = if frame % 10 < 4 { 1 << (frame / 10 % 16) } else { 0 };
+            cpu.keypad_mut().set_state(keys);
+            recorder.run_frame(&mut cpu).unwrap();
+        }
+        recorder.finish(&cpu)
+    }
+
+    #[test]
+    fn replay() {
+        let movie = record(&ROM, 42, 200);
+        let bytes = movie.to_bytes();
+        // Runs of equal states are stored once
+        assert!(bytes.len() < 50 + 40 * 3, "{} bytes", bytes.len());
+
+        let parsed = Movie::from_bytes(&bytes).unwrap();
+        assert_eq!(movie, parsed);
+
+        // A fresh machine with a different configuration ends up in the same state
+        let mut cpu = CPU::new();
+        cpu.set_block_cache(false);
+        assert_eq!(None, parsed.replay(&mut cpu, &ROM).unwrap());
+        assert_eq!(Quirks::schip(), cpu.quirks());
+        assert_eq!(movie.final_hash, cpu.state_hash());
+    }
+
+    #[test]
+    fn configuration() {
+        let mut cpu = CPU::new();
+        cpu.set_timing(Timing::CosmacVip);
+        cpu.set_machine_code(true);
+        cpu.set_variant(Variant::Chip8X);
+        cpu.set_stack_config(StackConfig::octo().with_memory(true));
+        // CHIP-8X programs start at 0x300
+        let mut rom = ROM;
+        rom[8] = 0x13;
+        let mut recorder = Recorder::new(&mut cpu, &rom, 3, 15).unwrap();
+        for frame in 0..120 {
+            cpu.keypad_mut().set_state(if frame % 20 < 5 { 1 << (frame / 20) } else { 0 });
+            recorder.run_frame(&mut cpu).unwrap();
+        }
+        let movie = Movie::from_bytes(&recorder.finish(&cpu).to_bytes()).unwrap();
+        assert_eq!(Timing::CosmacVip, movie.timing);
+        assert!(movie.machine_code);
+        assert_eq!(Variant::Chip8X, movie.variant);
+        assert_eq!(StackConfig::octo().with_memory(true), movie.stack);
+
+        let mut cpu = CPU::new();
+        assert_eq!(None, movie.replay(&mut cpu, &rom).unwrap());
+        assert_eq!(Timing::CosmacVip, cpu.timing());
+        assert!(cpu.is_machine_code_enabled());
+        assert_eq!(Variant::Chip8X, cpu.variant());
+        assert_eq!(StackConfig::octo().with_memory(true), cpu.stack_config());
+    }
+
+    #[test]
+    fn fonts() {
+        let vip = Font::new(FontSet::CosmacVip).with_address(0x50).unwrap();
+        let mut glyphs = *FontSet::Eti660.glyphs();
+        glyphs[0] = 0xFF;
+        let custom = Font::custom(&glyphs).unwrap().with_address(0x100).unwrap();
+
+        for font in [vip, custom].iter() {
+            let mut cpu = CPU::new();
+            cpu.set_font(*font);
+            let mut recorder = Recorder::new(&mut cpu, &ROM, 5, 15).unwrap();
+            for frame in 0..60 {
+                cpu.keypad_mut().set_state(if frame % 10 < 3 { 1 << (frame / 10) } else { 0 });
+                recorder.run_frame(&mut cpu).unwrap();
+            }
+            let movie = Movie::from_bytes(&recorder.finish(&cpu).to_bytes()).unwrap();
+            assert_eq!(*font, movie.font);
+
+            // Replays on a machine with another font still match
+            let mut cpu = CPU::new();
+            cpu.set_font(Font::new(FontSet::Dream6800).with_address(0x20).unwrap());
+            assert_eq!(None, movie.replay(&mut cpu, &ROM).unwrap());
+            assert_eq!(font, cpu.font());
+        }
+    }
+
+    #[test]
+    fn chip8x_input() {
+        // Adds every byte from the input port to V3 and counts the bytes read while key 5 on the
+        // second keypad is down in V4
+        let rom = [0xF0, 0xFB, 0x83, 0x04, 0x62, 0x05, 0xE2, 0xF2, 0x13, 0x00, 0x74, 0x01, 0x13, 0x00];
+        let mut cpu = CPU::new();
+        cpu.set_variant(Variant::Chip8X);
+        let mut recorder = Recorder::new(&mut cpu, &rom, 0, 15).unwrap();
+        for frame in 0..100 {
+            cpu.second_keypad_mut().set_state(if frame % 30 < 10 { 1 << 5 } else { 0 });
+            if frame % 7 == 0 {
+                cpu.set_input_port(frame as u8);
+            }
+            recorder.run_frame(&mut cpu).unwrap();
+        }
+        let movie = recorder.finish(&cpu);
+        let parsed = Movie::from_bytes(&movie.to_bytes()).unwrap();
+        assert_eq!(movie, parsed);
+
+        let mut replayed = CPU::new();
+        assert_eq!(None, parsed.replay(&mut replayed, &rom).unwrap());
+        assert_ne!(0, replayed.registers()[3]);
+        assert_ne!(0, replayed.registers()[4]);
+
+        let mut without_input = parsed;
+        without_input.frames.iter_mut().for_each(|frame| frame.input_port = None);
+        assert!(matches!(without_input.replay(&mut CPU::new(), &rom), Err(ReplayError::Desync { .. })));
+    }
+
+    #[test]
+    fn conformance_roms() {
+        for (name, rom) in conformance::roms(Quirks::default()) {
+            let movie = record(&rom, 7, 60);
+            let movie = Movie::from_bytes(&movie.to_bytes()).unwrap();
+            movie.replay(&mut CPU::new(), &rom).unwrap_or_else(|err| panic!("{}: {}", name, err));
+        }
+    }
+
+    #[test]
+    fn desync() {
+        let mut movie = record(&ROM, 42, 100);
+        // A key press while the program waits for one
+        movie.frames[5].keys = 0x8000;
+        assert!(matches!(movie.replay(&mut CPU::new(), &ROM), Err(ReplayError::Desync { .. })));
+
+        // The random sprite positions depend on the seed
+        let mut movie = record(&ROM, 42, 100);
+        movie.seed += 1;
+        assert!(matches!(movie.replay(&mut CPU::new(), &ROM), Err(ReplayError::Desync { .. })));
+
+        let mut other = ROM;
+        other[5] = 0x1F;
+        assert!(matches!(movie.replay(&mut CPU::new(), &other), Err(ReplayError::RomMismatch { .. })));
+    }
+
+    #[test]
+    fn errors_are_replayed() {
+        // RET without a CALL
+        let rom = [0x00, 0xEE];
+        let mut cpu = CPU::new();
+        let mut recorder = Recorder::new(&mut cpu, &rom, 0, 10).unwrap();
+        let error = recorder.run_frame(&mut cpu).unwrap_err();
+        let movie = recorder.finish(&cpu);
+        assert_eq!(Some(error), movie.replay(&mut CPU::new(), &rom).unwrap());
+    }
+
+    #[test]
+    fn invalid_files() {
+        let bytes = record(&ROM, 1, 30).to_bytes();
+        assert_eq!(Err(MovieError::InvalidMagic), Movie::from_bytes(b"GIF89a"));
+
+        let mut version = bytes.clone();
+        version[4] = 9;
+        assert_eq!(Err(MovieError::UnsupportedVersion(9)), Movie::from_bytes(&version));
+        // Their final hashes were taken before the stack changed the state hash
+        version[4] = 4;
+        assert_eq!(Err(MovieError::UnsupportedVersion(4)), Movie::from_bytes(&version));
+
+        for len in 0..bytes.len() {
+            assert!(Movie::from_bytes(&bytes[..len]).is_err(), "{} bytes", len);
+        }
+
+        let mut policy = bytes.clone();
+        policy[7] = 3;
+        assert_eq!(Err(MovieError::Invalid), Movie::from_bytes(&policy));
+
+        let mut options = bytes.clone();
+        options[10] = 0x08;
+        assert_eq!(Err(MovieError::Invalid), Movie::from_bytes(&options));
+
+        let mut font = bytes.clone();
+        font[14] = 5;
+        assert_eq!(Err(MovieError::Invalid), Movie::from_bytes(&font));
+        // The glyphs have to stay below the program
+        font[14] = 0;
+        font[15] = 0xF0;
+        font[16] = 0x01;
+        assert_eq!(Err(MovieError::Invalid), Movie::from_bytes(&font));
+
+        // More frames in the runs than announced
+        let mut count = bytes.clone();
+        count[45] -= 1;
+        assert_eq!(Err(MovieError::Invalid), Movie::from_bytes(&count));
+
+        // A single run of four billion frames would have to be allocated in one go
+        let mut huge = bytes[..45].to_vec();
+        huge.extend_from_slice(&[0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x0F, 0x00, 0x00]);
+        assert_eq!(56, huge.len());
+        assert_eq!(Err(MovieError::TooLong(u32::MAX)), Movie::from_bytes(&huge));
+
+        let mut trailing = bytes;
+        trailing.push(0);
+        assert_eq!(Err(MovieError::Invalid), Movie::from_bytes(&trailing));
+    }
+}
@@ -0,0 +1,562 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::rc::Rc;
+
+use crate::cpu::{CpuError, State, CPU};
+
+// How many frames a session may run ahead of the peer's inputs before it waits for them
+pub const DEFAULT_MAX_PREDICTION: u32 = 8;
+
+// Older unacknowledged inputs have to wait for the next packet
+const MAX_INPUTS_PER_PACKET: usize = 64;
+// First frame, acknowledged frames, hash frame, hash, input count
+const HEADER_SIZE: usize = 4 + 4 + 4 + 8 + 1;
+const MAX_PACKET_SIZE: usize = HEADER_SIZE + 2 * MAX_INPUTS_PER_PACKET;
+// Hashes of final states kept around to compare with the peer's
+const HASH_HISTORY: usize = 64;
+
+// Carries packets between the two peers. Packets may get lost or arrive out of order.
+pub trait Transport {
+    fn send(&mut self, packet: &[u8]) -> io::Result<()>;
+
+    // The next packet received, without blocking
+    fn receive(&mut self, buf: &mut [u8]) -> io::Result<Option<usize>>;
+}
+
+pub struct UdpTransport {
+    socket: UdpSocket,
+}
+
+impl UdpTransport {
+    pub fn new<A: ToSocketAddrs, B: ToSocketAddrs>(local: A, peer: B) -> io::Result<Self> {
+        let socket = UdpSocket::bind(local)?;
+        let peer = peer
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "No peer address"))?;
+        UdpTransport::from_socket(socket, peer)
+    }
+
+    // Only packets from the peer are received
+    pub fn from_socket(socket: UdpSocket, peer: SocketAddr) -> io::Result<Self> {
+        socket.connect(peer)?;
+        socket.set_nonblocking(true)?;
+        Ok(UdpTransport { socket })
+    }
+}
+
+impl Transport for UdpTransport {
+    fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+        match self.socket.send(packet) {
+            Ok(_) => Ok(()),
+            // Nobody listening yet, the peer will get a later packet
+            Err(err) if err.kind() == io::ErrorKind::ConnectionRefused => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn receive(&mut self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        match self.socket.recv(buf) {
+            Ok(len) => Ok(Some(len)),
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(err) if err.kind() == io::ErrorKind::ConnectionRefused => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+// Connects two sessions in the same process, for tests. Packets are delivered in order and
+// whenever the other end asks for them.
+pub struct Loopback {
+    incoming: Rc<RefCell<VecDeque<Vec<u8>>>>,
+    outgoing: Rc<RefCell<VecDeque<Vec<u8>>>>,
+}
+
+impl Loopback {
+    pub fn pair() -> (Loopback, Loopback) {
+        let a = Rc::new(RefCell::new(VecDeque::new()));
+        let b = Rc::new(RefCell::new(VecDeque::new()));
+        (
+            Loopback { incoming: a.clone(), outgoing: b.clone() },
+            Loopback { incoming: b, outgoing: a },
+        )
+    }
+}
+
+impl Transport for Loopback {
+    fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+        self.outgoing.borrow_mut().push_back(packet.to_vec());
+        Ok(())
+    }
+
+    fn receive(&mut self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        Ok(self.incoming.borrow_mut().pop_front().map(|packet| {
+            buf[..packet.len()].copy_from_slice(&packet);
+            packet.len()
+        }))
+    }
+}
+
+#[derive(Debug)]
+pub enum NetplayError {
+    Io(io::Error),
+    Cpu(CpuError),
+    // The peers ended up in different states, e.g. because they didn't start with the same
+    // program, seed and configuration
+    Desync { frame: u32, local: u64, remote: u64 },
+}
+
+impl fmt::Display for NetplayError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NetplayError::Io(err) => write!(f, "Network error: {}", err),
+            NetplayError::Cpu(err) => write!(f, "{}", err),
+            NetplayError::Desync { frame, local, remote } => write!(
+                f,
+                "Peers desynced at frame {}, state {:016x} here and {:016x} there",
+                frame, local, remote
+            ),
+        }
+    }
+}
+
+impl std::error::Error for NetplayError {}
+
+impl From<io::Error> for NetplayError {
+    fn from(err: io::Error) -> Self {
+        NetplayError::Io(err)
+    }
+}
+
+impl From<CpuError> for NetplayError {
+    fn from(err: CpuError) -> Self {
+        NetplayError::Cpu(err)
+    }
+}
+
+// Little endian: first frame (u32), number of the receiver's inputs the sender has (u32), frame
+// and hash of the sender's latest final state (u32, u64), input count (u8), inputs (u16 each)
+struct Packet {
+    start: u32,
+    ack: u32,
+    hash_frame: u32,
+    hash: u64,
+    inputs: Vec<u16>,
+}
+
+impl Packet {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + 2 * self.inputs.len());
+        bytes.extend_from_slice(&self.start.to_le_bytes());
+        bytes.extend_from_slice(&self.ack.to_le_bytes());
+        bytes.extend_from_slice(&self.hash_frame.to_le_bytes());
+        bytes.extend_from_slice(&self.hash.to_le_bytes());
+        bytes.push(self.inputs.len() as u8);
+        for keys in self.inputs.iter() {
+            bytes.extend_from_slice(&keys.to_le_bytes());
+        }
+        bytes
+    }
+
+    // None for anything malformed, which is dropped like a lost packet
+    fn parse(bytes: &[u8]) -> Option<Packet> {
+        if bytes.len() < HEADER_SIZE || bytes.len() != HEADER_SIZE + 2 * bytes[HEADER_SIZE - 1] as usize {
+            return None;
+        }
+        let u32_at = |i: usize| u32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+        let mut hash = [0; 8];
+        hash.copy_from_slice(&bytes[12..20]);
+
+        Some(Packet {
+            start: u32_at(0),
+            ack: u32_at(4),
+            hash_frame: u32_at(8),
+            hash: u64::from_le_bytes(hash),
+            inputs: bytes[HEADER_SIZE..]
+                .chunks(2)
+                .map(|keys| u16::from_le_bytes([keys[0], keys[1]]))
+                .collect(),
+        })
+    }
+}
+
+// One side of a two player game over the network. Both players share the keypad, each owns some
+// of its keys. The session runs frames with its own input right away and predicts that the peer
+// keeps holding the keys it pressed last. When the peer's actual input turns out different, the
+// session rolls back to a saved state and runs the frames again.
+//
+// Both peers have to start from the same state: the same program, seed and configuration.
+pub struct Session<T: Transport> {
+    cpu: CPU,
+    transport: T,
+    instructions_per_frame: u32,
+    local_keys: u16,
+    remote_keys: u16,
+    max_prediction: u32,
+
+    // Inputs of every frame, for the peer only those received so far
+    local: Vec<u16>,
+    remote: Vec<u16>,
+    // The peer's input each frame was run with, which may be a prediction
+    used: Vec<u16>,
+    // Frames before this one ran with the peer's actual input, their results are final
+    verified: u32,
+    // State at the start of every frame from verified on
+    states: VecDeque<State>,
+    // How many of our inputs the peer has
+    acked: u32,
+
+    hashes: VecDeque<(u32, u64)>,
+    // The peer's latest hash, for a frame that isn't final here yet
+    peer_hash: Option<(u32, u64)>,
+    rollbacks: u32,
+}
+
+impl<T: Transport> Session<T> {
+    // local_keys and remote_keys are masks of the keys each player owns, one bit per key
+    pub fn new(cpu: CPU, transport: T, instructions_per_frame: u32, local_keys: u16, remote_keys: u16) -> Self {
+        let mut hashes = VecDeque::new();
+        hashes.push_back((0, cpu.state_hash()));
+        Session {
+            cpu,
+            transport,
+            instructions_per_frame,
+            local_keys,
+            remote_keys,
+            max_prediction: DEFAULT_MAX_PREDICTION,
+            local: Vec::new(),
+            remote: Vec::new(),
+            used: Vec::new(),
+            verified: 0,
+            states: VecDeque::new(),
+            acked: 0,
+            hashes,
+            peer_hash: None,
+            rollbacks: 0,
+        }
+    }
+
+    pub fn with_max_prediction(mut self, frames: u32) -> Self {
+        self.max_prediction = frames;
+        self
+    }
+
+    pub fn cpu(&self) -> &CPU {
+        &self.cpu
+    }
+
+    // Number of frames run so far
+    pub fn frame(&self) -> u32 {
+        self.local.len() as u32
+    }
+
+    // How often a misprediction made the session run frames again
+    pub fn rollbacks(&self) -> u32 {
+        self.rollbacks
+    }
+
+    // Call once per frame with the local keypad state, keys the local player doesn't own are
+    // ignored. Returns false if the frame couldn't run yet because the peer is too far behind,
+    // the same input should be passed again next time.
+    pub fn advance(&mut self, keys: u16) -> Result<bool, NetplayError> {
+        self.receive()?;
+        self.rollback()?;
+
+        let frame = self.frame();
+        if frame.saturating_sub(self.remote.len() as u32) >= self.max_prediction {
+            self.send()?;
+            return Ok(false);
+        }
+
+        self.local.push(keys & self.local_keys);
+        self.run(frame)?;
+        self.verify()?;
+        self.send()?;
+        Ok(true)
+    }
+
+    fn receive(&mut self) -> Result<(), NetplayError> {
+        let mut buf = [0; MAX_PACKET_SIZE];
+        while let Some(len) = self.transport.receive(&mut buf)? {
+            let packet = match Packet::parse(&buf[..len]) {
+                Some(packet) => packet,
+                None => continue,
+            };
+
+            self.acked = self.acked.max(packet.ack.min(self.frame()));
+            for (i, &keys) in packet.inputs.iter().enumerate() {
+                // Anything past a gap comes again with a later packet
+                if packet.start as usize + i == self.remote.len() {
+                    self.remote.push(keys & self.remote_keys);
+                }
+            }
+            self.check_hash(packet.hash_frame, packet.hash)?;
+        }
+        Ok(())
+    }
+
+    fn send(&mut self) -> Result<(), NetplayError> {
+        let start = self.acked as usize;
+        let end = self.local.len().min(start + MAX_INPUTS_PER_PACKET);
+        let (hash_frame, hash) = *self.hashes.back().unwrap();
+        let packet = Packet {
+            start: start as u32,
+            ack: self.remote.len() as u32,
+            hash_frame,
+            hash,
+            inputs: self.local[start..end].to_vec(),
+        };
+        self.transport.send(&packet.to_bytes())?;
+        Ok(())
+    }
+
+    // Runs a frame with the peer's input if it's known and the prediction otherwise
+    fn run(&mut self, frame: u32) -> Result<(), CpuError> {
+        let index = frame as usize;
+        let remote = match self.remote.get(index) {
+            Some(&keys) => keys,
+            None => self.remote.last().copied().unwrap_or(0),
+        };
+        if index < self.used.len() {
+            self.used[index] = remote;
+        } else {
+            self.used.push(remote);
+        }
+
+        self.states.push_back(self.cpu.save_state());
+        self.cpu.keypad_mut().set_state(self.local[index] | remote);
+        self.cpu.run_frame(self.instructions_per_frame)
+    }
+
+    // Runs everything from the first wrong prediction again
+    fn rollback(&mut self) -> Result<(), NetplayError> {
+        let frame = self.frame();
+        let known = frame.min(self.remote.len() as u32);
+        let missed = (self.verified..known).find(|&f| self.used[f as usize] != self.remote[f as usize]);
+        if let Some(missed) = missed {
+            let index = (missed - self.verified) as usize;
+            self.cpu.load_state(&self.states[index]);
+            self.states.truncate(index);
+            for f in missed..frame {
+                self.run(f)?;
+            }
+            self.rollbacks += 1;
+        }
+        self.verify()
+    }
+
+    // Marks frames that ran with the peer's actual input as final
+    fn verify(&mut self) -> Result<(), NetplayError> {
+        let known = self.frame().min(self.remote.len() as u32);
+        while self.verified < known {
+            self.states.pop_front();
+            self.verified += 1;
+            let hash = match self.states.front() {
+                Some(state) => state.hash(),
+                None => self.cpu.state_hash(),
+            };
+            self.hashes.push_back((self.verified, hash));
+            if self.hashes.len() > HASH_HISTORY {
+                self.hashes.pop_front();
+            }
+        }
+
+        match self.peer_hash {
+            Some((frame, hash)) if frame <= self.verified => {
+                self.peer_hash = None;
+                self.check_hash(frame, hash)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn check_hash(&mut self, frame: u32, remote: u64) -> Result<(), NetplayError> {
+        if frame > self.verified {
+            self.peer_hash = Some((frame, remote));
+            return Ok(());
+        }
+        match self.hashes.iter().find(|&&(f, _)| f == frame) {
+            Some(&(_, local)) if local != remote => Err(NetplayError::Desync { frame, local, remote }),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Adds a random number to V2 for every pressed key, keys are checked one per loop, and draws
+    // a sprite at the result
+    // RND V0, 0xFF; SKNP V1; ADD V2, V0; ADD V1, 1; SNE V1, 0x10; LD V1, 0; LD I, 0x200;
+    // DRW V2, V1, 5; JP 0x200
+    const ROM: [u8; 18] = [
+        0xC0, 0xFF, 0xE1, 0xA1, 0x82, 0x04, 0x71, 0x01, 0x41, 0x10, 0x61, 0x00, 0xA2, 0x00, 0xD2, 0x15, 0x12, 0x00,
+    ];
+    const INSTRUCTIONS_PER_FRAME: u32 = 20;
+    const PLAYER_1: u16 = 0x00FF;
+    const PLAYER_2: u16 = 0xFF00;
+
+    // Changes every few frames like a human would, parts of it belong to the other player
+    fn keys(player: u32, frame: u32) -> u16 {
+        let x = (frame / 5 + player * 7919).wrapping_mul(2_654_435_761);
+        (x >> 16) as u16
+    }
+
+    fn session<T: Transport>(transport: T, seed: u64, local_keys: u16, remote_keys: u16) -> Session<T> {
+        let mut cpu = CPU::new();
+        cpu.load_rom(&ROM).unwrap();
+        cpu.seed_rng(seed);
+        Session::new(cpu, transport, INSTRUCTIONS_PER_FRAME, local_keys, remote_keys)
+    }
+
+    // Hashes of the state at the start of every frame when both players sit at the same machine
+    fn offline(frames: u32) -> Vec<u64> {
+        let mut cpu = CPU::new();
+        cpu.load_rom(&ROM).unwrap();
+        cpu.seed_rng(1);
+        let mut hashes = vec![cpu.state_hash()];
+        for frame in 0..frames {
+            cpu.keypad_mut().set_state(keys(0, frame) & PLAYER_1 | keys(1, frame) & PLAYER_2);
+            cpu.run_frame(INSTRUCTIONS_PER_FRAME).unwrap();
+            hashes.push(cpu.state_hash());
+        }
+        hashes
+    }
+
+    // Runs each side a few frames at a time, which is what latency looks like to them
+    fn play<T: Transport>(a: &mut Session<T>, b: &mut Session<T>, frames: u32, a_steps: u32, b_steps: u32) {
+        for _ in 0..10_000 {
+            if a.verified >= frames && b.verified >= frames {
+                return;
+            }
+            for _ in 0..a_steps {
+                a.advance(keys(0, a.frame())).unwrap();
+            }
+            for _ in 0..b_steps {
+                b.advance(keys(1, b.frame())).unwrap();
+            }
+        }
+        panic!("Stuck at frames {} and {}", a.frame(), b.frame());
+    }
+
+    fn assert_matches_offline<T: Transport>(session: &Session<T>, expected: &[u64]) {
+        assert!(session.hashes.len() > 10);
+        for &(frame, hash) in session.hashes.iter() {
+            if let Some(&expected) = expected.get(frame as usize) {
+                assert_eq!(expected, hash, "frame {}", frame);
+            }
+        }
+    }
+
+    #[test]
+    fn packets() {
+        let packet = Packet { start: 7, ack: 3, hash_frame: 2, hash: 0x0123_4567_89AB_CDEF, inputs: vec![1, 0x8000] };
+        let bytes = packet.to_bytes();
+        let parsed = Packet::parse(&bytes).unwrap();
+        assert_eq!((7, 3, 2, 0x0123_4567_89AB_CDEF), (parsed.start, parsed.ack, parsed.hash_frame, parsed.hash));
+        assert_eq!(vec![1, 0x8000], parsed.inputs);
+
+        assert!(Packet::parse(&bytes[..bytes.len() - 1]).is_none());
+        assert!(Packet::parse(&[0; 3]).is_none());
+    }
+
+    #[test]
+    fn rollback() {
+        let (a, b) = Loopback::pair();
+        let mut a = session(a, 1, PLAYER_1, PLAYER_2);
+        let mut b = session(b, 1, PLAYER_2, PLAYER_1);
+        play(&mut a, &mut b, 300, 3, 2);
+
+        // b is always behind and never has to predict
+        assert!(a.rollbacks() > 0);
+        assert_eq!(0, b.rollbacks());
+        let expected = offline(a.frame().max(b.frame()));
+        assert_matches_offline(&a, &expected);
+        assert_matches_offline(&b, &expected);
+    }
+
+    #[test]
+    fn waits_for_peer() {
+        let (a, b) = Loopback::pair();
+        let mut a = session(a, 1, PLAYER_1, PLAYER_2).with_max_prediction(4);
+        let mut b = session(b, 1, PLAYER_2, PLAYER_1);
+        for _ in 0..4 {
+            assert!(a.advance(0).unwrap());
+        }
+        assert!(!a.advance(0).unwrap());
+        assert_eq!(4, a.frame());
+
+        b.advance(0).unwrap();
+        assert!(a.advance(0).unwrap());
+    }
+
+    // Drops every third packet
+    struct Lossy<T: Transport> {
+        inner: T,
+        sent: u32,
+    }
+
+    impl<T: Transport> Transport for Lossy<T> {
+        fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+            self.sent += 1;
+            if self.sent.is_multiple_of(3) {
+                return Ok(());
+            }
+            self.inner.send(packet)
+        }
+
+        fn receive(&mut self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+            self.inner.receive(buf)
+        }
+    }
+
+    #[test]
+    fn packet_loss() {
+        let (a, b) = Loopback::pair();
+        let mut a = session(Lossy { inner: a, sent: 0 }, 1, PLAYER_1, PLAYER_2);
+        let mut b = session(Lossy { inner: b, sent: 0 }, 1, PLAYER_2, PLAYER_1);
+        play(&mut a, &mut b, 200, 1, 4);
+
+        let expected = offline(a.frame().max(b.frame()));
+        assert_matches_offline(&a, &expected);
+        assert_matches_offline(&b, &expected);
+    }
+
+    #[test]
+    fn udp() {
+        let socket_a = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let socket_b = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr_a = socket_a.local_addr().unwrap();
+        let addr_b = socket_b.local_addr().unwrap();
+        let mut a = session(UdpTransport::from_socket(socket_a, addr_b).unwrap(), 1, PLAYER_1, PLAYER_2);
+        let mut b = session(UdpTransport::from_socket(socket_b, addr_a).unwrap(), 1, PLAYER_2, PLAYER_1);
+
+        for _ in 0..10_000 {
+            if a.verified >= 100 && b.verified >= 100 {
+                break;
+            }
+            a.advance(keys(0, a.frame())).unwrap();
+            b.advance(keys(1, b.frame())).unwrap();
+            std::thread::sleep(std::time::Duration::from_micros(100));
+        }
+
+        let expected = offline(a.frame().max(b.frame()));
+        assert_matches_offline(&a, &expected);
+        assert_matches_offline(&b, &expected);
+    }
+
+    #[test]
+    fn desync() {
+        let (a, b) = Loopback::pair();
+        let mut a = session(a, 1, PLAYER_1, PLAYER_2);
+        let mut b = session(b, 2, PLAYER_2, PLAYER_1);
+        let error = (0..100)
+            .find_map(|frame| a.advance(keys(0, frame)).and_then(|_| b.advance(keys(1, frame))).err())
+            .unwrap();
+        assert!(matches!(error, NetplayError::Desync { .. }), "{}", error);
+    }
+}
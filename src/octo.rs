@@ -0,0 +1,1274 @@
+// An assembler for Octo, the language Octo saves in its cartridges. It covers the instructions of
+// CHIP-8, SCHIP and XO-CHIP with Octo's syntax, labels (also used before they are defined),
+// :const, :alias, :unpack, :next, :org, :byte, :call, :macro, :calc, :stringmode and :assert,
+// and the structured if/else/end and loop/while/again. :breakpoint and :monitor are accepted
+// and ignored.
+//
+// Like Octo, the program starts with a jump to the label main, which is left out if main is the
+// first thing in the program. :calc expressions are evaluated right to left without precedence,
+// use parentheses to group.
+// Reference: https://github.com/JohnEarnest/Octo/blob/gh-pages/docs/Manual.md
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fmt;
+
+use crate::memory::PROGRAM_OFFSET;
+
+const START: u32 = PROGRAM_OFFSET as u32;
+// Programs can't grow past what XO-CHIP addresses
+const END: u32 = 0x10000;
+
+// How often macros may expand inside each other before giving up
+const MAX_EXPANSIONS: usize = 100_000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AssembleErrorKind {
+    UnexpectedEnd,
+    // A token that can't start a statement or doesn't fit where it is
+    Unexpected(String),
+    NotARegister(String),
+    // A value that doesn't fit into the operand, like 300 for a byte
+    OutOfRange(String),
+    Undefined(String),
+    Redefined(String),
+    // else, end, again or while without its opening statement, or a block left open
+    Unbalanced(String),
+    NoMain,
+    // The program runs past the end of memory or :org points outside of it
+    Address(u32),
+    AssertionFailed(String),
+    TooManyExpansions,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AssembleError {
+    pub line: usize,
+    pub kind: AssembleErrorKind,
+}
+
+impl fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Line {}: ", self.line)?;
+        match &self.kind {
+            AssembleErrorKind::UnexpectedEnd => write!(f, "Unexpected end of the program"),
+            AssembleErrorKind::Unexpected(token) => write!(f, "Unexpected '{}'", token),
+            AssembleErrorKind::NotARegister(token) => write!(f, "'{}' is not a register", token),
+            AssembleErrorKind::OutOfRange(token) => write!(f, "'{}' is out of range", token),
+            AssembleErrorKind::Undefined(name) => write!(f, "'{}' is not defined", name),
+            AssembleErrorKind::Redefined(name) => write!(f, "'{}' is already defined", name),
+            AssembleErrorKind::Unbalanced(token) => write!(f, "Unbalanced '{}'", token),
+            AssembleErrorKind::NoMain => write!(f, "The program doesn't define main"),
+            AssembleErrorKind::Address(addr) => write!(f, "Address {:#x} is outside of memory", addr),
+            AssembleErrorKind::AssertionFailed(message) => write!(f, "Assertion failed: {}", message),
+            AssembleErrorKind::TooManyExpansions => write!(f, "Macros expand too often"),
+        }
+    }
+}
+
+impl std::error::Error for AssembleError {}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Token {
+    text: String,
+    // Quoted strings keep their quotes out of text
+    string: bool,
+    line: usize,
+}
+
+// Where the value of a label goes once it is known
+#[derive(Debug, Clone, Copy)]
+enum Patch {
+    // The low 12 bits of the instruction at the address
+    Address,
+    // Both bytes at the address
+    Long,
+    // The immediate byte of 6xnn: a nibble in the high half, bits 8-11 of the label in the low
+    UnpackHigh(u8),
+    UnpackLow,
+    UnpackLongHigh,
+}
+
+struct Fixup {
+    addr: u32,
+    patch: Patch,
+    name: String,
+    line: usize,
+}
+
+struct Macro {
+    args: Vec<String>,
+    body: Vec<Token>,
+    calls: u32,
+}
+
+struct StringMode {
+    alphabet: Vec<char>,
+    body: Vec<Token>,
+}
+
+// What if/else/end and loop/again have left open, with the jumps still to be patched
+enum Block {
+    If { jump: u32 },
+    Else { jump: u32 },
+    Loop { start: u32, exits: Vec<u32> },
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Operand {
+    Register(u8),
+    Value(i64),
+}
+
+// A condition as one of the skip instructions tests it
+#[derive(Debug, Clone, Copy)]
+enum Condition {
+    Equal(u8, Operand),
+    NotEqual(u8, Operand),
+    Key(u8),
+    NotKey(u8),
+}
+
+impl Condition {
+    fn negate(self) -> Condition {
+        match self {
+            Condition::Equal(x, rhs) => Condition::NotEqual(x, rhs),
+            Condition::NotEqual(x, rhs) => Condition::Equal(x, rhs),
+            Condition::Key(x) => Condition::NotKey(x),
+            Condition::NotKey(x) => Condition::Key(x),
+        }
+    }
+}
+
+pub fn assemble(source: &str) -> Result<Vec<u8>, AssembleError> {
+    let mut assembler = Assembler::new(tokenize(source)?);
+    assembler.run()?;
+    assembler.finish()
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, AssembleError> {
+    let mut tokens = Vec::new();
+    for (index, line) in source.lines().enumerate() {
+        let line_number = index + 1;
+        let mut chars = line.chars().peekable();
+        while let Some(&c) = chars.peek() {
+            if c.is_whitespace() {
+                chars.next();
+            } else if c == '#' {
+                break;
+            } else if c == '"' {
+                chars.next();
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some('n') => text.push('\n'),
+                            Some('t') => text.push('\t'),
+                            Some(c) => text.push(c),
+                            None => break,
+                        },
+                        Some(c) => text.push(c),
+                        None => {
+                            return Err(AssembleError {
+                                line: line_number,
+                                kind: AssembleErrorKind::Unexpected(format!("\"{}", text)),
+                            })
+                        }
+                    }
+                }
+                tokens.push(Token { text, string: true, line: line_number });
+            } else {
+                let mut text = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() {
+                        break;
+                    }
+                    text.push(c);
+                    chars.next();
+                }
+                tokens.push(Token { text, string: false, line: line_number });
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+fn parse_number(text: &str) -> Option<i64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, text),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+        i64::from_str_radix(hex, 16).ok()?
+    } else if let Some(binary) = digits.strip_prefix("0b").or_else(|| digits.strip_prefix("0B")) {
+        i64::from_str_radix(binary, 2).ok()?
+    } else if !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit()) {
+        digits.parse().ok()?
+    } else {
+        return None;
+    };
+    Some(if negative { -value } else { value })
+}
+
+fn register_number(text: &str) -> Option<u8> {
+    let mut chars = text.chars();
+    match (chars.next(), chars.next(), chars.next()) {
+        (Some('v') | Some('V'), Some(digit), None) => digit.to_digit(16).map(|digit| digit as u8),
+        _ => None,
+    }
+}
+
+struct Assembler {
+    // The statements still to assemble, macros are expanded into it
+    tokens: Vec<Token>,
+    pos: usize,
+    line: usize,
+    rom: Vec<u8>,
+    here: u32,
+    // Whether the first two bytes are the jump to main
+    main_jump: bool,
+    labels: HashMap<String, u32>,
+    constants: HashMap<String, f64>,
+    aliases: HashMap<String, u8>,
+    macros: HashMap<String, Macro>,
+    string_modes: HashMap<String, StringMode>,
+    fixups: Vec<Fixup>,
+    blocks: Vec<Block>,
+    expansions: usize,
+}
+
+impl Assembler {
+    fn new(tokens: Vec<Token>) -> Self {
+        let mut aliases = HashMap::new();
+        aliases.insert("compare-temp".to_string(), 0xF);
+        aliases.insert("unpack-hi".to_string(), 0x0);
+        aliases.insert("unpack-lo".to_string(), 0x1);
+        Assembler {
+            tokens,
+            pos: 0,
+            line: 1,
+            rom: Vec::new(),
+            here: START,
+            main_jump: true,
+            labels: HashMap::new(),
+            constants: HashMap::new(),
+            aliases,
+            macros: HashMap::new(),
+            string_modes: HashMap::new(),
+            fixups: Vec::new(),
+            blocks: Vec::new(),
+            expansions: 0,
+        }
+    }
+
+    fn error<T>(&self, kind: AssembleErrorKind) -> Result<T, AssembleError> {
+        Err(AssembleError { line: self.line, kind })
+    }
+
+    fn next(&mut self) -> Result<Token, AssembleError> {
+        match self.tokens.get(self.pos) {
+            Some(token) => {
+                self.pos += 1;
+                self.line = token.line;
+                Ok(token.clone())
+            }
+            None => self.error(AssembleErrorKind::UnexpectedEnd),
+        }
+    }
+
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.pos).map(|token| token.text.as_str())
+    }
+
+    fn expect(&mut self, text: &str) -> Result<(), AssembleError> {
+        let token = self.next()?;
+        if token.text != text || token.string {
+            return self.error(AssembleErrorKind::Unexpected(token.text));
+        }
+        Ok(())
+    }
+
+    fn run(&mut self) -> Result<(), AssembleError> {
+        // Room for the jump to main
+        self.emit(&[0, 0])?;
+        while self.pos < self.tokens.len() {
+            self.statement()?;
+        }
+        if let Some(block) = self.blocks.last() {
+            let token = match block {
+                Block::If { .. } | Block::Else { .. } => "begin",
+                Block::Loop { .. } => "loop",
+            };
+            return self.error(AssembleErrorKind::Unbalanced(token.to_string()));
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<Vec<u8>, AssembleError> {
+        for fixup in std::mem::take(&mut self.fixups) {
+            self.line = fixup.line;
+            let value = match self.labels.get(&fixup.name) {
+                Some(&value) => value,
+                None => return self.error(AssembleErrorKind::Undefined(fixup.name)),
+            };
+            let at = (fixup.addr - START) as usize;
+            match fixup.patch {
+                Patch::Address => {
+                    if value > 0xFFF {
+                        return self.error(AssembleErrorKind::OutOfRange(fixup.name));
+                    }
+                    self.rom[at] = (self.rom[at] & 0xF0) | (value >> 8) as u8;
+                    self.rom[at + 1] = value as u8;
+                }
+                Patch::Long => {
+                    self.rom[at] = (value >> 8) as u8;
+                    self.rom[at + 1] = value as u8;
+                }
+                Patch::UnpackHigh(nibble) => self.rom[at + 1] = nibble << 4 | (value >> 8 & 0x0F) as u8,
+                Patch::UnpackLow => self.rom[at + 1] = value as u8,
+                Patch::UnpackLongHigh => self.rom[at + 1] = (value >> 8) as u8,
+            }
+        }
+
+        if self.main_jump {
+            let main = match self.labels.get("main") {
+                Some(&main) => main,
+                None => return self.error(AssembleErrorKind::NoMain),
+            };
+            self.rom[0] = 0x10 | (main >> 8 & 0x0F) as u8;
+            self.rom[1] = main as u8;
+        }
+        Ok(self.rom)
+    }
+
+    fn emit(&mut self, bytes: &[u8]) -> Result<(), AssembleError> {
+        for &byte in bytes {
+            if self.here >= END {
+                return self.error(AssembleErrorKind::Address(self.here));
+            }
+            let at = (self.here - START) as usize;
+            if at >= self.rom.len() {
+                self.rom.resize(at + 1, 0);
+            }
+            self.rom[at] = byte;
+            self.here += 1;
+        }
+        Ok(())
+    }
+
+    fn emit_op(&mut self, op: u16) -> Result<(), AssembleError> {
+        self.emit(&op.to_be_bytes())
+    }
+
+    fn define_label(&mut self, name: String, value: u32) -> Result<(), AssembleError> {
+        if self.labels.contains_key(&name) || self.constants.contains_key(&name) {
+            return self.error(AssembleErrorKind::Redefined(name));
+        }
+        self.labels.insert(name, value);
+        Ok(())
+    }
+
+    fn statement(&mut self) -> Result<(), AssembleError> {
+        let token = self.next()?;
+        if token.string {
+            return self.error(AssembleErrorKind::Unexpected(token.text));
+        }
+        let text = token.text.as_str();
+
+        if let Some(x) = self.register(text) {
+            return self.register_statement(x);
+        }
+        if let Some(value) = parse_number(text) {
+            let byte = self.byte(value, text)?;
+            return self.emit(&[byte]);
+        }
+
+        match text {
+            ":" => {
+                let name = self.name()?;
+                if name == "main" && self.main_jump && self.rom.len() == 2 && self.here == START + 2 && self.labels.is_empty() {
+                    // Nothing before main, the jump isn't needed
+                    self.main_jump = false;
+                    self.rom.clear();
+                    self.here = START;
+                }
+                let here = self.here;
+                self.define_label(name, here)?;
+            }
+            ":const" => {
+                let name = self.name()?;
+                let value = self.value()?;
+                if self.labels.contains_key(&name) || self.constants.contains_key(&name) {
+                    return self.error(AssembleErrorKind::Redefined(name));
+                }
+                self.constants.insert(name, value as f64);
+            }
+            ":alias" => {
+                let name = self.name()?;
+                let register = if self.peek() == Some("{") {
+                    let value = self.expression()?;
+                    if !(0.0..16.0).contains(&value) {
+                        return self.error(AssembleErrorKind::NotARegister(name));
+                    }
+                    value as u8
+                } else {
+                    let token = self.next()?;
+                    self.register_operand(&token.text)?
+                };
+                self.aliases.insert(name, register);
+            }
+            ":unpack" => {
+                let long = self.peek() == Some("long");
+                let nibble = if long {
+                    self.next()?;
+                    0
+                } else {
+                    let value = self.value()?;
+                    if !(0..16).contains(&value) {
+                        return self.error(AssembleErrorKind::OutOfRange(value.to_string()));
+                    }
+                    value as u8
+                };
+                let name = self.next()?.text;
+                let high = self.aliases["unpack-hi"];
+                let low = self.aliases["unpack-lo"];
+                let (high_patch, low_patch) = if long {
+                    (Patch::UnpackLongHigh, Patch::UnpackLow)
+                } else {
+                    (Patch::UnpackHigh(nibble), Patch::UnpackLow)
+                };
+                self.reference(0x6000 | (high as u16) << 8, high_patch, &name)?;
+                self.reference(0x6000 | (low as u16) << 8, low_patch, &name)?;
+            }
+            ":next" => {
+                let name = self.name()?;
+                let next = self.here + 1;
+                self.define_label(name, next)?;
+            }
+            ":org" => {
+                let value = self.value()?;
+                if !(START as i64..END as i64).contains(&value) {
+                    return self.error(AssembleErrorKind::Address(value as u32));
+                }
+                self.here = value as u32;
+            }
+            ":byte" => {
+                let token = self.tokens.get(self.pos).map(|token| token.text.clone()).unwrap_or_default();
+                let value = if token == "{" { self.expression()? as i64 } else { self.value()? };
+                let byte = self.byte(value, &token)?;
+                self.emit(&[byte])?;
+            }
+            ":call" => {
+                let name = self.next()?.text;
+                self.reference(0x2000, Patch::Address, &name)?;
+            }
+            ":macro" => self.define_macro()?,
+            ":calc" => {
+                let name = self.name()?;
+                let value = self.expression()?;
+                if self.labels.contains_key(&name) {
+                    return self.error(AssembleErrorKind::Redefined(name));
+                }
+                self.constants.insert(name, value);
+            }
+            ":stringmode" => self.define_string_mode()?,
+            ":assert" => {
+                let message = if self.tokens.get(self.pos).is_some_and(|token| token.string) {
+                    self.next()?.text
+                } else {
+                    "".to_string()
+                };
+                if self.expression()? == 0.0 {
+                    return self.error(AssembleErrorKind::AssertionFailed(message));
+                }
+            }
+            ":breakpoint" | ":proto" => {
+                self.next()?;
+            }
+            ":monitor" => {
+                self.next()?;
+                self.next()?;
+            }
+            ";" | "return" => self.emit_op(0x00EE)?,
+            "clear" => self.emit_op(0x00E0)?,
+            "exit" => self.emit_op(0x00FD)?,
+            "lores" => self.emit_op(0x00FE)?,
+            "hires" => self.emit_op(0x00FF)?,
+            "scroll-right" => self.emit_op(0x00FB)?,
+            "scroll-left" => self.emit_op(0x00FC)?,
+            "scroll-down" | "scroll-up" => {
+                let lines = self.nibble()?;
+                let op = if text == "scroll-down" { 0x00C0 } else { 0x00D0 };
+                self.emit_op(op | lines as u16)?;
+            }
+            "audio" => self.emit_op(0xF002)?,
+            "plane" => {
+                let planes = self.nibble()?;
+                self.emit_op(0xF001 | (planes as u16) << 8)?;
+            }
+            "bcd" | "saveflags" | "loadflags" => {
+                let x = self.register_token()?;
+                let low = match text {
+                    "bcd" => 0x33,
+                    "saveflags" => 0x75,
+                    _ => 0x85,
+                };
+                self.emit_op(0xF000 | (x as u16) << 8 | low)?;
+            }
+            "save" | "load" => {
+                let x = self.register_token()?;
+                if self.peek() == Some("-") {
+                    self.next()?;
+                    let y = self.register_token()?;
+                    let low = if text == "save" { 0x2 } else { 0x3 };
+                    self.emit_op(0x5000 | (x as u16) << 8 | (y as u16) << 4 | low)?;
+                } else {
+                    let low = if text == "save" { 0x55 } else { 0x65 };
+                    self.emit_op(0xF000 | (x as u16) << 8 | low)?;
+                }
+            }
+            "sprite" => {
+                let x = self.register_token()?;
+                let y = self.register_token()?;
+                let lines = self.nibble()?;
+                self.emit_op(0xD000 | (x as u16) << 8 | (y as u16) << 4 | lines as u16)?;
+            }
+            "jump" | "jump0" | "native" => {
+                let name = self.next()?.text;
+                let op = match text {
+                    "jump" => 0x1000,
+                    "jump0" => 0xB000,
+                    _ => 0x0000,
+                };
+                self.reference(op, Patch::Address, &name)?;
+            }
+            "delay" | "buzzer" | "pitch" => {
+                self.expect(":=")?;
+                let x = self.register_token()?;
+                let low = match text {
+                    "delay" => 0x15,
+                    "buzzer" => 0x18,
+                    _ => 0x3A,
+                };
+                self.emit_op(0xF000 | (x as u16) << 8 | low)?;
+            }
+            "i" => self.index_statement()?,
+            "if" => {
+                let condition = self.condition()?;
+                let token = self.next()?;
+                match token.text.as_str() {
+                    // The skip jumps over the next statement unless the condition holds
+                    "then" => self.skip(condition.negate())?,
+                    "begin" => {
+                        self.skip(condition)?;
+                        let jump = self.here;
+                        self.emit_op(0x1000)?;
+                        self.blocks.push(Block::If { jump });
+                    }
+                    _ => return self.error(AssembleErrorKind::Unexpected(token.text)),
+                }
+            }
+            "else" => {
+                let jump = match self.blocks.pop() {
+                    Some(Block::If { jump }) => jump,
+                    _ => return self.error(AssembleErrorKind::Unbalanced(text.to_string())),
+                };
+                let end_jump = self.here;
+                self.emit_op(0x1000)?;
+                let here = self.here;
+                self.patch_jump(jump, here)?;
+                self.blocks.push(Block::Else { jump: end_jump });
+            }
+            "end" => {
+                let jump = match self.blocks.pop() {
+                    Some(Block::If { jump }) | Some(Block::Else { jump }) => jump,
+                    _ => return self.error(AssembleErrorKind::Unbalanced(text.to_string())),
+                };
+                let here = self.here;
+                self.patch_jump(jump, here)?;
+            }
+            "loop" => {
+                let start = self.here;
+                self.blocks.push(Block::Loop { start, exits: Vec::new() });
+            }
+            "while" => {
+                let condition = self.condition()?;
+                self.skip(condition)?;
+                let jump = self.here;
+                self.emit_op(0x1000)?;
+                match self.blocks.iter_mut().rev().find(|block| matches!(block, Block::Loop { .. })) {
+                    Some(Block::Loop { exits, .. }) => exits.push(jump),
+                    _ => return self.error(AssembleErrorKind::Unbalanced(text.to_string())),
+                }
+            }
+            "again" => {
+                let (start, exits) = match self.blocks.pop() {
+                    Some(Block::Loop { start, exits }) => (start, exits),
+                    _ => return self.error(AssembleErrorKind::Unbalanced(text.to_string())),
+                };
+                self.emit_op(0x1000 | (start & 0x0FFF) as u16)?;
+                let here = self.here;
+                for exit in exits {
+                    self.patch_jump(exit, here)?;
+                }
+            }
+            _ => {
+                if self.macros.contains_key(text) {
+                    return self.expand_macro(text);
+                }
+                if self.string_modes.contains_key(text) {
+                    return self.expand_string_mode(text);
+                }
+                if let Some(&value) = self.constants.get(text) {
+                    let byte = self.byte(value as i64, text)?;
+                    return self.emit(&[byte]);
+                }
+                if text.starts_with(':') || matches!(text, "{" | "}" | ":=" | "then" | "begin") {
+                    return self.error(AssembleErrorKind::Unexpected(token.text));
+                }
+                // Anything else calls a label, which may come later
+                self.reference(0x2000, Patch::Address, text)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn register_statement(&mut self, x: u8) -> Result<(), AssembleError> {
+        let op = self.next()?.text;
+        let x_bits = (x as u16) << 8;
+        match op.as_str() {
+            ":=" => {
+                let token = self.next()?;
+                match token.text.as_str() {
+                    "random" => {
+                        let mask = self.value()?;
+                        let mask = self.byte(mask, &token.text)?;
+                        self.emit_op(0xC000 | x_bits | mask as u16)
+                    }
+                    "key" => self.emit_op(0xF00A | x_bits),
+                    "delay" => self.emit_op(0xF007 | x_bits),
+                    _ => match self.operand(&token.text)? {
+                        Operand::Register(y) => self.emit_op(0x8000 | x_bits | (y as u16) << 4),
+                        Operand::Value(value) => {
+                            let byte = self.byte(value, &token.text)?;
+                            self.emit_op(0x6000 | x_bits | byte as u16)
+                        }
+                    },
+                }
+            }
+            "+=" | "-=" => {
+                let token = self.next()?;
+                match self.operand(&token.text)? {
+                    Operand::Register(y) => {
+                        let low = if op == "+=" { 0x4 } else { 0x5 };
+                        self.emit_op(0x8000 | x_bits | (y as u16) << 4 | low)
+                    }
+                    Operand::Value(value) => {
+                        let value = if op == "+=" { value } else { -value };
+                        let byte = self.byte(value, &token.text)?;
+                        self.emit_op(0x7000 | x_bits | byte as u16)
+                    }
+                }
+            }
+            "=-" | "|=" | "&=" | "^=" | ">>=" | "<<=" => {
+                let y = self.register_token()?;
+                let low = match op.as_str() {
+                    "|=" => 0x1,
+                    "&=" => 0x2,
+                    "^=" => 0x3,
+                    ">>=" => 0x6,
+                    "=-" => 0x7,
+                    _ => 0xE,
+                };
+                self.emit_op(0x8000 | x_bits | (y as u16) << 4 | low)
+            }
+            _ => self.error(AssembleErrorKind::Unexpected(op)),
+        }
+    }
+
+    fn index_statement(&mut self) -> Result<(), AssembleError> {
+        let op = self.next()?.text;
+        match op.as_str() {
+            ":=" => {
+                let token = self.next()?;
+                match token.text.as_str() {
+                    "hex" => {
+                        let x = self.register_token()?;
+                        self.emit_op(0xF029 | (x as u16) << 8)
+                    }
+                    "bighex" => {
+                        let x = self.register_token()?;
+                        self.emit_op(0xF030 | (x as u16) << 8)
+                    }
+                    "long" => {
+                        self.emit_op(0xF000)?;
+                        let name = self.next()?.text;
+                        self.reference_long(&name)
+                    }
+                    name => self.reference(0xA000, Patch::Address, name),
+                }
+            }
+            "+=" => {
+                let x = self.register_token()?;
+                self.emit_op(0xF01E | (x as u16) << 8)
+            }
+            _ => self.error(AssembleErrorKind::Unexpected(op)),
+        }
+    }
+
+    // Parses the condition of if and while, comparisons go through the compare-temp register
+    fn condition(&mut self) -> Result<Condition, AssembleError> {
+        let x = self.register_token()?;
+        let op = self.next()?.text;
+        match op.as_str() {
+            "key" => return Ok(Condition::Key(x)),
+            "-key" => return Ok(Condition::NotKey(x)),
+            _ => {}
+        }
+        let token = self.next()?;
+        let rhs = self.operand(&token.text)?;
+        if let Operand::Value(value) = rhs {
+            self.byte(value, &token.text)?;
+        }
+        let rhs = match rhs {
+            Operand::Value(value) => Operand::Value(value & 0xFF),
+            register => register,
+        };
+
+        match op.as_str() {
+            "==" => Ok(Condition::Equal(x, rhs)),
+            "!=" => Ok(Condition::NotEqual(x, rhs)),
+            "<" | ">" | "<=" | ">=" => {
+                // temp := rhs, then subtract so that the carry tells the comparison
+                let temp = self.aliases["compare-temp"];
+                let temp_bits = (temp as u16) << 8;
+                match rhs {
+                    Operand::Register(y) => self.emit_op(0x8000 | temp_bits | (y as u16) << 4)?,
+                    Operand::Value(value) => self.emit_op(0x6000 | temp_bits | value as u16)?,
+                }
+                let x_bits = (x as u16) << 4;
+                // x < rhs and x >= rhs: temp = x - rhs, temp is the carry, 1 if x >= rhs.
+                // x > rhs and x <= rhs: temp = rhs - x, 1 if rhs >= x.
+                let (low, holds_if_carry) = match op.as_str() {
+                    "<" => (0x7, false),
+                    ">=" => (0x7, true),
+                    ">" => (0x5, false),
+                    _ => (0x5, true),
+                };
+                self.emit_op(0x8000 | temp_bits | x_bits | low)?;
+                let zero = Operand::Value(0);
+                Ok(if holds_if_carry {
+                    Condition::NotEqual(temp, zero)
+                } else {
+                    Condition::Equal(temp, zero)
+                })
+            }
+            _ => self.error(AssembleErrorKind::Unexpected(op)),
+        }
+    }
+
+    // Emits the instruction that skips the next one if the condition holds
+    fn skip(&mut self, condition: Condition) -> Result<(), AssembleError> {
+        let op = match condition {
+            Condition::Equal(x, Operand::Value(value)) => 0x3000 | (x as u16) << 8 | value as u16,
+            Condition::NotEqual(x, Operand::Value(value)) => 0x4000 | (x as u16) << 8 | value as u16,
+            Condition::Equal(x, Operand::Register(y)) => 0x5000 | (x as u16) << 8 | (y as u16) << 4,
+            Condition::NotEqual(x, Operand::Register(y)) => 0x9000 | (x as u16) << 8 | (y as u16) << 4,
+            Condition::Key(x) => 0xE09E | (x as u16) << 8,
+            Condition::NotKey(x) => 0xE0A1 | (x as u16) << 8,
+        };
+        self.emit_op(op)
+    }
+
+    fn patch_jump(&mut self, addr: u32, target: u32) -> Result<(), AssembleError> {
+        if target > 0xFFF {
+            return self.error(AssembleErrorKind::Address(target));
+        }
+        let at = (addr - START) as usize;
+        self.rom[at] = 0x10 | (target >> 8) as u8;
+        self.rom[at + 1] = target as u8;
+        Ok(())
+    }
+
+    // Emits the instruction with the label or number filled in, or later if it isn't known yet
+    fn reference(&mut self, op: u16, patch: Patch, name: &str) -> Result<(), AssembleError> {
+        let addr = self.here;
+        let value = match parse_number(name) {
+            Some(value) => Some(value),
+            None => self.known_value(name),
+        };
+        match value {
+            Some(value) => {
+                let op = match patch {
+                    Patch::Address => {
+                        if !(0..=0xFFF).contains(&value) {
+                            return self.error(AssembleErrorKind::OutOfRange(name.to_string()));
+                        }
+                        op | value as u16
+                    }
+                    Patch::UnpackHigh(nibble) => op | (nibble as u16) << 4 | (value as u16 >> 8 & 0x0F),
+                    Patch::UnpackLow => op | (value as u16 & 0xFF),
+                    Patch::UnpackLongHigh => op | (value as u16 >> 8),
+                    Patch::Long => value as u16,
+                };
+                self.emit_op(op)
+            }
+            None => {
+                if self.register(name).is_some() || name.starts_with(':') {
+                    return self.error(AssembleErrorKind::Unexpected(name.to_string()));
+                }
+                self.fixups.push(Fixup { addr, patch, name: name.to_string(), line: self.line });
+                self.emit_op(op)
+            }
+        }
+    }
+
+    fn reference_long(&mut self, name: &str) -> Result<(), AssembleError> {
+        self.reference(0, Patch::Long, name)
+    }
+
+    fn known_value(&self, name: &str) -> Option<i64> {
+        if let Some(&value) = self.labels.get(name) {
+            return Some(value as i64);
+        }
+        self.constants.get(name).map(|&value| value as i64)
+    }
+
+    fn register(&self, text: &str) -> Option<u8> {
+        register_number(text).or_else(|| self.aliases.get(text).copied())
+    }
+
+    fn register_operand(&self, text: &str) -> Result<u8, AssembleError> {
+        match self.register(text) {
+            Some(register) => Ok(register),
+            None => self.error(AssembleErrorKind::NotARegister(text.to_string())),
+        }
+    }
+
+    fn register_token(&mut self) -> Result<u8, AssembleError> {
+        let token = self.next()?;
+        self.register_operand(&token.text)
+    }
+
+    fn operand(&self, text: &str) -> Result<Operand, AssembleError> {
+        if let Some(register) = self.register(text) {
+            return Ok(Operand::Register(register));
+        }
+        match parse_number(text).or_else(|| self.known_value(text)) {
+            Some(value) => Ok(Operand::Value(value)),
+            None => self.error(AssembleErrorKind::Undefined(text.to_string())),
+        }
+    }
+
+    // A number, constant or label that is already defined
+    fn value(&mut self) -> Result<i64, AssembleError> {
+        let token = self.next()?;
+        match parse_number(&token.text).or_else(|| self.known_value(&token.text)) {
+            Some(value) => Ok(value),
+            None => self.error(AssembleErrorKind::Undefined(token.text)),
+        }
+    }
+
+    fn nibble(&mut self) -> Result<u8, AssembleError> {
+        let value = self.value()?;
+        if !(0..16).contains(&value) {
+            return self.error(AssembleErrorKind::OutOfRange(value.to_string()));
+        }
+        Ok(value as u8)
+    }
+
+    // Bytes can be given signed or unsigned
+    fn byte(&self, value: i64, text: &str) -> Result<u8, AssembleError> {
+        if !(-128..=255).contains(&value) {
+            return self.error(AssembleErrorKind::OutOfRange(text.to_string()));
+        }
+        Ok(value as u8)
+    }
+
+    fn name(&mut self) -> Result<String, AssembleError> {
+        let token = self.next()?;
+        if token.string || parse_number(&token.text).is_some() || register_number(&token.text).is_some() {
+            return self.error(AssembleErrorKind::Unexpected(token.text));
+        }
+        Ok(token.text)
+    }
+
+    // The tokens between { and the matching }
+    fn braced(&mut self) -> Result<Vec<Token>, AssembleError> {
+        self.expect("{")?;
+        let mut depth = 1;
+        let mut body = Vec::new();
+        loop {
+            let token = self.next()?;
+            if !token.string {
+                match token.text.as_str() {
+                    "{" => depth += 1,
+                    "}" => {
+                        depth -= 1;
+                        if depth == 0 {
+                            return Ok(body);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            body.push(token);
+        }
+    }
+
+    fn define_macro(&mut self) -> Result<(), AssembleError> {
+        let name = self.name()?;
+        let mut args = Vec::new();
+        while self.peek() != Some("{") {
+            args.push(self.name()?);
+        }
+        let body = self.braced()?;
+        self.macros.insert(name, Macro { args, body, calls: 0 });
+        Ok(())
+    }
+
+    fn expand_macro(&mut self, name: &str) -> Result<(), AssembleError> {
+        let arg_count = self.macros[name].args.len();
+        let mut values = Vec::with_capacity(arg_count);
+        for _ in 0..arg_count {
+            values.push(self.next()?);
+        }
+        let line = self.line;
+        let definition = self.macros.get_mut(name).unwrap();
+        let calls = definition.calls;
+        definition.calls += 1;
+        let body: Vec<Token> = definition
+            .body
+            .iter()
+            .map(|token| {
+                if token.string {
+                    return Token { line, ..token.clone() };
+                }
+                if token.text == "CALLS" {
+                    return Token { text: calls.to_string(), string: false, line };
+                }
+                match definition.args.iter().position(|arg| *arg == token.text) {
+                    Some(index) => Token { line, ..values[index].clone() },
+                    None => Token { line, ..token.clone() },
+                }
+            })
+            .collect();
+        self.splice(body)
+    }
+
+    fn define_string_mode(&mut self) -> Result<(), AssembleError> {
+        let name = self.name()?;
+        let alphabet = self.next()?;
+        if !alphabet.string {
+            return self.error(AssembleErrorKind::Unexpected(alphabet.text));
+        }
+        let body = self.braced()?;
+        self.string_modes.insert(name, StringMode { alphabet: alphabet.text.chars().collect(), body });
+        Ok(())
+    }
+
+    fn expand_string_mode(&mut self, name: &str) -> Result<(), AssembleError> {
+        let text = self.next()?;
+        if !text.string {
+            return self.error(AssembleErrorKind::Unexpected(text.text));
+        }
+        let line = self.line;
+        let mode = &self.string_modes[name];
+        let mut expanded = Vec::new();
+        for (index, c) in text.text.chars().enumerate() {
+            let value = match mode.alphabet.iter().position(|&letter| letter == c) {
+                Some(value) => value,
+                None => return self.error(AssembleErrorKind::OutOfRange(c.to_string())),
+            };
+            for token in &mode.body {
+                let replacement = match token.text.as_str() {
+                    _ if token.string => None,
+                    "CHAR" => Some(c as u32 as usize),
+                    "INDEX" => Some(index),
+                    "VALUE" => Some(value),
+                    _ => None,
+                };
+                expanded.push(match replacement {
+                    Some(number) => Token { text: number.to_string(), string: false, line },
+                    None => Token { line, ..token.clone() },
+                });
+            }
+        }
+        self.splice(expanded)
+    }
+
+    fn splice(&mut self, tokens: Vec<Token>) -> Result<(), AssembleError> {
+        self.expansions += 1;
+        if self.expansions > MAX_EXPANSIONS {
+            return self.error(AssembleErrorKind::TooManyExpansions);
+        }
+        let pos = self.pos;
+        self.tokens.splice(pos..pos, tokens);
+        Ok(())
+    }
+
+    // { expression }, see the top of the file for how it's evaluated
+    fn expression(&mut self) -> Result<f64, AssembleError> {
+        let tokens = self.braced()?;
+        let mut pos = 0;
+        let value = self.calc(&tokens, &mut pos)?;
+        if pos != tokens.len() {
+            return self.error(AssembleErrorKind::Unexpected(tokens[pos].text.clone()));
+        }
+        Ok(value)
+    }
+
+    fn calc(&self, tokens: &[Token], pos: &mut usize) -> Result<f64, AssembleError> {
+        let left = self.calc_term(tokens, pos)?;
+        let op = match tokens.get(*pos) {
+            Some(token) if token.text != ")" => token.text.as_str(),
+            _ => return Ok(left),
+        };
+        let apply: fn(f64, f64) -> f64 = match op {
+            "+" => |a, b| a + b,
+            "-" => |a, b| a - b,
+            "*" => |a, b| a * b,
+            "/" => |a, b| a / b,
+            "%" => |a, b| a % b,
+            "&" => |a, b| (a as i64 & b as i64) as f64,
+            "|" => |a, b| (a as i64 | b as i64) as f64,
+            "^" => |a, b| (a as i64 ^ b as i64) as f64,
+            "<<" => |a, b| ((a as i64) << (b as i64 & 63)) as f64,
+            ">>" => |a, b| ((a as i64) >> (b as i64 & 63)) as f64,
+            "pow" => f64::powf,
+            "min" => f64::min,
+            "max" => f64::max,
+            "<" => |a, b| (a < b) as u8 as f64,
+            "<=" => |a, b| (a <= b) as u8 as f64,
+            ">" => |a, b| (a > b) as u8 as f64,
+            ">=" => |a, b| (a >= b) as u8 as f64,
+            "==" => |a, b| (a == b) as u8 as f64,
+            "!=" => |a, b| (a != b) as u8 as f64,
+            _ => return self.error(AssembleErrorKind::Unexpected(op.to_string())),
+        };
+        *pos += 1;
+        let right = self.calc(tokens, pos)?;
+        Ok(apply(left, right))
+    }
+
+    fn calc_term(&self, tokens: &[Token], pos: &mut usize) -> Result<f64, AssembleError> {
+        let token = match tokens.get(*pos) {
+            Some(token) => token,
+            None => return self.error(AssembleErrorKind::UnexpectedEnd),
+        };
+        *pos += 1;
+        let text = token.text.as_str();
+        if text == "(" {
+            let value = self.calc(tokens, pos)?;
+            match tokens.get(*pos) {
+                Some(token) if token.text == ")" => *pos += 1,
+                _ => return self.error(AssembleErrorKind::Unbalanced("(".to_string())),
+            }
+            return Ok(value);
+        }
+        let unary: Option<fn(f64) -> f64> = match text {
+            "-" => Some(|a| -a),
+            "~" => Some(|a| !(a as i64) as f64),
+            "!" => Some(|a| (a == 0.0) as u8 as f64),
+            "sin" => Some(f64::sin),
+            "cos" => Some(f64::cos),
+            "tan" => Some(f64::tan),
+            "exp" => Some(f64::exp),
+            "log" => Some(f64::ln),
+            "abs" => Some(f64::abs),
+            "sqrt" => Some(f64::sqrt),
+            "sign" => Some(|a: f64| if a == 0.0 { 0.0 } else { a.signum() }),
+            "ceil" => Some(f64::ceil),
+            "floor" => Some(f64::floor),
+            _ => None,
+        };
+        if let Some(unary) = unary {
+            return Ok(unary(self.calc_term(tokens, pos)?));
+        }
+        if text == "@" {
+            // A byte of the program assembled so far
+            let addr = self.calc_term(tokens, pos)? as i64;
+            let byte = (addr - START as i64)
+                .try_into()
+                .ok()
+                .and_then(|at: usize| self.rom.get(at).copied())
+                .unwrap_or(0);
+            return Ok(byte as f64);
+        }
+        match text {
+            "HERE" => return Ok(self.here as f64),
+            "PI" => return Ok(std::f64::consts::PI),
+            "E" => return Ok(std::f64::consts::E),
+            _ => {}
+        }
+        if let Some(value) = parse_number(text) {
+            return Ok(value as f64);
+        }
+        if let Ok(value) = text.parse::<f64>() {
+            return Ok(value);
+        }
+        if let Some(&value) = self.constants.get(text) {
+            return Ok(value);
+        }
+        if let Some(&value) = self.labels.get(text) {
+            return Ok(value as f64);
+        }
+        if let Some(register) = self.register(text) {
+            return Ok(register as f64);
+        }
+        self.error(AssembleErrorKind::Undefined(text.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(rom: &[u8]) -> Vec<u16> {
+        rom.chunks(2).map(|pair| u16::from_be_bytes([pair[0], *pair.get(1).unwrap_or(&0)])).collect()
+    }
+
+    fn error(source: &str) -> AssembleErrorKind {
+        assemble(source).unwrap_err().kind
+    }
+
+    #[test]
+    fn instructions() {
+        let source = "
+            : main
+                clear
+                v0 := 5   v1 := v0   v2 := random 0x0F   v3 := key   v4 := delay
+                v0 += 1   v0 -= 1   v0 += v1   v0 -= v1   v0 =- v1
+                v0 |= v1   v0 &= v1   v0 ^= v1   v0 >>= v1   v0 <<= v1
+                i := 0x300   i += v2   i := hex v3   i := bighex v3   i := long 0x1234
+                sprite v0 v1 5   bcd v2   save v3   load v4   save v1 - v2   load v3 - v4
+                delay := v5   buzzer := v6   pitch := v7   saveflags v8   loadflags v9
+                scroll-down 3   scroll-up 2   scroll-right   scroll-left   lores   hires   exit
+                plane 3   audio   jump0 0x210   native 0x123   jump main   return ;
+        ";
+        assert_eq!(
+            vec![
+                0x00E0, 0x6005, 0x8100, 0xC20F, 0xF30A, 0xF407, 0x7001, 0x70FF, 0x8014, 0x8015, 0x8017, 0x8011, 0x8012,
+                0x8013, 0x8016, 0x801E, 0xA300, 0xF21E, 0xF329, 0xF330, 0xF000, 0x1234, 0xD015, 0xF233, 0xF355,
+                0xF465, 0x5122, 0x5343, 0xF515, 0xF618, 0xF73A, 0xF875, 0xF985, 0x00C3, 0x00D2, 0x00FB, 0x00FC,
+                0x00FE, 0x00FF, 0x00FD, 0xF301, 0xF002, 0xB210, 0x0123, 0x1200, 0x00EE, 0x00EE,
+            ],
+            words(&assemble(source).unwrap())
+        );
+    }
+
+    #[test]
+    fn labels_and_data() {
+        // Calls and data before main need the jump, labels can be used before they're defined
+        let source = "
+            : draw  i := glyph  sprite v0 v0 2  ;
+            : main  draw  loop again
+            : glyph  0x3C -1 0b101
+            :byte 7
+        ";
+        assert_eq!(
+            vec![0x12, 0x08, 0xA2, 0x0C, 0xD0, 0x02, 0x00, 0xEE, 0x22, 0x02, 0x12, 0x0A, 0x3C, 0xFF, 0x05, 0x07],
+            assemble(source).unwrap()
+        );
+
+        // :org, :next, :unpack and :const
+        let source = "
+            :const count 3
+            : main
+                :unpack 0xA target
+                v2 := count
+            : patched
+                :next operand v3 := 0
+                :org 0x280
+            : target
+        ";
+        assert_eq!(vec![0x60, 0xA2, 0x61, 0x80, 0x62, 0x03, 0x63, 0x00], assemble(source).unwrap()[..8]);
+        let source = ": main  i := operand  :next operand  v0 := 0";
+        assert_eq!(vec![0xA2, 0x03, 0x60, 0x00], assemble(source).unwrap());
+    }
+
+    #[test]
+    fn control_flow() {
+        let source = "
+            : main
+                if v0 == 1 then v1 := 2
+                if v0 != v1 then v1 := 2
+                if v2 key then v1 := 2
+                if v2 -key begin v1 := 2 else v1 := 3 end
+                loop
+                    v0 += 1
+                    while v0 != 10
+                    if v0 < 5 then v1 := 0
+                again
+        ";
+        assert_eq!(
+            vec![
+                0x4001, 0x6102, 0x5010, 0x6102, 0xE2A1, 0x6102, 0xE2A1, 0x1214, 0x6102, 0x1216, 0x6103, 0x7001,
+                0x400A, 0x1226, 0x6F05, 0x8F07, 0x4F00, 0x6100, 0x1216,
+            ],
+            words(&assemble(source).unwrap())
+        );
+    }
+
+    #[test]
+    fn comparisons() {
+        // Runs every comparison on the CPU, which must take the branch exactly when it holds
+        for &op in &["<", ">", "<=", ">="] {
+            for &(a, b) in &[(3u8, 5u8), (5, 5), (7, 5), (0, 255)] {
+                let holds = match op {
+                    "<" => a < b,
+                    ">" => a > b,
+                    "<=" => a <= b,
+                    _ => a >= b,
+                };
+                let source = format!(": main  v0 := {}  v1 := {}  v2 := 0  if v0 {} v1 then v2 := 1  loop again", a, b, op);
+                let mut cpu = crate::cpu::CPU::new();
+                cpu.load_rom(&assemble(&source).unwrap()).unwrap();
+                cpu.run_frame(20).unwrap();
+                assert_eq!(holds as u8, cpu.registers()[2], "{} {} {}", a, op, b);
+            }
+        }
+    }
+
+    #[test]
+    fn macros_and_calc() {
+        let source = "
+            :alias counter v5
+            :macro bump reg amount { reg += amount }
+            :calc half { 0x10 / 2 }
+            :calc sum { 1 + 2 * 3 }
+            :calc grouped { ( 1 + 2 ) * 3 }
+            :stringmode text \"ABC\" { :byte { VALUE + 1 } }
+            : main
+                bump counter half
+                bump v1 1
+                counter := sum
+                counter := grouped
+                text \"CAB\"
+                :assert \"fits\" { HERE < 0x300 }
+        ";
+        assert_eq!(
+            vec![0x75, 0x08, 0x71, 0x01, 0x65, 0x07, 0x65, 0x09, 0x03, 0x01, 0x02],
+            assemble(source).unwrap()
+        );
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(AssembleErrorKind::NoMain, error(": start ;"));
+        assert_eq!(AssembleErrorKind::Undefined("nowhere".to_string()), error(": main jump nowhere"));
+        assert_eq!(AssembleErrorKind::Redefined("main".to_string()), error(": main : main"));
+        assert_eq!(AssembleErrorKind::OutOfRange("300".to_string()), error(": main v0 := 300"));
+        assert_eq!(AssembleErrorKind::NotARegister("i".to_string()), error(": main v0 += 1 sprite i v0 1"));
+        assert_eq!(AssembleErrorKind::Unbalanced("again".to_string()), error(": main again"));
+        assert_eq!(AssembleErrorKind::Unbalanced("loop".to_string()), error(": main loop"));
+        assert_eq!(AssembleErrorKind::UnexpectedEnd, error(": main v0 :="));
+        assert_eq!(AssembleErrorKind::AssertionFailed("no".to_string()), error(": main :assert \"no\" { 1 == 2 }"));
+        assert_eq!(AssembleErrorKind::TooManyExpansions, error(":macro forever { forever } : main forever"));
+
+        let err = assemble(": main\n  clear\n  v0 := nothing").unwrap_err();
+        assert_eq!(3, err.line);
+        assert_eq!("Line 3: 'nothing' is not defined", err.to_string());
+    }
+}
@@ -0,0 +1,73 @@
+// Behaviours that differ between interpreters. Programs written for one platform can break on
+// another, see: https://github.com/Timendus/chip8-test-suite#quirks-test
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quirks {
+    // 8xy1, 8xy2 and 8xy3 reset VF to 0
+    pub vf_reset: bool,
+
+    // 8xy6 and 8xyE shift Vy into Vx instead of shifting Vx in place
+    pub shift_uses_vy: bool,
+
+    // Fx55 and Fx65 leave I pointing past the last register accessed
+    pub load_store_increments_i: bool,
+
+    // Bxnn jumps to xnn + Vx instead of nnn + V0
+    pub jump_uses_vx: bool,
+
+    // Sprites are cut off at the screen edges instead of wrapping around
+    pub clip_sprites: bool,
+}
+
+impl Quirks {
+    pub fn cosmac_vip() -> Self {
+        Quirks {
+            vf_reset: true,
+            shift_uses_vy: true,
+            load_store_increments_i: true,
+            jump_uses_vx: false,
+            clip_sprites: true,
+        }
+    }
+
+    pub fn schip() -> Self {
+        Quirks {
+            vf_reset: false,
+            shift_uses_vy: false,
+            load_store_increments_i: false,
+            jump_uses_vx: true,
+            clip_sprites: true,
+        }
+    }
+
+    pub fn xo_chip() -> Self {
+        Quirks {
+            vf_reset: false,
+            shift_uses_vy: true,
+            load_store_increments_i: true,
+            jump_uses_vx: false,
+            clip_sprites: false,
+        }
+    }
+
+    pub fn presets() -> [(&'static str, Quirks); 4] {
+        [
+            ("default", Quirks::default()),
+            ("cosmac-vip", Quirks::cosmac_vip()),
+            ("schip", Quirks::schip()),
+            ("xo-chip", Quirks::xo_chip()),
+        ]
+    }
+}
+
+// What this interpreter always did: SCHIP style shifts and loads, but the original jump
+impl Default for Quirks {
+    fn default() -> Self {
+        Quirks {
+            vf_reset: false,
+            shift_uses_vy: false,
+            load_store_increments_i: false,
+            jump_uses_vx: false,
+            clip_sprites: true,
+        }
+    }
+}
@@ -0,0 +1,1125 @@
+// Ahead-of-time translation of a loaded program into a WebAssembly module. Every basic block
+// found by the analysis becomes a function, a dispatcher runs them until the instruction budget
+// is used up or it hits something it can't do, then the interpreter takes over for a single
+// instruction. The module doesn't need any runtime support from this crate, the host instantiates
+// it and shares the machine state through the module's memory, see run_frame for the protocol.
+//
+// What isn't compiled: Fx0A, Fx29, anything the interpreter would reject, on CHIP-8X Bxyn, on
+// the hi-res variant everything that touches its larger screen and MEGA-CHIP programs at all,
+// their memory is too large to mirror, and nothing under VIP timing, the module counts
+// instructions and not cycles. Calls and returns only with a stack of limited depth that
+// isn't in memory. Instructions that would fault, like a full stack or I pointing past the end
+// of memory, exit the block so the interpreter can raise the error. Stores that hit compiled
+// code set the modified flag and stop, the module is stale from then on and everything runs on
+// the interpreter.
+
+use std::collections::BTreeSet;
+
+use rand_chacha::ChaCha20Rng;
+
+use crate::analysis;
+use crate::cpu::{CpuError, CPU, REGISTER_COUNT};
+use crate::memory::TOTAL_MEMORY;
+use crate::quirks::Quirks;
+use crate::screen::{SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::timing::Timing;
+use crate::variant::{self, Variant};
+
+// Layout of the module's memory. The first 4K mirror the machine's memory.
+pub const STATE_REGISTERS: usize = 0x1000;
+pub const STATE_I: usize = 0x1010;
+pub const STATE_PC: usize = 0x1014;
+pub const STATE_SP: usize = 0x1018;
+pub const STATE_DELAY: usize = 0x101C;
+pub const STATE_SOUND: usize = 0x101D;
+pub const STATE_KEYS: usize = 0x1020;
+pub const STATE_MODIFIED: usize = 0x1024;
+pub const STATE_STACK: usize = 0x1040;
+pub const STATE_SCREEN: usize = 0x1100;
+// Return addresses that fit between STATE_STACK and STATE_SCREEN
+const STACK_SLOTS: usize = (STATE_SCREEN - STATE_STACK) / 4;
+// Everything below is written by the host before each run
+pub const STATE_SIZE: usize = STATE_SCREEN + SCREEN_WIDTH * SCREEN_HEIGHT;
+
+// Per address: index of the block starting there plus one, as u16
+const BLOCKS: usize = 0x2000;
+// Per address: number of instructions in the block starting there
+const LENS: usize = 0x4000;
+// Per address: 1 if the byte belongs to compiled code
+const CODE: usize = 0x5000;
+const TABLES_END: usize = 0x6000;
+
+const MAX_BLOCK_LEN: u32 = 255;
+
+// Bnnn is assumed to index a table of instructions with an even offset of up to this
+const JUMP_TABLE_SIZE: u16 = 256;
+
+// Function indices, random is imported
+const FN_RANDOM: u32 = 0;
+const FN_RUN: u32 = 1;
+const FN_DRAW: u32 = 2;
+const FN_CLEAR: u32 = 3;
+const FN_TOUCHES_CODE: u32 = 4;
+const FN_FIRST_BLOCK: u32 = 5;
+
+// Type indices
+const TYPE_BLOCK: u32 = 0;
+const TYPE_RUN: u32 = 1;
+const TYPE_DRAW: u32 = 2;
+const TYPE_CLEAR: u32 = 3;
+const TYPE_TOUCHES_CODE: u32 = 4;
+
+const I32: u8 = 0x7F;
+
+const BLOCK: u8 = 0x02;
+const LOOP: u8 = 0x03;
+const IF: u8 = 0x04;
+const END: u8 = 0x0B;
+const BR: u8 = 0x0C;
+const BR_IF: u8 = 0x0D;
+const RETURN: u8 = 0x0F;
+const CALL: u8 = 0x10;
+const CALL_INDIRECT: u8 = 0x11;
+const SELECT: u8 = 0x1B;
+const LOCAL_GET: u8 = 0x20;
+const LOCAL_SET: u8 = 0x21;
+const LOCAL_TEE: u8 = 0x22;
+const I32_LOAD: u8 = 0x28;
+const I32_LOAD8_U: u8 = 0x2D;
+const I32_LOAD16_U: u8 = 0x2F;
+const I32_STORE: u8 = 0x36;
+const I32_STORE8: u8 = 0x3A;
+const I32_CONST: u8 = 0x41;
+const I32_EQZ: u8 = 0x45;
+const I32_EQ: u8 = 0x46;
+const I32_NE: u8 = 0x47;
+const I32_LT_U: u8 = 0x49;
+const I32_GT_U: u8 = 0x4B;
+const I32_GE_U: u8 = 0x4F;
+const I32_ADD: u8 = 0x6A;
+const I32_SUB: u8 = 0x6B;
+const I32_MUL: u8 = 0x6C;
+const I32_DIV_U: u8 = 0x6E;
+const I32_REM_U: u8 = 0x70;
+const I32_AND: u8 = 0x71;
+const I32_OR: u8 = 0x72;
+const I32_XOR: u8 = 0x73;
+const I32_SHL: u8 = 0x74;
+const I32_SHR_U: u8 = 0x76;
+
+// What the host provides to run a compiled module
+pub trait Instance {
+    // The memory exported as "memory"
+    fn memory(&mut self) -> &mut [u8];
+
+    // Calls the exported run(budget) and returns its result. The imported env.random has to
+    // return rng.next_u32() as u8 to stay in step with the interpreter.
+    fn run(&mut self, budget: u32, rng: &mut ChaCha20Rng) -> u32;
+}
+
+pub fn compile(cpu: &CPU) -> Vec<u8> {
+    let memory = cpu.memory();
+
+    // Computed jumps can lead to more code, and that code to more computed jumps
+    let mut entries = BTreeSet::new();
+    entries.insert(cpu.ip());
+    let mut analysis = analysis::analyze(memory, cpu.ip());
+    loop {
+        let count = entries.len();
+        for &addr in &analysis.computed_jumps {
+            let base = ((memory[addr as usize] as u16) << 8 | memory[addr as usize + 1] as u16) & 0x0FFF;
+            let targets = (0..JUMP_TABLE_SIZE).step_by(2).map(|offset| base + offset);
+            entries.extend(targets.filter(|&target| (target as usize) < TOTAL_MEMORY));
+        }
+        if entries.len() == count {
+            break;
+        }
+        analysis = analysis::analyze_entries(memory, &entries.iter().copied().collect::<Vec<_>>());
+    }
+
+    let mut tables = vec![0; TABLES_END - BLOCKS];
+    let mut blocks = Vec::new();
+    for block in analysis.blocks.values() {
+        let (code, len) = compile_block(memory, block.start, block.end, cpu);
+        if len == 0 {
+            continue;
+        }
+
+        blocks.push(code);
+        let start = block.start as usize;
+        let index = blocks.len() as u16;
+        // The tables are placed at BLOCKS
+        let entry = start * 2;
+        tables[entry..entry + 2].copy_from_slice(&index.to_le_bytes());
+        tables[LENS - BLOCKS + start] = len as u8;
+        for addr in start..start + 2 * len as usize {
+            tables[CODE - BLOCKS + addr] = 1;
+        }
+    }
+
+    module(blocks, &tables, cpu.quirks())
+}
+
+// Copies the machine state into the module's memory
+pub fn write_state(cpu: &CPU, memory: &mut [u8]) {
+    memory[..TOTAL_MEMORY].copy_from_slice(&cpu.memory()[..TOTAL_MEMORY]);
+    memory[STATE_REGISTERS..STATE_REGISTERS + REGISTER_COUNT].copy_from_slice(cpu.registers());
+    put_u32(memory, STATE_I, cpu.addr_reg());
+    put_u32(memory, STATE_PC, cpu.ip() as u32);
+    memory[STATE_DELAY] = cpu.delay_timer();
+    memory[STATE_SOUND] = cpu.sound_timer();
+    put_u32(memory, STATE_KEYS, cpu.keypad().state() as u32);
+    // Other stacks are only touched by the interpreter
+    if compiles_stack(cpu) {
+        let stack = cpu.stack();
+        put_u32(memory, STATE_SP, stack.len() as u32);
+        for (i, &addr) in stack.iter().enumerate() {
+            put_u32(memory, STATE_STACK + i * 4, addr as u32);
+        }
+    }
+    // Larger screens are only ever drawn by the interpreter
+    for (i, &pixel) in cpu.screen().pixels().iter().take(SCREEN_WIDTH * SCREEN_HEIGHT).enumerate() {
+        memory[STATE_SCREEN + i] = pixel as u8;
+    }
+}
+
+// Copies the machine state back after a run
+pub fn read_state(cpu: &mut CPU, memory: &[u8]) {
+    cpu.memory_mut()[..TOTAL_MEMORY].copy_from_slice(&memory[..TOTAL_MEMORY]);
+    cpu.registers_mut().copy_from_slice(&memory[STATE_REGISTERS..STATE_REGISTERS + REGISTER_COUNT]);
+    cpu.set_addr_reg(get_u32(memory, STATE_I));
+    cpu.set_ip(get_u32(memory, STATE_PC) as u16);
+    cpu.set_timers(memory[STATE_DELAY], memory[STATE_SOUND]);
+    if compiles_stack(cpu) {
+        let sp = get_u32(memory, STATE_SP) as usize;
+        cpu.set_stack((0..sp).map(|i| get_u32(memory, STATE_STACK + i * 4) as u16).collect());
+    }
+    for (i, pixel) in cpu.screen_mut().pixels_mut().iter_mut().take(SCREEN_WIDTH * SCREEN_HEIGHT).enumerate() {
+        *pixel = memory[STATE_SCREEN + i] != 0;
+    }
+}
+
+// Stacks the module can keep in its state
+fn compiles_stack(cpu: &CPU) -> bool {
+    let config = cpu.stack_config();
+    !config.in_memory() && config.depth().is_some_and(|depth| depth as usize <= STACK_SLOTS)
+}
+
+// True once the program wrote to its own compiled code
+pub fn is_code_modified(memory: &[u8]) -> bool {
+    get_u32(memory, STATE_MODIFIED) != 0
+}
+
+// Same as CPU::run_frame, but runs as much as possible on the compiled module. Returns false if
+// the module went stale, it can be used further but only the interpreter will run. With VIP
+// timing the whole frame runs on the interpreter.
+pub fn run_frame<I: Instance>(cpu: &mut CPU, instance: &mut I, instructions: u32) -> Result<bool, CpuError> {
+    if cpu.timing() != Timing::InstructionsPerFrame {
+        cpu.run_frame(instructions)?;
+        return Ok(true);
+    }
+
+    let mut remaining = instructions;
+    let mut compiled = true;
+    while remaining > 0 {
+        if compiled {
+            write_state(cpu, instance.memory());
+            let executed = instance.run(remaining, cpu.rng_mut());
+            read_state(cpu, instance.memory());
+
+            remaining -= executed;
+            compiled = !is_code_modified(instance.memory());
+            if remaining == 0 {
+                break;
+            }
+        }
+
+        cpu.tick()?;
+        remaining -= 1;
+    }
+
+    cpu.tick_timers();
+    Ok(compiled)
+}
+
+fn put_u32(memory: &mut [u8], addr: usize, value: u32) {
+    memory[addr..addr + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+fn get_u32(memory: &[u8], addr: usize) -> u32 {
+    u32::from_le_bytes([memory[addr], memory[addr + 1], memory[addr + 2], memory[addr + 3]])
+}
+
+// Code of one function body
+#[derive(Default)]
+struct Asm {
+    bytes: Vec<u8>,
+}
+
+impl Asm {
+    fn op(&mut self, op: u8) -> &mut Self {
+        self.bytes.push(op);
+        self
+    }
+
+    fn block(&mut self, op: u8) -> &mut Self {
+        // Blocks never produce values
+        self.op(op).op(0x40)
+    }
+
+    fn index(&mut self, op: u8, index: u32) -> &mut Self {
+        self.op(op);
+        uleb(&mut self.bytes, index);
+        self
+    }
+
+    fn i32_const(&mut self, value: i32) -> &mut Self {
+        self.op(I32_CONST);
+        sleb(&mut self.bytes, value);
+        self
+    }
+
+    fn mem(&mut self, op: u8, offset: usize) -> &mut Self {
+        let align = match op {
+            I32_LOAD | I32_STORE => 2,
+            I32_LOAD16_U => 1,
+            _ => 0,
+        };
+        self.op(op);
+        uleb(&mut self.bytes, align);
+        uleb(&mut self.bytes, offset as u32);
+        self
+    }
+
+    // Value of a state word or byte at a fixed address
+    fn load(&mut self, op: u8, addr: usize) -> &mut Self {
+        self.i32_const(0).mem(op, addr)
+    }
+
+    fn load_v(&mut self, register: u16) -> &mut Self {
+        self.load(I32_LOAD8_U, STATE_REGISTERS + register as usize)
+    }
+
+    // Stores what emit leaves on the stack
+    fn store(&mut self, op: u8, addr: usize, emit: impl FnOnce(&mut Self)) -> &mut Self {
+        self.i32_const(0);
+        emit(self);
+        self.mem(op, addr)
+    }
+
+    fn store_v(&mut self, register: u16, emit: impl FnOnce(&mut Self)) -> &mut Self {
+        self.store(I32_STORE8, STATE_REGISTERS + register as usize, emit)
+    }
+
+    fn set_pc(&mut self, pc: u32) -> &mut Self {
+        self.store(I32_STORE, STATE_PC, |asm| {
+            asm.i32_const(pc as i32);
+        })
+    }
+
+    // Leaves the block, count instructions have been executed
+    fn exit(&mut self, pc: u32, count: u32) -> &mut Self {
+        self.set_pc(pc).i32_const(count as i32).op(RETURN)
+    }
+
+    // Hands the instruction at pc to the interpreter if the condition on the stack holds
+    fn exit_if(&mut self, pc: u32, count: u32) -> &mut Self {
+        self.block(IF).exit(pc, count).op(END)
+    }
+}
+
+fn uleb(out: &mut Vec<u8>, mut value: u32) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn sleb(out: &mut Vec<u8>, mut value: i32) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0) {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn is_supported(instr: u16, cpu: &CPU) -> bool {
+    let variant = cpu.variant();
+    if variant == Variant::MegaChip || cpu.timing() != Timing::InstructionsPerFrame {
+        return false;
+    }
+    if (instr >> 12 == 0x2 || instr == 0x00EE) && !compiles_stack(cpu) {
+        return false;
+    }
+    match instr >> 12 {
+        0x0 if variant == Variant::HiRes => instr == 0x00EE,
+        0x1 if variant == Variant::HiRes => instr != variant::HIRES_BOOT,
+        0xB => variant != Variant::Chip8X,
+        0xD => variant != Variant::HiRes,
+        0x0 => instr == 0x00E0 || instr == 0x00EE,
+        0x5 | 0x9 => instr & 0x000F == 0,
+        0x8 => matches!(instr & 0x000F, 0x0..=0x7 | 0xE),
+        0xE => matches!(instr & 0x00FF, 0x9E | 0xA1),
+        0xF => matches!(instr & 0x00FF, 0x07 | 0x15 | 0x18 | 0x1E | 0x33 | 0x55 | 0x65),
+        _ => true,
+    }
+}
+
+// Compiles the straight run of supported instructions from start up to end. Returns the code and
+// the number of instructions.
+fn compile_block(memory: &[u8], start: u16, end: u16, cpu: &CPU) -> (Asm, u32) {
+    let quirks = cpu.quirks();
+    // Where the protected interpreter area ends, if it is protected
+    let protected = if cpu.is_interpreter_protected() { Some(cpu.variant().program_start()) } else { None };
+    // Only used if compiles_stack holds
+    let stack_depth = cpu.stack_config().depth().unwrap_or(0) as i32;
+
+    let mut asm = Asm::default();
+    let mut addr = start as u32;
+    let mut count = 0;
+    loop {
+        let pc = addr as usize;
+        if addr >= end as u32 || pc + 1 >= TOTAL_MEMORY || count == MAX_BLOCK_LEN {
+            asm.exit(addr, count);
+            break;
+        }
+
+        let instr = (memory[pc] as u16) << 8 | memory[pc + 1] as u16;
+        if !is_supported(instr, cpu) {
+            asm.exit(addr, count);
+            break;
+        }
+
+        let ends_block = compile_instr(&mut asm, instr, addr, count, &quirks, protected, stack_depth);
+        count += 1;
+        if ends_block {
+            break;
+        }
+        addr += 2;
+    }
+
+    (asm, count)
+}
+
+// Emits one instruction, count instructions of the block ran before it. Returns true if the
+// instruction leaves the block, the code to do so has been emitted then.
+fn compile_instr(asm: &mut Asm, instr: u16, addr: u32, count: u32, quirks: &Quirks, protected: Option<u16>, stack_depth: i32) -> bool {
+    let x = (instr >> 8) & 0x0F;
+    let y = (instr >> 4) & 0x0F;
+    let kk = (instr & 0x00FF) as i32;
+    let nnn = (instr & 0x0FFF) as i32;
+    let next = addr + 2;
+
+    // Locals of block functions
+    const A: u32 = 0;
+    const B: u32 = 1;
+    const TOUCHED: u32 = 2;
+
+    // Sets the PC to skip the next instruction if the condition left by emit holds
+    let skip = |asm: &mut Asm, emit: &dyn Fn(&mut Asm)| {
+        asm.store(I32_STORE, STATE_PC, |asm| {
+            asm.i32_const(addr as i32 + 4).i32_const(addr as i32 + 2);
+            emit(asm);
+            asm.op(SELECT);
+        });
+        asm.i32_const(count as i32 + 1).op(RETURN);
+    };
+
+    // I past the end of memory or writes to protected memory are left to the interpreter
+    let check_range = |asm: &mut Asm, len: i32, write: bool| {
+        asm.load(I32_LOAD, STATE_I).i32_const(len).op(I32_ADD).i32_const(TOTAL_MEMORY as i32).op(I32_GT_U);
+        if let (true, Some(end)) = (write, protected) {
+            asm.load(I32_LOAD, STATE_I).i32_const(end as i32).op(I32_LT_U).op(I32_OR);
+        }
+        asm.exit_if(addr, count);
+    };
+
+    // Stops after a store that changed compiled code
+    let check_code = |asm: &mut Asm, len: i32| {
+        asm.load(I32_LOAD, STATE_I).i32_const(len).index(CALL, FN_TOUCHES_CODE).index(LOCAL_SET, TOUCHED);
+    };
+    let exit_if_touched = |asm: &mut Asm| {
+        asm.index(LOCAL_GET, TOUCHED).block(IF);
+        asm.store(I32_STORE, STATE_MODIFIED, |asm| {
+            asm.i32_const(1);
+        });
+        asm.exit(next, count + 1).op(END);
+    };
+
+    let increment_i = |asm: &mut Asm| {
+        if quirks.load_store_increments_i {
+            asm.store(I32_STORE, STATE_I, |asm| {
+                asm.load(I32_LOAD, STATE_I).i32_const(x as i32 + 1).op(I32_ADD).i32_const(0xFFFF).op(I32_AND);
+            });
+        }
+    };
+
+    match instr >> 12 {
+        0x0 if instr == 0x00E0 => {
+            asm.index(CALL, FN_CLEAR);
+        }
+        0x0 => {
+            // RET
+            asm.load(I32_LOAD, STATE_SP).op(I32_EQZ).exit_if(addr, count);
+            asm.load(I32_LOAD, STATE_SP).i32_const(1).op(I32_SUB).index(LOCAL_SET, A);
+            asm.store(I32_STORE, STATE_PC, |asm| {
+                asm.index(LOCAL_GET, A).i32_const(2).op(I32_SHL).mem(I32_LOAD, STATE_STACK);
+            });
+            asm.store(I32_STORE, STATE_SP, |asm| {
+                asm.index(LOCAL_GET, A);
+            });
+            asm.i32_const(count as i32 + 1).op(RETURN);
+            return true;
+        }
+        0x1 => {
+            asm.exit(nnn as u32, count + 1);
+            return true;
+        }
+        0x2 => {
+            asm.load(I32_LOAD, STATE_SP).i32_const(stack_depth).op(I32_GE_U).exit_if(addr, count);
+            asm.load(I32_LOAD, STATE_SP).index(LOCAL_SET, A);
+            asm.store(I32_STORE, STATE_SP, |asm| {
+                asm.index(LOCAL_GET, A).i32_const(1).op(I32_ADD);
+            });
+            asm.index(LOCAL_GET, A).i32_const(2).op(I32_SHL).i32_const(next as i32).mem(I32_STORE, STATE_STACK);
+            asm.exit(nnn as u32, count + 1);
+            return true;
+        }
+        0x3 => {
+            skip(asm, &|asm| {
+                asm.load_v(x).i32_const(kk).op(I32_EQ);
+            });
+            return true;
+        }
+        0x4 => {
+            skip(asm, &|asm| {
+                asm.load_v(x).i32_const(kk).op(I32_NE);
+            });
+            return true;
+        }
+        0x5 => {
+            skip(asm, &|asm| {
+                asm.load_v(x).load_v(y).op(I32_EQ);
+            });
+            return true;
+        }
+        0x6 => {
+            asm.store_v(x, |asm| {
+                asm.i32_const(kk);
+            });
+        }
+        0x7 => {
+            asm.store_v(x, |asm| {
+                asm.load_v(x).i32_const(kk).op(I32_ADD);
+            });
+        }
+        0x8 => {
+            asm.load_v(x).index(LOCAL_SET, A);
+            asm.load_v(y).index(LOCAL_SET, B);
+            let shift_source = if quirks.shift_uses_vy { B } else { A };
+
+            // Result and flag from the original values, the flag is written last
+            let op = instr & 0x000F;
+            asm.store_v(x, |asm| {
+                match op {
+                    0x0 => asm.index(LOCAL_GET, B),
+                    0x1 => asm.index(LOCAL_GET, A).index(LOCAL_GET, B).op(I32_OR),
+                    0x2 => asm.index(LOCAL_GET, A).index(LOCAL_GET, B).op(I32_AND),
+                    0x3 => asm.index(LOCAL_GET, A).index(LOCAL_GET, B).op(I32_XOR),
+                    0x4 => asm.index(LOCAL_GET, A).index(LOCAL_GET, B).op(I32_ADD),
+                    0x5 => asm.index(LOCAL_GET, A).index(LOCAL_GET, B).op(I32_SUB),
+                    0x6 => asm.index(LOCAL_GET, shift_source).i32_const(1).op(I32_SHR_U),
+                    0x7 => asm.index(LOCAL_GET, B).index(LOCAL_GET, A).op(I32_SUB),
+                    _ => asm.index(LOCAL_GET, shift_source).i32_const(1).op(I32_SHL),
+                };
+            });
+
+            let has_flag = match op {
+                0x0 => false,
+                0x1..=0x3 => quirks.vf_reset,
+                _ => true,
+            };
+            if has_flag {
+                asm.store_v(0xF, |asm| {
+                    match op {
+                        0x1..=0x3 => asm.i32_const(0),
+                        0x4 => asm.index(LOCAL_GET, A).index(LOCAL_GET, B).op(I32_ADD).i32_const(8).op(I32_SHR_U),
+                        0x5 => asm.index(LOCAL_GET, A).index(LOCAL_GET, B).op(I32_GE_U),
+                        0x6 => asm.index(LOCAL_GET, shift_source).i32_const(1).op(I32_AND),
+                        0x7 => asm.index(LOCAL_GET, B).index(LOCAL_GET, A).op(I32_GE_U),
+                        _ => asm.index(LOCAL_GET, shift_source).i32_const(7).op(I32_SHR_U),
+                    };
+                });
+            }
+        }
+        0x9 => {
+            skip(asm, &|asm| {
+                asm.load_v(x).load_v(y).op(I32_NE);
+            });
+            return true;
+        }
+        0xA => {
+            asm.store(I32_STORE, STATE_I, |asm| {
+                asm.i32_const(nnn);
+            });
+        }
+        0xB => {
+            let register = if quirks.jump_uses_vx { x } else { 0 };
+            asm.store(I32_STORE, STATE_PC, |asm| {
+                asm.load_v(register).i32_const(nnn).op(I32_ADD);
+            });
+            asm.i32_const(count as i32 + 1).op(RETURN);
+            return true;
+        }
+        0xC => {
+            asm.store_v(x, |asm| {
+                asm.index(CALL, FN_RANDOM).i32_const(kk).op(I32_AND);
+            });
+        }
+        0xD => {
+            let lines = (instr & 0x000F) as i32;
+            check_range(asm, lines, false);
+            asm.load_v(x).i32_const(SCREEN_WIDTH as i32 - 1).op(I32_AND);
+            asm.load_v(y).i32_const(SCREEN_HEIGHT as i32 - 1).op(I32_AND);
+            asm.i32_const(lines).index(CALL, FN_DRAW);
+        }
+        0xE => {
+            let pressed = |asm: &mut Asm| {
+                asm.load(I32_LOAD, STATE_KEYS).load_v(x).i32_const(0x0F).op(I32_AND).op(I32_SHR_U);
+                asm.i32_const(1).op(I32_AND);
+            };
+            if instr & 0x00FF == 0x9E {
+                skip(asm, &|asm| pressed(asm));
+            } else {
+                skip(asm, &|asm| {
+                    pressed(asm);
+                    asm.op(I32_EQZ);
+                });
+            }
+            return true;
+        }
+        _ => match instr & 0x00FF {
+            0x07 => {
+                asm.store_v(x, |asm| {
+                    asm.load(I32_LOAD8_U, STATE_DELAY);
+                });
+            }
+            0x15 => {
+                asm.store(I32_STORE8, STATE_DELAY, |asm| {
+                    asm.load_v(x);
+                });
+            }
+            0x18 => {
+                asm.store(I32_STORE8, STATE_SOUND, |asm| {
+                    asm.load_v(x);
+                });
+            }
+            0x1E => {
+                asm.store(I32_STORE, STATE_I, |asm| {
+                    asm.load(I32_LOAD, STATE_I).load_v(x).op(I32_ADD).i32_const(0xFFFF).op(I32_AND);
+                });
+            }
+            0x33 => {
+                check_range(asm, 3, true);
+                for (i, divisor) in [100, 10, 1].iter().enumerate() {
+                    asm.load(I32_LOAD, STATE_I);
+                    asm.load_v(x).i32_const(*divisor).op(I32_DIV_U).i32_const(10).op(I32_REM_U);
+                    asm.mem(I32_STORE8, i);
+                }
+                check_code(asm, 3);
+                exit_if_touched(asm);
+            }
+            0x55 => {
+                check_range(asm, x as i32 + 1, true);
+                for i in 0..=x {
+                    asm.load(I32_LOAD, STATE_I).load_v(i).mem(I32_STORE8, i as usize);
+                }
+                check_code(asm, x as i32 + 1);
+                increment_i(asm);
+                exit_if_touched(asm);
+            }
+            _ => {
+                // Fx65
+                check_range(asm, x as i32 + 1, false);
+                for i in 0..=x {
+                    asm.store_v(i, |asm| {
+                        asm.load(I32_LOAD, STATE_I).mem(I32_LOAD8_U, i as usize);
+                    });
+                }
+                increment_i(asm);
+            }
+        },
+    }
+
+    false
+}
+
+// Executes blocks while they fit into the budget, returns the number of instructions executed
+fn run_function() -> Asm {
+    const BUDGET: u32 = 0;
+    const DONE: u32 = 1;
+    const PC: u32 = 2;
+    const INDEX: u32 = 3;
+    const LEN: u32 = 4;
+    const EXECUTED: u32 = 5;
+
+    let mut asm = Asm::default();
+    let return_done = |asm: &mut Asm| {
+        asm.block(IF).index(LOCAL_GET, DONE).op(RETURN).op(END);
+    };
+
+    asm.block(LOOP);
+    asm.load(I32_LOAD, STATE_MODIFIED);
+    return_done(&mut asm);
+    asm.load(I32_LOAD, STATE_PC).index(LOCAL_TEE, PC).i32_const(TOTAL_MEMORY as i32).op(I32_GE_U);
+    return_done(&mut asm);
+    asm.index(LOCAL_GET, PC).i32_const(1).op(I32_SHL).mem(I32_LOAD16_U, BLOCKS).index(LOCAL_TEE, INDEX).op(I32_EQZ);
+    return_done(&mut asm);
+    asm.index(LOCAL_GET, PC).mem(I32_LOAD8_U, LENS).index(LOCAL_TEE, LEN);
+    asm.index(LOCAL_GET, BUDGET).index(LOCAL_GET, DONE).op(I32_SUB).op(I32_GT_U);
+    return_done(&mut asm);
+
+    asm.index(LOCAL_GET, INDEX).i32_const(1).op(I32_SUB);
+    asm.index(CALL_INDIRECT, TYPE_BLOCK).op(0x00).index(LOCAL_TEE, EXECUTED);
+    asm.index(LOCAL_GET, DONE).op(I32_ADD).index(LOCAL_SET, DONE);
+
+    // The block stopped early, the interpreter has to continue
+    asm.index(LOCAL_GET, EXECUTED).index(LOCAL_GET, LEN).op(I32_LT_U);
+    return_done(&mut asm);
+    asm.index(BR, 0).op(END);
+
+    asm.index(LOCAL_GET, DONE);
+    asm
+}
+
+// draw(x, y, lines) with x and y already wrapped, reads the sprite at I and sets VF
+fn draw_function(quirks: &Quirks) -> Asm {
+    const X: u32 = 0;
+    const Y: u32 = 1;
+    const LINES: u32 = 2;
+    const I: u32 = 3;
+    const LINE: u32 = 4;
+    const BIT: u32 = 5;
+    const COLUMN: u32 = 6;
+    const ROW: u32 = 7;
+    const COLLISION: u32 = 8;
+    const ADDR: u32 = 9;
+    const OLD: u32 = 10;
+
+    let mut asm = Asm::default();
+    asm.block(BLOCK).block(LOOP);
+    asm.index(LOCAL_GET, I).index(LOCAL_GET, LINES).op(I32_GE_U).index(BR_IF, 1);
+
+    asm.block(BLOCK);
+    asm.index(LOCAL_GET, Y).index(LOCAL_GET, I).op(I32_ADD).index(LOCAL_SET, ROW);
+    if quirks.clip_sprites {
+        asm.index(LOCAL_GET, ROW).i32_const(SCREEN_HEIGHT as i32).op(I32_GE_U).index(BR_IF, 0);
+    } else {
+        asm.index(LOCAL_GET, ROW).i32_const(SCREEN_HEIGHT as i32 - 1).op(I32_AND).index(LOCAL_SET, ROW);
+    }
+    asm.load(I32_LOAD, STATE_I).index(LOCAL_GET, I).op(I32_ADD).mem(I32_LOAD8_U, 0).index(LOCAL_SET, LINE);
+    asm.i32_const(0).index(LOCAL_SET, BIT);
+
+    asm.block(LOOP);
+    asm.index(LOCAL_GET, LINE).i32_const(7).index(LOCAL_GET, BIT).op(I32_SUB).op(I32_SHR_U);
+    asm.i32_const(1).op(I32_AND).block(IF);
+    asm.index(LOCAL_GET, X).index(LOCAL_GET, BIT).op(I32_ADD).index(LOCAL_SET, COLUMN);
+    asm.block(BLOCK);
+    if quirks.clip_sprites {
+        asm.index(LOCAL_GET, COLUMN).i32_const(SCREEN_WIDTH as i32).op(I32_GE_U).index(BR_IF, 0);
+    } else {
+        asm.index(LOCAL_GET, COLUMN).i32_const(SCREEN_WIDTH as i32 - 1).op(I32_AND).index(LOCAL_SET, COLUMN);
+    }
+    asm.index(LOCAL_GET, ROW).i32_const(SCREEN_WIDTH as i32).op(I32_MUL);
+    asm.index(LOCAL_GET, COLUMN).op(I32_ADD).index(LOCAL_TEE, ADDR);
+    asm.mem(I32_LOAD8_U, STATE_SCREEN).index(LOCAL_SET, OLD);
+    asm.index(LOCAL_GET, ADDR).index(LOCAL_GET, OLD).i32_const(1).op(I32_XOR).mem(I32_STORE8, STATE_SCREEN);
+    asm.index(LOCAL_GET, COLLISION).index(LOCAL_GET, OLD).op(I32_OR).index(LOCAL_SET, COLLISION);
+    asm.op(END).op(END);
+    asm.index(LOCAL_GET, BIT).i32_const(1).op(I32_ADD).index(LOCAL_TEE, BIT).i32_const(8).op(I32_LT_U).index(BR_IF, 0);
+    asm.op(END);
+
+    asm.op(END);
+    asm.index(LOCAL_GET, I).i32_const(1).op(I32_ADD).index(LOCAL_SET, I);
+    asm.index(BR, 0).op(END).op(END);
+
+    asm.store_v(0xF, |asm| {
+        asm.index(LOCAL_GET, COLLISION);
+    });
+    asm
+}
+
+fn clear_function() -> Asm {
+    const I: u32 = 0;
+
+    let mut asm = Asm::default();
+    asm.block(LOOP);
+    asm.index(LOCAL_GET, I).i32_const(0).mem(I32_STORE, STATE_SCREEN);
+    asm.index(LOCAL_GET, I).i32_const(4).op(I32_ADD).index(LOCAL_TEE, I);
+    asm.i32_const((SCREEN_WIDTH * SCREEN_HEIGHT) as i32).op(I32_LT_U).index(BR_IF, 0);
+    asm.op(END);
+    asm
+}
+
+// touches_code(start, len) returns 1 if any of the bytes is compiled code
+fn touches_code_function() -> Asm {
+    const START: u32 = 0;
+    const LEN: u32 = 1;
+    const I: u32 = 2;
+
+    let mut asm = Asm::default();
+    asm.block(BLOCK).block(LOOP);
+    asm.index(LOCAL_GET, I).index(LOCAL_GET, LEN).op(I32_GE_U).index(BR_IF, 1);
+    asm.index(LOCAL_GET, START).index(LOCAL_GET, I).op(I32_ADD).mem(I32_LOAD8_U, CODE);
+    asm.block(IF).i32_const(1).op(RETURN).op(END);
+    asm.index(LOCAL_GET, I).i32_const(1).op(I32_ADD).index(LOCAL_SET, I);
+    asm.index(BR, 0).op(END).op(END);
+    asm.i32_const(0);
+    asm
+}
+
+fn section(out: &mut Vec<u8>, id: u8, content: &[u8]) {
+    out.push(id);
+    uleb(out, content.len() as u32);
+    out.extend_from_slice(content);
+}
+
+fn name(out: &mut Vec<u8>, name: &str) {
+    uleb(out, name.len() as u32);
+    out.extend_from_slice(name.as_bytes());
+}
+
+fn body(out: &mut Vec<u8>, locals: u32, asm: &Asm) {
+    let mut content = Vec::new();
+    if locals > 0 {
+        uleb(&mut content, 1);
+        uleb(&mut content, locals);
+        content.push(I32);
+    } else {
+        uleb(&mut content, 0);
+    }
+    content.extend_from_slice(&asm.bytes);
+    content.push(END);
+
+    uleb(out, content.len() as u32);
+    out.extend_from_slice(&content);
+}
+
+fn module(blocks: Vec<Asm>, tables: &[u8], quirks: Quirks) -> Vec<u8> {
+    let mut out = b"\0asm".to_vec();
+    out.extend_from_slice(&1u32.to_le_bytes());
+
+    // Types: block and random () -> i32, run (i32) -> i32, draw (i32, i32, i32) -> (),
+    // clear () -> () and touches_code (i32, i32) -> i32
+    let types: [(&[u8], &[u8]); 5] = [(&[], &[I32]), (&[I32], &[I32]), (&[I32, I32, I32], &[]), (&[], &[]), (&[I32, I32], &[I32])];
+    let mut content = Vec::new();
+    uleb(&mut content, types.len() as u32);
+    for (params, results) in types.iter() {
+        content.push(0x60);
+        uleb(&mut content, params.len() as u32);
+        content.extend_from_slice(params);
+        uleb(&mut content, results.len() as u32);
+        content.extend_from_slice(results);
+    }
+    section(&mut out, 1, &content);
+
+    let mut content = Vec::new();
+    uleb(&mut content, 1);
+    name(&mut content, "env");
+    name(&mut content, "random");
+    content.push(0x00);
+    uleb(&mut content, TYPE_BLOCK);
+    section(&mut out, 2, &content);
+
+    let mut content = Vec::new();
+    uleb(&mut content, 4 + blocks.len() as u32);
+    for &ty in [TYPE_RUN, TYPE_DRAW, TYPE_CLEAR, TYPE_TOUCHES_CODE].iter() {
+        uleb(&mut content, ty);
+    }
+    for _ in &blocks {
+        uleb(&mut content, TYPE_BLOCK);
+    }
+    section(&mut out, 3, &content);
+
+    let mut content = vec![1, 0x70, 0x01];
+    uleb(&mut content, blocks.len() as u32);
+    uleb(&mut content, blocks.len() as u32);
+    section(&mut out, 4, &content);
+
+    section(&mut out, 5, &[1, 0x00, 1]);
+
+    let mut content = Vec::new();
+    uleb(&mut content, 2);
+    name(&mut content, "memory");
+    content.push(0x02);
+    uleb(&mut content, 0);
+    name(&mut content, "run");
+    content.push(0x00);
+    uleb(&mut content, FN_RUN);
+    section(&mut out, 7, &content);
+
+    let mut content = vec![1, 0x00, I32_CONST, 0, END];
+    uleb(&mut content, blocks.len() as u32);
+    for i in 0..blocks.len() as u32 {
+        uleb(&mut content, FN_FIRST_BLOCK + i);
+    }
+    section(&mut out, 9, &content);
+
+    let mut content = Vec::new();
+    uleb(&mut content, 4 + blocks.len() as u32);
+    body(&mut content, 5, &run_function());
+    body(&mut content, 8, &draw_function(&quirks));
+    body(&mut content, 1, &clear_function());
+    body(&mut content, 1, &touches_code_function());
+    for block in &blocks {
+        body(&mut content, 3, block);
+    }
+    section(&mut out, 10, &content);
+
+    let mut content = vec![1, 0x00, I32_CONST];
+    sleb(&mut content, BLOCKS as i32);
+    content.push(END);
+    uleb(&mut content, tables.len() as u32);
+    content.extend_from_slice(tables);
+    section(&mut out, 11, &content);
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::{RngCore, SeedableRng};
+    use wasmi::{Caller, Engine, Linker, Memory, Module, Store, TypedFunc};
+
+    use super::*;
+    use crate::conformance;
+    use crate::reference;
+
+    struct Wasmi {
+        store: Store<ChaCha20Rng>,
+        memory: Memory,
+        run: TypedFunc<i32, i32>,
+        // Instructions executed by the module
+        executed: u32,
+    }
+
+    impl Wasmi {
+        fn new(module: &[u8]) -> Self {
+            let engine = Engine::default();
+            let module = Module::new(&engine, module).unwrap();
+            let mut store = Store::new(&engine, ChaCha20Rng::seed_from_u64(0));
+            let mut linker = Linker::new(&engine);
+            linker
+                .func_wrap("env", "random", |mut caller: Caller<'_, ChaCha20Rng>| caller.data_mut().next_u32() as u8 as i32)
+                .unwrap();
+            let instance = linker.instantiate(&mut store, &module).unwrap().start(&mut store).unwrap();
+
+            Wasmi {
+                memory: instance.get_memory(&store, "memory").unwrap(),
+                run: instance.get_typed_func(&store, "run").unwrap(),
+                store,
+                executed: 0,
+            }
+        }
+    }
+
+    impl Instance for Wasmi {
+        fn memory(&mut self) -> &mut [u8] {
+            self.memory.data_mut(&mut self.store)
+        }
+
+        fn run(&mut self, budget: u32, rng: &mut ChaCha20Rng) -> u32 {
+            std::mem::swap(rng, self.store.data_mut());
+            let executed = self.run.call(&mut self.store, budget as i32).unwrap() as u32;
+            std::mem::swap(rng, self.store.data_mut());
+
+            assert!(executed <= budget);
+            self.executed += executed;
+            executed
+        }
+    }
+
+    fn assert_same(expected: &CPU, actual: &CPU, context: &str) {
+        assert_eq!(expected.ip(), actual.ip(), "PC {}", context);
+        assert_eq!(expected.registers(), actual.registers(), "V {}", context);
+        assert_eq!(expected.addr_reg(), actual.addr_reg(), "I {}", context);
+        assert_eq!(expected.stack(), actual.stack(), "stack {}", context);
+        assert_eq!(expected.delay_timer(), actual.delay_timer(), "DT {}", context);
+        assert_eq!(expected.sound_timer(), actual.sound_timer(), "ST {}", context);
+        assert!(expected.memory() == actual.memory(), "memory {}", context);
+        assert!(expected.screen().pixels() == actual.screen().pixels(), "screen {}", context);
+    }
+
+    // Runs the same machine on the interpreter and the compiled module, frame by frame. Returns
+    // the share of instructions the module executed.
+    fn differential(image: &[u8], quirks: Quirks, seed: u64, frames: u32, instructions: u32, keys: &mut dyn FnMut(u32) -> u16, context: &str) -> f64 {
+        let setup = || {
+            let mut cpu = CPU::from_memory(image);
+            cpu.set_quirks(quirks);
+            cpu.seed_rng(seed);
+            cpu
+        };
+        let mut interpreted = setup();
+        let mut compiled = setup();
+        let mut instance = Wasmi::new(&compile(&compiled));
+
+        for frame in 0..frames {
+            let state = keys(frame);
+            interpreted.keypad_mut().set_state(state);
+            compiled.keypad_mut().set_state(state);
+
+            let expected = interpreted.run_frame(instructions);
+            let actual = run_frame(&mut compiled, &mut instance, instructions);
+            let context = format!("in frame {} of {}", frame, context);
+            assert_eq!(expected.err(), actual.err(), "result {}", context);
+            assert_same(&interpreted, &compiled, &context);
+        }
+
+        instance.executed as f64 / (frames * instructions) as f64
+    }
+
+    fn image(rom: &[u8]) -> Vec<u8> {
+        let mut cpu = CPU::new();
+        cpu.load_rom(rom).unwrap();
+        cpu.memory().to_vec()
+    }
+
+    // Section ids in order of appearance
+    fn sections(module: &[u8]) -> Vec<u8> {
+        let mut ids = Vec::new();
+        let mut pos = 8;
+        while pos < module.len() {
+            ids.push(module[pos]);
+            let (mut size, mut shift) = (0usize, 0);
+            loop {
+                pos += 1;
+                size |= ((module[pos] & 0x7F) as usize) << shift;
+                shift += 7;
+                if module[pos] & 0x80 == 0 {
+                    break;
+                }
+            }
+            pos += 1 + size;
+        }
+        assert_eq!(module.len(), pos);
+        ids
+    }
+
+    #[test]
+    fn leb128() {
+        let encode = |value: i32| {
+            let mut out = Vec::new();
+            sleb(&mut out, value);
+            out
+        };
+        assert_eq!(vec![0x00], encode(0));
+        assert_eq!(vec![0x3F], encode(63));
+        assert_eq!(vec![0xC0, 0x00], encode(64));
+        assert_eq!(vec![0x7F], encode(-1));
+        assert_eq!(vec![0x80, 0x7F], encode(-128));
+        assert_eq!(vec![0x80, 0x20], encode(0x1000));
+
+        let mut out = Vec::new();
+        uleb(&mut out, 624485);
+        assert_eq!(vec![0xE5, 0x8E, 0x26], out);
+    }
+
+    #[test]
+    fn module_structure() {
+        for (name, rom) in conformance::roms(Quirks::default()) {
+            let mut cpu = CPU::new();
+            cpu.load_rom(&rom).unwrap();
+            let module = compile(&cpu);
+
+            assert_eq!(b"\0asm\x01\0\0\0", &module[..8], "{}", name);
+            assert_eq!(vec![1, 2, 3, 4, 5, 7, 9, 10, 11], sections(&module), "{}", name);
+            Module::new(&Engine::default(), &module[..]).unwrap();
+        }
+
+        // Nothing to compile is still a valid module
+        let mut cpu = CPU::new();
+        cpu.load_rom(&[0xF0, 0x0A]).unwrap();
+        Wasmi::new(&compile(&cpu));
+    }
+
+    #[test]
+    fn conformance() {
+        for &(preset, quirks) in Quirks::presets().iter() {
+            for (name, rom) in conformance::roms(quirks) {
+                let context = format!("{} with {} quirks", name, preset);
+                let mut keys = |_| 1 << conformance::HELD_KEY;
+                let share = differential(&image(&rom), quirks, 0, conformance::FRAMES, conformance::INSTRUCTIONS_PER_FRAME, &mut keys, &context);
+                assert!(share > 0.5, "only {:.0}% compiled {}", share * 100.0, context);
+            }
+        }
+    }
+
+    #[test]
+    fn random_programs() {
+        for &(preset, quirks) in Quirks::presets().iter() {
+            for seed in 0..25 {
+                let mut rng = StdRng::seed_from_u64(seed);
+                let image = reference::random_image(&mut rng);
+                let mut keys = |frame| if frame % 7 == 3 { rng.next_u32() as u16 } else { 0 };
+                differential(&image, quirks, seed, 100, 10, &mut keys, &format!("program {} with {} quirks", seed, preset));
+            }
+        }
+    }
+
+    #[test]
+    fn self_modifying() {
+        let rom = [
+            0xA2, 0x0B, // 200: LD I, 20B
+            0x60, 0x07, // 202: LD V0, 7
+            0xF0, 0x55, // 204: LD [I], V0
+            0x12, 0x0A, // 206: JP 20A
+            0x00, 0x00, // 208
+            0x60, 0x05, // 20A: LD V0, 5, patched to LD V0, 7
+            0x61, 0x01, // 20C: LD V1, 1
+            0x12, 0x0E, // 20E: JP 20E
+        ];
+        let mut cpu = CPU::new();
+        cpu.load_rom(&rom).unwrap();
+        let mut instance = Wasmi::new(&compile(&cpu));
+
+        assert_eq!(Ok(false), run_frame(&mut cpu, &mut instance, 10));
+        assert_eq!(0x07, cpu.registers()[0]);
+        assert_eq!(0x01, cpu.registers()[1]);
+        assert_eq!(0x20E, cpu.ip());
+        // Stopped right after the store
+        assert_eq!(3, instance.executed);
+
+        differential(&image(&rom), Quirks::default(), 0, 3, 10, &mut |_| 0, "self modifying program");
+    }
+
+    #[test]
+    fn vip_timing() {
+        // ADD V0, 1; DRW V0, V0, 5; JP 200
+        let rom = [0x70, 0x01, 0xD0, 0x05, 0x12, 0x00];
+        let setup = || {
+            let mut cpu = CPU::new();
+            cpu.set_timing(Timing::CosmacVip);
+            cpu.load_rom(&rom).unwrap();
+            cpu
+        };
+        let mut interpreted = setup();
+        let mut compiled = setup();
+        let mut instance = Wasmi::new(&compile(&compiled));
+        for frame in 0..10 {
+            interpreted.run_frame(100).unwrap();
+            assert_eq!(Ok(true), run_frame(&mut compiled, &mut instance, 100));
+            assert_same(&interpreted, &compiled, &format!("in frame {}", frame));
+        }
+        assert_eq!(0, instance.executed);
+    }
+}
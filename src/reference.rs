@@ -0,0 +1,328 @@
+// Deliberately simple second model of the machine, written from the spec rather than from cpu.rs,
+// used to cross-check the interpreter on random programs. Every instruction is a pure function
+// from one state to the next. Speed and elegance are non-goals, obviousness is the goal.
+// Spec: http://devernay.free.fr/hacks/chip8/C8TECH10.HTM and https://github.com/Timendus/chip8-test-suite
+
+use rand::prelude::*;
+
+use crate::quirks::Quirks;
+
+const WIDTH: usize = 64;
+const HEIGHT: usize = 32;
+const MEMORY: usize = 4096;
+const MAX_STACK: usize = 16;
+const PROGRAM_LENGTH: usize = 256;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct State {
+    pub pc: u16,
+    pub v: [u8; 16],
+    pub i: u16,
+    pub stack: Vec<u16>,
+    pub memory: Vec<u8>,
+    pub screen: Vec<bool>,
+    pub delay: u8,
+    pub sound: u8,
+    pub keys: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    Invalid,
+    StackOverflow,
+    StackUnderflow,
+}
+
+impl State {
+    pub fn new(image: &[u8]) -> Self {
+        let mut memory = vec![0; MEMORY];
+        let len = image.len().min(MEMORY);
+        memory[..len].copy_from_slice(&image[..len]);
+
+        State {
+            pc: 0x200,
+            v: [0; 16],
+            i: 0,
+            stack: Vec::new(),
+            memory,
+            screen: vec![false; WIDTH * HEIGHT],
+            delay: 0,
+            sound: 0,
+            keys: 0,
+        }
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        self.memory[addr as usize % MEMORY]
+    }
+
+    fn poke(&mut self, addr: u16, value: u8) {
+        self.memory[addr as usize % MEMORY] = value;
+    }
+
+    pub fn fetch(&self) -> u16 {
+        (self.peek(self.pc) as u16) << 8 | self.peek(self.pc.wrapping_add(1)) as u16
+    }
+}
+
+pub fn tick_timers(state: &State) -> State {
+    let mut next = state.clone();
+    next.delay = next.delay.saturating_sub(1);
+    next.sound = next.sound.saturating_sub(1);
+    next
+}
+
+// `random` is only used by Cxkk
+pub fn step(state: &State, instr: u16, quirks: &Quirks, random: u8) -> Result<State, Fault> {
+    let mut s = state.clone();
+    let nnn = instr & 0x0FFF;
+    let kk = (instr & 0xFF) as u8;
+    let n = (instr & 0xF) as u8;
+    let x = ((instr >> 8) & 0xF) as usize;
+    let y = ((instr >> 4) & 0xF) as usize;
+    let next = s.pc.wrapping_add(2);
+    let skip = s.pc.wrapping_add(4);
+
+    s.pc = next;
+    match instr >> 12 {
+        0x0 if instr == 0x00E0 => s.screen = vec![false; WIDTH * HEIGHT],
+        0x0 if instr == 0x00EE => s.pc = s.stack.pop().ok_or(Fault::StackUnderflow)?,
+        0x1 => s.pc = nnn,
+        0x2 => {
+            if s.stack.len() == MAX_STACK {
+                return Err(Fault::StackOverflow);
+            }
+            s.stack.push(next);
+            s.pc = nnn;
+        }
+        0x3 => if s.v[x] == kk { s.pc = skip },
+        0x4 => if s.v[x] != kk { s.pc = skip },
+        0x5 if n == 0 => if s.v[x] == s.v[y] { s.pc = skip },
+        0x6 => s.v[x] = kk,
+        0x7 => s.v[x] = s.v[x].wrapping_add(kk),
+        0x8 => {
+            let (vx, vy) = (s.v[x] as u16, s.v[y] as u16);
+            let source = if quirks.shift_uses_vy { vy } else { vx };
+            let (result, flag) = match n {
+                0x0 => (vy, None),
+                0x1 => (vx | vy, if quirks.vf_reset { Some(0) } else { None }),
+                0x2 => (vx & vy, if quirks.vf_reset { Some(0) } else { None }),
+                0x3 => (vx ^ vy, if quirks.vf_reset { Some(0) } else { None }),
+                0x4 => (vx + vy, Some((vx + vy > 0xFF) as u8)),
+                0x5 => (vx + 0x100 - vy, Some((vx >= vy) as u8)),
+                0x6 => (source / 2, Some((source % 2) as u8)),
+                0x7 => (vy + 0x100 - vx, Some((vy >= vx) as u8)),
+                0xE => (source * 2, Some((source / 0x80) as u8)),
+                _ => return Err(Fault::Invalid),
+            };
+            s.v[x] = (result % 0x100) as u8;
+            if let Some(flag) = flag {
+                s.v[0xF] = flag;
+            }
+        }
+        0x9 if n == 0 => if s.v[x] != s.v[y] { s.pc = skip },
+        0xA => s.i = nnn,
+        0xB => s.pc = nnn + s.v[if quirks.jump_uses_vx { x } else { 0 }] as u16,
+        0xC => s.v[x] = random & kk,
+        0xD => {
+            let left = s.v[x] as usize % WIDTH;
+            let top = s.v[y] as usize % HEIGHT;
+            let mut collision = 0;
+            for row in 0..n as usize {
+                let bits = s.peek(s.i.wrapping_add(row as u16));
+                for col in 0..8 {
+                    if bits & (0x80 >> col) == 0 {
+                        continue;
+                    }
+
+                    let (mut px, mut py) = (left + col, top + row);
+                    if quirks.clip_sprites {
+                        if px >= WIDTH || py >= HEIGHT {
+                            continue;
+                        }
+                    } else {
+                        px %= WIDTH;
+                        py %= HEIGHT;
+                    }
+
+                    let pixel = &mut s.screen[py * WIDTH + px];
+                    if *pixel {
+                        collision = 1;
+                    }
+                    *pixel = !*pixel;
+                }
+            }
+            s.v[0xF] = collision;
+        }
+        0xE if kk == 0x9E => if s.keys & (1 << (s.v[x] & 0xF)) != 0 { s.pc = skip },
+        0xE if kk == 0xA1 => if s.keys & (1 << (s.v[x] & 0xF)) == 0 { s.pc = skip },
+        0xF => match kk {
+            0x07 => s.v[x] = s.delay,
+            0x0A => match (0..16).find(|key| s.keys & (1 << key) != 0) {
+                Some(key) => s.v[x] = key as u8,
+                None => s.pc = state.pc,
+            },
+            0x15 => s.delay = s.v[x],
+            0x18 => s.sound = s.v[x],
+            0x1E => s.i = s.i.wrapping_add(s.v[x] as u16),
+            0x29 => s.i = (s.v[x] & 0xF) as u16 * 5,
+            0x33 => {
+                let value = s.v[x];
+                s.poke(s.i, value / 100);
+                s.poke(s.i.wrapping_add(1), value / 10 % 10);
+                s.poke(s.i.wrapping_add(2), value % 10);
+            }
+            0x55 => {
+                for r in 0..=x {
+                    let value = s.v[r];
+                    s.poke(s.i.wrapping_add(r as u16), value);
+                }
+                if quirks.load_store_increments_i {
+                    s.i = s.i.wrapping_add(x as u16 + 1);
+                }
+            }
+            0x65 => {
+                for r in 0..=x {
+                    s.v[r] = s.peek(s.i.wrapping_add(r as u16));
+                }
+                if quirks.load_store_increments_i {
+                    s.i = s.i.wrapping_add(x as u16 + 1);
+                }
+            }
+            _ => return Err(Fault::Invalid),
+        },
+        _ => return Err(Fault::Invalid),
+    }
+
+    Ok(s)
+}
+
+// Random memory image with a program of mostly valid instructions at 0x200
+pub fn random_image<R: Rng>(rng: &mut R) -> Vec<u8> {
+    let mut image = vec![0; MEMORY];
+    rng.fill_bytes(&mut image);
+    for i in 0..PROGRAM_LENGTH {
+        let instr = random_instruction(rng);
+        image[0x200 + 2 * i..0x200 + 2 * i + 2].copy_from_slice(&instr.to_be_bytes());
+    }
+    image
+}
+
+// Mostly valid instructions with random operands. Jumps stay within the program.
+fn random_instruction<R: Rng>(rng: &mut R) -> u16 {
+    let x = rng.gen_range(0, 16) << 8;
+    let y = rng.gen_range(0, 16) << 4;
+    let kk = rng.gen_range(0, 0x100);
+    let target = 0x200 + 2 * rng.gen_range(0, PROGRAM_LENGTH as u16);
+    let data = rng.gen_range(0, 0x1000);
+
+    match rng.gen_range(0, 36) {
+        0 => 0x00E0,
+        1 => 0x00EE,
+        2 => 0x1000 | target,
+        3 => 0x2000 | target,
+        4 => 0x3000 | x | kk,
+        5 => 0x4000 | x | kk,
+        6 => 0x5000 | x | y,
+        7 => 0x6000 | x | kk,
+        8 => 0x7000 | x | kk,
+        9 => 0x8000 | x | y | [0x0, 0x1, 0x2, 0x3, 0x4, 0x5, 0x6, 0x7, 0xE][rng.gen_range(0, 9)],
+        10 => 0x9000 | x | y,
+        11 => 0xA000 | data,
+        12 => 0xB000 | (target - 0x10),
+        13 => 0xC000 | x | kk,
+        14 => 0xD000 | x | y | rng.gen_range(0, 16),
+        15 => 0xE09E | x,
+        16 => 0xE0A1 | x,
+        17 => 0xF007 | x,
+        18 => 0xF00A | x,
+        19 => 0xF015 | x,
+        20 => 0xF018 | x,
+        21 => 0xF01E | x,
+        22 => 0xF029 | x,
+        23 => 0xF033 | x,
+        24 => 0xF055 | x,
+        25 => 0xF065 | x,
+        26..=31 => 0x8000 | x | y | [0x4, 0x5, 0x6, 0x7, 0xE][rng.gen_range(0, 5)],
+        _ => rng.gen(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::prelude::*;
+    use rand_chacha::ChaCha20Rng;
+
+    use super::*;
+    use crate::cpu::{CpuError, CPU};
+
+    const PROGRAMS: u64 = 100;
+    const STEPS: usize = 2000;
+    const STEPS_PER_FRAME: usize = 10;
+
+    fn same_fault(fault: Fault, err: CpuError) -> bool {
+        matches!(
+            (fault, err),
+            (Fault::Invalid, CpuError::InvalidInstruction { .. })
+                | (Fault::StackOverflow, CpuError::StackOverflow { .. })
+                | (Fault::StackUnderflow, CpuError::StackUnderflow { .. })
+        )
+    }
+
+    fn assert_same(state: &State, cpu: &CPU, context: &str) {
+        assert_eq!(state.pc, cpu.ip(), "PC {}", context);
+        assert_eq!(&state.v, cpu.registers(), "V {}", context);
+        assert_eq!(state.i as u32, cpu.addr_reg(), "I {}", context);
+        assert_eq!(&state.stack[..], cpu.stack(), "stack {}", context);
+        assert_eq!(state.delay, cpu.delay_timer(), "DT {}", context);
+        assert_eq!(state.sound, cpu.sound_timer(), "ST {}", context);
+        assert!(state.memory[..] == cpu.memory()[..], "memory {}", context);
+        assert!(state.screen[..] == cpu.screen().pixels()[..], "screen {}", context);
+    }
+
+    fn run_program(seed: u64, name: &str, quirks: Quirks) {
+        let mut rng = ChaCha20Rng::seed_from_u64(seed);
+        let image = random_image(&mut rng);
+
+        let mut cpu = CPU::from_memory(&image);
+        cpu.set_quirks(quirks);
+        cpu.seed_rng(seed);
+        let mut state = State::new(&image);
+        // The generator CPU::seed_rng sets up
+        let mut random = ChaCha20Rng::seed_from_u64(seed);
+
+        for step_count in 0..STEPS {
+            let keys = if rng.gen_bool(0.1) { rng.gen() } else { 0 };
+            cpu.keypad_mut().set_state(keys);
+            state.keys = keys;
+
+            let instr = state.fetch();
+            let context = format!("after {:#06x} @ {:#06x}, step {} of program {} with {} quirks",
+                                  instr, state.pc, step_count, seed, name);
+
+            // Only RND draws from the generator, both sides have to stay in sync
+            let value = if instr >> 12 == 0xC { random.next_u32() as u8 } else { 0 };
+            match (step(&state, instr, &quirks, value), cpu.tick()) {
+                (Ok(next), Ok(())) => state = next,
+                // A faulting instruction is skipped without any other effect
+                (Err(fault), Err(err)) if same_fault(fault, err) => state.pc = state.pc.wrapping_add(2),
+                (expected, actual) => panic!("{:?} vs {:?} {}", expected.err(), actual.err(), context),
+            }
+
+            if step_count % STEPS_PER_FRAME == STEPS_PER_FRAME - 1 {
+                cpu.tick_timers();
+                state = tick_timers(&state);
+            }
+            assert_same(&state, &cpu, &context);
+        }
+    }
+
+    #[test]
+    fn differential() {
+        for (name, quirks) in Quirks::presets().iter() {
+            for seed in 0..PROGRAMS {
+                run_program(seed, name, *quirks);
+            }
+        }
+    }
+}
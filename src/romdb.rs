@@ -0,0 +1,439 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use serde::{Deserialize, Deserializer};
+use sha1::{Digest, Sha1};
+
+use crate::cpu::{LoadError, CPU, DEFAULT_INSTRUCTIONS_PER_FRAME};
+use crate::keypad::KEY_COUNT;
+use crate::quirks::Quirks;
+use crate::stack::StackConfig;
+use crate::variant::Variant;
+
+// Database shipped with the emulator, same format as user supplied files
+const BUILTIN: &str = include_str!("romdb.json");
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum Platform {
+    #[serde(rename = "chip-8")]
+    Chip8,
+    #[serde(rename = "chip-8x")]
+    Chip8X,
+    #[serde(rename = "chip-8-hires")]
+    HiRes,
+    #[serde(rename = "megachip")]
+    MegaChip,
+    #[serde(rename = "schip")]
+    Schip,
+    #[serde(rename = "xo-chip")]
+    XoChip,
+}
+
+impl Platform {
+    pub fn quirks(self) -> Quirks {
+        match self {
+            Platform::Chip8 | Platform::Chip8X | Platform::HiRes => Quirks::cosmac_vip(),
+            Platform::Schip | Platform::MegaChip => Quirks::schip(),
+            Platform::XoChip => Quirks::xo_chip(),
+        }
+    }
+
+    // How deeply the platform's interpreter lets subroutines nest
+    pub fn stack_config(self) -> StackConfig {
+        match self {
+            Platform::Chip8 | Platform::Chip8X | Platform::HiRes => StackConfig::cosmac_vip(),
+            Platform::Schip | Platform::MegaChip => StackConfig::schip(),
+            Platform::XoChip => StackConfig::octo(),
+        }
+    }
+
+    // MEGA-CHIP is SCHIP until a program turns mega mode on, so SCHIP programs run on it
+    pub fn variant(self) -> Variant {
+        match self {
+            Platform::Chip8X => Variant::Chip8X,
+            Platform::HiRes => Variant::HiRes,
+            Platform::MegaChip | Platform::Schip => Variant::MegaChip,
+            Platform::Chip8 | Platform::XoChip => Variant::Chip8,
+        }
+    }
+}
+
+// Quirks that differ from the platform's preset, everything missing is taken from the preset
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QuirkOverrides {
+    pub vf_reset: Option<bool>,
+    pub shift_uses_vy: Option<bool>,
+    pub load_store_increments_i: Option<bool>,
+    pub jump_uses_vx: Option<bool>,
+    pub clip_sprites: Option<bool>,
+}
+
+impl QuirkOverrides {
+    pub fn apply(&self, quirks: Quirks) -> Quirks {
+        Quirks {
+            vf_reset: self.vf_reset.unwrap_or(quirks.vf_reset),
+            shift_uses_vy: self.shift_uses_vy.unwrap_or(quirks.shift_uses_vy),
+            load_store_increments_i: self
+                .load_store_increments_i
+                .unwrap_or(quirks.load_store_increments_i),
+            jump_uses_vx: self.jump_uses_vx.unwrap_or(quirks.jump_uses_vx),
+            clip_sprites: self.clip_sprites.unwrap_or(quirks.clip_sprites),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Colors {
+    #[serde(deserialize_with = "deserialize_rgb")]
+    pub foreground: [u8; 3],
+    #[serde(deserialize_with = "deserialize_rgb")]
+    pub background: [u8; 3],
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RomInfo {
+    pub title: String,
+    #[serde(default)]
+    pub author: Option<String>,
+    pub platform: Platform,
+    #[serde(default)]
+    pub quirks: QuirkOverrides,
+    // Instructions per frame
+    #[serde(default)]
+    pub speed: Option<u32>,
+    // Host key name to CHIP-8 key, the names are up to the frontend
+    #[serde(default)]
+    pub keymap: BTreeMap<String, u8>,
+    #[serde(default)]
+    pub colors: Option<Colors>,
+}
+
+impl RomInfo {
+    pub fn quirks(&self) -> Quirks {
+        self.quirks.apply(self.platform.quirks())
+    }
+
+    pub fn instructions_per_frame(&self) -> u32 {
+        self.speed.unwrap_or(DEFAULT_INSTRUCTIONS_PER_FRAME)
+    }
+}
+
+#[derive(Debug)]
+pub enum DatabaseError {
+    Io(io::Error),
+    Parse(serde_json::Error),
+    InvalidHash { hash: String },
+    InvalidKey { hash: String, key: u8 },
+}
+
+impl fmt::Display for DatabaseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DatabaseError::Io(err) => write!(f, "Cannot read ROM database: {}", err),
+            DatabaseError::Parse(err) => write!(f, "Invalid ROM database: {}", err),
+            DatabaseError::InvalidHash { hash } => {
+                write!(f, "Invalid SHA-1 in ROM database: {}", hash)
+            }
+            DatabaseError::InvalidKey { hash, key } => {
+                write!(f, "Invalid key {:#x} in keymap of ROM {}", key, hash)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DatabaseError {}
+
+impl From<io::Error> for DatabaseError {
+    fn from(err: io::Error) -> Self {
+        DatabaseError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for DatabaseError {
+    fn from(err: serde_json::Error) -> Self {
+        DatabaseError::Parse(err)
+    }
+}
+
+// Known programs by the SHA-1 of their bytes. A database file is a JSON object with the
+// lowercase hex hash as key:
+//
+// {
+//   "0123456789abcdef0123456789abcdef01234567": {
+//     "title": "Pong",
+//     "author": "Paul Vervalin",
+//     "platform": "chip-8",
+//     "quirks": { "vf_reset": false },
+//     "speed": 15,
+//     "keymap": { "w": 1, "s": 4, "up": 12, "down": 13 },
+//     "colors": { "foreground": "#ffcc00", "background": "#996600" }
+//   }
+// }
+//
+// Only title and platform are required. platform is one of "chip-8", "chip-8x", "chip-8-hires",
+// "megachip", "schip" or "xo-chip" and selects the variant and quirk preset, quirks overrides
+// single flags of it.
+#[derive(Debug, Clone, Default)]
+pub struct RomDatabase {
+    entries: HashMap<String, RomInfo>,
+}
+
+impl RomDatabase {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn builtin() -> Self {
+        Self::from_json(BUILTIN).expect("Invalid builtin ROM database")
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, DatabaseError> {
+        let raw: HashMap<String, RomInfo> = serde_json::from_str(json)?;
+
+        let mut entries = HashMap::with_capacity(raw.len());
+        for (hash, info) in raw {
+            if hash.len() != 40 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(DatabaseError::InvalidHash { hash });
+            }
+            if let Some(&key) = info.keymap.values().find(|&&key| key >= KEY_COUNT) {
+                return Err(DatabaseError::InvalidKey { hash, key });
+            }
+            entries.insert(hash.to_ascii_lowercase(), info);
+        }
+
+        Ok(RomDatabase { entries })
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, DatabaseError> {
+        Self::from_json(&fs::read_to_string(path)?)
+    }
+
+    // Entries of other replace existing ones for the same program
+    pub fn extend(&mut self, other: RomDatabase) {
+        self.entries.extend(other.entries);
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn lookup(&self, rom: &[u8]) -> Option<&RomInfo> {
+        self.entries.get(&sha1_hex(rom))
+    }
+
+    // Loads the program and, if it is known, switches to the variant, quirks and stack it needs. The returned
+    // entry carries what the frontend has to set up, like speed, keymap and colours. The variant
+    // is switched first, it decides how much memory there is for the program.
+    pub fn load_rom(&self, cpu: &mut CPU, rom: &[u8]) -> Result<Option<&RomInfo>, LoadError> {
+        let info = self.lookup(rom);
+        if let Some(info) = info {
+            cpu.set_quirks(info.quirks());
+            cpu.set_variant(info.platform.variant());
+            cpu.set_stack_config(info.platform.stack_config());
+        }
+
+        cpu.load_rom(rom)?;
+        Ok(info)
+    }
+}
+
+pub fn sha1_hex(rom: &[u8]) -> String {
+    Sha1::digest(rom)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn deserialize_rgb<'de, D: Deserializer<'de>>(deserializer: D) -> Result<[u8; 3], D::Error> {
+    let value = String::deserialize(deserializer)?;
+    parse_rgb(&value).ok_or_else(|| {
+        serde::de::Error::custom(format!("invalid colour {:?}, expected #rrggbb", value))
+    })
+}
+
+pub(crate) fn parse_rgb(value: &str) -> Option<[u8; 3]> {
+    let hex = value.strip_prefix('#')?;
+    if hex.len() != 6 || !hex.is_ascii() {
+        return None;
+    }
+
+    let mut rgb = [0; 3];
+    for (i, channel) in rgb.iter_mut().enumerate() {
+        *channel = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(rgb)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROM: [u8; 4] = [0x12, 0x00, 0x00, 0xE0];
+
+    fn database() -> RomDatabase {
+        let json = format!(
+            r##"{{
+                "{}": {{
+                    "title": "Test",
+                    "author": "Someone",
+                    "platform": "schip",
+                    "quirks": {{ "clip_sprites": false }},
+                    "speed": 30,
+                    "keymap": {{ "up": 5, "space": 15 }},
+                    "colors": {{ "foreground": "#FFCC00", "background": "#000080" }}
+                }}
+            }}"##,
+            sha1_hex(&ROM)
+        );
+        RomDatabase::from_json(&json).unwrap()
+    }
+
+    #[test]
+    fn sha1() {
+        assert_eq!("da39a3ee5e6b4b0d3255bfef95601890afd80709", sha1_hex(&[]));
+        assert_eq!("a9993e364706816aba3e25717850c26c9cd0d89d", sha1_hex(b"abc"));
+    }
+
+    #[test]
+    fn builtin() {
+        RomDatabase::builtin();
+    }
+
+    #[test]
+    fn lookup() {
+        let db = database();
+        let info = db.lookup(&ROM).unwrap();
+        assert_eq!("Test", info.title);
+        assert_eq!(Some("Someone".to_string()), info.author);
+        assert_eq!(Platform::Schip, info.platform);
+        assert_eq!(30, info.instructions_per_frame());
+        assert_eq!(Some(&15), info.keymap.get("space"));
+        assert_eq!(
+            Some(Colors {
+                foreground: [0xFF, 0xCC, 0x00],
+                background: [0x00, 0x00, 0x80],
+            }),
+            info.colors
+        );
+        assert_eq!(
+            Quirks {
+                clip_sprites: false,
+                ..Quirks::schip()
+            },
+            info.quirks()
+        );
+
+        assert_eq!(None, db.lookup(&ROM[..3]));
+    }
+
+    #[test]
+    fn load_rom() {
+        let db = database();
+        let mut cpu = CPU::new();
+        assert_eq!("Test", db.load_rom(&mut cpu, &ROM).unwrap().unwrap().title);
+        assert!(!cpu.quirks().clip_sprites);
+        assert!(cpu.quirks().jump_uses_vx);
+        assert_eq!(Variant::MegaChip, cpu.variant());
+        assert_eq!(StackConfig::schip(), cpu.stack_config());
+
+        // SCHIP programs get its instructions: HIGH; SCD 1; LOW; JP 0x206
+        let rom = [0x00, 0xFF, 0x00, 0xC1, 0x00, 0xFE, 0x12, 0x06];
+        let json = format!(r#"{{ "{}": {{ "title": "Scroll", "platform": "schip", "speed": 4 }} }}"#, sha1_hex(&rom));
+        let db = RomDatabase::from_json(&json).unwrap();
+        let mut cpu = CPU::new();
+        let info = db.load_rom(&mut cpu, &rom).unwrap().unwrap();
+        assert_eq!(4, info.instructions_per_frame());
+        cpu.run_frame(info.instructions_per_frame()).unwrap();
+        assert_eq!((64, 32), (cpu.screen().width(), cpu.screen().height()));
+        assert_eq!(0x206, cpu.ip());
+
+        // Unknown programs keep the configured quirks and variant
+        let mut cpu = CPU::new();
+        cpu.set_variant(Variant::Chip8X);
+        assert_eq!(None, db.load_rom(&mut cpu, &[0x00, 0xE0]).unwrap());
+        assert_eq!(Quirks::default(), cpu.quirks());
+        assert_eq!(Variant::Chip8X, cpu.variant());
+
+        let json = format!(r#"{{ "{}": {{ "title": "Colours", "platform": "chip-8x" }} }}"#, sha1_hex(&ROM));
+        let db = RomDatabase::from_json(&json).unwrap();
+        let mut cpu = CPU::new();
+        db.load_rom(&mut cpu, &ROM).unwrap();
+        assert_eq!(Variant::Chip8X, cpu.variant());
+        assert_eq!(Quirks::cosmac_vip(), cpu.quirks());
+        assert_eq!(StackConfig::cosmac_vip(), cpu.stack_config());
+
+        // Too large for plain CHIP-8
+        let mut rom = vec![0; 0x2000];
+        rom[..2].copy_from_slice(&[0x00, 0x11]);
+        let json = format!(r#"{{ "{}": {{ "title": "Mega", "platform": "megachip" }} }}"#, sha1_hex(&rom));
+        let db = RomDatabase::from_json(&json).unwrap();
+        let mut cpu = CPU::new();
+        db.load_rom(&mut cpu, &rom).unwrap();
+        assert_eq!(Variant::MegaChip, cpu.variant());
+    }
+
+    #[test]
+    fn extend() {
+        let mut db = database();
+        let user = format!(
+            r#"{{ "{}": {{ "title": "Mine", "platform": "chip-8" }} }}"#,
+            sha1_hex(&ROM).to_uppercase()
+        );
+        db.extend(RomDatabase::from_json(&user).unwrap());
+
+        let info = db.lookup(&ROM).unwrap();
+        assert_eq!("Mine", info.title);
+        assert_eq!(Quirks::cosmac_vip(), info.quirks());
+        assert_eq!(
+            DEFAULT_INSTRUCTIONS_PER_FRAME,
+            info.instructions_per_frame()
+        );
+        assert_eq!(1, db.len());
+    }
+
+    #[test]
+    fn invalid() {
+        let entry = |hash: &str, extra: &str| {
+            RomDatabase::from_json(&format!(
+                r#"{{ "{}": {{ "title": "T", "platform": "chip-8"{} }} }}"#,
+                hash, extra
+            ))
+        };
+        let hash = sha1_hex(&ROM);
+
+        assert!(entry(&hash, "").is_ok());
+        assert!(matches!(
+            entry("1234", ""),
+            Err(DatabaseError::InvalidHash { .. })
+        ));
+        assert!(matches!(
+            entry(&hash, r#", "keymap": { "a": 16 }"#),
+            Err(DatabaseError::InvalidKey { key: 16, .. })
+        ));
+        assert!(matches!(
+            entry(
+                &hash,
+                r##", "colors": { "foreground": "red", "background": "#000000" }"##
+            ),
+            Err(DatabaseError::Parse(_))
+        ));
+        assert!(matches!(
+            entry(&hash, r#", "quirks": { "wrap": true }"#),
+            Err(DatabaseError::Parse(_))
+        ));
+        assert!(matches!(
+            entry(&hash, r#", "platform": "vip""#),
+            Err(DatabaseError::Parse(_))
+        ));
+    }
+}
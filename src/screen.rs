@@ -1,18 +1,197 @@
-use std::cmp;
+use alloc::boxed::Box;
+use alloc::vec;
+use core::cmp;
 
-const SCREEN_WIDTH: usize = 64;
-const SCREEN_HEIGHT: usize = 32;
-const SCREEN_PIXELS: usize = SCREEN_WIDTH * SCREEN_HEIGHT;
+use crate::megachip::{self, Canvas};
+
+pub const SCREEN_WIDTH: usize = 64;
+pub const SCREEN_HEIGHT: usize = 32;
+
+// The two page display of the hi-res VIP interpreter
+pub const HIRES_HEIGHT: usize = 64;
+
+// The VP-590 colour board colours the screen in zones 8 pixels wide and one line high
+pub const ZONE_WIDTH: usize = 8;
+pub const ZONE_COLUMNS: usize = SCREEN_WIDTH / ZONE_WIDTH;
+
+// The eight colours of the VP-590, bit 0 is red, bit 1 blue and bit 2 green
+pub const PALETTE: [[u8; 3]; 8] = [
+    [0x00, 0x00, 0x00], // black
+    [0xFF, 0x00, 0x00], // red
+    [0x00, 0x00, 0xFF], // blue
+    [0xFF, 0x00, 0xFF], // violet
+    [0x00, 0xFF, 0x00], // green
+    [0xFF, 0xFF, 0x00], // yellow
+    [0x00, 0xFF, 0xFF], // aqua
+    [0xFF, 0xFF, 0xFF], // white
+];
+
+// The background can only be set to these, in the order 02A0 steps through them
+const BACKGROUNDS: [u8; 4] = [2, 0, 4, 1];
+
+// Colours of lit pixels by zone, and of everything else, as indices into PALETTE
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Colours {
+    zones: [u8; ZONE_COLUMNS * SCREEN_HEIGHT],
+    background: u8,
+}
+
+impl Colours {
+    // Red on blue, like the CHIP-8X interpreter starts
+    pub fn new() -> Self {
+        Colours {
+            zones: [1; ZONE_COLUMNS * SCREEN_HEIGHT],
+            background: BACKGROUNDS[0],
+        }
+    }
+
+    pub fn foreground(&self, x: usize, y: usize) -> u8 {
+        self.zones[y * ZONE_COLUMNS + x / ZONE_WIDTH]
+    }
+
+    pub fn background(&self) -> u8 {
+        self.background
+    }
+
+    // Row-major, one byte per zone
+    pub fn zones(&self) -> &[u8] {
+        &self.zones
+    }
+
+    // Zones outside the screen are ignored, only the low three bits of the colour count
+    pub fn set_zone(&mut self, column: usize, y: usize, colour: u8) {
+        if column < ZONE_COLUMNS && y < SCREEN_HEIGHT {
+            self.zones[y * ZONE_COLUMNS + column] = colour & 0x07;
+        }
+    }
+
+    pub fn cycle_background(&mut self) {
+        let index = BACKGROUNDS.iter().position(|&colour| colour == self.background).unwrap_or(0);
+        self.background = BACKGROUNDS[(index + 1) % BACKGROUNDS.len()];
+    }
+}
+
+impl Default for Colours {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 pub struct Screen {
     // TODO is there a better way of storing instead of a bool array
-    pixels: [bool; SCREEN_PIXELS],
+    pixels: Box<[bool]>,
+    width: usize,
+    height: usize,
+    // Only on variants with a colour board
+    colours: Option<Colours>,
+    // Only in MEGA-CHIP mode, the pixels then just tell which ones were drawn to
+    canvas: Option<Canvas>,
 }
 
 impl Screen {
     pub fn new() -> Self {
+        Screen::with_size(SCREEN_WIDTH, SCREEN_HEIGHT)
+    }
+
+    pub fn with_size(width: usize, height: usize) -> Self {
         Screen {
-            pixels: [false; SCREEN_PIXELS],
+            pixels: vec![false; width * height].into_boxed_slice(),
+            width,
+            height,
+            colours: None,
+            canvas: None,
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    // Switches to a blank screen of the new size, unless it already has that size. The canvas
+    // goes with the old size.
+    pub(crate) fn resize(&mut self, width: usize, height: usize) {
+        if (width, height) != (self.width, self.height) {
+            let colours = self.colours.take();
+            *self = Screen::with_size(width, height);
+            self.colours = colours;
+        }
+    }
+
+    // Row-major, starting at the top left
+    pub fn pixels(&self) -> &[bool] {
+        &self.pixels
+    }
+
+    pub(crate) fn pixels_mut(&mut self) -> &mut [bool] {
+        &mut self.pixels
+    }
+
+    pub fn get_pixel(&self, x: usize, y: usize) -> bool {
+        self.pixels[y * self.width + x]
+    }
+
+    pub fn colours(&self) -> Option<&Colours> {
+        self.colours.as_ref()
+    }
+
+    pub(crate) fn colours_mut(&mut self) -> Option<&mut Colours> {
+        self.colours.as_mut()
+    }
+
+    pub(crate) fn set_colours(&mut self, colours: Option<Colours>) {
+        self.colours = colours;
+    }
+
+    pub fn canvas(&self) -> Option<&Canvas> {
+        self.canvas.as_ref()
+    }
+
+    pub(crate) fn canvas_mut(&mut self) -> Option<&mut Canvas> {
+        self.canvas.as_mut()
+    }
+
+    pub(crate) fn set_canvas(&mut self, canvas: Option<Canvas>) {
+        self.canvas = canvas;
+    }
+
+    // Shows what was drawn to the canvas, the pixels are lit where anything was drawn
+    pub(crate) fn present(&mut self) {
+        if let Some(canvas) = &mut self.canvas {
+            for (pixel, &index) in self.pixels.iter_mut().zip(canvas.indices()) {
+                *pixel = index != 0;
+            }
+            canvas.present();
+        }
+    }
+
+    // Fills out with four bytes per pixel in the order of pixels(), alpha is always opaque. With
+    // a colour layer its colours are used, otherwise the given ones. A canvas is shown over
+    // black.
+    pub fn render_rgba(&self, foreground: [u8; 3], background: [u8; 3], out: &mut [u8]) {
+        if let Some(canvas) = &self.canvas {
+            for (&argb, rgba) in canvas.shown().iter().zip(out.chunks_exact_mut(4)) {
+                let alpha = (argb >> 24) * canvas.alpha() as u32 / 0xFF;
+                for (i, channel) in rgba[..3].iter_mut().enumerate() {
+                    *channel = ((argb >> (16 - i * 8) & 0xFF) * alpha / 0xFF) as u8;
+                }
+                rgba[3] = 0xFF;
+            }
+            return;
+        }
+
+        for (i, (&pixel, rgba)) in self.pixels.iter().zip(out.chunks_exact_mut(4)).enumerate() {
+            let rgb = match &self.colours {
+                Some(colours) if pixel => PALETTE[colours.foreground(i % self.width, i / self.width) as usize],
+                Some(colours) => PALETTE[colours.background() as usize],
+                None if pixel => foreground,
+                None => background,
+            };
+            rgba[..3].copy_from_slice(&rgb);
+            rgba[3] = 0xFF;
         }
     }
 
@@ -22,31 +201,64 @@ impl Screen {
         }
     }
 
+    // Moves the pixels right by dx and down by dy, uncovering unlit ones. With a canvas it's
+    // what was drawn to the canvas that moves.
+    pub(crate) fn scroll(&mut self, dx: isize, dy: isize) {
+        match &mut self.canvas {
+            Some(canvas) => canvas.scroll(self.width, dx, dy),
+            None => megachip::scroll(&mut self.pixels, self.width, dx, dy, false),
+        }
+    }
+
     fn set_pixel(&mut self, x: usize, y: usize, state: bool) -> bool {
-        let index = y * SCREEN_WIDTH + x;
+        let index = y * self.width + x;
         let prev = self.pixels[index];
         self.pixels[index] = state;
         state != prev
     }
 
-    pub fn draw_sprite_line(&mut self, x: u8, y: u8, line: u8) -> bool {
-        let x_usize = x as usize;
-        let y_usize = y as usize;
+    // XORs the line onto the screen, clipping at the right edge. Returns true if any pixel got
+    // turned off, which is what sets VF on collisions.
+    pub fn draw_sprite_line(&mut self, x: usize, y: usize, line: u8) -> bool {
+        let mut collision = false;
+        let x_max = cmp::min(x + 8, self.width);
+        for i in 0..x_max-x {
+            if (line >> (7 - i)) & 1 == 1 {
+                let state = !self.get_pixel(i + x, y);
+                self.set_pixel(i + x, y, state);
+                collision |= !state;
+            }
+        }
+
+        collision
+    }
 
-        let mut any_changes = false;
-        let x_max = cmp::min(x_usize + 8, SCREEN_WIDTH);
-        for i in 0..x_max-x_usize {
-            let state = (line >> (7 - i)) & 1 == 1;
-            any_changes |= self.set_pixel(i + x_usize, y_usize, state);
+    // Same as draw_sprite_line, but pixels past the right edge continue on the left
+    pub fn draw_sprite_line_wrapped(&mut self, x: usize, y: usize, line: u8) -> bool {
+        let mut collision = false;
+        for i in 0..8 {
+            if (line >> (7 - i)) & 1 == 1 {
+                let x = (x + i) % self.width;
+                let state = !self.get_pixel(x, y);
+                self.set_pixel(x, y, state);
+                collision |= !state;
+            }
         }
 
-        any_changes
+        collision
+    }
+}
+
+impl Default for Screen {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::megachip::BlendMode;
 
     #[test]
     fn clear() {
@@ -58,45 +270,146 @@ mod tests {
 
         screen.clear();
         for i in 0..screen.pixels.len() {
-            assert_eq!(false, screen.pixels[i]);
+            assert!(!screen.pixels[i]);
         }
     }
 
     #[test]
     fn set_pixel() {
         let mut screen = Screen::new();
-        assert_eq!(false, screen.pixels[10]);
+        assert!(!screen.pixels[10]);
 
         let mut changed = screen.set_pixel(10, 0, false);
-        assert_eq!(false, screen.pixels[10]);
-        assert_eq!(false, changed);
+        assert!(!screen.pixels[10]);
+        assert!(!changed);
 
         changed = screen.set_pixel(10, 0, true);
-        assert_eq!(true, screen.pixels[10]);
-        assert_eq!(true, changed);
+        assert!(screen.pixels[10]);
+        assert!(changed);
 
         changed = screen.set_pixel(10, 0, true);
-        assert_eq!(true, screen.pixels[10]);
-        assert_eq!(false, changed);
+        assert!(screen.pixels[10]);
+        assert!(!changed);
     }
 
     #[test]
     fn draw_sprite_line() {
         let line = 0b00111100;
         let mut screen = Screen::new();
-        let mut changed = screen.draw_sprite_line(10, 0, line);
-
-        assert_eq!(false, screen.pixels[10]);
-        assert_eq!(false, screen.pixels[11]);
-        assert_eq!(true, screen.pixels[12]);
-        assert_eq!(true, screen.pixels[13]);
-        assert_eq!(true, screen.pixels[14]);
-        assert_eq!(true, screen.pixels[15]);
-        assert_eq!(false, screen.pixels[16]);
-        assert_eq!(false, screen.pixels[17]);
-        assert_eq!(true, changed);
-
-        changed = screen.draw_sprite_line(10, 0, line);
-        assert_eq!(false, changed);
+        let mut collision = screen.draw_sprite_line(10, 0, line);
+
+        assert!(!screen.pixels[10]);
+        assert!(!screen.pixels[11]);
+        assert!(screen.pixels[12]);
+        assert!(screen.pixels[13]);
+        assert!(screen.pixels[14]);
+        assert!(screen.pixels[15]);
+        assert!(!screen.pixels[16]);
+        assert!(!screen.pixels[17]);
+        assert!(!collision);
+
+        // Drawing the same line again erases it
+        collision = screen.draw_sprite_line(10, 0, line);
+        assert!(collision);
+        assert!(screen.pixels.iter().all(|&p| !p));
+    }
+
+    #[test]
+    fn draw_sprite_line_wrapped() {
+        let mut screen = Screen::new();
+        screen.draw_sprite_line_wrapped(SCREEN_WIDTH - 2, 1, 0b11110000);
+
+        assert!(screen.get_pixel(SCREEN_WIDTH - 2, 1));
+        assert!(screen.get_pixel(SCREEN_WIDTH - 1, 1));
+        assert!(screen.get_pixel(0, 1));
+        assert!(screen.get_pixel(1, 1));
+        assert!(!screen.get_pixel(2, 1));
+        assert_eq!(4, screen.pixels.iter().filter(|&&p| p).count());
+    }
+
+    #[test]
+    fn draw_sprite_line_clipped() {
+        let mut screen = Screen::new();
+        screen.draw_sprite_line(SCREEN_WIDTH - 2, 1, 0b11111111);
+
+        assert!(screen.get_pixel(SCREEN_WIDTH - 2, 1));
+        assert!(screen.get_pixel(SCREEN_WIDTH - 1, 1));
+        assert!(!screen.get_pixel(0, 2));
+        assert_eq!(2, screen.pixels.iter().filter(|&&p| p).count());
+    }
+
+    #[test]
+    fn resize() {
+        let mut screen = Screen::new();
+        screen.draw_sprite_line(0, 0, 0xFF);
+        screen.resize(SCREEN_WIDTH, SCREEN_HEIGHT);
+        assert!(screen.get_pixel(0, 0));
+
+        screen.set_colours(Some(Colours::new()));
+        screen.resize(SCREEN_WIDTH, HIRES_HEIGHT);
+        assert_eq!(HIRES_HEIGHT, screen.height());
+        assert_eq!(SCREEN_WIDTH * HIRES_HEIGHT, screen.pixels().len());
+        assert!(screen.pixels().iter().all(|&p| !p));
+        assert!(screen.colours().is_some());
+
+        screen.draw_sprite_line_wrapped(SCREEN_WIDTH - 4, HIRES_HEIGHT - 1, 0xFF);
+        assert!(screen.get_pixel(3, HIRES_HEIGHT - 1));
+        assert!(!screen.get_pixel(4, HIRES_HEIGHT - 1));
+    }
+
+    #[test]
+    fn render_rgba() {
+        let mut screen = Screen::new();
+        screen.draw_sprite_line(7, 3, 0b11000000);
+        let mut out = vec![0; SCREEN_WIDTH * SCREEN_HEIGHT * 4];
+        screen.render_rgba([1, 2, 3], [4, 5, 6], &mut out);
+        let pixel = |out: &[u8], x: usize, y: usize| out[(y * SCREEN_WIDTH + x) * 4..][..4].to_vec();
+        assert_eq!(vec![1, 2, 3, 0xFF], pixel(&out, 7, 3));
+        assert_eq!(vec![4, 5, 6, 0xFF], pixel(&out, 6, 3));
+
+        // The two lit pixels are in different zones
+        let mut colours = Colours::new();
+        colours.set_zone(1, 3, 0xF4);
+        colours.set_zone(ZONE_COLUMNS, 3, 7);
+        colours.cycle_background();
+        screen.set_colours(Some(colours));
+        screen.render_rgba([1, 2, 3], [4, 5, 6], &mut out);
+        assert_eq!(vec![0xFF, 0, 0, 0xFF], pixel(&out, 7, 3));
+        assert_eq!(vec![0, 0xFF, 0, 0xFF], pixel(&out, 8, 3));
+        assert_eq!(vec![0, 0, 0, 0xFF], pixel(&out, 6, 3));
+    }
+
+    #[test]
+    fn render_canvas() {
+        let mut screen = Screen::with_size(2, 1);
+        let mut canvas = Canvas::new(2);
+        canvas.plot(0, 1, 0xFF_FF_80_00, BlendMode::Normal, 0);
+        canvas.plot(1, 2, 0x80_FF_FF_FF, BlendMode::Normal, 0);
+        screen.set_canvas(Some(canvas));
+        screen.present();
+        assert_eq!(&[true, true], screen.pixels());
+
+        let mut out = [0; 8];
+        screen.render_rgba([1, 2, 3], [4, 5, 6], &mut out);
+        assert_eq!([0xFF, 0x80, 0x00, 0xFF, 0x80, 0x80, 0x80, 0xFF], out);
+
+        screen.canvas_mut().unwrap().set_alpha(0);
+        screen.render_rgba([1, 2, 3], [4, 5, 6], &mut out);
+        assert_eq!([0, 0, 0, 0xFF, 0, 0, 0, 0xFF], out);
+
+        // Nothing was drawn since
+        screen.present();
+        assert_eq!(&[false, false], screen.pixels());
+    }
+
+    #[test]
+    fn cycle_background() {
+        let mut colours = Colours::new();
+        let mut seen = vec![colours.background()];
+        for _ in 0..4 {
+            colours.cycle_background();
+            seen.push(colours.background());
+        }
+        assert_eq!(vec![2, 0, 4, 1, 2], seen);
     }
 }
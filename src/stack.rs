@@ -0,0 +1,96 @@
+// The VIP interpreter keeps return addresses at the start of its work area, growing down from
+// here. Each one is two bytes, high byte first, the way STXD pushes them.
+pub const VIP_STACK_TOP: u16 = 0xED0;
+
+// How deeply subroutines can nest and where the return addresses are kept. Too many calls fault
+// with CpuError::StackOverflow, returning with none left with CpuError::StackUnderflow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StackConfig {
+    // None for no limit
+    depth: Option<u16>,
+    // In memory below VIP_STACK_TOP, where programs can read and overwrite them
+    in_memory: bool,
+}
+
+impl StackConfig {
+    pub fn new(depth: Option<u16>) -> Self {
+        StackConfig {
+            depth,
+            in_memory: false,
+        }
+    }
+
+    pub fn cosmac_vip() -> Self {
+        StackConfig::new(Some(12))
+    }
+
+    pub fn schip() -> Self {
+        StackConfig::new(Some(16))
+    }
+
+    // Octo grows its stack as needed
+    pub fn octo() -> Self {
+        StackConfig::new(None)
+    }
+
+    pub fn presets() -> [(&'static str, StackConfig); 3] {
+        [
+            ("cosmac-vip", StackConfig::cosmac_vip()),
+            ("schip", StackConfig::schip()),
+            ("octo", StackConfig::octo()),
+        ]
+    }
+
+    // An unlimited stack in memory keeps growing down into the program
+    pub fn with_memory(mut self, in_memory: bool) -> Self {
+        self.in_memory = in_memory;
+        self
+    }
+
+    pub fn depth(self) -> Option<u16> {
+        self.depth
+    }
+
+    pub fn in_memory(self) -> bool {
+        self.in_memory
+    }
+
+    // True if another return address fits on top of the given number
+    pub fn has_room(self, used: usize) -> bool {
+        self.depth.is_none_or(|depth| used < depth as usize)
+    }
+
+    // Address of the return address at the given index, 0 being the oldest
+    pub fn slot(index: usize) -> u16 {
+        VIP_STACK_TOP.wrapping_sub((index as u16).wrapping_add(1).wrapping_mul(2))
+    }
+}
+
+// 16 like SCHIP, the size of the fixed stack this replaced
+impl Default for StackConfig {
+    fn default() -> Self {
+        StackConfig::schip()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn depth() {
+        assert!(StackConfig::cosmac_vip().has_room(11));
+        assert!(!StackConfig::cosmac_vip().has_room(12));
+        assert!(StackConfig::octo().has_room(100_000));
+        assert!(!StackConfig::default().in_memory());
+        assert!(StackConfig::default().with_memory(true).in_memory());
+    }
+
+    #[test]
+    fn slots() {
+        assert_eq!(0xECE, StackConfig::slot(0));
+        assert_eq!(0xECC, StackConfig::slot(1));
+        // The VIP's twelve stay above 0xEA0, where its stack area starts
+        assert_eq!(0xEB8, StackConfig::slot(11));
+    }
+}
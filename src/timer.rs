@@ -1,38 +1,28 @@
-use std::time::{SystemTime, UNIX_EPOCH};
-
-const MS_PER_TIMER_TICK: u128 = 1000 / 60;
-
+// Counts down at 60Hz. Decrementing is driven by the caller once per frame instead of the wall
+// clock, so a run is reproducible no matter how fast the host executes it.
 #[derive(Debug)]
 pub struct Timer {
-    end_time: u128,
+    ticks: u8,
 }
 
 impl Timer {
     pub fn new() -> Self {
         Timer {
-            end_time: 0,
+            ticks: 0,
         }
     }
 
     pub fn get_timeout(&self) -> u8 {
-        let now = get_time_millis();
-        if now >= self.end_time {
-            return 0;
-        }
-
-        ((self.end_time - now) / MS_PER_TIMER_TICK) as u8
+        self.ticks
     }
 
     pub fn set_timeout(&mut self, ticks: u8) {
-        self.end_time = get_time_millis() + ((ticks as u128) * MS_PER_TIMER_TICK);
+        self.ticks = ticks;
     }
-}
 
-fn get_time_millis() -> u128 {
-    SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .expect("Invalid time")
-        .as_millis()
+    pub fn tick(&mut self) {
+        self.ticks = self.ticks.saturating_sub(1);
+    }
 }
 
 #[cfg(test)]
@@ -49,7 +39,7 @@ mod tests {
         assert!(timer.get_timeout() > max_timeout - 2);
         assert!(timer.get_timeout() <= max_timeout);
 
-        let some_timeout = 10 as u8;
+        let some_timeout = 10_u8;
         timer.set_timeout(some_timeout);
         assert!(timer.get_timeout() > some_timeout - 2);
         assert!(timer.get_timeout() <= some_timeout);
@@ -57,4 +47,17 @@ mod tests {
         timer.set_timeout(0);
         assert_eq!(0, timer.get_timeout());
     }
+
+    #[test]
+    fn countdown() {
+        let mut timer = Timer::new();
+        timer.set_timeout(2);
+
+        timer.tick();
+        assert_eq!(1, timer.get_timeout());
+        timer.tick();
+        assert_eq!(0, timer.get_timeout());
+        timer.tick();
+        assert_eq!(0, timer.get_timeout());
+    }
 }
@@ -0,0 +1,161 @@
+use crate::instr::Instr;
+
+// How much a frame runs
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Timing {
+    // The count passed to CPU::run_frame, however long those instructions would have taken
+    #[default]
+    InstructionsPerFrame,
+    // As many instructions as the COSMAC VIP interpreter gets through in a 60Hz frame, with DRW
+    // waiting for the display interrupt. The count passed to run_frame is ignored.
+    CosmacVip,
+}
+
+// Everything below is in CDP1802 machine cycles of 8 clock cycles. The VIP runs at 1.7609MHz.
+pub const CYCLES_PER_FRAME: i32 = 3668;
+// The CDP1861 fetches 128 lines of 8 bytes per frame by DMA, each byte steals a cycle
+pub const DMA_CYCLES: i32 = 1024;
+// The interrupt routine counts down the timers and points the display at the frame buffer
+pub const INTERRUPT_CYCLES: i32 = 46;
+// Left to the interpreter in every frame
+pub const FRAME_BUDGET: i32 = CYCLES_PER_FRAME - DMA_CYCLES - INTERRUPT_CYCLES;
+
+// Short CDP1802 instructions take two machine cycles, the routines below are counted in those
+const INSTRUCTION_CYCLES: i32 = 2;
+
+// Fetching the two opcode bytes, advancing PC and jumping through the dispatch table
+const FETCH_CYCLES: i32 = 10 * INSTRUCTION_CYCLES;
+// Taken skips have to advance PC once more
+pub const SKIP_CYCLES: i32 = 2 * INSTRUCTION_CYCLES;
+
+// 00E0 points a register at the end of the frame buffer, then stores a zero, decrements and
+// branches back for each of its 256 bytes
+const CLS_SETUP_CYCLES: i32 = 12 * INSTRUCTION_CYCLES;
+const CLS_BYTE_CYCLES: i32 = 3 * INSTRUCTION_CYCLES;
+const FRAME_BUFFER_BYTES: i32 = 256;
+
+// DXYN works out the frame buffer address from Vx and Vy first. Each line is then loaded,
+// shifted right one bit per loop iteration into a second byte when x isn't on a byte boundary,
+// and XORed into the frame buffer with a collision check on both bytes.
+const DRAW_SETUP_CYCLES: i32 = 13 * INSTRUCTION_CYCLES;
+const DRAW_LINE_CYCLES: i32 = 17 * INSTRUCTION_CYCLES;
+const DRAW_SHIFTED_LINE_CYCLES: i32 = 25 * INSTRUCTION_CYCLES;
+const DRAW_SHIFT_BIT_CYCLES: i32 = 4 * INSTRUCTION_CYCLES;
+
+// Cost of an instruction including its fetch, given the registers before it runs. Taken skips
+// cost SKIP_CYCLES on top.
+pub fn cycles(instr: Instr, registers: &[u8; 16]) -> i32 {
+    let execute = match instr {
+        // Only the jump into the routine, the CPU adds what the routine takes
+        Instr::Sys(_) => 10,
+        Instr::Cls => CLS_SETUP_CYCLES + FRAME_BUFFER_BYTES * CLS_BYTE_CYCLES,
+        Instr::Ret => 10,
+        Instr::Jp(_) => 12,
+        Instr::Call(_) => 26,
+        Instr::SeImm(..) | Instr::SneImm(..) => 10,
+        Instr::SeReg(..) | Instr::SneReg(..) => 14,
+        Instr::LdImm(..) => 6,
+        Instr::AddImm(..) => 10,
+        // The VIP builds each ALU instruction in RAM and runs it from there
+        Instr::LdReg(..)
+        | Instr::Or(..)
+        | Instr::And(..)
+        | Instr::Xor(..)
+        | Instr::Add(..)
+        | Instr::Sub(..)
+        | Instr::Shr(..)
+        | Instr::Subn(..)
+        | Instr::Shl(..) => 44,
+        Instr::LdAddr(_) => 12,
+        Instr::JpReg(..) => 22,
+        Instr::Rnd(..) => 36,
+        Instr::Drw(x, _, n) => draw_cycles(registers[x], n),
+        Instr::Skp(_) | Instr::Sknp(_) => 14,
+        // Polled once per fetch until a key is down
+        Instr::LdVxKey(_) => 10,
+        Instr::LdVxDt(_) | Instr::LdDtVx(_) | Instr::LdStVx(_) => 10,
+        Instr::AddAddr(_) => 16,
+        Instr::LdFont(_) => 20,
+        // Every digit is counted up by repeated subtraction
+        Instr::LdBcd(x) => {
+            let value = registers[x] as i32;
+            80 + 16 * (value / 100 + value / 10 % 10 + value % 10)
+        }
+        Instr::LdMemVx(x) | Instr::LdVxMem(x) => 14 + 14 * (x as i32 + 1),
+        // The CHIP-8X interpreter hasn't been modelled, these are rough guesses
+        Instr::CycleBackground => 16,
+        Instr::ColourZones(..) | Instr::ColourRows(..) => 60,
+        Instr::SkpSecond(_) | Instr::SknpSecond(_) => 14,
+        Instr::Out(_) | Instr::In(_) => 10,
+        // MEGA-CHIP never ran on a VIP
+        Instr::MegaOff
+        | Instr::MegaOn
+        | Instr::LdAddrLong(_)
+        | Instr::LdPalette(_)
+        | Instr::SpriteWidth(_)
+        | Instr::SpriteHeight(_)
+        | Instr::ScreenAlpha(_)
+        | Instr::PlaySample(_)
+        | Instr::StopSample
+        | Instr::Blend(_)
+        | Instr::CollisionColour(_)
+        | Instr::ScrollUp(_)
+        | Instr::ScrollDown(_)
+        | Instr::ScrollRight
+        | Instr::ScrollLeft
+        | Instr::Exit
+        | Instr::LowRes
+        | Instr::HighRes
+        | Instr::LdBigFont(_)
+        | Instr::SaveFlags(_)
+        | Instr::LoadFlags(_) => 10,
+    };
+    FETCH_CYCLES + execute
+}
+
+fn draw_cycles(x: u8, height: u8) -> i32 {
+    let shift = (x % 8) as i32;
+    let per_line = if shift == 0 {
+        DRAW_LINE_CYCLES
+    } else {
+        DRAW_SHIFTED_LINE_CYCLES + DRAW_SHIFT_BIT_CYCLES * shift
+    };
+    DRAW_SETUP_CYCLES + per_line * height as i32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn costs() {
+        let mut registers = [0; 16];
+        assert_eq!(26, cycles(Instr::LdImm(0, 1), &registers));
+        assert_eq!(1580, cycles(Instr::Cls, &registers));
+
+        // A 5 line sprite on a byte boundary, one pixel past it and at the furthest shift
+        registers[1] = 8;
+        assert_eq!(216, cycles(Instr::Drw(1, 2, 5), &registers));
+        registers[1] = 9;
+        assert_eq!(336, cycles(Instr::Drw(1, 2, 5), &registers));
+        registers[1] = 15;
+        assert_eq!(576, cycles(Instr::Drw(1, 2, 5), &registers));
+
+        // Unaligned sprites take longer, the further they are shifted
+        registers[1] = 8;
+        let aligned = cycles(Instr::Drw(1, 2, 5), &registers);
+        registers[1] = 9;
+        let shifted = cycles(Instr::Drw(1, 2, 5), &registers);
+        registers[1] = 15;
+        assert!(aligned < shifted);
+        assert!(shifted < cycles(Instr::Drw(1, 2, 5), &registers));
+        assert!(cycles(Instr::Drw(1, 2, 1), &registers) < cycles(Instr::Drw(1, 2, 2), &registers));
+
+        registers[3] = 0;
+        let zero = cycles(Instr::LdBcd(3), &registers);
+        registers[3] = 199;
+        assert_eq!(zero + 16 * 19, cycles(Instr::LdBcd(3), &registers));
+
+        assert!(cycles(Instr::LdMemVx(0), &registers) < cycles(Instr::LdMemVx(15), &registers));
+    }
+}
@@ -0,0 +1,107 @@
+use crate::megachip::MEGA_MEMORY;
+use crate::memory::{PROGRAM_OFFSET, TOTAL_MEMORY};
+use crate::screen::{HIRES_HEIGHT, SCREEN_HEIGHT, SCREEN_WIDTH};
+
+// The first instruction of hi-res programs, jumping to the code that sets up the display
+pub const HIRES_BOOT: u16 = 0x1260;
+// Where hi-res programs continue once the display is set up
+pub const HIRES_START: u16 = 0x2C0;
+// The CHIP-8X interpreter takes up 0x000-0x2FF, its programs start after it
+pub const CHIP8X_START: u16 = 0x300;
+// Switches MEGA-CHIP programs to the 256x192 display, usually their first instruction
+pub const MEGA_ON: u16 = 0x0011;
+
+// Interpreters that extend CHIP-8 with instructions of their own. Unlike the quirks these change
+// what opcodes mean, e.g. Bxyn sets colours instead of jumping on CHIP-8X.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Variant {
+    #[default]
+    Chip8,
+    // The VIP interpreter for the VP-590 colour board and the VP-580 second keypad
+    Chip8X,
+    // The VIP interpreter patched for a 64x64 display. Programs bring machine code at 0x260 that
+    // switches the display over, which is skipped by continuing at 0x2C0 right away. 0230 clears
+    // the screen.
+    HiRes,
+    // Adds a 256x192 display with 256 colours, 24 bit I and sampled sound, once 0011 turns mega
+    // mode on. Until then, or after 0010, it's SCHIP: scrolling, 128x64 hi-res, 16x16 sprites,
+    // big digits and the flag registers.
+    MegaChip,
+}
+
+impl Variant {
+    pub fn all() -> [Variant; 4] {
+        [Variant::Chip8, Variant::Chip8X, Variant::HiRes, Variant::MegaChip]
+    }
+
+    // Same names as the platforms of the ROM database
+    pub fn name(self) -> &'static str {
+        match self {
+            Variant::Chip8 => "chip-8",
+            Variant::Chip8X => "chip-8x",
+            Variant::HiRes => "chip-8-hires",
+            Variant::MegaChip => "megachip",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Variant> {
+        Variant::all().iter().copied().find(|variant| variant.name() == name)
+    }
+
+    // Width and height of the display, MEGA-CHIP starts out with the plain one
+    pub fn screen_size(self) -> (usize, usize) {
+        match self {
+            Variant::Chip8 | Variant::Chip8X | Variant::MegaChip => (SCREEN_WIDTH, SCREEN_HEIGHT),
+            Variant::HiRes => (SCREEN_WIDTH, HIRES_HEIGHT),
+        }
+    }
+
+    // Where programs are loaded and start executing
+    pub fn program_start(self) -> u16 {
+        match self {
+            Variant::Chip8X => CHIP8X_START,
+            Variant::Chip8 | Variant::HiRes | Variant::MegaChip => PROGRAM_OFFSET as u16,
+        }
+    }
+
+    // Bytes of memory
+    pub fn memory_size(self) -> usize {
+        match self {
+            Variant::MegaChip => MEGA_MEMORY,
+            _ => TOTAL_MEMORY,
+        }
+    }
+
+    // A guess from the first instruction, hi-res programs all start with the boot jump and
+    // MEGA-CHIP ones by turning mega mode on, while others rarely do either
+    pub fn detect(rom: &[u8]) -> Variant {
+        if rom.starts_with(&HIRES_BOOT.to_be_bytes()) {
+            Variant::HiRes
+        } else if rom.starts_with(&MEGA_ON.to_be_bytes()) {
+            Variant::MegaChip
+        } else {
+            Variant::Chip8
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names() {
+        for variant in Variant::all().iter() {
+            assert_eq!(Some(*variant), Variant::from_name(variant.name()));
+        }
+        assert_eq!(None, Variant::from_name("schip"));
+    }
+
+    #[test]
+    fn detect() {
+        assert_eq!(Variant::HiRes, Variant::detect(&[0x12, 0x60, 0x00, 0xE0]));
+        assert_eq!(Variant::MegaChip, Variant::detect(&[0x00, 0x11, 0x00, 0xE0]));
+        assert_eq!(Variant::Chip8, Variant::detect(&[0x12, 0x62]));
+        assert_eq!(Variant::Chip8, Variant::detect(&[0x12]));
+    }
+}
@@ -0,0 +1,313 @@
+use rand::RngCore;
+use wasm_bindgen::prelude::*;
+
+use std::collections::VecDeque;
+
+use crate::audio::AudioSink;
+use crate::cartridge::Cartridge;
+use crate::cpu::{CpuError, CPU, DEFAULT_INSTRUCTIONS_PER_FRAME};
+use crate::font::{Font, FontSet};
+use crate::host::{HeldKeys, Machine};
+use crate::movie::{Movie, Recorder};
+use crate::quirks::Quirks;
+use crate::recompiler;
+use crate::romdb::{self, Colors, RomDatabase};
+use crate::stack::StackConfig;
+use crate::timing::Timing;
+use crate::variant::Variant;
+
+// How much audio waits for the AudioWorklet at most, older samples are dropped
+const MAX_QUEUED_MS: usize = 250;
+
+// Keeps the audio of every frame until the AudioWorklet asks for it
+struct AudioQueue {
+    sample_rate: u32,
+    samples: VecDeque<f32>,
+}
+
+impl AudioSink for AudioQueue {
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn queue_samples(&mut self, samples: &[f32]) {
+        self.samples.extend(samples);
+        let max = self.sample_rate as usize * MAX_QUEUED_MS / 1000;
+        let excess = self.samples.len().saturating_sub(max);
+        self.samples.drain(..excess);
+    }
+}
+
+// The browser paces the frames and pulls the screen itself, keys arrive as events
+type WebMachine = Machine<(), AudioQueue, HeldKeys, ()>;
+
+#[wasm_bindgen]
+pub struct Emulator {
+    machine: WebMachine,
+    db: RomDatabase,
+    // The loaded program, recordings restart it
+    rom: Vec<u8>,
+    recorder: Option<Recorder>,
+    // For screen_rgba, unless the variant has colours of its own
+    colors: Colors,
+}
+
+const DEFAULT_COLORS: Colors = Colors {
+    foreground: [0xFF, 0xFF, 0xFF],
+    background: [0x00, 0x00, 0x00],
+};
+
+#[wasm_bindgen]
+impl Emulator {
+    #[wasm_bindgen(constructor)]
+    pub fn new(sample_rate: u32) -> Emulator {
+        let audio = AudioQueue {
+            sample_rate,
+            samples: VecDeque::new(),
+        };
+        Emulator {
+            machine: Machine::new(CPU::new()).with_audio(audio).with_input(HeldKeys::default()),
+            db: RomDatabase::builtin(),
+            rom: Vec::new(),
+            recorder: None,
+            colors: DEFAULT_COLORS,
+        }
+    }
+
+    // Known programs also get their quirks and speed, the title is returned. Unknown ones start
+    // from the defaults, not with what the previous program needed, on the variant they look
+    // like. It is set before loading since MEGA-CHIP programs don't fit into 4K.
+    pub fn load_rom(&mut self, rom: &[u8]) -> Result<Option<String>, JsValue> {
+        let cpu = self.machine.cpu_mut();
+        cpu.set_quirks(Quirks::default());
+        cpu.set_variant(Variant::detect(rom));
+        cpu.set_stack_config(StackConfig::default());
+        let info = self
+            .db
+            .load_rom(cpu, rom)
+            .map_err(|err| JsValue::from_str(&err.to_string()))?;
+
+        self.machine
+            .set_instructions_per_frame(info.map_or(DEFAULT_INSTRUCTIONS_PER_FRAME, |info| info.instructions_per_frame()));
+        self.colors = info.and_then(|info| info.colors).unwrap_or(DEFAULT_COLORS);
+        self.rom = rom.to_vec();
+        self.recorder = None;
+        Ok(info.map(|info| info.title.clone()))
+    }
+
+    // Loads an Octo cartridge GIF and applies its quirks and speed
+    pub fn load_cartridge(&mut self, data: &[u8]) -> Result<(), JsValue> {
+        let cartridge = Cartridge::from_gif(data).map_err(|err| JsValue::from_str(&err.to_string()))?;
+        cartridge
+            .load(self.machine.cpu_mut())
+            .map_err(|err| JsValue::from_str(&err.to_string()))?;
+        self.machine.set_instructions_per_frame(cartridge.instructions_per_frame());
+        self.colors = cartridge.colors().unwrap_or(DEFAULT_COLORS);
+        self.rom = cartridge.program;
+        self.recorder = None;
+        Ok(())
+    }
+
+    // Adds entries from a user supplied database file, see romdb.rs for the format
+    pub fn add_rom_database(&mut self, json: &str) -> Result<(), JsValue> {
+        let db = RomDatabase::from_json(json).map_err(|err| JsValue::from_str(&err.to_string()))?;
+        self.db.extend(db);
+        Ok(())
+    }
+
+    // One of "vip", "dream6800", "eti660", "fish" or "octo", stored at the given address
+    pub fn set_font(&mut self, name: &str, address: u16) -> Result<(), JsValue> {
+        let set = FontSet::from_name(name).ok_or_else(|| JsValue::from_str(&format!("Unknown font: {}", name)))?;
+        self.set_custom_font(set.glyphs(), address)
+    }
+
+    // 16 glyphs of 5 bytes each
+    pub fn set_custom_font(&mut self, glyphs: &[u8], address: u16) -> Result<(), JsValue> {
+        let font = Font::custom(glyphs)
+            .and_then(|font| font.with_address(address))
+            .map_err(|err| JsValue::from_str(&err.to_string()))?;
+        self.machine.cpu_mut().set_font(font);
+        Ok(())
+    }
+
+    // Runs at the speed of the original interpreter instead of a fixed number of instructions
+    pub fn set_vip_timing(&mut self, enabled: bool) {
+        self.machine.cpu_mut().set_timing(if enabled { Timing::CosmacVip } else { Timing::InstructionsPerFrame });
+    }
+
+    // Runs 0nnn routines as CDP1802 machine code instead of rejecting them
+    pub fn set_machine_code(&mut self, enabled: bool) {
+        self.machine.cpu_mut().set_machine_code(enabled);
+    }
+
+    // "chip-8", "chip-8x", "chip-8-hires" or "megachip"
+    pub fn set_variant(&mut self, name: &str) -> Result<(), JsValue> {
+        let variant = Variant::from_name(name).ok_or_else(|| JsValue::from_str(&format!("Unknown variant: {}", name)))?;
+        self.machine.cpu_mut().set_variant(variant);
+        Ok(())
+    }
+
+    // "cosmac-vip", "schip" or "octo", optionally kept in memory below 0xED0 like the VIP does
+    pub fn set_stack(&mut self, name: &str, in_memory: bool) -> Result<(), JsValue> {
+        let (_, config) = StackConfig::presets()
+            .iter()
+            .copied()
+            .find(|(preset, _)| *preset == name)
+            .ok_or_else(|| JsValue::from_str(&format!("Unknown stack: {}", name)))?;
+        self.machine.cpu_mut().set_stack_config(config.with_memory(in_memory));
+        Ok(())
+    }
+
+    // Both as #rrggbb
+    pub fn set_colors(&mut self, foreground: &str, background: &str) -> Result<(), JsValue> {
+        let parse = |value: &str| romdb::parse_rgb(value).ok_or_else(|| JsValue::from_str(&format!("Invalid colour: {}", value)));
+        self.colors = Colors {
+            foreground: parse(foreground)?,
+            background: parse(background)?,
+        };
+        Ok(())
+    }
+
+    // Four bytes per pixel, row by row, ready for an ImageData
+    pub fn screen_rgba(&self) -> Vec<u8> {
+        let screen = self.machine.cpu().screen();
+        let mut rgba = vec![0; screen.width() * screen.height() * 4];
+        screen.render_rgba(self.colors.foreground, self.colors.background, &mut rgba);
+        rgba
+    }
+
+    pub fn reset(&mut self) {
+        self.machine.cpu_mut().reset();
+    }
+
+    pub fn tick(&mut self) -> Result<(), JsValue> {
+        self.machine.cpu_mut().tick().map_err(to_js_error)
+    }
+
+    // One bit per key, bit 0 is key 0. Takes effect right away, for tick as well.
+    pub fn set_keys(&mut self, state: u16) {
+        self.machine.input_mut().0 = state;
+        self.machine.cpu_mut().keypad_mut().set_state(state);
+    }
+
+    // The second keypad of CHIP-8X, same layout
+    pub fn set_second_keys(&mut self, state: u16) {
+        self.machine.cpu_mut().second_keypad_mut().set_state(state);
+    }
+
+    // The byte the next CHIP-8X FxFB reads
+    pub fn set_input_port(&mut self, value: u8) {
+        self.machine.cpu_mut().set_input_port(value);
+    }
+
+    pub fn output_port(&self) -> u8 {
+        self.machine.cpu().output_port()
+    }
+
+    // Should be called at 60Hz, e.g. from requestAnimationFrame
+    pub fn run_frame(&mut self) -> Result<(), JsValue> {
+        if let Some(recorder) = &mut self.recorder {
+            recorder.record_frame(self.machine.cpu());
+        }
+        self.machine.run_frame().map_err(to_js_error)
+    }
+
+    // Restarts the loaded program and records every following frame, see movie.rs
+    pub fn start_recording(&mut self, seed: u64) -> Result<(), JsValue> {
+        let instructions_per_frame = self.machine.instructions_per_frame();
+        let recorder = Recorder::new(self.machine.cpu_mut(), &self.rom, seed, instructions_per_frame)
+            .map_err(|err| JsValue::from_str(&err.to_string()))?;
+        self.recorder = Some(recorder);
+        Ok(())
+    }
+
+    // The movie file, if a recording was running
+    pub fn stop_recording(&mut self) -> Option<Vec<u8>> {
+        let recorder = self.recorder.take()?;
+        Some(recorder.finish(self.machine.cpu()).to_bytes())
+    }
+
+    // Replays a movie of the loaded program and fails if it doesn't end in the recorded state
+    pub fn replay_movie(&mut self, movie: &[u8]) -> Result<(), JsValue> {
+        let movie = Movie::from_bytes(movie).map_err(|err| JsValue::from_str(&err.to_string()))?;
+        self.recorder = None;
+        movie
+            .replay(self.machine.cpu_mut(), &self.rom)
+            .map_err(|err| JsValue::from_str(&err.to_string()))?;
+        self.machine.set_instructions_per_frame(movie.instructions_per_frame);
+        Ok(())
+    }
+
+    // Ticks the timers and queues the frame's audio, for hosts that drive a recompiled module
+    // instead of run_frame
+    pub fn end_frame(&mut self) {
+        self.machine.end_frame();
+    }
+
+    // The loaded program as a WebAssembly module. It imports env.random, which has to call
+    // random_byte, and exports memory and run(budget). Per frame: write_state into its memory,
+    // call run, read_state, and if fewer instructions than the budget ran, tick once and repeat
+    // with the rest. Finally end_frame. VIP timing counts cycles, which the module can't, so
+    // it isn't available then.
+    pub fn recompile(&self) -> Result<Vec<u8>, JsValue> {
+        if self.machine.cpu().timing() != Timing::InstructionsPerFrame {
+            return Err(JsValue::from_str("Can't recompile with VIP timing"));
+        }
+        Ok(recompiler::compile(self.machine.cpu()))
+    }
+
+    pub fn write_state(&self, memory: &mut [u8]) {
+        recompiler::write_state(self.machine.cpu(), memory);
+    }
+
+    pub fn read_state(&mut self, memory: &[u8]) {
+        recompiler::read_state(self.machine.cpu_mut(), memory);
+    }
+
+    pub fn random_byte(&mut self) -> u8 {
+        self.machine.cpu_mut().rng_mut().next_u32() as u8
+    }
+
+    // Called from the AudioWorklet's process() with its output channel. Takes what run_frame
+    // produced so far, silence if that isn't enough.
+    pub fn fill_audio(&mut self, buffer: &mut [f32]) {
+        let queue = &mut self.machine.audio_mut().samples;
+        for out in buffer.iter_mut() {
+            *out = queue.pop_front().unwrap_or(0.0);
+        }
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        let audio = self.machine.audio_mut();
+        audio.sample_rate = sample_rate;
+        audio.samples.clear();
+        self.machine.sync_sample_rate();
+    }
+
+    pub fn set_beeper_frequency(&mut self, frequency: f32) {
+        self.machine.beeper_mut().set_frequency(frequency);
+    }
+
+    pub fn set_beeper_volume(&mut self, volume: f32) {
+        self.machine.beeper_mut().set_volume(volume);
+    }
+}
+
+fn to_js_error(err: CpuError) -> JsValue {
+    JsValue::from_str(&err.to_string())
+}
+
+#[cfg(all(test, target_arch = "wasm32"))]
+mod tests {
+    use wasm_bindgen_test::*;
+
+    use super::*;
+
+    // Seeding from the browser's crypto has to work, CPU::new panics without an entropy source
+    #[wasm_bindgen_test]
+    fn construct() {
+        let mut emulator = Emulator::new(44100);
+        emulator.load_rom(&[0x12, 0x00]).unwrap();
+        emulator.run_frame().unwrap();
+    }
+}
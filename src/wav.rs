@@ -0,0 +1,125 @@
+use std::io;
+use std::io::prelude::*;
+
+use crate::audio::AudioSink;
+
+const BITS_PER_SAMPLE: u16 = 16;
+const CHANNELS: u16 = 1;
+
+// Collects queued samples in memory so they can be written out as a PCM WAV file afterwards
+#[derive(Debug)]
+pub struct WavRecorder {
+    sample_rate: u32,
+    samples: Vec<f32>,
+}
+
+impl WavRecorder {
+    pub fn new(sample_rate: u32) -> Self {
+        WavRecorder {
+            sample_rate,
+            samples: Vec::new(),
+        }
+    }
+
+    pub fn samples(&self) -> &[f32] {
+        &self.samples
+    }
+
+    pub fn write_to<W: Write>(&self, out: &mut W) -> Result<(), io::Error> {
+        write_wav(out, self.sample_rate, &self.samples)
+    }
+}
+
+impl AudioSink for WavRecorder {
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn queue_samples(&mut self, samples: &[f32]) {
+        self.samples.extend_from_slice(samples);
+    }
+}
+
+// Encodes mono samples as 16 bit signed PCM, format: http://soundfile.sapp.org/doc/WaveFormat/
+pub fn write_wav<W: Write>(out: &mut W, sample_rate: u32, samples: &[f32]) -> Result<(), io::Error> {
+    let block_align = CHANNELS * BITS_PER_SAMPLE / 8;
+    let byte_rate = sample_rate * block_align as u32;
+    let data_size = (samples.len() * block_align as usize) as u32;
+
+    out.write_all(b"RIFF")?;
+    out.write_all(&(36 + data_size).to_le_bytes())?;
+    out.write_all(b"WAVE")?;
+
+    out.write_all(b"fmt ")?;
+    out.write_all(&16_u32.to_le_bytes())?;
+    out.write_all(&1_u16.to_le_bytes())?; // PCM
+    out.write_all(&CHANNELS.to_le_bytes())?;
+    out.write_all(&sample_rate.to_le_bytes())?;
+    out.write_all(&byte_rate.to_le_bytes())?;
+    out.write_all(&block_align.to_le_bytes())?;
+    out.write_all(&BITS_PER_SAMPLE.to_le_bytes())?;
+
+    out.write_all(b"data")?;
+    out.write_all(&data_size.to_le_bytes())?;
+    for sample in samples {
+        let pcm = (sample.clamp(-1.0, 1.0) * i16::MAX as f32).round() as i16;
+        out.write_all(&pcm.to_le_bytes())?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::CPU;
+    use crate::host::Machine;
+
+    // FNV-1a, good enough to notice any change in the rendered output
+    fn checksum(data: &[u8]) -> u64 {
+        data.iter().fold(0xcbf29ce484222325, |hash, &byte| {
+            (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+        })
+    }
+
+    fn render_rom(rom: &[u8], frames: u32) -> Vec<u8> {
+        let mut cpu = CPU::new();
+        cpu.load_rom(rom).unwrap();
+
+        let mut machine = Machine::new(cpu).with_audio(WavRecorder::new(8000));
+        machine.run_frames(frames).unwrap();
+
+        let mut wav = Vec::new();
+        machine.audio().write_to(&mut wav).unwrap();
+        wav
+    }
+
+    #[test]
+    fn header() {
+        let mut wav = Vec::new();
+        write_wav(&mut wav, 8000, &[0.0, 1.0, -1.0]).unwrap();
+
+        assert_eq!(44 + 6, wav.len());
+        assert_eq!(b"RIFF", &wav[0..4]);
+        assert_eq!(b"WAVE", &wav[8..12]);
+        assert_eq!(8000, u32::from_le_bytes([wav[24], wav[25], wav[26], wav[27]]));
+        assert_eq!(6, u32::from_le_bytes([wav[40], wav[41], wav[42], wav[43]]));
+        assert_eq!([0x00, 0x00, 0xFF, 0x7F, 0x01, 0x80], wav[44..]);
+    }
+
+    #[test]
+    fn golden_beep() {
+        let rom = [
+            0x6A, 0x1E, // LD VA, 30
+            0xFA, 0x18, // LD ST, VA
+            0x12, 0x04, // JP 0x204
+        ];
+        let wav = render_rom(&rom, 60);
+
+        // One second of audio at 8kHz, the first half beeping
+        assert_eq!(44 + 2 * 8000, wav.len());
+        assert!(wav[44 + 2 * 3990..44 + 2 * 4000].iter().any(|&b| b != 0));
+        assert!(wav[44 + 2 * 4000..].iter().all(|&b| b == 0));
+        assert_eq!(0x3728c2fdb9864b28, checksum(&wav));
+    }
+}